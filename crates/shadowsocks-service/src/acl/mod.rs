@@ -19,12 +19,35 @@ use log::{trace, warn};
 use once_cell::sync::Lazy;
 use regex::bytes::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 
-use shadowsocks::{context::Context, relay::socks5::Address};
+use shadowsocks::{config::ServerAddr, context::Context, relay::socks5::Address};
 
+pub use self::rule_stat::AclRuleStat;
 use self::sub_domains_tree::SubDomainsTree;
 
+mod rule_stat;
 mod sub_domains_tree;
 
+/// Name of the rule for addresses that don't match any rules, which are handled by the default mode
+pub const DEFAULT_RULE_NAME: &str = "default";
+
+/// Decision made by ACL for a target address, with the rule that made it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclDecision {
+    /// Target should be bypassed (for client)
+    pub bypassed: bool,
+    /// The matched rule, or `DEFAULT_RULE_NAME`
+    pub rule: String,
+}
+
+impl AclDecision {
+    fn new(bypassed: bool, rule: Option<String>) -> AclDecision {
+        AclDecision {
+            bypassed,
+            rule: rule.unwrap_or_else(|| DEFAULT_RULE_NAME.to_owned()),
+        }
+    }
+}
+
 /// Strategy mode that ACL is running
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Mode {
@@ -122,12 +145,36 @@ impl Rules {
         }
     }
 
+    /// Find the rule that matches the specified address
+    fn find_ip_matched(&self, addr: &IpAddr) -> Option<String> {
+        match addr {
+            IpAddr::V4(v4) => self.ipv4.supernet(v4).map(|n| n.to_string()),
+            IpAddr::V6(v6) => self.ipv6.supernet(v6).map(|n| n.to_string()),
+        }
+    }
+
     /// Check if the specified ASCII host matches any rules
     fn check_host_matched(&self, host: &str) -> bool {
         let host = host.trim_end_matches('.'); // FQDN, removes the last `.`
         self.rule_set.contains(host) || self.rule_tree.contains(host) || self.rule_regex.is_match(host.as_bytes())
     }
 
+    /// Find the rule that matches the specified ASCII host
+    fn find_host_matched(&self, host: &str) -> Option<String> {
+        let host = host.trim_end_matches('.'); // FQDN, removes the last `.`
+        if self.rule_set.contains(host) {
+            return Some(format!("|{}", host));
+        }
+        if let Some(domain) = self.rule_tree.find(host) {
+            return Some(format!("||{}", domain));
+        }
+        self.rule_regex
+            .matches(host.as_bytes())
+            .iter()
+            .next()
+            .map(|idx| self.rule_regex.patterns()[idx].clone())
+    }
+
     /// Check if there are no rules for IP addresses
     fn is_ip_empty(&self) -> bool {
         self.ipv4.is_empty() && self.ipv6.is_empty()
@@ -510,24 +557,56 @@ impl AccessControl {
     ///
     /// This function may perform a DNS resolution
     pub async fn check_target_bypassed(&self, context: &Context, addr: &Address) -> bool {
+        self.check_target(context, addr).await.bypassed
+    }
+
+    /// Check if `IpAddr` should be proxied, with the matched rule
+    fn check_ip_rule(&self, ip: &IpAddr) -> AclDecision {
+        match self.mode {
+            Mode::BlackList => {
+                let rule = self.black_list.find_ip_matched(ip);
+                AclDecision::new(rule.is_some(), rule)
+            }
+            Mode::WhiteList => {
+                let rule = self.white_list.find_ip_matched(ip);
+                AclDecision::new(rule.is_none(), rule)
+            }
+        }
+    }
+
+    /// Check if target address should be bypassed (for client), and which rule makes the decision
+    ///
+    /// This function may perform a DNS resolution
+    pub async fn check_target(&self, context: &Context, addr: &Address) -> AclDecision {
         match *addr {
-            Address::SocketAddress(ref addr) => !self.check_ip_in_proxy_list(&addr.ip()),
+            Address::SocketAddress(ref addr) => self.check_ip_rule(&addr.ip()),
             // Resolve hostname and check the list
             Address::DomainNameAddress(ref host, port) => {
-                if let Some(value) = self.check_host_in_proxy_list(host) {
-                    return !value;
+                let ascii_host = Self::convert_to_ascii(host);
+                // Addresses in proxy_list will be proxied
+                if let Some(rule) = self.white_list.find_host_matched(&ascii_host) {
+                    return AclDecision::new(false, Some(rule));
+                }
+                // Addresses in bypass_list will be bypassed
+                if let Some(rule) = self.black_list.find_host_matched(&ascii_host) {
+                    return AclDecision::new(true, Some(rule));
                 }
                 if self.is_ip_empty() {
-                    return !self.is_default_in_proxy_list();
+                    return AclDecision::new(!self.is_default_in_proxy_list(), None);
                 }
+                let mut decision = AclDecision::new(false, None);
                 if let Ok(vaddr) = context.dns_resolve(host, port).await {
                     for addr in vaddr {
-                        if !self.check_ip_in_proxy_list(&addr.ip()) {
-                            return true;
+                        let d = self.check_ip_rule(&addr.ip());
+                        if d.bypassed {
+                            return d;
+                        }
+                        if d.rule != DEFAULT_RULE_NAME {
+                            decision = d;
                         }
                     }
                 }
-                false
+                decision
             }
         }
     }
@@ -570,4 +649,16 @@ impl AccessControl {
             }
         }
     }
+
+    /// Check if address of a remote server is blocked by outbound rules (for local)
+    ///
+    /// NOTE: `ServerAddr::DomainName` is only validated by host rules, it is not resolved
+    pub fn check_server_blocked(&self, addr: &ServerAddr) -> bool {
+        match *addr {
+            ServerAddr::SocketAddr(ref saddr) => self.outbound_block.check_ip_matched(&saddr.ip()),
+            ServerAddr::DomainName(ref host, _) => {
+                self.outbound_block.check_host_matched(&Self::convert_to_ascii(host))
+            }
+        }
+    }
 }
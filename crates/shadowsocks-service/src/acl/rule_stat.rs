@@ -0,0 +1,37 @@
+//! Statistic of ACL rules
+
+use std::{collections::HashMap, sync::Mutex};
+
+/// Counts connections that are decided by each ACL rule
+#[derive(Debug, Default)]
+pub struct AclRuleStat {
+    conns: Mutex<HashMap<String, u64>>,
+}
+
+impl AclRuleStat {
+    /// Create an empty statistic
+    pub fn new() -> AclRuleStat {
+        AclRuleStat::default()
+    }
+
+    /// Increase connection count of `rule`
+    pub fn incr_conn(&self, rule: &str) {
+        let mut conns = self.conns.lock().unwrap();
+        match conns.get_mut(rule) {
+            Some(count) => *count += 1,
+            None => {
+                conns.insert(rule.to_owned(), 1);
+            }
+        }
+    }
+
+    /// Connection count of `rule`
+    pub fn conn_count(&self, rule: &str) -> u64 {
+        self.conns.lock().unwrap().get(rule).copied().unwrap_or(0)
+    }
+
+    /// Connection counts of all rules
+    pub fn conn_counts(&self) -> HashMap<String, u64> {
+        self.conns.lock().unwrap().clone()
+    }
+}
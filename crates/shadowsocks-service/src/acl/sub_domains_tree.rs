@@ -68,6 +68,25 @@ impl SubDomainsTree {
         false
     }
 
+    /// Find the included domain that `value` is a subdomain of
+    pub fn find(&self, value: &str) -> Option<String> {
+        let mut current_map = &self.0;
+        let mut parts = Vec::new();
+        for part in value.rsplit('.') {
+            if let Some(el) = current_map.get(part) {
+                parts.push(part);
+                if el.included {
+                    parts.reverse();
+                    return Some(parts.join("."));
+                }
+                current_map = &el.children;
+            } else {
+                break;
+            }
+        }
+        None
+    }
+
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
@@ -41,6 +41,8 @@
 //!
 //! These defined server will be used with a load balancing algorithm.
 
+#[cfg(feature = "local-http")]
+use std::collections::BTreeMap;
 use std::{
     borrow::Cow,
     convert::{From, Infallible},
@@ -58,25 +60,34 @@ use std::{
 };
 
 use cfg_if::cfg_if;
-#[cfg(feature = "local-tun")]
+#[cfg(feature = "local")]
 use ipnet::IpNet;
 use log::warn;
 use serde::{Deserialize, Serialize};
 #[cfg(any(feature = "local-tunnel", feature = "local-dns"))]
 use shadowsocks::relay::socks5::Address;
 use shadowsocks::{
-    config::{ManagerAddr, Mode, ReplayAttackPolicy, ServerAddr, ServerConfig, ServerWeight},
+    config::{
+        ConnectStrategy,
+        HttpFronting,
+        ManagerAddr,
+        Mode,
+        ReplayAttackPolicy,
+        ServerAddr,
+        ServerConfig,
+        ServerWeight,
+    },
     crypto::CipherKind,
     plugin::PluginConfig,
 };
 #[cfg(feature = "trust-dns")]
 use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig};
 
-use crate::acl::AccessControl;
 #[cfg(feature = "local-dns")]
 use crate::local::dns::NameServerAddr;
 #[cfg(feature = "local")]
 use crate::local::socks::config::Socks5AuthConfig;
+use crate::{acl::AccessControl, net::WorkerPoolConfig};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -100,12 +111,34 @@ struct SSSecurityReplayAttackConfig {
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct SSBalancerConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_server_rtt: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     check_interval: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     check_best_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reload_grace: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_timeout_rtt_factor: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_timeout_floor: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_failure_policy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_failure_retry_after: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_failure_retries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_down_cooldown: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    egress_ip_probe: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    egress_ip_probe_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    egress_ip_probe_max_redirects: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -142,11 +175,31 @@ struct SSConfig {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_timeout: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strict_servers: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_retries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_read_ahead: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slow_connection_threshold: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_pending_dns_resolutions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    direct_connect_strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_timeout: Option<u64>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_timeout: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_max_associations: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_max_datagram: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_oversized_datagram: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none", alias = "shadowsocks")]
     servers: Option<Vec<SSServerExtConfig>>,
@@ -164,6 +217,12 @@ struct SSConfig {
     no_delay: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     keep_alive: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    tcp_congestion: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    tcp_user_timeout: Option<u64>,
 
     #[cfg(all(unix, not(target_os = "android")))]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -176,11 +235,33 @@ struct SSConfig {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     fast_open: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listen_backlog: Option<u32>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[cfg(any(target_os = "linux", target_os = "android"))]
     outbound_fwmark: Option<u32>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_proxy_protocol: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_conns_per_host: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_limit_bps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    global_rate_limit_bps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_addr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_stats: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    worker_pool_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    worker_queue_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_pending_tasks: Option<usize>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     security: Option<SSSecurityConfig>,
 
@@ -239,6 +320,10 @@ struct SSLocalExtConfig {
     #[cfg(feature = "local-dns")]
     #[serde(skip_serializing_if = "Option::is_none")]
     remote_dns_port: Option<u16>,
+    /// Hostnames answered with unspecified addresses
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_sinkhole_hosts: Option<Vec<String>>,
 
     /// Tunnel
     #[cfg(feature = "local-tunnel")]
@@ -256,10 +341,43 @@ struct SSLocalExtConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     tun_interface_address: Option<String>,
 
+    /// Networks of clients allowed to connect, for socks and http
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_clients: Option<Vec<String>>,
+
+    /// Remarks of servers used by this local server
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    servers: Option<Vec<String>>,
+
     /// SOCKS5
     #[cfg(feature = "local")]
     #[serde(skip_serializing_if = "Option::is_none")]
     socks5_auth_config_path: Option<String>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_request_timeout: Option<u64>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_lazy_connect: Option<bool>,
+    #[cfg(feature = "local-socks5-bind")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_bind_timeout: Option<u64>,
+
+    /// HTTP
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_forward_headers: Option<BTreeMap<String, String>>,
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_max_request_body: Option<u64>,
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_body_start_timeout: Option<u64>,
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_auth_config_path: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -301,6 +419,39 @@ struct SSServerExtConfig {
     tcp_weight: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_weight: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_retries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tcp_connections: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    tcp_maxseg: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fronting_proxy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fronting_host: Option<String>,
+}
+
+#[cfg(feature = "stream-compression")]
+fn ser_server_compression(svr: &ServerConfig) -> Option<bool> {
+    if svr.compression() {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(feature = "stream-compression"))]
+fn ser_server_compression(_svr: &ServerConfig) -> Option<bool> {
+    None
 }
 
 /// Server config type
@@ -819,6 +970,11 @@ pub struct LocalConfig {
     /// Sending DNS query through proxy to this address
     #[cfg(feature = "local-dns")]
     pub remote_dns_addr: Option<Address>,
+    /// Hostnames answered with unspecified addresses (`0.0.0.0` and `::`), including their subdomains
+    ///
+    /// Queries of them won't be sent to any DNS servers
+    #[cfg(feature = "local-dns")]
+    pub dns_sinkhole_hosts: Vec<String>,
 
     /// Tun interface's name
     ///
@@ -842,6 +998,37 @@ pub struct LocalConfig {
     /// SOCKS5 Authentication configuration
     #[cfg(feature = "local")]
     pub socks5_auth: Socks5AuthConfig,
+    /// Timeout of reading SOCKS5 request after the method negotiation, wait until clients close by default
+    #[cfg(feature = "local")]
+    pub socks5_request_timeout: Option<Duration>,
+    /// Reply success to SOCKS5 CONNECT before connecting, and connect after the client sends its first byte
+    #[cfg(feature = "local")]
+    pub socks5_lazy_connect: bool,
+    /// Timeout of waiting for the incoming peer of SOCKS5 BIND, `None` for the default timeout
+    #[cfg(feature = "local-socks5-bind")]
+    pub socks5_bind_timeout: Option<Duration>,
+
+    /// Networks of clients allowed to connect to socks and http local servers, all clients are allowed if empty
+    #[cfg(feature = "local")]
+    pub allowed_clients: Vec<IpNet>,
+
+    /// Remarks of servers used by this local server, with its own balancer. All servers are used if empty
+    #[cfg(feature = "local")]
+    pub servers: Vec<String>,
+
+    /// Headers added to (or overriding existing headers of) HTTP requests forwarded by HTTP local server
+    #[cfg(feature = "local-http")]
+    pub http_forward_headers: Vec<(String, String)>,
+    /// Maximum size of HTTP request bodies forwarded by HTTP local server in bytes, unlimited by default
+    #[cfg(feature = "local-http")]
+    pub http_max_request_body: Option<u64>,
+    /// Maximum delay between HTTP request headers and the first byte of their bodies, unlimited by default
+    #[cfg(feature = "local-http")]
+    pub http_body_start_timeout: Option<Duration>,
+    /// Users allowed by HTTP local server's `Proxy-Authorization: Basic`, in the same format of `socks5_auth`.
+    /// Clients are not authenticated if it is empty
+    #[cfg(feature = "local-http")]
+    pub http_auth: Socks5AuthConfig,
 }
 
 impl LocalConfig {
@@ -867,6 +1054,8 @@ impl LocalConfig {
             local_dns_addr: None,
             #[cfg(feature = "local-dns")]
             remote_dns_addr: None,
+            #[cfg(feature = "local-dns")]
+            dns_sinkhole_hosts: Vec::new(),
 
             #[cfg(feature = "local-tun")]
             tun_interface_name: None,
@@ -881,6 +1070,25 @@ impl LocalConfig {
 
             #[cfg(feature = "local")]
             socks5_auth: Socks5AuthConfig::default(),
+            #[cfg(feature = "local")]
+            socks5_request_timeout: None,
+            #[cfg(feature = "local")]
+            socks5_lazy_connect: false,
+            #[cfg(feature = "local-socks5-bind")]
+            socks5_bind_timeout: None,
+            #[cfg(feature = "local")]
+            allowed_clients: Vec::new(),
+            #[cfg(feature = "local")]
+            servers: Vec::new(),
+
+            #[cfg(feature = "local-http")]
+            http_forward_headers: Vec::new(),
+            #[cfg(feature = "local-http")]
+            http_max_request_body: None,
+            #[cfg(feature = "local-http")]
+            http_body_start_timeout: None,
+            #[cfg(feature = "local-http")]
+            http_auth: Socks5AuthConfig::default(),
         }
     }
 
@@ -904,6 +1112,114 @@ impl LocalConfig {
             }
         }
 
+        #[cfg(feature = "local")]
+        if !self.allowed_clients.is_empty() {
+            let supported = match self.protocol {
+                ProtocolType::Socks => true,
+                #[cfg(feature = "local-http")]
+                ProtocolType::Http => true,
+                #[allow(unreachable_patterns)]
+                _ => false,
+            };
+
+            if !supported {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`allowed_clients` is only supported by socks and http",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local")]
+        if let Some(timeout) = self.socks5_request_timeout {
+            if self.protocol != ProtocolType::Socks {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`socks5_request_timeout` is only supported by socks",
+                    None,
+                );
+                return Err(err);
+            }
+
+            if timeout.is_zero() {
+                let err = Error::new(ErrorKind::Invalid, "`socks5_request_timeout` must be > 0", None);
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local")]
+        if self.socks5_lazy_connect && self.protocol != ProtocolType::Socks {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`socks5_lazy_connect` is only supported by socks",
+                None,
+            );
+            return Err(err);
+        }
+
+        #[cfg(feature = "local-socks5-bind")]
+        if let Some(timeout) = self.socks5_bind_timeout {
+            if self.protocol != ProtocolType::Socks {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`socks5_bind_timeout` is only supported by socks",
+                    None,
+                );
+                return Err(err);
+            }
+
+            if timeout.is_zero() {
+                let err = Error::new(ErrorKind::Invalid, "`socks5_bind_timeout` must be > 0", None);
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-http")]
+        if let Some(max_request_body) = self.http_max_request_body {
+            if self.protocol != ProtocolType::Http {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`http_max_request_body` is only supported by http",
+                    None,
+                );
+                return Err(err);
+            }
+
+            if max_request_body == 0 {
+                let err = Error::new(ErrorKind::Invalid, "`http_max_request_body` must be > 0", None);
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-http")]
+        if let Some(body_start_timeout) = self.http_body_start_timeout {
+            if self.protocol != ProtocolType::Http {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "`http_body_start_timeout` is only supported by http",
+                    None,
+                );
+                return Err(err);
+            }
+
+            if body_start_timeout.is_zero() {
+                let err = Error::new(ErrorKind::Invalid, "`http_body_start_timeout` must be > 0", None);
+                return Err(err);
+            }
+        }
+
+        #[cfg(feature = "local-http")]
+        if self.http_auth.auth_required() && self.protocol != ProtocolType::Http {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`http_auth_config_path` is only supported by http",
+                None,
+            );
+            return Err(err);
+        }
+
         match self.protocol {
             #[cfg(feature = "local-dns")]
             ProtocolType::Dns => {
@@ -930,6 +1246,19 @@ impl LocalConfig {
                     let err = Error::new(ErrorKind::Invalid, "TCP mode have to be enabled for http", None);
                     return Err(err);
                 }
+
+                for (name, value) in &self.http_forward_headers {
+                    if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+                        || hyper::header::HeaderValue::from_str(value).is_err()
+                    {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid header in `http_forward_headers`",
+                            Some(format!("{}: {}", name, value)),
+                        );
+                        return Err(err);
+                    }
+                }
             }
 
             _ => {}
@@ -959,6 +1288,20 @@ impl LocalConfig {
             return false;
         }
 
+        #[cfg(feature = "local")]
+        if !self.allowed_clients.is_empty()
+            || self.socks5_request_timeout.is_some()
+            || self.socks5_lazy_connect
+            || !self.servers.is_empty()
+        {
+            return false;
+        }
+
+        #[cfg(feature = "local-socks5-bind")]
+        if self.socks5_bind_timeout.is_some() {
+            return false;
+        }
+
         true
     }
 }
@@ -989,17 +1332,193 @@ pub struct SecurityReplayAttackConfig {
     pub policy: ReplayAttackPolicy,
 }
 
+/// Default delay of `ServerFailurePolicy::RetryAfter`
+pub const DEFAULT_SERVER_FAILURE_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Default retries of `ServerFailurePolicy::RetryAfter`
+pub const DEFAULT_SERVER_FAILURE_RETRIES: usize = 1;
+
+/// Action taken when connecting to the chosen server fails after all its retries
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum ServerFailurePolicy {
+    /// Fail the client's connection
+    #[default]
+    Reject,
+    /// Panic, which aborts the client's connection task
+    Panic,
+    /// Connect to the target directly, bypassing the server, unless ACL requires the target to be proxied
+    FallbackDirect,
+    /// Connect to the server again after `delay`, at most `retries` times
+    RetryAfter { delay: Duration, retries: usize },
+}
+
+/// Parsing ServerFailurePolicy error
+#[derive(Debug, Clone, Copy)]
+pub struct ServerFailurePolicyError;
+
+impl Display for ServerFailurePolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ServerFailurePolicy")
+    }
+}
+
+impl FromStr for ServerFailurePolicy {
+    type Err = ServerFailurePolicyError;
+
+    /// `retry_after` is parsed with `DEFAULT_SERVER_FAILURE_RETRY_AFTER` and `DEFAULT_SERVER_FAILURE_RETRIES`
+    fn from_str(s: &str) -> Result<ServerFailurePolicy, Self::Err> {
+        match s {
+            "reject" => Ok(ServerFailurePolicy::Reject),
+            "panic" => Ok(ServerFailurePolicy::Panic),
+            "fallback_direct" => Ok(ServerFailurePolicy::FallbackDirect),
+            "retry_after" => Ok(ServerFailurePolicy::RetryAfter {
+                delay: DEFAULT_SERVER_FAILURE_RETRY_AFTER,
+                retries: DEFAULT_SERVER_FAILURE_RETRIES,
+            }),
+            _ => Err(ServerFailurePolicyError),
+        }
+    }
+}
+
+impl Display for ServerFailurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerFailurePolicy::Reject => f.write_str("reject"),
+            ServerFailurePolicy::Panic => f.write_str("panic"),
+            ServerFailurePolicy::FallbackDirect => f.write_str("fallback_direct"),
+            ServerFailurePolicy::RetryAfter { .. } => f.write_str("retry_after"),
+        }
+    }
+}
+
+/// Strategy of choosing servers for new connections
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum BalancerStrategy {
+    /// Server with the lowest score of active probing
+    #[default]
+    Ping,
+    /// Server with the lowest recent throughput
+    Bandwidth,
+    /// Server with the fewest open TCP connections
+    LeastConnections,
+    /// Servers in turn, in proportion to their `weight`
+    WeightedRoundRobin,
+    /// Servers randomly, in proportion to their `weight` reduced by recent connect failures
+    WeightedRandom,
+}
+
+/// Parsing BalancerStrategy error
+#[derive(Debug, Clone, Copy)]
+pub struct BalancerStrategyError;
+
+impl Display for BalancerStrategyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid BalancerStrategy")
+    }
+}
+
+impl FromStr for BalancerStrategy {
+    type Err = BalancerStrategyError;
+
+    fn from_str(s: &str) -> Result<BalancerStrategy, Self::Err> {
+        match s {
+            "ping" => Ok(BalancerStrategy::Ping),
+            "bandwidth" => Ok(BalancerStrategy::Bandwidth),
+            "least_connections" => Ok(BalancerStrategy::LeastConnections),
+            "weighted_round_robin" => Ok(BalancerStrategy::WeightedRoundRobin),
+            "weighted_random" => Ok(BalancerStrategy::WeightedRandom),
+            _ => Err(BalancerStrategyError),
+        }
+    }
+}
+
+impl Display for BalancerStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalancerStrategy::Ping => f.write_str("ping"),
+            BalancerStrategy::Bandwidth => f.write_str("bandwidth"),
+            BalancerStrategy::LeastConnections => f.write_str("least_connections"),
+            BalancerStrategy::WeightedRoundRobin => f.write_str("weighted_round_robin"),
+            BalancerStrategy::WeightedRandom => f.write_str("weighted_random"),
+        }
+    }
+}
+
+/// Action taken on UDP datagrams larger than `udp_max_datagram`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum UdpOversizedDatagramPolicy {
+    /// Drop the datagram
+    #[default]
+    Reject,
+    /// Split the payload and send each part as a separated datagram
+    ///
+    /// Shadowsocks relays one datagram per packet, the target receives the parts as independent datagrams.
+    /// This corrupts the datagrams of most protocols (DNS, QUIC, ...), only use it for targets that reassemble them.
+    Split,
+}
+
+/// Parsing UdpOversizedDatagramPolicy error
+#[derive(Debug, Clone, Copy)]
+pub struct UdpOversizedDatagramPolicyError;
+
+impl Display for UdpOversizedDatagramPolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid UdpOversizedDatagramPolicy")
+    }
+}
+
+impl FromStr for UdpOversizedDatagramPolicy {
+    type Err = UdpOversizedDatagramPolicyError;
+
+    fn from_str(s: &str) -> Result<UdpOversizedDatagramPolicy, Self::Err> {
+        match s {
+            "reject" => Ok(UdpOversizedDatagramPolicy::Reject),
+            "split" => Ok(UdpOversizedDatagramPolicy::Split),
+            _ => Err(UdpOversizedDatagramPolicyError),
+        }
+    }
+}
+
+impl Display for UdpOversizedDatagramPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpOversizedDatagramPolicy::Reject => f.write_str("reject"),
+            UdpOversizedDatagramPolicy::Split => f.write_str("split"),
+        }
+    }
+}
+
 /// Balancer Config
 #[derive(Clone, Debug, Default)]
 pub struct BalancerConfig {
+    /// Strategy of choosing servers, `Ping` by default
+    pub strategy: BalancerStrategy,
     /// MAX rtt of servers, which is the timeout duration of each check requests
     pub max_server_rtt: Option<Duration>,
     /// Interval between each checking
     pub check_interval: Option<Duration>,
     /// Interval for checking the best server
     pub check_best_interval: Option<Duration>,
+    /// Grace period for connections of the old servers after reloading servers, they will be closed afterwards
+    pub reload_grace: Option<Duration>,
+    /// Tunes servers' connect timeout to `rtt * connect_timeout_rtt_factor + connect_timeout_floor` with their observed RTT
+    pub connect_timeout_rtt_factor: Option<u32>,
+    /// Minimum of the tuned connect timeout
+    pub connect_timeout_floor: Option<Duration>,
+    /// Action taken when connecting to the chosen server fails, `Reject` by default
+    pub server_failure_policy: ServerFailurePolicy,
+    /// Servers failed to connect are skipped by the balancer in this period, never skipped by default
+    pub server_down_cooldown: Option<Duration>,
+    /// IP-echo endpoint for probing servers' egress IP address, in `host:port`
+    pub egress_ip_probe: Option<ServerAddr>,
+    /// Interval between each egress IP probing, servers are only probed once when they are loaded by default
+    pub egress_ip_probe_interval: Option<Duration>,
+    /// Maximum redirects followed in each egress IP probing, redirects are not followed by default
+    pub egress_ip_probe_max_redirects: Option<usize>,
 }
 
+/// Timeout of servers which don't have `timeout` configured
+pub const DEFAULT_SERVER_TIMEOUT: Duration = Duration::from_secs(300);
+
 /// Configuration
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -1029,12 +1548,21 @@ pub struct Config {
     pub no_delay: bool,
     /// Set `TCP_FASTOPEN` socket option
     pub fast_open: bool,
+    /// Backlog of TCP listeners, `DEFAULT_LISTEN_BACKLOG` by default
+    pub listen_backlog: Option<u32>,
     /// Set TCP Keep-Alive duration, will set both `TCP_KEEPIDLE` and `TCP_KEEPINTVL`
     ///
     /// <https://github.com/shadowsocks/shadowsocks-rust/issues/546>
     ///
     /// If this is not set, sockets will be set with a default timeout
     pub keep_alive: Option<Duration>,
+    /// Set `TCP_CONGESTION` socket option, congestion control algorithm for both inbound and outbound sockets
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub tcp_congestion: Option<String>,
+    /// Set `TCP_USER_TIMEOUT` socket option for both inbound and outbound sockets, closing connections whose
+    /// transmitted data isn't acknowledged in time
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub tcp_user_timeout: Option<Duration>,
 
     /// `RLIMIT_NOFILE` option for *nix systems
     #[cfg(all(unix, not(target_os = "android")))]
@@ -1053,6 +1581,29 @@ pub struct Config {
     /// Path to protect callback unix address, only for Android
     #[cfg(target_os = "android")]
     pub outbound_vpn_protect_path: Option<PathBuf>,
+    /// Send a PROXY protocol (v1) header carrying the client's address to remote servers before the shadowsocks handshake
+    pub outbound_proxy_protocol: bool,
+
+    /// Maximum concurrent connections to each destination host (sslocal only), default is unlimited
+    pub max_conns_per_host: Option<usize>,
+    /// Bandwidth limit of each TCP tunnel (sslocal only) in bytes per second, for both directions. Default is unlimited
+    ///
+    /// Connections of the HTTP client and UDP associations are limited as tunnels.
+    pub rate_limit_bps: Option<u64>,
+    /// Bandwidth limit shared by all TCP tunnels, HTTP client connections and UDP associations (sslocal only) in bytes
+    /// per second, for both directions. Default is unlimited
+    pub global_rate_limit_bps: Option<u64>,
+    /// Address serving Prometheus metrics on `/metrics` (sslocal only), disabled by default
+    pub metrics_addr: Option<ServerAddr>,
+    /// Account bytes relayed to each destination (sslocal only), served in JSON on `/destinations` of `metrics_addr`.
+    /// Disabled by default
+    pub destination_stats: bool,
+    /// Handles accepted TCP connections with a fixed number of workers, instead of a task for each of them.
+    /// Connections wait in a bounded queue for a free worker. Default is unbounded
+    pub worker_pool: Option<WorkerPoolConfig>,
+    /// Rejects new TCP connections (ssserver only) when the number of relay tasks waiting for the scheduler reaches it.
+    /// Default is unlimited
+    pub max_pending_tasks: Option<usize>,
 
     /// Set `SO_SNDBUF` for inbound sockets
     pub inbound_send_buffer_size: Option<u32>,
@@ -1069,10 +1620,36 @@ pub struct Config {
     /// Config is for Client or Server
     pub config_type: ConfigType,
 
+    /// Runs servers without `timeout` without any timeout, instead of `DEFAULT_SERVER_TIMEOUT`
+    pub no_timeout: bool,
+    /// Fails when servers are configured with the same address more than once, instead of warning
+    pub strict_servers: bool,
+    /// Retries after failing to connect to a server, default is 0.
+    /// Could be overridden by each server's `max_retries`
+    pub connect_retries: Option<usize>,
+    /// Sends handshake to servers right after connecting, without waiting for clients' first packet.
+    /// For protocols that servers speak first
+    pub connect_read_ahead: bool,
+    /// Connections through servers taking longer than it to connect, or to receive the first byte, are logged
+    pub slow_connection_threshold: Option<Duration>,
+
+    /// Maximum number of DNS resolutions in flight, resolutions beyond it fail immediately. Default is unlimited
+    pub max_pending_dns_resolutions: Option<usize>,
+    /// Order of trying addresses resolved from targets' hostnames when connecting directly, Happy Eyeballs by default
+    pub direct_connect_strategy: ConnectStrategy,
+    /// TCP tunnels without any bytes transferred in either direction for this period are closed. Never by default
+    pub idle_timeout: Option<Duration>,
+
     /// Timeout for UDP Associations, default is 5 minutes
     pub udp_timeout: Option<Duration>,
     /// Maximum number of UDP Associations, default is unconfigured
     pub udp_max_associations: Option<usize>,
+    /// Maximum size of UDP datagrams, larger datagrams will be dropped. Default is 65507 bytes
+    ///
+    /// sslocal applies it to the packets sent, including the header and encryption of proxied packets
+    pub udp_max_datagram: Option<usize>,
+    /// Action taken on UDP datagrams larger than `udp_max_datagram` sent by clients (sslocal only), `Reject` by default
+    pub udp_oversized_datagram: UdpOversizedDatagramPolicy,
 
     /// ACL configuration
     pub acl: Option<AccessControl>,
@@ -1117,11 +1694,24 @@ pub struct Error {
     pub kind: ErrorKind,
     pub desc: &'static str,
     pub detail: Option<String>,
+    /// Path of the field causing this error in the configuration file, like `servers[1].method`
+    pub field: Option<String>,
 }
 
 impl Error {
     pub fn new(kind: ErrorKind, desc: &'static str, detail: Option<String>) -> Error {
-        Error { kind, desc, detail }
+        Error {
+            kind,
+            desc,
+            detail,
+            field: None,
+        }
+    }
+
+    /// Set path of the field causing this error
+    pub fn with_field<F: Into<String>>(mut self, field: F) -> Error {
+        self.field = Some(field.into());
+        self
     }
 }
 
@@ -1140,6 +1730,9 @@ impl_from!(json5::Error, ErrorKind::JsonParsingError, "json parse error");
 
 impl Debug for Error {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(ref field) = self.field {
+            write!(f, "{}: ", field)?;
+        }
         match self.detail {
             None => write!(f, "{}", self.desc),
             Some(ref det) => write!(f, "{} {}", self.desc, det),
@@ -1149,6 +1742,9 @@ impl Debug for Error {
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(ref field) = self.field {
+            write!(f, "{}: ", field)?;
+        }
         match self.detail {
             None => f.write_str(self.desc),
             Some(ref d) => write!(f, "{}, {}", self.desc, d),
@@ -1169,7 +1765,12 @@ impl Config {
 
             no_delay: false,
             fast_open: false,
+            listen_backlog: None,
             keep_alive: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            tcp_congestion: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            tcp_user_timeout: None,
 
             #[cfg(all(unix, not(target_os = "android")))]
             nofile: None,
@@ -1182,6 +1783,15 @@ impl Config {
             outbound_bind_addr: None,
             #[cfg(target_os = "android")]
             outbound_vpn_protect_path: None,
+            outbound_proxy_protocol: false,
+
+            max_conns_per_host: None,
+            rate_limit_bps: None,
+            global_rate_limit_bps: None,
+            metrics_addr: None,
+            destination_stats: false,
+            worker_pool: None,
+            max_pending_tasks: None,
 
             inbound_send_buffer_size: None,
             inbound_recv_buffer_size: None,
@@ -1192,8 +1802,19 @@ impl Config {
 
             config_type,
 
+            connect_retries: None,
+            connect_read_ahead: false,
+            slow_connection_threshold: None,
+            max_pending_dns_resolutions: None,
+            direct_connect_strategy: ConnectStrategy::default(),
+            no_timeout: false,
+            strict_servers: false,
+            idle_timeout: None,
+
             udp_timeout: None,
             udp_max_associations: None,
+            udp_max_datagram: None,
+            udp_oversized_datagram: UdpOversizedDatagramPolicy::default(),
 
             acl: None,
 
@@ -1433,6 +2054,17 @@ impl Config {
                             });
                         }
 
+                        #[cfg(feature = "local-dns")]
+                        if let Some(hosts) = local.dns_sinkhole_hosts {
+                            use trust_dns_resolver::proto::rr::Name;
+
+                            if hosts.iter().any(|h| h.parse::<Name>().is_err()) {
+                                let err = Error::new(ErrorKind::Malformed, "`dns_sinkhole_hosts` invalid", None);
+                                return Err(err);
+                            }
+                            local_config.dns_sinkhole_hosts = hosts;
+                        }
+
                         #[cfg(feature = "local-tun")]
                         if let Some(tun_interface_address) = local.tun_interface_address {
                             match tun_interface_address.parse::<IpNet>() {
@@ -1449,11 +2081,73 @@ impl Config {
                             local_config.tun_interface_name = Some(tun_interface_name);
                         }
 
+                        #[cfg(feature = "local")]
+                        if let Some(allowed_clients) = local.allowed_clients {
+                            for client in allowed_clients {
+                                // Single address is allowed without prefix length
+                                let network = match client.parse::<IpNet>() {
+                                    Ok(n) => n,
+                                    Err(..) => match client.parse::<IpAddr>() {
+                                        Ok(ip) => IpNet::from(ip),
+                                        Err(..) => {
+                                            let err = Error::new(
+                                                ErrorKind::Malformed,
+                                                "`allowed_clients` invalid",
+                                                Some(client),
+                                            );
+                                            return Err(err);
+                                        }
+                                    },
+                                };
+                                local_config.allowed_clients.push(network);
+                            }
+                        }
+
+                        #[cfg(feature = "local")]
+                        if let Some(servers) = local.servers {
+                            local_config.servers = servers;
+                        }
+
                         #[cfg(feature = "local")]
                         if let Some(socks5_auth_config_path) = local.socks5_auth_config_path {
                             local_config.socks5_auth = Socks5AuthConfig::load_from_file(&socks5_auth_config_path)?;
                         }
 
+                        #[cfg(feature = "local")]
+                        if let Some(timeout) = local.socks5_request_timeout {
+                            local_config.socks5_request_timeout = Some(Duration::from_secs(timeout));
+                        }
+
+                        #[cfg(feature = "local")]
+                        if let Some(lazy_connect) = local.socks5_lazy_connect {
+                            local_config.socks5_lazy_connect = lazy_connect;
+                        }
+
+                        #[cfg(feature = "local-socks5-bind")]
+                        if let Some(timeout) = local.socks5_bind_timeout {
+                            local_config.socks5_bind_timeout = Some(Duration::from_secs(timeout));
+                        }
+
+                        #[cfg(feature = "local-http")]
+                        if let Some(http_forward_headers) = local.http_forward_headers {
+                            local_config.http_forward_headers = http_forward_headers.into_iter().collect();
+                        }
+
+                        #[cfg(feature = "local-http")]
+                        if let Some(max_request_body) = local.http_max_request_body {
+                            local_config.http_max_request_body = Some(max_request_body);
+                        }
+
+                        #[cfg(feature = "local-http")]
+                        if let Some(body_start_timeout) = local.http_body_start_timeout {
+                            local_config.http_body_start_timeout = Some(Duration::from_secs(body_start_timeout));
+                        }
+
+                        #[cfg(feature = "local-http")]
+                        if let Some(http_auth_config_path) = local.http_auth_config_path {
+                            local_config.http_auth = Socks5AuthConfig::load_from_file(&http_auth_config_path)?;
+                        }
+
                         nconfig.local.push(local_config);
                     }
                 }
@@ -1545,7 +2239,10 @@ impl Config {
 
         // Ext servers
         if let Some(servers) = config.servers {
-            for svr in servers {
+            for (idx, svr) in servers.into_iter().enumerate() {
+                // Location of the server's fields in the configuration file
+                let field = |name: &str| format!("servers[{}].{}", idx, name);
+
                 // Skip if server is disabled
                 if svr.disabled.unwrap_or(false) {
                     continue;
@@ -1569,7 +2266,8 @@ impl Config {
                             ErrorKind::Invalid,
                             "unsupported method",
                             Some(format!("`{}` is not a supported method", svr.method)),
-                        );
+                        )
+                        .with_field(field("method"));
                         return Err(err);
                     }
                 };
@@ -1585,7 +2283,8 @@ impl Config {
                                 ErrorKind::MissingField,
                                 "`password` is required",
                                 Some(format!("`password` is required for method {}", method)),
-                            );
+                            )
+                            .with_field(field("password"));
                             return Err(err);
                         }
                     }
@@ -1597,7 +2296,7 @@ impl Config {
                     Some(mode) => match mode.parse::<Mode>() {
                         Ok(mode) => nsvr.set_mode(mode),
                         Err(..) => {
-                            let err = Error::new(ErrorKind::Invalid, "invalid `mode`", None);
+                            let err = Error::new(ErrorKind::Invalid, "invalid `mode`", None).with_field(field("mode"));
                             return Err(err);
                         }
                     },
@@ -1622,7 +2321,7 @@ impl Config {
                     }
                 }
 
-                if let Some(timeout) = config.timeout.map(Duration::from_secs) {
+                if let Some(timeout) = svr.timeout.map(Duration::from_secs) {
                     nsvr.set_timeout(timeout);
                 }
 
@@ -1634,23 +2333,103 @@ impl Config {
                     nsvr.set_id(id);
                 }
 
-                if svr.tcp_weight.is_some() || svr.udp_weight.is_some() {
+                if svr.tcp_weight.is_some() || svr.udp_weight.is_some() || svr.weight.is_some() {
                     let tcp_weight = svr.tcp_weight.unwrap_or(1.0);
                     if !(0.0..=1.0).contains(&tcp_weight) {
-                        let err = Error::new(ErrorKind::Invalid, "invalid `tcp_weight`, must be in [0, 1]", None);
+                        let err = Error::new(ErrorKind::Invalid, "invalid `tcp_weight`, must be in [0, 1]", None)
+                            .with_field(field("tcp_weight"));
                         return Err(err);
                     }
                     let udp_weight = svr.udp_weight.unwrap_or(1.0);
                     if !(0.0..=1.0).contains(&udp_weight) {
-                        let err = Error::new(ErrorKind::Invalid, "invalid `udp_weight`, must be in [0, 1]", None);
+                        let err = Error::new(ErrorKind::Invalid, "invalid `udp_weight`, must be in [0, 1]", None)
+                            .with_field(field("udp_weight"));
+                        return Err(err);
+                    }
+                    let round_robin_weight = svr.weight.unwrap_or(1);
+                    if round_robin_weight == 0 {
+                        let err = Error::new(ErrorKind::Invalid, "invalid `weight`, must be > 0", None)
+                            .with_field(field("weight"));
                         return Err(err);
                     }
                     let mut weight = ServerWeight::new();
                     weight.set_tcp_weight(tcp_weight);
                     weight.set_udp_weight(udp_weight);
+                    weight.set_round_robin_weight(round_robin_weight);
                     nsvr.set_weight(weight);
                 }
 
+                if let Some(max_retries) = svr.max_retries {
+                    nsvr.set_max_retries(max_retries);
+                }
+
+                if let Some(max_tcp_connections) = svr.max_tcp_connections {
+                    if max_tcp_connections == 0 {
+                        let err = Error::new(ErrorKind::Invalid, "`max_tcp_connections` must be greater than 0", None)
+                            .with_field(field("max_tcp_connections"));
+                        return Err(err);
+                    }
+                    nsvr.set_max_tcp_connections(max_tcp_connections);
+                }
+
+                if let Some(compression) = svr.compression {
+                    #[cfg(feature = "stream-compression")]
+                    nsvr.set_compression(compression);
+
+                    #[cfg(not(feature = "stream-compression"))]
+                    if compression {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "compression is not supported",
+                            Some("enable feature \"stream-compression\"".to_owned()),
+                        )
+                        .with_field(field("compression"));
+                        return Err(err);
+                    }
+                }
+
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                if let Some(tcp_maxseg) = svr.tcp_maxseg {
+                    if tcp_maxseg == 0 {
+                        let err = Error::new(ErrorKind::Invalid, "`tcp_maxseg` must be greater than 0", None)
+                            .with_field(field("tcp_maxseg"));
+                        return Err(err);
+                    }
+                    nsvr.set_tcp_maxseg(tcp_maxseg);
+                }
+
+                match (svr.fronting_proxy, svr.fronting_host) {
+                    (Some(proxy), host) => {
+                        let proxy = match proxy.parse::<ServerAddr>() {
+                            Ok(p) => p,
+                            Err(..) => {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "invalid `fronting_proxy`",
+                                    Some(format!("`{}` is not an address with port", proxy)),
+                                )
+                                .with_field(field("fronting_proxy"));
+                                return Err(err);
+                            }
+                        };
+                        let mut fronting = HttpFronting::new(proxy);
+                        if let Some(host) = host {
+                            fronting.set_host(host);
+                        }
+                        nsvr.set_http_fronting(fronting);
+                    }
+                    (None, Some(..)) => {
+                        let err = Error::new(
+                            ErrorKind::MissingField,
+                            "`fronting_host` requires `fronting_proxy`",
+                            None,
+                        )
+                        .with_field(field("fronting_host"));
+                        return Err(err);
+                    }
+                    (None, None) => {}
+                }
+
                 nconfig.server.push(nsvr);
             }
         }
@@ -1666,6 +2445,9 @@ impl Config {
             }
         }
 
+        nconfig.no_timeout = config.no_timeout.unwrap_or(false);
+        nconfig.strict_servers = config.strict_servers.unwrap_or(false);
+
         // Manager Address
         if let Some(ma) = config.manager_address {
             let manager = match config.manager_port {
@@ -1739,17 +2521,76 @@ impl Config {
             nconfig.fast_open = b;
         }
 
+        // Backlog of TCP listeners
+        nconfig.listen_backlog = config.listen_backlog;
+
         // TCP Keep-Alive
         if let Some(d) = config.keep_alive {
             nconfig.keep_alive = Some(Duration::from_secs(d));
         }
 
+        // TCP congestion control
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            nconfig.tcp_congestion = config.tcp_congestion;
+        }
+
+        // TCP user timeout
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(t) = config.tcp_user_timeout {
+            if t == 0 {
+                let err = Error::new(ErrorKind::Invalid, "`tcp_user_timeout` must be greater than 0", None);
+                return Err(err);
+            }
+            nconfig.tcp_user_timeout = Some(Duration::from_secs(t));
+        }
+
+        // Retries of connecting to servers
+        nconfig.connect_retries = config.connect_retries;
+        if let Some(read_ahead) = config.connect_read_ahead {
+            nconfig.connect_read_ahead = read_ahead;
+        }
+        nconfig.slow_connection_threshold = config.slow_connection_threshold.map(Duration::from_millis);
+
+        // Limit of pending DNS resolutions
+        nconfig.max_pending_dns_resolutions = config.max_pending_dns_resolutions;
+
+        if let Some(strategy) = config.direct_connect_strategy {
+            match strategy.parse::<ConnectStrategy>() {
+                Ok(s) => nconfig.direct_connect_strategy = s,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid direct_connect_strategy", None);
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Some(idle_timeout) = config.idle_timeout {
+            if idle_timeout == 0 {
+                let err = Error::new(ErrorKind::Invalid, "`idle_timeout` must be > 0", None);
+                return Err(err);
+            }
+            nconfig.idle_timeout = Some(Duration::from_secs(idle_timeout));
+        }
+
         // UDP
         nconfig.udp_timeout = config.udp_timeout.map(Duration::from_secs);
 
         // Maximum associations to be kept simultaneously
         nconfig.udp_max_associations = config.udp_max_associations;
 
+        // Maximum size of datagrams to be relayed
+        nconfig.udp_max_datagram = config.udp_max_datagram;
+        if let Some(policy) = config.udp_oversized_datagram {
+            match policy.parse::<UdpOversizedDatagramPolicy>() {
+                Ok(p) => nconfig.udp_oversized_datagram = p,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid udp_oversized_datagram", None);
+                    return Err(err);
+                }
+            }
+        }
+
         // RLIMIT_NOFILE
         #[cfg(all(unix, not(target_os = "android")))]
         {
@@ -1772,6 +2613,58 @@ impl Config {
             nconfig.outbound_fwmark = Some(fwmark);
         }
 
+        // PROXY protocol to remote servers
+        if let Some(p) = config.outbound_proxy_protocol {
+            nconfig.outbound_proxy_protocol = p;
+        }
+
+        // Maximum connections per destination host
+        nconfig.max_conns_per_host = config.max_conns_per_host;
+
+        // Bandwidth limits
+        nconfig.rate_limit_bps = config.rate_limit_bps;
+        nconfig.global_rate_limit_bps = config.global_rate_limit_bps;
+
+        #[cfg(not(feature = "local-metrics"))]
+        if config.metrics_addr.is_some() {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "metrics_addr is not supported",
+                Some("enable feature \"local-metrics\"".to_owned()),
+            );
+            return Err(err);
+        }
+
+        if let Some(ref addr) = config.metrics_addr {
+            match addr.parse::<ServerAddr>() {
+                Ok(addr) => nconfig.metrics_addr = Some(addr),
+                Err(..) => {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "invalid metrics_addr, should be \"host:port\"",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Some(d) = config.destination_stats {
+            nconfig.destination_stats = d;
+        }
+
+        if let Some(workers) = config.worker_pool_size {
+            nconfig.worker_pool = Some(WorkerPoolConfig {
+                workers,
+                queue_size: config.worker_queue_size.unwrap_or(workers),
+            });
+        } else if config.worker_queue_size.is_some() {
+            let err = Error::new(ErrorKind::Invalid, "worker_queue_size requires worker_pool_size", None);
+            return Err(err);
+        }
+
+        nconfig.max_pending_tasks = config.max_pending_tasks;
+
         // Security
         if let Some(sec) = config.security {
             if let Some(replay_attack) = sec.replay_attack {
@@ -1788,10 +2681,107 @@ impl Config {
         }
 
         if let Some(balancer) = config.balancer {
+            let strategy = match balancer.strategy {
+                None => BalancerStrategy::default(),
+                Some(strategy) => match strategy.parse::<BalancerStrategy>() {
+                    Ok(s) => s,
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid balancer.strategy", None);
+                        return Err(err);
+                    }
+                },
+            };
+
+            let mut server_failure_policy = match balancer.server_failure_policy {
+                None => ServerFailurePolicy::default(),
+                Some(policy) => match policy.parse::<ServerFailurePolicy>() {
+                    Ok(p) => p,
+                    Err(..) => {
+                        let err = Error::new(ErrorKind::Invalid, "invalid balancer.server_failure_policy", None);
+                        return Err(err);
+                    }
+                },
+            };
+
+            if let Some(retry_after) = balancer.server_failure_retry_after {
+                match server_failure_policy {
+                    ServerFailurePolicy::RetryAfter { ref mut delay, .. } => *delay = Duration::from_secs(retry_after),
+                    _ => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "balancer.server_failure_retry_after requires server_failure_policy \"retry_after\"",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+
+            if let Some(server_failure_retries) = balancer.server_failure_retries {
+                match server_failure_policy {
+                    ServerFailurePolicy::RetryAfter { ref mut retries, .. } => *retries = server_failure_retries,
+                    _ => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "balancer.server_failure_retries requires server_failure_policy \"retry_after\"",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+
+            if balancer.server_down_cooldown == Some(0) {
+                let err = Error::new(ErrorKind::Invalid, "balancer.server_down_cooldown must be > 0", None);
+                return Err(err);
+            }
+
+            let egress_ip_probe = match balancer.egress_ip_probe {
+                None => None,
+                Some(ref addr) => match addr.parse::<ServerAddr>() {
+                    Ok(addr) => Some(addr),
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "invalid balancer.egress_ip_probe, should be \"host:port\"",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                },
+            };
+
+            if balancer.egress_ip_probe_interval.is_some() && egress_ip_probe.is_none() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "balancer.egress_ip_probe_interval requires balancer.egress_ip_probe",
+                    None,
+                );
+                return Err(err);
+            }
+
+            if balancer.egress_ip_probe_max_redirects.is_some() && egress_ip_probe.is_none() {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "balancer.egress_ip_probe_max_redirects requires balancer.egress_ip_probe",
+                    None,
+                );
+                return Err(err);
+            }
+
             nconfig.balancer = BalancerConfig {
+                strategy,
                 max_server_rtt: balancer.max_server_rtt.map(Duration::from_secs),
                 check_interval: balancer.check_interval.map(Duration::from_secs),
                 check_best_interval: balancer.check_best_interval.map(Duration::from_secs),
+                reload_grace: balancer.reload_grace.map(Duration::from_secs),
+                connect_timeout_rtt_factor: balancer.connect_timeout_rtt_factor,
+                connect_timeout_floor: balancer.connect_timeout_floor.map(Duration::from_secs),
+                server_failure_policy,
+                server_down_cooldown: balancer.server_down_cooldown.map(Duration::from_secs),
+                egress_ip_probe,
+                egress_ip_probe_interval: balancer.egress_ip_probe_interval.map(Duration::from_secs),
+                egress_ip_probe_max_redirects: balancer.egress_ip_probe_max_redirects,
             };
         }
 
@@ -1977,6 +2967,26 @@ impl Config {
         false
     }
 
+    /// Set `DEFAULT_SERVER_TIMEOUT` to servers without `timeout`, unless `no_timeout` is set
+    ///
+    /// Connections of servers without timeout may hang forever.
+    pub fn apply_default_timeout(&mut self) {
+        if self.no_timeout {
+            return;
+        }
+
+        for server in self.server.iter_mut() {
+            if server.timeout().is_none() {
+                warn!(
+                    "server {} doesn't have timeout configured, using default {}s. Set \"no_timeout\" to run without timeout",
+                    server.addr(),
+                    DEFAULT_SERVER_TIMEOUT.as_secs()
+                );
+                server.set_timeout(DEFAULT_SERVER_TIMEOUT);
+            }
+        }
+    }
+
     /// Check if all required fields are already set
     pub fn check_integrity(&self) -> Result<(), Error> {
         if self.config_type.is_local() {
@@ -1991,6 +3001,18 @@ impl Config {
 
             for local_config in &self.local {
                 local_config.check_integrity()?;
+
+                #[cfg(feature = "local")]
+                for remarks in &local_config.servers {
+                    if !self.server.iter().any(|svr| svr.remarks() == Some(remarks.as_str())) {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "`servers` of local server must be remarks of servers",
+                            Some(format!("no server with remarks {:?}", remarks)),
+                        );
+                        return Err(err);
+                    }
+                }
             }
 
             // Balancer related checks
@@ -2007,6 +3029,61 @@ impl Config {
                     return Err(err);
                 }
             }
+
+            if let Some(0) = self.balancer.connect_timeout_rtt_factor {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "balancer.connect_timeout_rtt_factor must be > 0",
+                    None,
+                );
+                return Err(err);
+            }
+
+            if self.destination_stats && self.metrics_addr.is_none() {
+                let err = Error::new(ErrorKind::Invalid, "destination_stats requires metrics_addr", None);
+                return Err(err);
+            }
+
+            if let Some(0) = self.max_conns_per_host {
+                let err = Error::new(ErrorKind::Invalid, "max_conns_per_host must be > 0", None);
+                return Err(err);
+            }
+
+            if let Some(0) = self.rate_limit_bps {
+                let err = Error::new(ErrorKind::Invalid, "rate_limit_bps must be > 0", None);
+                return Err(err);
+            }
+
+            if let Some(0) = self.global_rate_limit_bps {
+                let err = Error::new(ErrorKind::Invalid, "global_rate_limit_bps must be > 0", None);
+                return Err(err);
+            }
+        }
+
+        if let Some(0) = self.listen_backlog {
+            let err = Error::new(ErrorKind::Invalid, "listen_backlog must be > 0", None);
+            return Err(err);
+        }
+
+        if let Some(0) = self.udp_max_datagram {
+            let err = Error::new(ErrorKind::Invalid, "udp_max_datagram must be > 0", None);
+            return Err(err);
+        }
+
+        if let Some(ref pool) = self.worker_pool {
+            if pool.workers == 0 || pool.queue_size == 0 {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "worker_pool_size and worker_queue_size must be > 0",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
+        if let Some(0) = self.max_pending_tasks {
+            let err = Error::new(ErrorKind::Invalid, "max_pending_tasks must be > 0", None);
+            return Err(err);
         }
 
         if self.config_type.is_server() && self.server.is_empty() {
@@ -2070,6 +3147,37 @@ impl Config {
             }
         }
 
+        // Servers with the same address are still treated as different servers, which is likely a misconfiguration
+        for (idx, server) in self.server.iter().enumerate() {
+            let prev = match self.server[..idx].iter().find(|s| s.addr() == server.addr()) {
+                Some(prev) => prev,
+                None => continue,
+            };
+
+            let conflicting = prev.method() != server.method() || prev.password() != server.password();
+            if self.strict_servers {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    if conflicting {
+                        "server is configured more than once with different method or password"
+                    } else {
+                        "server is configured more than once"
+                    },
+                    Some(format!("server {}", server.addr())),
+                );
+                return Err(err);
+            }
+
+            if conflicting {
+                warn!(
+                    "server {} is configured more than once with different method or password",
+                    server.addr()
+                );
+            } else {
+                warn!("server {} is configured more than once", server.addr());
+            }
+        }
+
         Ok(())
     }
 }
@@ -2188,13 +3296,51 @@ impl fmt::Display for Config {
                                 Address::DomainNameAddress(.., port) => Some(*port),
                             },
                         },
+                        #[cfg(feature = "local-dns")]
+                        dns_sinkhole_hosts: if local.dns_sinkhole_hosts.is_empty() {
+                            None
+                        } else {
+                            Some(local.dns_sinkhole_hosts.clone())
+                        },
                         #[cfg(feature = "local-tun")]
                         tun_interface_name: local.tun_interface_name.clone(),
                         #[cfg(feature = "local-tun")]
                         tun_interface_address: local.tun_interface_address.as_ref().map(ToString::to_string),
 
+                        #[cfg(feature = "local")]
+                        allowed_clients: if local.allowed_clients.is_empty() {
+                            None
+                        } else {
+                            Some(local.allowed_clients.iter().map(ToString::to_string).collect())
+                        },
+                        #[cfg(feature = "local")]
+                        servers: if local.servers.is_empty() {
+                            None
+                        } else {
+                            Some(local.servers.clone())
+                        },
+
                         #[cfg(feature = "local")]
                         socks5_auth_config_path: None,
+                        #[cfg(feature = "local")]
+                        socks5_request_timeout: local.socks5_request_timeout.map(|t| t.as_secs()),
+                        #[cfg(feature = "local")]
+                        socks5_lazy_connect: if local.socks5_lazy_connect { Some(true) } else { None },
+                        #[cfg(feature = "local-socks5-bind")]
+                        socks5_bind_timeout: local.socks5_bind_timeout.map(|t| t.as_secs()),
+
+                        #[cfg(feature = "local-http")]
+                        http_forward_headers: if local.http_forward_headers.is_empty() {
+                            None
+                        } else {
+                            Some(local.http_forward_headers.iter().cloned().collect())
+                        },
+                        #[cfg(feature = "local-http")]
+                        http_max_request_body: local.http_max_request_body,
+                        #[cfg(feature = "local-http")]
+                        http_body_start_timeout: local.http_body_start_timeout.map(|t| t.as_secs()),
+                        #[cfg(feature = "local-http")]
+                        http_auth_config_path: None,
                     };
                     jlocals.push(jlocal);
                 }
@@ -2279,6 +3425,18 @@ impl fmt::Display for Config {
                         } else {
                             None
                         },
+                        weight: if svr.weight().round_robin_weight() != 1 {
+                            Some(svr.weight().round_robin_weight())
+                        } else {
+                            None
+                        },
+                        max_retries: svr.max_retries(),
+                        max_tcp_connections: svr.max_tcp_connections(),
+                        compression: ser_server_compression(svr),
+                        #[cfg(any(target_os = "linux", target_os = "android"))]
+                        tcp_maxseg: svr.tcp_maxseg(),
+                        fronting_proxy: svr.http_fronting().map(|f| f.proxy().to_string()),
+                        fronting_host: svr.http_fronting().and_then(|f| f.host().map(ToOwned::to_owned)),
                     });
                 }
 
@@ -2331,11 +3489,18 @@ impl fmt::Display for Config {
         if self.fast_open {
             jconf.fast_open = Some(self.fast_open);
         }
+        jconf.listen_backlog = self.listen_backlog;
 
         if let Some(keepalive) = self.keep_alive {
             jconf.keep_alive = Some(keepalive.as_secs());
         }
 
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            jconf.tcp_congestion = self.tcp_congestion.clone();
+            jconf.tcp_user_timeout = self.tcp_user_timeout.map(|t| t.as_secs());
+        }
+
         match self.dns {
             DnsConfig::System => {}
             #[cfg(feature = "trust-dns")]
@@ -2348,9 +3513,32 @@ impl fmt::Display for Config {
             }
         }
 
+        jconf.connect_retries = self.connect_retries;
+        if self.connect_read_ahead {
+            jconf.connect_read_ahead = Some(self.connect_read_ahead);
+        }
+        jconf.slow_connection_threshold = self.slow_connection_threshold.as_ref().map(|t| t.as_millis() as u64);
+        jconf.max_pending_dns_resolutions = self.max_pending_dns_resolutions;
+        if self.no_timeout {
+            jconf.no_timeout = Some(true);
+        }
+        if self.strict_servers {
+            jconf.strict_servers = Some(true);
+        }
+
+        if self.direct_connect_strategy != ConnectStrategy::default() {
+            jconf.direct_connect_strategy = Some(self.direct_connect_strategy.to_string());
+        }
+
+        jconf.idle_timeout = self.idle_timeout.map(|t| t.as_secs());
+
         jconf.udp_timeout = self.udp_timeout.map(|t| t.as_secs());
 
         jconf.udp_max_associations = self.udp_max_associations;
+        jconf.udp_max_datagram = self.udp_max_datagram;
+        if self.udp_oversized_datagram != UdpOversizedDatagramPolicy::default() {
+            jconf.udp_oversized_datagram = Some(self.udp_oversized_datagram.to_string());
+        }
 
         #[cfg(all(unix, not(target_os = "android")))]
         {
@@ -2370,6 +3558,23 @@ impl fmt::Display for Config {
             jconf.outbound_fwmark = self.outbound_fwmark;
         }
 
+        if self.outbound_proxy_protocol {
+            jconf.outbound_proxy_protocol = Some(self.outbound_proxy_protocol);
+        }
+
+        jconf.max_conns_per_host = self.max_conns_per_host;
+        jconf.rate_limit_bps = self.rate_limit_bps;
+        jconf.global_rate_limit_bps = self.global_rate_limit_bps;
+        jconf.metrics_addr = self.metrics_addr.as_ref().map(ToString::to_string);
+        if self.destination_stats {
+            jconf.destination_stats = Some(self.destination_stats);
+        }
+        if let Some(ref pool) = self.worker_pool {
+            jconf.worker_pool_size = Some(pool.workers);
+            jconf.worker_queue_size = Some(pool.queue_size);
+        }
+        jconf.max_pending_tasks = self.max_pending_tasks;
+
         // Security
         if self.security.replay_attack.policy != ReplayAttackPolicy::default() {
             jconf.security = Some(SSSecurityConfig {
@@ -2380,11 +3585,47 @@ impl fmt::Display for Config {
         }
 
         // Balancer
-        if self.balancer.max_server_rtt.is_some() || self.balancer.check_interval.is_some() {
+        if self.balancer.strategy != BalancerStrategy::default()
+            || self.balancer.max_server_rtt.is_some()
+            || self.balancer.check_interval.is_some()
+            || self.balancer.reload_grace.is_some()
+            || self.balancer.connect_timeout_rtt_factor.is_some()
+            || self.balancer.connect_timeout_floor.is_some()
+            || self.balancer.server_failure_policy != ServerFailurePolicy::default()
+            || self.balancer.server_down_cooldown.is_some()
+            || self.balancer.egress_ip_probe.is_some()
+        {
             jconf.balancer = Some(SSBalancerConfig {
+                strategy: match self.balancer.strategy {
+                    BalancerStrategy::Ping => None,
+                    strategy => Some(strategy.to_string()),
+                },
                 max_server_rtt: self.balancer.max_server_rtt.as_ref().map(Duration::as_secs),
                 check_interval: self.balancer.check_interval.as_ref().map(Duration::as_secs),
                 check_best_interval: self.balancer.check_best_interval.as_ref().map(Duration::as_secs),
+                reload_grace: self.balancer.reload_grace.as_ref().map(Duration::as_secs),
+                connect_timeout_rtt_factor: self.balancer.connect_timeout_rtt_factor,
+                connect_timeout_floor: self.balancer.connect_timeout_floor.as_ref().map(Duration::as_secs),
+                server_failure_policy: match self.balancer.server_failure_policy {
+                    ServerFailurePolicy::Reject => None,
+                    policy => Some(policy.to_string()),
+                },
+                server_failure_retry_after: match self.balancer.server_failure_policy {
+                    ServerFailurePolicy::RetryAfter { delay, .. } if delay != DEFAULT_SERVER_FAILURE_RETRY_AFTER => {
+                        Some(delay.as_secs())
+                    }
+                    _ => None,
+                },
+                server_failure_retries: match self.balancer.server_failure_policy {
+                    ServerFailurePolicy::RetryAfter { retries, .. } if retries != DEFAULT_SERVER_FAILURE_RETRIES => {
+                        Some(retries)
+                    }
+                    _ => None,
+                },
+                server_down_cooldown: self.balancer.server_down_cooldown.as_ref().map(Duration::as_secs),
+                egress_ip_probe: self.balancer.egress_ip_probe.as_ref().map(ToString::to_string),
+                egress_ip_probe_interval: self.balancer.egress_ip_probe_interval.as_ref().map(Duration::as_secs),
+                egress_ip_probe_max_redirects: self.balancer.egress_ip_probe_max_redirects,
             });
         }
 
@@ -2418,3 +3659,180 @@ pub fn read_variable_field_value(value: &str) -> Cow<'_, str> {
 
     value.into()
 }
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::{capture_warnings, captured_warnings};
+
+    use super::*;
+
+    #[test]
+    fn default_server_timeout() {
+        capture_warnings();
+
+        let mut config = Config::load_from_str(
+            r#"{
+                "servers": [
+                    { "server": "127.0.0.1", "server_port": 8388, "method": "aes-256-gcm", "password": "p" },
+                    { "server": "127.0.0.1", "server_port": 8389, "method": "aes-256-gcm", "password": "p", "timeout": 10 }
+                ]
+            }"#,
+            ConfigType::Server,
+        )
+        .unwrap();
+        config.apply_default_timeout();
+
+        assert_eq!(config.server[0].timeout(), Some(DEFAULT_SERVER_TIMEOUT));
+        assert_eq!(config.server[1].timeout(), Some(Duration::from_secs(10)));
+
+        let warnings = captured_warnings();
+        let warnings = warnings
+            .iter()
+            .filter(|w| w.contains("doesn't have timeout configured"))
+            .collect::<Vec<_>>();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("127.0.0.1:8388"), "{}", warnings[0]);
+    }
+
+    #[test]
+    fn balancer_strategy() {
+        let config =
+            Config::load_from_str(r#"{ "balancer": { "strategy": "bandwidth" } }"#, ConfigType::Local).unwrap();
+        assert_eq!(config.balancer.strategy, BalancerStrategy::Bandwidth);
+
+        // Serialized back
+        let config = Config::load_from_str(&config.to_string(), ConfigType::Local).unwrap();
+        assert_eq!(config.balancer.strategy, BalancerStrategy::Bandwidth);
+
+        let config = Config::load_from_str(
+            r#"{ "balancer": { "strategy": "least_connections" } }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+        assert_eq!(config.balancer.strategy, BalancerStrategy::LeastConnections);
+
+        let config = Config::load_from_str(
+            r#"{ "balancer": { "strategy": "weighted_round_robin" } }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+        assert_eq!(config.balancer.strategy, BalancerStrategy::WeightedRoundRobin);
+
+        let config = Config::load_from_str(
+            r#"{ "balancer": { "strategy": "weighted_random" } }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+        assert_eq!(config.balancer.strategy, BalancerStrategy::WeightedRandom);
+
+        let err = Config::load_from_str(r#"{ "balancer": { "strategy": "fastest" } }"#, ConfigType::Local).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Invalid), "{:?}", err);
+    }
+
+    #[test]
+    fn no_server_timeout() {
+        let mut config = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "method": "aes-256-gcm",
+                "password": "p",
+                "no_timeout": true
+            }"#,
+            ConfigType::Server,
+        )
+        .unwrap();
+        config.apply_default_timeout();
+
+        assert_eq!(config.server[0].timeout(), None);
+    }
+
+    #[test]
+    fn conflicting_duplicate_servers() {
+        capture_warnings();
+
+        let servers = r#"
+            "servers": [
+                { "server": "127.0.0.1", "server_port": 8390, "method": "aes-256-gcm", "password": "p1" },
+                { "server": "127.0.0.1", "server_port": 8391, "method": "aes-256-gcm", "password": "p1" },
+                { "server": "127.0.0.1", "server_port": 8390, "method": "aes-256-gcm", "password": "p2" }
+            ]
+        "#;
+
+        let config = Config::load_from_str(&format!("{{ {} }}", servers), ConfigType::Server).unwrap();
+        config.check_integrity().unwrap();
+
+        let warnings = captured_warnings();
+        let warnings = warnings
+            .iter()
+            .filter(|w| w.contains("configured more than once"))
+            .collect::<Vec<_>>();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0],
+            "server 127.0.0.1:8390 is configured more than once with different method or password"
+        );
+
+        let config = Config::load_from_str(
+            &format!("{{ {}, \"strict_servers\": true }}", servers),
+            ConfigType::Server,
+        )
+        .unwrap();
+        let err = config.check_integrity().unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Invalid), "{:?}", err);
+    }
+
+    #[test]
+    fn server_error_field() {
+        let err = Config::load_from_str(
+            r#"{
+                "servers": [
+                    { "server": "127.0.0.1", "server_port": 8388, "method": "aes-256-gcm", "password": "p" },
+                    { "server": "127.0.0.1", "server_port": 8389, "method": "aes-256-gcn", "password": "p" }
+                ]
+            }"#,
+            ConfigType::Server,
+        )
+        .unwrap_err();
+
+        assert!(matches!(err.kind, ErrorKind::Invalid), "{:?}", err);
+        assert_eq!(err.field.as_deref(), Some("servers[1].method"));
+        assert_eq!(
+            err.to_string(),
+            "servers[1].method: unsupported method, `aes-256-gcn` is not a supported method"
+        );
+    }
+
+    #[cfg(feature = "local")]
+    #[test]
+    fn local_servers() {
+        let config_str = |servers: &str| {
+            format!(
+                r#"{{
+                    "servers": [
+                        {{ "server": "127.0.0.1", "server_port": 8388, "method": "aes-256-gcm", "password": "p", "remarks": "hk" }},
+                        {{ "server": "127.0.0.1", "server_port": 8389, "method": "aes-256-gcm", "password": "p", "remarks": "jp" }}
+                    ],
+                    "locals": [
+                        {{ "local_address": "127.0.0.1", "local_port": 1080 }},
+                        {{ "local_address": "127.0.0.1", "local_port": 1081, "servers": {} }}
+                    ]
+                }}"#,
+                servers
+            )
+        };
+
+        let config = Config::load_from_str(&config_str(r#"["jp"]"#), ConfigType::Local).unwrap();
+        config.check_integrity().unwrap();
+        assert!(config.local[0].servers.is_empty());
+        assert_eq!(config.local[1].servers, ["jp"]);
+
+        // Kept after serializing
+        let config = Config::load_from_str(&config.to_string(), ConfigType::Local).unwrap();
+        assert_eq!(config.local[1].servers, ["jp"]);
+
+        let config = Config::load_from_str(&config_str(r#"["tw"]"#), ConfigType::Local).unwrap();
+        let err = config.check_integrity().unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Invalid), "{:?}", err);
+    }
+}
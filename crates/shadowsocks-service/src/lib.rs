@@ -70,11 +70,17 @@ pub mod net;
 #[cfg(feature = "server")]
 pub mod server;
 mod sys;
+#[cfg(test)]
+mod test_utils;
 
 /// Default UDP association's expire duration
 #[allow(dead_code)]
 const DEFAULT_UDP_EXPIRY_DURATION: Duration = Duration::from_secs(5 * 60);
 
+/// Default maximum size of UDP datagram's payload, which is the maximum payload of an IPv4 UDP packet
+#[allow(dead_code)]
+const DEFAULT_UDP_MAX_DATAGRAM_SIZE: usize = 65507;
+
 #[cfg(feature = "trust-dns")]
 fn hint_support_default_system_resolver() -> bool {
     // Nearly all *nix system have /etc/resolv.conf, except Android.
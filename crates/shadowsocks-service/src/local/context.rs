@@ -1,22 +1,55 @@
 //! Shadowsocks Local Server Context
 
-use std::sync::Arc;
 #[cfg(feature = "local-dns")]
-use std::{net::IpAddr, time::Duration};
+use std::net::IpAddr;
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
+use arc_swap::ArcSwap;
+use log::{debug, trace, warn};
 #[cfg(feature = "local-dns")]
 use lru_time_cache::LruCache;
 use shadowsocks::{
-    config::ServerType,
+    config::{ConnectStrategy, ServerType},
     context::{Context, SharedContext},
     dns_resolver::DnsResolver,
     net::{AcceptOpts, ConnectOpts},
     relay::Address,
 };
+use spin::Mutex as SpinMutex;
 #[cfg(feature = "local-dns")]
 use tokio::sync::Mutex;
+use tokio::time;
+
+use crate::{
+    acl::{AccessControl, AclDecision, AclRuleStat},
+    config::{SecurityConfig, ServerFailurePolicy, UdpOversizedDatagramPolicy},
+    local::{
+        loadbalancing::AdaptiveConnectTimeout,
+        metrics::{ConnectionGauge, LocalMetrics},
+        net::{HostConnectionGuard, HostConnectionLimiter, RateLimiter},
+        relay_error::{ErrorContext, RelayErrorKind, RelayErrorSink},
+        trace_span::{SpanEvent, SpanEventKind, SpanSink},
+    },
+    net::{FlowStat, WorkerPoolConfig},
+};
+
+/// ACL rule name for targets decided by the DNS relay's reverse lookup cache
+#[cfg(feature = "local-dns")]
+pub const REVERSE_LOOKUP_RULE_NAME: &str = "reverse_lookup";
+
+/// Accepting new connections is paused in this period after file descriptors were exhausted
+pub const FD_EXHAUSTED_ACCEPT_PAUSE: Duration = Duration::from_secs(1);
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+/// Interval of checking active connections when draining
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Local Service Context
 pub struct ServiceContext {
@@ -25,10 +58,67 @@ pub struct ServiceContext {
     accept_opts: AcceptOpts,
 
     // Access Control
-    acl: Option<AccessControl>,
+    acl: Option<ArcSwap<AccessControl>>,
+    acl_rule_stat: AclRuleStat,
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
+    // Bytes relayed directly, not through servers
+    bypassed_flow_stat: Arc<FlowStat>,
+
+    // Send PROXY protocol header to remote servers
+    outbound_proxy_protocol: bool,
+
+    // Limits concurrent connections per destination host
+    host_limiter: Option<Arc<HostConnectionLimiter>>,
+
+    // Bandwidth limits of each tunnel, and of all tunnels
+    rate_limit: Option<u64>,
+    global_rate_limiter: Option<Arc<RateLimiter>>,
+
+    // Pool of workers handling accepted connections
+    worker_pool: Option<WorkerPoolConfig>,
+
+    // Maximum size of UDP datagrams' payload to be relayed
+    udp_max_datagram_size: usize,
+    udp_oversized_datagram_policy: UdpOversizedDatagramPolicy,
+
+    // Retries after failing to connect to a server, if the server doesn't have its own `max_retries`
+    connect_retries: usize,
+
+    // Send handshake to remote servers right after connecting, without waiting for the client's first packet
+    connect_read_ahead: bool,
+    slow_connection_threshold: Option<Duration>,
+
+    // Tunnels without any bytes transferred in this period are closed
+    idle_timeout: Option<Duration>,
+
+    // Connect timeout tuned by servers' observed RTT
+    adaptive_connect_timeout: Option<AdaptiveConnectTimeout>,
+
+    // Action taken when connecting to the chosen server fails after all retries
+    server_failure_policy: ServerFailurePolicy,
+    // Servers failed to connect are skipped by balancers in this period
+    server_down_cooldown: Option<Duration>,
+
+    // Receives relay errors for embedders
+    error_sink: Option<Arc<dyn RelayErrorSink>>,
+    next_conn_id: AtomicUsize,
+
+    // Receives connections' span events for embedders
+    span_sink: Option<Arc<dyn SpanSink>>,
+
+    // Counters of connections, exposed by the metrics server
+    metrics: Option<Arc<LocalMetrics>>,
+
+    // Connections waited by `Server::shutdown`
+    active_connections: Arc<AtomicUsize>,
+    // Set while shutting down, new requests are refused
+    draining: AtomicBool,
+
+    // Accepting is paused until then, after file descriptors were exhausted
+    fd_exhausted_pause: Duration,
+    accept_paused_until: SpinMutex<Option<Instant>>,
 
     // For DNS relay's ACL domain name reverse lookup -- whether the IP shall be forwarded
     #[cfg(feature = "local-dns")]
@@ -49,7 +139,31 @@ impl ServiceContext {
             connect_opts: ConnectOpts::default(),
             accept_opts: AcceptOpts::default(),
             acl: None,
+            acl_rule_stat: AclRuleStat::new(),
             flow_stat: Arc::new(FlowStat::new()),
+            bypassed_flow_stat: Arc::new(FlowStat::new()),
+            outbound_proxy_protocol: false,
+            host_limiter: None,
+            rate_limit: None,
+            global_rate_limiter: None,
+            worker_pool: None,
+            udp_max_datagram_size: crate::DEFAULT_UDP_MAX_DATAGRAM_SIZE,
+            udp_oversized_datagram_policy: UdpOversizedDatagramPolicy::default(),
+            connect_retries: 0,
+            adaptive_connect_timeout: None,
+            server_failure_policy: ServerFailurePolicy::default(),
+            server_down_cooldown: None,
+            connect_read_ahead: false,
+            slow_connection_threshold: None,
+            idle_timeout: None,
+            error_sink: None,
+            metrics: None,
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            draining: AtomicBool::new(false),
+            fd_exhausted_pause: FD_EXHAUSTED_ACCEPT_PAUSE,
+            accept_paused_until: SpinMutex::new(None),
+            next_conn_id: AtomicUsize::new(0),
+            span_sink: None,
             #[cfg(feature = "local-dns")]
             reverse_lookup_cache: Mutex::new(LruCache::with_expiry_duration_and_capacity(
                 Duration::from_secs(3 * 24 * 60 * 60),
@@ -90,12 +204,39 @@ impl ServiceContext {
 
     /// Set Access Control List
     pub fn set_acl(&mut self, acl: AccessControl) {
-        self.acl = Some(acl);
+        self.acl = Some(ArcSwap::from_pointee(acl));
     }
 
-    /// Get Access Control List reference
-    pub fn acl(&self) -> Option<&AccessControl> {
-        self.acl.as_ref()
+    /// Get the current Access Control List
+    ///
+    /// Returns a snapshot instead of a reference, because the ACL could be replaced by `reload_acl` at any
+    /// time. Rules of the snapshot are not changed by reloading.
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.acl.as_ref().map(ArcSwap::load_full)
+    }
+
+    /// Reload Access Control List from its file, and replace the current rules atomically
+    ///
+    /// Established connections and servers are not affected, new connections will be checked with the new rules.
+    pub async fn reload_acl(&self) -> io::Result<()> {
+        let acl = match self.acl {
+            Some(ref a) => a,
+            None => return Err(io::Error::new(ErrorKind::NotFound, "ACL is not configured")),
+        };
+
+        let new_acl = AccessControl::load_from_file(acl.load().file_path())?;
+        acl.store(Arc::new(new_acl));
+
+        // Entries of reverse lookup cache were decided with the old rules
+        #[cfg(feature = "local-dns")]
+        self.reverse_lookup_cache.lock().await.clear();
+
+        Ok(())
+    }
+
+    /// Get statistic of connections decided by each ACL rule
+    pub fn acl_rule_stat(&self) -> &AclRuleStat {
+        &self.acl_rule_stat
     }
 
     /// Get cloned flow statistic
@@ -108,6 +249,334 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Get cloned statistic of bytes relayed directly to targets
+    ///
+    /// They are not counted in `flow_stat`, which only counts bytes relayed through servers.
+    pub fn bypassed_flow_stat(&self) -> Arc<FlowStat> {
+        self.bypassed_flow_stat.clone()
+    }
+
+    /// Get reference of statistic of bytes relayed directly to targets
+    pub fn bypassed_flow_stat_ref(&self) -> &FlowStat {
+        self.bypassed_flow_stat.as_ref()
+    }
+
+    /// Set whether to send a PROXY protocol (v1) header carrying the client's address to remote servers
+    pub fn set_outbound_proxy_protocol(&mut self, outbound_proxy_protocol: bool) {
+        self.outbound_proxy_protocol = outbound_proxy_protocol;
+    }
+
+    /// Check if a PROXY protocol (v1) header should be sent to remote servers
+    pub fn outbound_proxy_protocol(&self) -> bool {
+        self.outbound_proxy_protocol
+    }
+
+    /// Set maximum size of UDP packets to be sent, including the header and encryption of proxied packets
+    pub fn set_udp_max_datagram_size(&mut self, udp_max_datagram_size: usize) {
+        self.udp_max_datagram_size = udp_max_datagram_size;
+    }
+
+    /// Maximum size of UDP packets to be sent, larger datagrams are handled by `udp_oversized_datagram_policy`
+    pub fn udp_max_datagram_size(&self) -> usize {
+        self.udp_max_datagram_size
+    }
+
+    /// Set action taken on UDP datagrams larger than `udp_max_datagram_size`
+    pub fn set_udp_oversized_datagram_policy(&mut self, policy: UdpOversizedDatagramPolicy) {
+        self.udp_oversized_datagram_policy = policy;
+    }
+
+    /// Action taken on UDP datagrams larger than `udp_max_datagram_size`
+    pub fn udp_oversized_datagram_policy(&self) -> UdpOversizedDatagramPolicy {
+        self.udp_oversized_datagram_policy
+    }
+
+    /// Set retries after failing to connect to a server
+    ///
+    /// This is the default for servers without `max_retries`
+    pub fn set_connect_retries(&mut self, connect_retries: usize) {
+        self.connect_retries = connect_retries;
+    }
+
+    /// Retries after failing to connect to a server
+    pub fn connect_retries(&self) -> usize {
+        self.connect_retries
+    }
+
+    /// Set connect timeout tuned by servers' observed RTT
+    ///
+    /// Servers without observed RTT still use their own `timeout`
+    pub fn set_adaptive_connect_timeout(&mut self, adaptive: AdaptiveConnectTimeout) {
+        self.adaptive_connect_timeout = Some(adaptive);
+    }
+
+    /// Connect timeout tuned by servers' observed RTT
+    pub fn adaptive_connect_timeout(&self) -> Option<&AdaptiveConnectTimeout> {
+        self.adaptive_connect_timeout.as_ref()
+    }
+
+    /// Set action taken when connecting to the chosen server fails after all retries
+    pub fn set_server_failure_policy(&mut self, policy: ServerFailurePolicy) {
+        self.server_failure_policy = policy;
+    }
+
+    /// Action taken when connecting to the chosen server fails after all retries
+    pub fn server_failure_policy(&self) -> ServerFailurePolicy {
+        self.server_failure_policy
+    }
+
+    /// Set cooldown of servers failed to connect after all retries
+    ///
+    /// Balancers skip servers in cooldown. Connections are refused if all servers are in cooldown,
+    /// regardless of the server failure policy.
+    pub fn set_server_down_cooldown(&mut self, cooldown: Duration) {
+        self.server_down_cooldown = Some(cooldown);
+    }
+
+    /// Cooldown of servers failed to connect, servers are never skipped if it is not set
+    pub fn server_down_cooldown(&self) -> Option<Duration> {
+        self.server_down_cooldown
+    }
+
+    /// Set read-ahead of tunnels through remote servers
+    ///
+    /// Handshake will be sent right after connecting, and data sent by remote will be relayed immediately,
+    /// instead of waiting for the client's first packet, which could be sent along with the handshake.
+    /// It is for protocols that servers speak first.
+    pub fn set_connect_read_ahead(&mut self, read_ahead: bool) {
+        self.connect_read_ahead = read_ahead;
+    }
+
+    /// Check if tunnels through remote servers read ahead
+    pub fn connect_read_ahead(&self) -> bool {
+        self.connect_read_ahead
+    }
+
+    /// Set threshold of slow connections through remote servers
+    ///
+    /// Connections taking longer than `threshold` to connect to the server, or to receive the first byte after the
+    /// handshake was sent, will be logged at warn level.
+    pub fn set_slow_connection_threshold(&mut self, threshold: Duration) {
+        self.slow_connection_threshold = Some(threshold);
+    }
+
+    /// Get threshold of slow connections through remote servers
+    pub fn slow_connection_threshold(&self) -> Option<Duration> {
+        self.slow_connection_threshold
+    }
+
+    /// Set idle timeout of TCP tunnels
+    ///
+    /// Tunnels without any bytes transferred in either direction for `timeout` will be closed.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// Get idle timeout of TCP tunnels
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Set the sink receiving relay errors
+    pub fn set_error_sink(&mut self, error_sink: Arc<dyn RelayErrorSink>) {
+        self.error_sink = Some(error_sink);
+    }
+
+    /// Set counters of connections, which are not counted by default
+    pub fn set_metrics(&mut self, metrics: Arc<LocalMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Get counters of connections, `None` if metrics are not enabled
+    pub fn metrics(&self) -> Option<&LocalMetrics> {
+        self.metrics.as_deref()
+    }
+
+    /// Count an active connection or UDP association until the returned guard is dropped, `drain` waits for them
+    pub fn track_active_connection(&self) -> ConnectionGauge {
+        ConnectionGauge::new(self.active_connections.clone())
+    }
+
+    /// Number of active connections
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Acquire)
+    }
+
+    /// Refuse new requests, the local server is shutting down
+    pub fn set_draining(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    /// Check if the local server is shutting down
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Refuse new requests, and wait until active connections are finished
+    ///
+    /// Local servers should keep running while draining, UDP associations are still served by them. Returns after
+    /// `grace_period` even if there are connections still active.
+    pub async fn drain(&self, grace_period: Duration) {
+        self.set_draining();
+
+        let deadline = Instant::now() + grace_period;
+        loop {
+            let active = self.active_connections();
+            if active == 0 {
+                debug!("all connections are finished, shut down");
+                break;
+            }
+
+            if Instant::now() >= deadline {
+                warn!(
+                    "shut down with {} connections still active after {:?}",
+                    active, grace_period
+                );
+                break;
+            }
+
+            trace!("waiting for {} active connections to shut down", active);
+            time::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Set the period of pausing accepting new connections after file descriptors were exhausted
+    pub fn set_fd_exhausted_pause(&mut self, pause: Duration) {
+        self.fd_exhausted_pause = pause;
+    }
+
+    /// Report that file descriptors were exhausted, local servers will pause accepting new connections for a while
+    ///
+    /// Only the first report of a pause is logged.
+    pub fn report_fd_exhausted(&self, err: &io::Error) {
+        let now = Instant::now();
+        let mut paused_until = self.accept_paused_until.lock();
+        if !matches!(*paused_until, Some(until) if until > now) {
+            warn!(
+                "file descriptors exhausted, pause accepting new connections for {:?}, error: {}",
+                self.fd_exhausted_pause, err
+            );
+        }
+        *paused_until = Some(now + self.fd_exhausted_pause);
+    }
+
+    /// Check if accepting new connections is paused by `report_fd_exhausted`
+    pub fn is_accept_paused(&self) -> bool {
+        matches!(*self.accept_paused_until.lock(), Some(until) if until > Instant::now())
+    }
+
+    /// Wait until accepting new connections is no longer paused by `report_fd_exhausted`
+    pub async fn accept_resumed(&self) {
+        loop {
+            let paused_until = *self.accept_paused_until.lock();
+            match paused_until {
+                Some(until) if until > Instant::now() => time::sleep_until(until.into()).await,
+                _ => return,
+            }
+        }
+    }
+
+    /// Allocate an identifier for a new connection
+    pub fn next_conn_id(&self) -> usize {
+        self.next_conn_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Report an error of connection `conn_id` to the error sink
+    pub fn report_relay_error(
+        &self,
+        conn_id: usize,
+        kind: RelayErrorKind,
+        peer_addr: SocketAddr,
+        target_addr: &Address,
+        error: &io::Error,
+    ) {
+        if let Some(ref sink) = self.error_sink {
+            let err = ErrorContext {
+                kind,
+                peer_addr,
+                target_addr: target_addr.clone(),
+                error: io::Error::new(error.kind(), error.to_string()),
+            };
+            sink.on_error(conn_id, err);
+        }
+    }
+
+    /// Set the sink receiving connections' span events
+    pub fn set_span_sink(&mut self, span_sink: Arc<dyn SpanSink>) {
+        self.span_sink = Some(span_sink);
+    }
+
+    /// Emit span event of connection `conn_id` to the span sink
+    pub fn emit_span_event(&self, conn_id: usize, kind: SpanEventKind, peer_addr: SocketAddr, target_addr: &Address) {
+        if let Some(ref sink) = self.span_sink {
+            let event = SpanEvent {
+                kind,
+                peer_addr,
+                target_addr: target_addr.clone(),
+                time: Instant::now(),
+            };
+            sink.on_span_event(conn_id, event);
+        }
+    }
+
+    /// Set maximum concurrent connections to each destination host
+    pub fn set_max_conns_per_host(&mut self, max_conns_per_host: usize) {
+        self.host_limiter = Some(Arc::new(HostConnectionLimiter::new(max_conns_per_host)));
+    }
+
+    /// Set bandwidth limit of each tunnel in bytes per second
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.rate_limit = Some(bytes_per_sec);
+    }
+
+    /// Set bandwidth limit shared by all tunnels in bytes per second
+    pub fn set_global_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.global_rate_limiter = Some(Arc::new(RateLimiter::new(bytes_per_sec)));
+    }
+
+    /// Create limiters for a new tunnel, it is unlimited if empty
+    pub fn tunnel_rate_limiters(&self) -> Vec<Arc<RateLimiter>> {
+        let mut limiters = Vec::new();
+        if let Some(bytes_per_sec) = self.rate_limit {
+            limiters.push(Arc::new(RateLimiter::new(bytes_per_sec)));
+        }
+        if let Some(ref limiter) = self.global_rate_limiter {
+            limiters.push(limiter.clone());
+        }
+        limiters
+    }
+
+    /// Set the pool of workers handling accepted connections, instead of a task for each of them
+    pub fn set_worker_pool(&mut self, config: WorkerPoolConfig) {
+        self.worker_pool = Some(config);
+    }
+
+    /// Pool of workers handling accepted connections
+    pub fn worker_pool(&self) -> Option<WorkerPoolConfig> {
+        self.worker_pool
+    }
+
+    /// Acquire a connection slot to the host of `addr`
+    ///
+    /// Returns `Ok(None)` if there is no limit, and an error if the host already has `max_conns_per_host` connections.
+    /// The slot is held until the returned guard is dropped.
+    pub fn acquire_host_connection(&self, addr: &Address) -> io::Result<Option<HostConnectionGuard>> {
+        match self.host_limiter {
+            None => Ok(None),
+            Some(ref limiter) => match limiter.acquire(addr) {
+                Some(guard) => Ok(Some(guard)),
+                None => Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "too many connections to {}, max_conns_per_host: {}",
+                        addr,
+                        limiter.max_conns_per_host()
+                    ),
+                )),
+            },
+        }
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -124,6 +593,8 @@ impl ServiceContext {
         match self.acl {
             None => false,
             Some(ref acl) => {
+                let acl = acl.load();
+
                 #[cfg(feature = "local-dns")]
                 {
                     if let Address::SocketAddress(ref saddr) = addr {
@@ -141,6 +612,34 @@ impl ServiceContext {
         }
     }
 
+    /// Check if target should be bypassed, and which ACL rule makes the decision
+    ///
+    /// The decision will be counted in `acl_rule_stat`. Returns `None` if ACL is not configured.
+    pub async fn check_target_acl(&self, addr: &Address) -> Option<AclDecision> {
+        let acl = self.acl.as_ref()?.load();
+
+        #[cfg(feature = "local-dns")]
+        {
+            if let Address::SocketAddress(ref saddr) = addr {
+                // do the reverse lookup in our local cache
+                let mut reverse_lookup_cache = self.reverse_lookup_cache.lock().await;
+                // if a qname is found
+                if let Some(forward) = reverse_lookup_cache.get(&saddr.ip()) {
+                    let decision = AclDecision {
+                        bypassed: !*forward,
+                        rule: REVERSE_LOOKUP_RULE_NAME.to_owned(),
+                    };
+                    self.acl_rule_stat.incr_conn(&decision.rule);
+                    return Some(decision);
+                }
+            }
+        }
+
+        let decision = acl.check_target(&self.context, addr).await;
+        self.acl_rule_stat.incr_conn(&decision.rule);
+        Some(decision)
+    }
+
     /// Add a record to the reverse lookup cache
     #[cfg(feature = "local-dns")]
     pub async fn add_to_reverse_lookup_cache(&self, addr: IpAddr, forward: bool) {
@@ -148,7 +647,7 @@ impl ServiceContext {
             != match self.acl {
                 // Proxy everything by default
                 None => true,
-                Some(ref a) => a.check_ip_in_proxy_list(&addr),
+                Some(ref a) => a.load().check_ip_in_proxy_list(&addr),
             };
         let mut reverse_lookup_cache = self.reverse_lookup_cache.lock().await;
         match reverse_lookup_cache.get_mut(&addr) {
@@ -174,9 +673,95 @@ impl ServiceContext {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Set the maximum number of DNS resolutions in flight, resolutions beyond it fail immediately
+    pub fn set_max_pending_dns_resolutions(&mut self, max: usize) {
+        let context =
+            Arc::get_mut(&mut self.context).expect("cannot set max_pending_dns_resolutions on a shared context");
+        context.set_max_pending_dns_resolutions(max);
+    }
+
+    /// Set the order of trying resolved addresses when connecting to targets directly
+    pub fn set_direct_connect_strategy(&mut self, strategy: ConnectStrategy) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set direct_connect_strategy on a shared context");
+        context.set_direct_connect_strategy(strategy);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
         context.set_replay_attack_policy(security.replay_attack.policy);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{fs, process};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn acl_rule_stat() {
+        let acl_path = std::env::temp_dir().join(format!("shadowsocks-acl-rule-stat-{}.acl", process::id()));
+        fs::write(&acl_path, "[proxy_all]\n[bypass_list]\n||example.com\n192.168.0.0/16\n").unwrap();
+        let acl = AccessControl::load_from_file(&acl_path).unwrap();
+        fs::remove_file(&acl_path).unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_acl(acl);
+
+        let decision = context
+            .check_target_acl(&Address::DomainNameAddress("www.example.com".to_owned(), 443))
+            .await
+            .unwrap();
+        assert!(decision.bypassed);
+        assert_eq!(decision.rule, "||example.com");
+
+        let decision = context
+            .check_target_acl(&Address::SocketAddress("192.168.1.1:80".parse().unwrap()))
+            .await
+            .unwrap();
+        assert!(decision.bypassed);
+        assert_eq!(decision.rule, "192.168.0.0/16");
+
+        let decision = context
+            .check_target_acl(&Address::SocketAddress("10.1.1.1:80".parse().unwrap()))
+            .await
+            .unwrap();
+        assert!(!decision.bypassed);
+        assert_eq!(decision.rule, crate::acl::DEFAULT_RULE_NAME);
+
+        let stat = context.acl_rule_stat();
+        assert_eq!(stat.conn_count("||example.com"), 1);
+        assert_eq!(stat.conn_count("192.168.0.0/16"), 1);
+        assert_eq!(stat.conn_count(crate::acl::DEFAULT_RULE_NAME), 1);
+    }
+
+    #[tokio::test]
+    async fn reload_acl() {
+        let acl_path = std::env::temp_dir().join(format!("shadowsocks-reload-acl-{}.acl", process::id()));
+        fs::write(&acl_path, "[proxy_all]\n[bypass_list]\n||example.com\n").unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_acl(AccessControl::load_from_file(&acl_path).unwrap());
+
+        let target = Address::DomainNameAddress("www.example.com".to_owned(), 443);
+        let decision = context.check_target_acl(&target).await.unwrap();
+        assert!(decision.bypassed);
+        assert_eq!(decision.rule, "||example.com");
+
+        fs::write(&acl_path, "[bypass_all]\n[proxy_list]\n||example.com\n").unwrap();
+        let result = context.reload_acl().await;
+        fs::remove_file(&acl_path).unwrap();
+        result.unwrap();
+
+        let decision = context.check_target_acl(&target).await.unwrap();
+        assert!(!decision.bypassed);
+        assert_eq!(decision.rule, "||example.com");
+
+        let decision = context
+            .check_target_acl(&Address::DomainNameAddress("www.example.org".to_owned(), 443))
+            .await
+            .unwrap();
+        assert!(decision.bypassed);
+    }
+}
@@ -24,7 +24,7 @@ use tokio::{
 };
 use trust_dns_resolver::proto::{
     op::{header::MessageType, response_code::ResponseCode, Message, OpCode, Query},
-    rr::{DNSClass, Name, RData, RecordType},
+    rr::{DNSClass, Name, RData, Record, RecordType},
 };
 
 use shadowsocks::{
@@ -42,12 +42,16 @@ use crate::{
 
 use super::{client_cache::DnsClientCache, config::NameServerAddr};
 
+/// TTL of answers for sinkholed names
+const SINKHOLE_TTL: u32 = 300;
+
 /// DNS Relay server
 pub struct Dns {
     context: Arc<ServiceContext>,
     mode: Mode,
     local_addr: Arc<NameServerAddr>,
     remote_addr: Arc<Address>,
+    sinkhole: HashSet<Name>,
 }
 
 impl Dns {
@@ -64,6 +68,7 @@ impl Dns {
             mode: Mode::UdpOnly,
             local_addr: Arc::new(local_addr),
             remote_addr: Arc::new(remote_addr),
+            sinkhole: HashSet::new(),
         }
     }
 
@@ -72,9 +77,30 @@ impl Dns {
         self.mode = mode;
     }
 
+    /// Set hostnames answered with unspecified addresses (`0.0.0.0` and `::`) without querying upstreams
+    ///
+    /// Their subdomains are also answered.
+    pub fn set_sinkhole_hosts(&mut self, hosts: &[String]) -> io::Result<()> {
+        self.sinkhole.clear();
+        for host in hosts {
+            let mut name = match Name::from_str(host) {
+                Ok(n) => n,
+                Err(err) => {
+                    let err = io::Error::new(ErrorKind::InvalidInput, format!("invalid host {}, {}", host, err));
+                    return Err(err);
+                }
+            };
+            name.set_fqdn(true);
+            self.sinkhole.insert(name);
+        }
+        Ok(())
+    }
+
     /// Run server
     pub async fn run(self, bind_addr: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let client = Arc::new(DnsClient::new(self.context.clone(), balancer, self.mode));
+        let mut client = DnsClient::new(self.context.clone(), balancer, self.mode);
+        client.sinkhole = self.sinkhole.clone();
+        let client = Arc::new(client);
 
         let tcp_fut = self.run_tcp_server(bind_addr, client.clone());
         let udp_fut = self.run_udp_server(bind_addr, client);
@@ -355,9 +381,9 @@ fn should_forward_by_query(context: &ServiceContext, balancer: &PingBalancer, qu
             // unconditionally use default for all non-IN queries
             Some(acl.is_default_in_proxy_list())
         } else if query.query_type() == RecordType::PTR {
-            Some(should_forward_by_ptr_name(acl, query.name()))
+            Some(should_forward_by_ptr_name(&acl, query.name()))
         } else {
-            let result = check_name_in_proxy_list(acl, query.name());
+            let result = check_name_in_proxy_list(&acl, query.name());
             if result.is_none() && acl.is_ip_empty() && acl.is_host_empty() {
                 Some(acl.is_default_in_proxy_list())
             } else {
@@ -456,6 +482,7 @@ struct DnsClient {
     mode: Mode,
     balancer: PingBalancer,
     attempts: usize,
+    sinkhole: HashSet<Name>,
 }
 
 impl DnsClient {
@@ -466,9 +493,41 @@ impl DnsClient {
             mode,
             balancer,
             attempts: 2,
+            sinkhole: HashSet::new(),
         }
     }
 
+    /// Check if `name` or one of its parents is sinkholed
+    fn is_sinkholed(&self, name: &Name) -> bool {
+        if self.sinkhole.is_empty() {
+            return false;
+        }
+
+        let mut name = name.clone();
+        name.set_fqdn(true);
+        loop {
+            if self.sinkhole.contains(&name) {
+                return true;
+            }
+            if name.is_root() {
+                return false;
+            }
+            name = name.base_name();
+        }
+    }
+
+    /// Answer `query` with unspecified addresses, other types of records get an empty answer
+    fn sinkhole_answer(query: &Query, message: &mut Message) {
+        message.add_query(query.clone());
+
+        let rdata = match query.query_type() {
+            RecordType::A => RData::A(Ipv4Addr::UNSPECIFIED),
+            RecordType::AAAA => RData::AAAA(Ipv6Addr::UNSPECIFIED),
+            _ => return,
+        };
+        message.add_answer(Record::from_rdata(query.name().clone(), SINKHOLE_TTL, rdata));
+    }
+
     async fn resolve(
         &self,
         request: Message,
@@ -490,6 +549,10 @@ impl DnsClient {
             // Other ops are not supported
 
             message.set_response_code(ResponseCode::NotImp);
+        } else if request.query_count() > 0 && self.is_sinkholed(request.queries()[0].name()) {
+            let query = &request.queries()[0];
+            debug!("DNS sinkholed {:?} {}", query.query_type(), query.name());
+            DnsClient::sinkhole_answer(query, &mut message);
         } else if request.query_count() > 0 {
             // Make queries according to ACL rules
 
@@ -537,7 +600,7 @@ impl DnsClient {
 
         let decider = async {
             let local_response = self.lookup_local(query, local_addr).await;
-            if should_forward_by_response(self.context.acl(), &local_response, query) {
+            if should_forward_by_response(self.context.acl().as_deref(), &local_response, query) {
                 None
             } else {
                 Some(local_response)
@@ -710,3 +773,90 @@ impl DnsClient {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::config::Mode;
+
+    use crate::local::loadbalancing::PingBalancerBuilder;
+
+    use super::*;
+
+    const UPSTREAM_ANSWER: Ipv4Addr = Ipv4Addr::new(1, 2, 3, 4);
+
+    /// Upstream DNS server answering every A query with `UPSTREAM_ANSWER`
+    async fn run_upstream(socket: UdpSocket) {
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        loop {
+            let (n, peer_addr) = socket.recv_from(&mut buffer).await.unwrap();
+            let request = Message::from_vec(&buffer[..n]).unwrap();
+
+            let mut response = Message::new();
+            response.set_id(request.id());
+            response.set_message_type(MessageType::Response);
+            for query in request.queries() {
+                response.add_query(query.clone());
+                response.add_answer(Record::from_rdata(query.name().clone(), 60, RData::A(UPSTREAM_ANSWER)));
+            }
+            socket.send_to(&response.to_vec().unwrap(), peer_addr).await.unwrap();
+        }
+    }
+
+    fn make_request(name: &str, query_type: RecordType) -> Message {
+        let mut request = Message::new();
+        request.set_id(thread_rng().gen());
+        request.set_recursion_desired(true);
+        request.add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+        // Header's counts are updated by encoding, like requests received from clients
+        Message::from_vec(&request.to_vec().unwrap()).unwrap()
+    }
+
+    fn answers(response: &Message) -> Vec<RData> {
+        response.answers().iter().filter_map(|r| r.data().cloned()).collect()
+    }
+
+    #[tokio::test]
+    async fn dns_sinkhole() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = NameServerAddr::SocketAddr(upstream.local_addr().unwrap());
+        tokio::spawn(run_upstream(upstream));
+        // No proxy server, all queries are sent to the local DNS
+        let remote_addr = Address::from(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 53));
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let mut server = Dns::with_context(context.clone(), local_addr.clone(), remote_addr.clone());
+        server.set_sinkhole_hosts(&["ads.example.com".to_owned()]).unwrap();
+
+        let mut client = DnsClient::new(context, balancer, Mode::UdpOnly);
+        client.sinkhole = server.sinkhole.clone();
+
+        for name in ["ads.example.com.", "tracker.ADS.example.com."] {
+            let response = client
+                .resolve(make_request(name, RecordType::A), &local_addr, &remote_addr)
+                .await
+                .unwrap();
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            assert_eq!(answers(&response), [RData::A(Ipv4Addr::UNSPECIFIED)], "{}", name);
+
+            let response = client
+                .resolve(make_request(name, RecordType::AAAA), &local_addr, &remote_addr)
+                .await
+                .unwrap();
+            assert_eq!(answers(&response), [RData::AAAA(Ipv6Addr::UNSPECIFIED)], "{}", name);
+        }
+
+        // Not blocked, even though they share the suffix
+        for name in ["example.com.", "bads.example.com."] {
+            let response = client
+                .resolve(make_request(name, RecordType::A), &local_addr, &remote_addr)
+                .await
+                .unwrap();
+            assert_eq!(answers(&response), [RData::A(UPSTREAM_ANSWER)], "{}", name);
+        }
+    }
+}
@@ -14,7 +14,7 @@ use super::{connector::Connector, http_client::ProxyHttpClient};
 /// Cached HTTP client for remote servers
 pub struct ProxyClientCache {
     context: Arc<ServiceContext>,
-    cache: Mutex<LruCache<ServerAddr, ProxyHttpClient>>,
+    cache: Mutex<LruCache<ServerAddr, (Arc<ServerIdent>, ProxyHttpClient)>>,
 }
 
 impl ProxyClientCache {
@@ -29,8 +29,22 @@ impl ProxyClientCache {
         let server_config = server.server_config();
 
         let mut cache = self.cache.lock().await;
-        if let Some(client) = cache.get(server_config.addr()) {
-            return client.clone();
+
+        // Clients of servers retired by reloading are dropped with their pooled connections
+        let retired = cache
+            .peek_iter()
+            .filter(|(_, (server, _))| server.is_retired())
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<_>>();
+        for addr in retired {
+            cache.remove(&addr);
+        }
+
+        // Server with the same address may be replaced by reloading, its client connects through the old one
+        if let Some((cached_server, client)) = cache.get(server_config.addr()) {
+            if Arc::ptr_eq(cached_server, server) {
+                return client.clone();
+            }
         }
 
         // Create a new client
@@ -38,7 +52,7 @@ impl ProxyClientCache {
             .http1_preserve_header_case(true)
             .http1_title_case_headers(true)
             .build::<_, Body>(Connector::new(self.context.clone(), Some(server.clone())));
-        cache.insert(server_config.addr().clone(), client.clone());
+        cache.insert(server_config.addr().clone(), (server.clone(), client.clone()));
 
         client
     }
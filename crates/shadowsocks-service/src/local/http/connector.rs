@@ -14,9 +14,16 @@ use log::error;
 use pin_project::pin_project;
 use tower::Service;
 
-use crate::local::{context::ServiceContext, loadbalancing::ServerIdent, net::AutoProxyClientStream};
+use crate::local::{
+    context::ServiceContext,
+    loadbalancing::ServerIdent,
+    net::{AutoProxyClientStream, RateLimitedStream},
+};
 
-use super::{http_stream::ProxyHttpStream, utils::host_addr};
+use super::{
+    http_stream::{HttpConnectionStream, ProxyHttpStream},
+    utils::host_addr,
+};
 
 #[derive(Clone)]
 pub struct Connector {
@@ -33,7 +40,7 @@ impl Connector {
 impl Service<Uri> for Connector {
     type Error = io::Error;
     type Future = Connecting;
-    type Response = ProxyHttpStream;
+    type Response = RateLimitedStream<ProxyHttpStream>;
 
     fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
@@ -53,17 +60,33 @@ impl Service<Uri> for Connector {
                         Err(err)
                     }
                     Some(addr) => {
-                        let s = match server {
-                            Some(ser) => AutoProxyClientStream::connect_proxied(context, ser.as_ref(), addr).await?,
-                            None => AutoProxyClientStream::connect_bypassed(context, addr).await?,
+                        let rate_limiters = context.tunnel_rate_limiters();
+                        let mut s = match server {
+                            Some(ser) => {
+                                let s =
+                                    AutoProxyClientStream::connect_proxied(context.clone(), ser.as_ref(), addr.clone())
+                                        .await?;
+                                let mut s = HttpConnectionStream::new(s, None);
+                                s.close_on_retired(ser);
+                                s
+                            }
+                            None => {
+                                let bypassed_flow_stat = context.bypassed_flow_stat();
+                                let s = AutoProxyClientStream::connect_bypassed(context.clone(), addr.clone()).await?;
+                                HttpConnectionStream::new(s, Some(bypassed_flow_stat))
+                            }
                         };
+                        s.set_destination(context, addr);
 
-                        if is_https {
+                        let stream = if is_https {
                             let host = dst.host().unwrap().trim_start_matches('[').trim_start_matches(']');
-                            ProxyHttpStream::connect_https(s, host).await
+                            ProxyHttpStream::connect_https(s, host).await?
                         } else {
-                            Ok(ProxyHttpStream::connect_http(s))
-                        }
+                            ProxyHttpStream::connect_http(s)
+                        };
+
+                        // Each connection of the client is limited like a tunnel
+                        Ok(RateLimitedStream::new(stream, rate_limiters))
                     }
                 }
             }
@@ -75,11 +98,11 @@ impl Service<Uri> for Connector {
 #[pin_project]
 pub struct Connecting {
     #[pin]
-    fut: BoxFuture<'static, io::Result<ProxyHttpStream>>,
+    fut: BoxFuture<'static, io::Result<RateLimitedStream<ProxyHttpStream>>>,
 }
 
 impl Future for Connecting {
-    type Output = io::Result<ProxyHttpStream>;
+    type Output = io::Result<RateLimitedStream<ProxyHttpStream>>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
         self.project().fut.poll(cx)
@@ -1,9 +1,36 @@
 //! HTTP Service Dispatcher
 
-use std::{io, net::SocketAddr, str::FromStr, sync::Arc};
+use std::{
+    error::Error as StdError,
+    io::{self, ErrorKind},
+    mem,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::{ready, Future, Stream, StreamExt};
 
 use hyper::{
-    header::{GetAll, HeaderValue},
+    body::HttpBody,
+    header::{
+        GetAll,
+        HeaderValue,
+        CONNECTION,
+        CONTENT_LENGTH,
+        CONTENT_TYPE,
+        HOST,
+        PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION,
+        TRANSFER_ENCODING,
+    },
     http::uri::{Authority, Scheme},
     upgrade,
     Body,
@@ -15,7 +42,8 @@ use hyper::{
     Uri,
     Version,
 };
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
+use tokio::time::{self, Sleep};
 
 use shadowsocks::relay::socks5::Address;
 
@@ -23,7 +51,10 @@ use crate::local::{
     context::ServiceContext,
     loadbalancing::PingBalancer,
     net::{AutoProxyClientStream, AutoProxyIo},
-    utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+    relay_error::RelayErrorKind,
+    socks::config::Socks5AuthConfig,
+    trace_span::SpanEventKind,
+    utils::{establish_tcp_tunnel_bypassed, establish_tcp_tunnel_with_failover},
 };
 
 use super::{
@@ -34,34 +65,88 @@ use super::{
 
 pub struct HttpDispatcher {
     context: Arc<ServiceContext>,
+    conn_id: usize,
     req: Request<Body>,
     balancer: PingBalancer,
     client_addr: SocketAddr,
     bypass_client: BypassHttpClient,
     proxy_client_cache: Arc<ProxyClientCache>,
+    forward_headers: Arc<HeaderMap>,
+    max_request_body: Option<u64>,
+    body_start_timeout: Option<Duration>,
+    auth: Arc<Socks5AuthConfig>,
 }
 
 impl HttpDispatcher {
+    /// Create a dispatcher of `req`, received from the accepted connection `conn_id`
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         context: Arc<ServiceContext>,
+        conn_id: usize,
         req: Request<Body>,
         balancer: PingBalancer,
         client_addr: SocketAddr,
         bypass_client: BypassHttpClient,
         proxy_client_cache: Arc<ProxyClientCache>,
+        forward_headers: Arc<HeaderMap>,
+        max_request_body: Option<u64>,
+        body_start_timeout: Option<Duration>,
+        auth: Arc<Socks5AuthConfig>,
     ) -> HttpDispatcher {
         HttpDispatcher {
             context,
+            conn_id,
             req,
             balancer,
             client_addr,
             bypass_client,
             proxy_client_cache,
+            forward_headers,
+            max_request_body,
+            body_start_timeout,
+            auth,
         }
     }
 
     pub async fn dispatch(mut self) -> io::Result<Response<Body>> {
-        trace!("request {} {:?}", self.client_addr, self.req);
+        let conn_id = self.conn_id;
+        trace!("[c{}] request {} {:?}", conn_id, self.client_addr, self.req);
+
+        // Keep-alive connections may still send requests while shutting down
+        if self.context.is_draining() {
+            debug!(
+                "[c{}] HTTP {} {} refused, shutting down",
+                conn_id,
+                self.req.method(),
+                self.client_addr
+            );
+            return make_service_unavailable();
+        }
+
+        // Requests that may be framed differently by the upstream server could be used for request smuggling
+        if let Err(reason) = check_request_framing(self.req.headers()) {
+            warn!(
+                "[c{}] HTTP {} {} rejected, {}",
+                conn_id,
+                self.req.method(),
+                self.client_addr,
+                reason
+            );
+            return make_bad_request();
+        }
+
+        // Proxy-Authorization is a hop-by-hop header, it will be removed before forwarding
+        //
+        // Clients usually send requests without credentials until they are challenged, which is not worth a warning
+        if self.auth.auth_required() && !check_proxy_authorization(self.req.headers(), &self.auth) {
+            debug!(
+                "[c{}] HTTP {} {} rejected, proxy authentication failed",
+                conn_id,
+                self.req.method(),
+                self.client_addr
+            );
+            return make_proxy_authentication_required();
+        }
 
         // Parse URI
         //
@@ -71,20 +156,22 @@ impl HttpDispatcher {
                 if self.req.uri().authority().is_some() {
                     // URI has authority but invalid
                     error!(
-                        "HTTP {} URI {} doesn't have a valid host",
+                        "[c{}] HTTP {} URI {} doesn't have a valid host",
+                        conn_id,
                         self.req.method(),
                         self.req.uri()
                     );
                     return make_bad_request();
                 } else {
                     trace!(
-                        "HTTP {} URI {} doesn't have a valid host",
+                        "[c{}] HTTP {} URI {} doesn't have a valid host",
+                        conn_id,
                         self.req.method(),
                         self.req.uri()
                     );
                 }
 
-                match get_addr_from_header(&mut self.req) {
+                match get_addr_from_header(conn_id, &mut self.req) {
                     Ok(h) => h,
                     Err(()) => return make_bad_request(),
                 }
@@ -92,29 +179,70 @@ impl HttpDispatcher {
             Some(h) => h,
         };
 
+        let host_guard = match self.context.acquire_host_connection(&host) {
+            Ok(g) => g,
+            Err(err) => {
+                warn!(
+                    "[c{}] HTTP {} {} rejected, {}",
+                    conn_id,
+                    self.req.method(),
+                    self.client_addr,
+                    err
+                );
+                return make_too_many_requests();
+            }
+        };
+
         if Method::CONNECT == self.req.method() {
             // Establish a TCP tunnel
             // https://tools.ietf.org/html/draft-luotonen-web-proxy-tunneling-01
 
-            debug!("HTTP CONNECT {}", host);
+            debug!("[c{}] HTTP CONNECT {}", conn_id, host);
+
+            // Held until the tunnel finishes, shutting down waits for it
+            let active = self.context.track_active_connection();
+
+            self.context
+                .emit_span_event(conn_id, SpanEventKind::ConnectStart, self.client_addr, &host);
 
             // Connect to Shadowsocks' remote
-            //
-            // FIXME: What STATUS should I return for connection error?
             let mut server_opt = None;
-            let mut stream = if self.balancer.is_empty() {
-                AutoProxyClientStream::connect_bypassed(self.context, &host).await?
-            } else {
-                let server = self.balancer.best_tcp_server();
+            let stream_result = match self.balancer.pick_tcp_server(conn_id) {
+                Ok(None) => AutoProxyClientStream::connect_bypassed(self.context.clone(), &host).await,
+                Ok(Some(server)) => {
+                    let r = AutoProxyClientStream::connect_with_peer(
+                        self.context.clone(),
+                        server.as_ref(),
+                        &host,
+                        self.client_addr,
+                    )
+                    .await;
+                    server_opt = Some(server);
+
+                    r
+                }
+                Err(err) => Err(err),
+            };
 
-                let stream = AutoProxyClientStream::connect(self.context, server.as_ref(), &host).await?;
-                server_opt = Some(server);
+            self.context
+                .emit_span_event(conn_id, SpanEventKind::ConnectEnd, self.client_addr, &host);
 
-                stream
+            let mut stream = match stream_result {
+                Ok(s) => s,
+                Err(err) => {
+                    error!(
+                        "[c{}] HTTP CONNECT {} <-> {} connect failed, error: {}",
+                        conn_id, self.client_addr, host, err
+                    );
+                    self.context
+                        .report_relay_error(conn_id, RelayErrorKind::Connect, self.client_addr, &host, &err);
+                    return Ok(make_error_response(connect_error_status(&err)));
+                }
             };
 
             debug!(
-                "CONNECT relay connected {} <-> {} ({})",
+                "[c{}] CONNECT relay connected {} <-> {} ({})",
+                conn_id,
                 self.client_addr,
                 host,
                 if stream.is_bypassed() { "bypassed" } else { "proxied" }
@@ -127,15 +255,40 @@ impl HttpDispatcher {
             // `on_upgrade` future.
             let req = self.req;
             let client_addr = self.client_addr;
+            let context = self.context;
+            let balancer = self.balancer;
             tokio::spawn(async move {
+                // Hold the slot until the tunnel finishes
+                let _host_guard = host_guard;
+                let _active = active;
+
                 match upgrade::on(req).await {
                     Ok(mut upgraded) => {
-                        trace!("CONNECT tunnel upgrade success, {} <-> {}", client_addr, host);
+                        trace!(
+                            "[c{}] CONNECT tunnel upgrade success, {} <-> {}",
+                            conn_id,
+                            client_addr,
+                            host
+                        );
 
                         let _ = match server_opt {
                             Some(server) => {
-                                establish_tcp_tunnel(
-                                    server.server_config(),
+                                establish_tcp_tunnel_with_failover(
+                                    &context,
+                                    conn_id,
+                                    &balancer,
+                                    &server,
+                                    &mut upgraded,
+                                    &mut stream,
+                                    client_addr,
+                                    &host,
+                                )
+                                .await
+                            }
+                            None => {
+                                establish_tcp_tunnel_bypassed(
+                                    &context,
+                                    conn_id,
                                     &mut upgraded,
                                     &mut stream,
                                     client_addr,
@@ -143,13 +296,12 @@ impl HttpDispatcher {
                                 )
                                 .await
                             }
-                            None => establish_tcp_tunnel_bypassed(&mut upgraded, &mut stream, client_addr, &host).await,
                         };
                     }
                     Err(e) => {
                         error!(
-                            "failed to upgrade TCP tunnel {} <-> {}, error: {}",
-                            client_addr, host, e
+                            "[c{}] failed to upgrade TCP tunnel {} <-> {}, error: {}",
+                            conn_id, client_addr, host, e
                         );
                     }
                 }
@@ -162,43 +314,144 @@ impl HttpDispatcher {
         } else {
             let method = self.req.method().clone();
             let version = self.req.version();
-            debug!("HTTP {} {} {:?}", method, host, version);
+            debug!("[c{}] HTTP {} {} {:?}", conn_id, method, host, version);
+
+            let _active = self.context.track_active_connection();
 
             // Check if client wants us to keep long connection
             let conn_keep_alive = check_keep_alive(version, self.req.headers(), true);
 
+            // Requests of a keep-alive connection may target different hosts, each of them is sent through
+            // the upstream connection of its own host, which is decided by URI instead of Host
+            set_host_from_uri(conn_id, &mut self.req);
+
             // Remove non-forwardable headers
             clear_hop_headers(self.req.headers_mut());
 
             // Set keep-alive for connection with remote
             set_conn_keep_alive(version, self.req.headers_mut(), conn_keep_alive);
-            let client = if self.balancer.is_empty() || self.context.check_target_bypassed(&host).await {
-                trace!("bypassed {} -> {} {:?}", self.client_addr, host, self.req);
-                HttpClientEnum::Bypass(self.bypass_client)
-            } else {
-                trace!("proxied {} -> {} {:?}", self.client_addr, host, self.req);
 
-                // Keep connections for clients in ServerScore::client
-                // client instance is kept for Keep-Alive connections
-                let server = self.balancer.best_tcp_server();
-                HttpClientEnum::Proxy(self.proxy_client_cache.get_connected(&server).await)
+            // Add configured headers, overriding the ones sent by client
+            set_forward_headers(self.req.headers_mut(), &self.forward_headers);
+
+            // Bodies with Content-Length are checked before connecting, the others while they are being forwarded
+            let body_exceeded = Arc::new(AtomicBool::new(false));
+            if let Some(max_request_body) = self.max_request_body {
+                match get_content_length(self.req.headers()) {
+                    Some(content_length) => {
+                        if content_length > max_request_body {
+                            warn!(
+                                "[c{}] HTTP {} {} rejected, Content-Length {} exceeds {}",
+                                conn_id, method, self.client_addr, content_length, max_request_body
+                            );
+                            return make_payload_too_large();
+                        }
+                    }
+                    None => {
+                        let body = mem::take(self.req.body_mut());
+                        *self.req.body_mut() = limit_body_size(body, max_request_body, body_exceeded.clone());
+                    }
+                }
+            }
+
+            // Counted from now, connecting to the target doesn't give clients more time
+            let body_timed_out = Arc::new(AtomicBool::new(false));
+            if let Some(body_start_timeout) = self.body_start_timeout {
+                if !self.req.body().is_end_stream() {
+                    let body = mem::take(self.req.body_mut());
+                    *self.req.body_mut() = limit_body_start(body, body_start_timeout, body_timed_out.clone());
+                }
+            }
+
+            let server = match self.balancer.pick_tcp_server(conn_id) {
+                Ok(s) => s,
+                Err(err) => {
+                    error!(
+                        "[c{}] HTTP {} {} <-> {} rejected, error: {}",
+                        conn_id, method, self.client_addr, host, err
+                    );
+                    return Ok(make_error_response(connect_error_status(&err)));
+                }
+            };
+
+            let bypassed = server.is_none()
+                || match self.context.check_target_acl(&host).await {
+                    None => false,
+                    Some(decision) => {
+                        debug!(
+                            "[c{}] ACL rule \"{}\" matched {} -> {}, {}",
+                            conn_id,
+                            decision.rule,
+                            self.client_addr,
+                            host,
+                            if decision.bypassed { "bypassed" } else { "proxied" }
+                        );
+                        decision.bypassed
+                    }
+                };
+
+            let client = match server {
+                Some(server) if !bypassed => {
+                    trace!("[c{}] proxied {} -> {} {:?}", conn_id, self.client_addr, host, self.req);
+
+                    // Keep connections for clients in ServerScore::client
+                    // client instance is kept for Keep-Alive connections
+                    HttpClientEnum::Proxy(self.proxy_client_cache.get_connected(&server).await)
+                }
+                _ => {
+                    trace!(
+                        "[c{}] bypassed {} -> {} {:?}",
+                        conn_id,
+                        self.client_addr,
+                        host,
+                        self.req
+                    );
+                    HttpClientEnum::Bypass(self.bypass_client)
+                }
             };
 
             let mut res = match client.send(self.req).await {
                 Ok(res) => res,
+                Err(..) if body_exceeded.load(Ordering::Acquire) => {
+                    warn!(
+                        "[c{}] HTTP {} {} <-> {} aborted, request body exceeds {}",
+                        conn_id,
+                        method,
+                        self.client_addr,
+                        host,
+                        self.max_request_body.unwrap_or_default()
+                    );
+                    return make_payload_too_large();
+                }
+                Err(..) if body_timed_out.load(Ordering::Acquire) => {
+                    warn!(
+                        "[c{}] HTTP {} {} <-> {} aborted, request body not started in {:?}",
+                        conn_id,
+                        method,
+                        self.client_addr,
+                        host,
+                        self.body_start_timeout.unwrap_or_default()
+                    );
+                    return make_request_timeout();
+                }
                 Err(err) => {
                     error!(
-                        "HTTP {} {} <-> {} relay failed, error: {}",
-                        method, self.client_addr, host, err
+                        "[c{}] HTTP {} {} <-> {} relay failed, error: {}",
+                        conn_id, method, self.client_addr, host, err
                     );
-
-                    let mut resp = Response::new(Body::from(format!("relay failed to {}", host)));
-                    *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                    return Ok(resp);
+                    let kind = if err.is_connect() {
+                        RelayErrorKind::Connect
+                    } else {
+                        RelayErrorKind::Relay
+                    };
+                    self.context
+                        .report_relay_error(conn_id, kind, self.client_addr, &host, &hyper_io_error(&err));
+
+                    return Ok(make_error_response(relay_error_status(&err)));
                 }
             };
 
-            trace!("received {} <- {} {:?}", self.client_addr, host, res);
+            trace!("[c{}] received {} <- {} {:?}", conn_id, self.client_addr, host, res);
 
             let res_keep_alive = conn_keep_alive && check_keep_alive(res.version(), res.headers(), false);
 
@@ -207,28 +460,243 @@ impl HttpDispatcher {
 
             if res.version() != version {
                 // Reset version to matches req's version
-                trace!("response version {:?} => {:?}", res.version(), version);
+                trace!("[c{}] response version {:?} => {:?}", conn_id, res.version(), version);
                 *res.version_mut() = version;
             }
 
             // Set Connection header
             set_conn_keep_alive(res.version(), res.headers_mut(), res_keep_alive);
 
-            trace!("response {} <- {} {:?}", self.client_addr, host, res);
+            trace!("[c{}] response {} <- {} {:?}", conn_id, self.client_addr, host, res);
 
-            debug!("HTTP {} relay {} <-> {} finished", method, self.client_addr, host);
+            // Hold the slot until the response body is sent to client
+            if let Some(host_guard) = host_guard {
+                if !res.body().is_end_stream() {
+                    let body = mem::take(res.body_mut());
+                    *res.body_mut() = hold_until_body_end(body, host_guard);
+                }
+            }
+
+            debug!(
+                "[c{}] HTTP {} relay {} <-> {} finished",
+                conn_id, method, self.client_addr, host
+            );
 
             Ok(res)
         }
     }
 }
 
+/// Make an error response with a minimal HTML page describing `status`
+fn make_error_response(status: StatusCode) -> Response<Body> {
+    let body = format!(
+        "<html><head><title>{0}</title></head><body><h1>{0}</h1></body></html>\r\n",
+        status
+    );
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .unwrap()
+}
+
 fn make_bad_request() -> io::Result<Response<Body>> {
-    let mut resp = Response::new(Body::empty());
-    *resp.status_mut() = StatusCode::BAD_REQUEST;
+    Ok(make_error_response(StatusCode::BAD_REQUEST))
+}
+
+fn make_too_many_requests() -> io::Result<Response<Body>> {
+    Ok(make_error_response(StatusCode::TOO_MANY_REQUESTS))
+}
+
+fn make_payload_too_large() -> io::Result<Response<Body>> {
+    Ok(make_error_response(StatusCode::PAYLOAD_TOO_LARGE))
+}
+
+fn make_proxy_authentication_required() -> io::Result<Response<Body>> {
+    let mut resp = make_error_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+    resp.headers_mut().insert(
+        PROXY_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"shadowsocks\""),
+    );
+    Ok(resp)
+}
+
+fn make_request_timeout() -> io::Result<Response<Body>> {
+    // The rest of the body may still come, the connection cannot be reused
+    let mut resp = make_error_response(StatusCode::REQUEST_TIMEOUT);
+    resp.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+    Ok(resp)
+}
+
+fn make_service_unavailable() -> io::Result<Response<Body>> {
+    let mut resp = make_error_response(StatusCode::SERVICE_UNAVAILABLE);
+    resp.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
     Ok(resp)
 }
 
+/// Status for failures of connecting to the target, including failures of resolving its hostname
+fn connect_error_status(err: &io::Error) -> StatusCode {
+    if err.kind() == ErrorKind::TimedOut {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    }
+}
+
+/// Status for failures of forwarding a request, the ones happened while connecting are gateway errors
+fn relay_error_status(err: &hyper::Error) -> StatusCode {
+    if !err.is_connect() {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    match find_io_error(err) {
+        Some(err) => connect_error_status(err),
+        None => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Find the `io::Error` causing `err`
+fn find_io_error(err: &hyper::Error) -> Option<&io::Error> {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return Some(err);
+        }
+        source = err.source();
+    }
+    None
+}
+
+/// Convert `err` to `io::Error`, keeping the kind of the `io::Error` causing it
+fn hyper_io_error(err: &hyper::Error) -> io::Error {
+    let kind = find_io_error(err).map(io::Error::kind).unwrap_or(ErrorKind::Other);
+    io::Error::new(kind, err.to_string())
+}
+
+/// Get the value of `Content-Length`, which have already been checked by `check_request_framing`
+fn get_content_length(headers: &HeaderMap<HeaderValue>) -> Option<u64> {
+    let value = headers.get(CONTENT_LENGTH)?.to_str().ok()?;
+    value.split(',').next()?.trim().parse::<u64>().ok()
+}
+
+/// Wrap `body` to fail after more than `max_size` bytes are read, which aborts the forwarded request
+///
+/// `exceeded` is set when it fails, to tell it apart from the other errors.
+fn limit_body_size(body: Body, max_size: u64, exceeded: Arc<AtomicBool>) -> Body {
+    let mut size = 0u64;
+    let body = body.map(move |chunk| -> Result<Bytes, Box<dyn StdError + Send + Sync>> {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            exceeded.store(true, Ordering::Release);
+            return Err(io::Error::new(ErrorKind::InvalidData, "request body is too large").into());
+        }
+        Ok(chunk)
+    });
+    Body::wrap_stream(body)
+}
+
+/// Wrap `body` to keep `guard` until the body is finished or dropped
+fn hold_until_body_end<G: Send + 'static>(body: Body, guard: G) -> Body {
+    let body = body.map(move |chunk| {
+        let _guard = &guard;
+        chunk
+    });
+    Body::wrap_stream(body)
+}
+
+/// Wrap `body` to fail if its first chunk isn't received in `timeout`, which aborts the forwarded request
+///
+/// `timed_out` is set when it fails, to tell it apart from the other errors.
+fn limit_body_start(body: Body, timeout: Duration, timed_out: Arc<AtomicBool>) -> Body {
+    Body::wrap_stream(BodyStartTimeout {
+        body,
+        sleep: Some(Box::pin(time::sleep(timeout))),
+        timed_out,
+    })
+}
+
+struct BodyStartTimeout {
+    body: Body,
+    sleep: Option<Pin<Box<Sleep>>>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Stream for BodyStartTimeout {
+    type Item = Result<Bytes, Box<dyn StdError + Send + Sync>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(chunk) = self.body.poll_next_unpin(cx) {
+            self.sleep = None;
+            return Poll::Ready(chunk.map(|c| c.map_err(Into::into)));
+        }
+
+        if let Some(ref mut sleep) = self.sleep {
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+            self.timed_out.store(true, Ordering::Release);
+            return Poll::Ready(Some(Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "request body is not started in time",
+            )
+            .into())));
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Check if `Proxy-Authorization: Basic` carries a user allowed by `auth`
+fn check_proxy_authorization(headers: &HeaderMap<HeaderValue>, auth: &Socks5AuthConfig) -> bool {
+    let value = match headers.get(PROXY_AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        Some(v) => v,
+        None => return false,
+    };
+
+    let credentials = match value.split_once(' ') {
+        Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("Basic") => credentials.trim(),
+        _ => return false,
+    };
+
+    let decoded = match base64::decode(credentials).ok().and_then(|d| String::from_utf8(d).ok()) {
+        Some(d) => d,
+        None => return false,
+    };
+
+    match decoded.split_once(':') {
+        Some((user_name, password)) => auth.passwd.check_user(user_name, password),
+        None => false,
+    }
+}
+
+/// Check if the request's body framing is unambiguous
+///
+/// Obsolete line folding and `Content-Length`s with differing values are already rejected by hyper while parsing,
+/// but requests with both `Content-Length` and `Transfer-Encoding` are accepted. hyper drops `Content-Length`s
+/// following `Transfer-Encoding`, so only those preceding it could be seen here.
+fn check_request_framing(headers: &HeaderMap<HeaderValue>) -> Result<(), &'static str> {
+    if headers.contains_key(CONTENT_LENGTH) && headers.contains_key(TRANSFER_ENCODING) {
+        return Err("both Content-Length and Transfer-Encoding are present");
+    }
+
+    let mut content_length = None;
+    for value in headers.get_all(CONTENT_LENGTH) {
+        let value = value.to_str().map_err(|_| "invalid Content-Length")?;
+        for part in value.split(',') {
+            let part = part.trim();
+            match content_length {
+                None => content_length = Some(part),
+                Some(len) if len == part => {}
+                Some(..) => return Err("Content-Length with differing values"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn get_keep_alive_val(values: GetAll<HeaderValue>) -> Option<bool> {
     let mut conn_keep_alive = None;
     for value in values {
@@ -334,7 +802,41 @@ fn set_conn_keep_alive(version: Version, headers: &mut HeaderMap<HeaderValue>, k
     }
 }
 
-fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
+fn set_host_from_uri(conn_id: usize, req: &mut Request<Body>) {
+    // Authority without userinfo
+    let host = match req.uri().authority() {
+        Some(authority) => authority.as_str().rsplit('@').next().unwrap_or_default(),
+        None => return,
+    };
+
+    if req.headers().get(HOST).map(HeaderValue::as_bytes) == Some(host.as_bytes()) {
+        return;
+    }
+
+    // Authority of a valid URI is always a valid header value
+    if let Ok(value) = HeaderValue::from_str(host) {
+        debug!(
+            "[c{}] HTTP {} URI {} replaced \"Host\" header {:?}",
+            conn_id,
+            req.method(),
+            req.uri(),
+            req.headers().get(HOST)
+        );
+        req.headers_mut().insert(HOST, value);
+    }
+}
+
+fn set_forward_headers(headers: &mut HeaderMap<HeaderValue>, forward_headers: &HeaderMap<HeaderValue>) {
+    for name in forward_headers.keys() {
+        headers.remove(name);
+    }
+
+    for (name, value) in forward_headers {
+        headers.append(name, value.clone());
+    }
+}
+
+fn get_addr_from_header(conn_id: usize, req: &mut Request<Body>) -> Result<Address, ()> {
     // Try to be compatible as a transparent HTTP proxy
     match req.headers().get("Host") {
         Some(hhost) => match hhost.to_str() {
@@ -342,7 +844,13 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
                 match Authority::from_str(shost) {
                     Ok(authority) => match authority_addr(req.uri().scheme_str(), &authority) {
                         Some(host) => {
-                            trace!("HTTP {} URI {} got host from header: {}", req.method(), req.uri(), host);
+                            trace!(
+                                "[c{}] HTTP {} URI {} got host from header: {}",
+                                conn_id,
+                                req.method(),
+                                req.uri(),
+                                host
+                            );
 
                             // Reassemble URI
                             let mut parts = req.uri().clone().into_parts();
@@ -355,13 +863,14 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
                             // Replaces URI
                             *req.uri_mut() = Uri::from_parts(parts).expect("Reassemble URI failed");
 
-                            debug!("reassembled URI from \"Host\", {}", req.uri());
+                            debug!("[c{}] reassembled URI from \"Host\", {}", conn_id, req.uri());
 
                             Ok(host)
                         }
                         None => {
                             error!(
-                                "HTTP {} URI {} \"Host\" header invalid, value: {}",
+                                "[c{}] HTTP {} URI {} \"Host\" header invalid, value: {}",
+                                conn_id,
                                 req.method(),
                                 req.uri(),
                                 shost
@@ -372,7 +881,8 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
                     },
                     Err(..) => {
                         error!(
-                            "HTTP {} URI {} \"Host\" header is not an Authority, value: {:?}",
+                            "[c{}] HTTP {} URI {} \"Host\" header is not an Authority, value: {:?}",
+                            conn_id,
                             req.method(),
                             req.uri(),
                             hhost
@@ -384,7 +894,8 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
             }
             Err(..) => {
                 error!(
-                    "HTTP {} URI {} \"Host\" header invalid encoding, value: {:?}",
+                    "[c{}] HTTP {} URI {} \"Host\" header invalid encoding, value: {:?}",
+                    conn_id,
                     req.method(),
                     req.uri(),
                     hhost
@@ -395,7 +906,8 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
         },
         None => {
             error!(
-                "HTTP {} URI doesn't have valid host and missing the \"Host\" header, URI: {}",
+                "[c{}] HTTP {} URI doesn't have valid host and missing the \"Host\" header, URI: {}",
+                conn_id,
                 req.method(),
                 req.uri()
             );
@@ -404,3 +916,28 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn error_response_with_body() {
+        let resp = make_error_response(StatusCode::FORBIDDEN);
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+
+        let content_length = resp.headers()[CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(!body.is_empty());
+        assert_eq!(body.len(), content_length);
+
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("403 Forbidden"), "{}", body);
+    }
+}
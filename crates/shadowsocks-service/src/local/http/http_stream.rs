@@ -3,32 +3,145 @@
 use std::{
     io::{self, ErrorKind},
     pin::Pin,
+    sync::Arc,
     task::{self, Poll},
 };
 
+use futures::{future::BoxFuture, ready, FutureExt};
 use hyper::client::connect::{Connected, Connection};
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use crate::local::net::AutoProxyClientStream;
+use shadowsocks::relay::socks5::Address;
+
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::ServerIdent,
+        metrics::LocalMetrics,
+        net::{AutoProxyClientStream, RateLimitedStream},
+    },
+    net::FlowStat,
+};
+
+/// Connection of the HTTP client, counted in `bypassed_flow_stat` if it bypasses servers
+///
+/// Proxied connections are counted by their `AutoProxyClientStream`s. Connections are kept in the client's pool, so
+/// bytes are accounted to `destination` as they are relayed, instead of after the connection is closed.
+#[pin_project]
+pub struct HttpConnectionStream {
+    #[pin]
+    stream: AutoProxyClientStream,
+    bypassed_flow_stat: Option<Arc<FlowStat>>,
+    destination: Option<(Arc<ServiceContext>, Address)>,
+    retired: Option<(Arc<ServerIdent>, BoxFuture<'static, ()>)>,
+}
+
+impl HttpConnectionStream {
+    pub fn new(stream: AutoProxyClientStream, bypassed_flow_stat: Option<Arc<FlowStat>>) -> HttpConnectionStream {
+        HttpConnectionStream {
+            stream,
+            bypassed_flow_stat,
+            destination: None,
+            retired: None,
+        }
+    }
+
+    /// Fail the connection after `server` is retired, even if it is idle in the client's pool
+    pub fn close_on_retired(&mut self, server: Arc<ServerIdent>) {
+        let fut = {
+            let server = server.clone();
+            async move { server.retired().await }.boxed()
+        };
+        self.retired = Some((server, fut));
+    }
+
+    /// Account bytes relayed to `addr` if destination statistics are enabled in `context`
+    pub fn set_destination(&mut self, context: Arc<ServiceContext>, addr: Address) {
+        if context.metrics().and_then(LocalMetrics::destination_stats).is_some() {
+            self.destination = Some((context, addr));
+        }
+    }
+}
+
+fn poll_retired(
+    retired: &mut Option<(Arc<ServerIdent>, BoxFuture<'static, ()>)>,
+    cx: &mut task::Context<'_>,
+) -> io::Result<()> {
+    if let Some((ref server, ref mut fut)) = *retired {
+        // Completed future mustn't be polled again
+        if server.is_retired() || fut.as_mut().poll(cx).is_ready() {
+            let err = io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("server {} was retired", server.server_config().addr()),
+            );
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn record_destination(destination: &Option<(Arc<ServiceContext>, Address)>, tx: u64, rx: u64) {
+    if let Some((ref context, ref addr)) = *destination {
+        if let Some(destinations) = context.metrics().and_then(LocalMetrics::destination_stats) {
+            destinations.record(addr, tx, rx);
+        }
+    }
+}
+
+impl AsyncRead for HttpConnectionStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        poll_retired(this.retired, cx)?;
+        let filled = buf.filled().len();
+        ready!(this.stream.poll_read(cx, buf))?;
+        let n = (buf.filled().len() - filled) as u64;
+        if let Some(flow_stat) = this.bypassed_flow_stat {
+            flow_stat.incr_rx(n);
+        }
+        record_destination(this.destination, 0, n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for HttpConnectionStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        poll_retired(this.retired, cx)?;
+        let n = ready!(this.stream.poll_write(cx, buf))?;
+        if let Some(flow_stat) = this.bypassed_flow_stat {
+            flow_stat.incr_tx(n as u64);
+        }
+        record_destination(this.destination, n as u64, 0);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
 
 #[allow(clippy::large_enum_variant)]
 #[pin_project(project = ProxyHttpStreamProj)]
 pub enum ProxyHttpStream {
-    Http(#[pin] AutoProxyClientStream),
+    Http(#[pin] HttpConnectionStream),
     #[cfg(feature = "local-http-native-tls")]
-    Https(#[pin] tokio_native_tls::TlsStream<AutoProxyClientStream>, bool),
+    Https(#[pin] tokio_native_tls::TlsStream<HttpConnectionStream>, bool),
     #[cfg(feature = "local-http-rustls")]
-    Https(#[pin] tokio_rustls::client::TlsStream<AutoProxyClientStream>, bool),
+    Https(#[pin] tokio_rustls::client::TlsStream<HttpConnectionStream>, bool),
 }
 
 impl ProxyHttpStream {
-    pub fn connect_http(stream: AutoProxyClientStream) -> ProxyHttpStream {
+    pub fn connect_http(stream: HttpConnectionStream) -> ProxyHttpStream {
         ProxyHttpStream::Http(stream)
     }
 
     #[cfg(feature = "local-http-native-tls")]
-    pub async fn connect_https(stream: AutoProxyClientStream, domain: &str) -> io::Result<ProxyHttpStream> {
+    pub async fn connect_https(stream: HttpConnectionStream, domain: &str) -> io::Result<ProxyHttpStream> {
         use native_tls::TlsConnector;
 
         let cx = match TlsConnector::builder().request_alpns(&["h2", "http/1.1"]).build() {
@@ -60,7 +173,7 @@ impl ProxyHttpStream {
     }
 
     #[cfg(feature = "local-http-rustls")]
-    pub async fn connect_https(stream: AutoProxyClientStream, domain: &str) -> io::Result<ProxyHttpStream> {
+    pub async fn connect_https(stream: HttpConnectionStream, domain: &str) -> io::Result<ProxyHttpStream> {
         use byte_string::ByteStr;
         use log::warn;
         use once_cell::sync::Lazy;
@@ -132,7 +245,7 @@ impl ProxyHttpStream {
     }
 
     #[cfg(not(any(feature = "local-http-native-tls", feature = "local-http-rustls")))]
-    pub async fn connect_https(_stream: AutoProxyClientStream, _domain: &str) -> io::Result<ProxyHttpStream> {
+    pub async fn connect_https(_stream: HttpConnectionStream, _domain: &str) -> io::Result<ProxyHttpStream> {
         let err = io::Error::new(
             ErrorKind::Other,
             "https is not supported, consider enable it by feature \"local-http-native-tls\" or \"local-http-rustls\"",
@@ -189,3 +302,12 @@ impl Connection for ProxyHttpStream {
         }
     }
 }
+
+impl<S> Connection for RateLimitedStream<S>
+where
+    S: Connection,
+{
+    fn connected(&self) -> Connected {
+        self.get_ref().connected()
+    }
+}
@@ -1,9 +1,9 @@
 //! Shadowsocks Local HTTP(S) Server
 
 use std::{
-    convert::Infallible,
     io::{self, ErrorKind},
     sync::Arc,
+    time::Duration,
 };
 
 use hyper::{
@@ -11,16 +11,19 @@ use hyper::{
     service::{make_service_fn, service_fn},
     Body,
     Client,
+    HeaderMap,
     Request,
     Server,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use shadowsocks::{config::ServerAddr, lookup_then, net::TcpListener};
 
 use crate::local::{
     context::ServiceContext,
     http::connector::Connector,
     loadbalancing::PingBalancer,
+    net::AllowedClients,
+    socks::config::Socks5AuthConfig,
     LOCAL_DEFAULT_KEEPALIVE_TIMEOUT,
 };
 
@@ -30,6 +33,11 @@ use super::{client_cache::ProxyClientCache, dispatcher::HttpDispatcher};
 pub struct Http {
     context: Arc<ServiceContext>,
     proxy_client_cache: Arc<ProxyClientCache>,
+    forward_headers: Arc<HeaderMap>,
+    allowed_clients: Arc<AllowedClients>,
+    max_request_body: Option<u64>,
+    body_start_timeout: Option<Duration>,
+    auth: Arc<Socks5AuthConfig>,
 }
 
 impl Default for Http {
@@ -51,11 +59,71 @@ impl Http {
         Http {
             context,
             proxy_client_cache,
+            forward_headers: Arc::new(HeaderMap::new()),
+            allowed_clients: Arc::new(AllowedClients::default()),
+            max_request_body: None,
+            body_start_timeout: None,
+            auth: Arc::new(Socks5AuthConfig::default()),
         }
     }
 
+    /// Set headers that will be added to forwarded HTTP requests, overriding existing headers with the same names
+    pub fn set_forward_headers(&mut self, headers: HeaderMap) {
+        self.forward_headers = Arc::new(headers);
+    }
+
+    /// Set clients allowed to connect, all clients are allowed by default
+    pub fn set_allowed_clients(&mut self, allowed_clients: AllowedClients) {
+        self.allowed_clients = Arc::new(allowed_clients);
+    }
+
+    /// Set maximum size of forwarded request bodies in bytes, unlimited by default
+    ///
+    /// Requests with larger `Content-Length` are answered with `413 Payload Too Large`,
+    /// chunked bodies are aborted once they grow larger.
+    pub fn set_max_request_body(&mut self, max_request_body: u64) {
+        self.max_request_body = Some(max_request_body);
+    }
+
+    /// Set maximum delay between request headers and the first byte of their bodies, unlimited by default
+    ///
+    /// Requests with bodies not started in time are answered with `408 Request Timeout`,
+    /// and their connections are closed.
+    pub fn set_body_start_timeout(&mut self, body_start_timeout: Duration) {
+        self.body_start_timeout = Some(body_start_timeout);
+    }
+
+    /// Set users allowed to use this proxy, clients are not authenticated by default
+    ///
+    /// Requests without valid `Proxy-Authorization: Basic` are answered with `407 Proxy Authentication Required`.
+    pub fn set_auth(&mut self, auth: Socks5AuthConfig) {
+        self.auth = Arc::new(auth);
+    }
+
     /// Run server
     pub async fn run(self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
+        let bind_result = match *client_config {
+            ServerAddr::SocketAddr(sa) => TcpListener::bind_with_opts(&sa, self.context.accept_opts().clone()).await,
+            ServerAddr::DomainName(ref dname, port) => lookup_then!(self.context.context_ref(), dname, port, |addr| {
+                TcpListener::bind_with_opts(&addr, self.context.accept_opts().clone()).await
+            })
+            .map(|(_, b)| b),
+        };
+
+        let listener = match bind_result {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("hyper server bind error: {}", err);
+                let err = io::Error::new(ErrorKind::InvalidInput, err);
+                return Err(err);
+            }
+        };
+
+        self.run_with_listener(listener, balancer).await
+    }
+
+    /// Run server on a bound listener
+    pub async fn run_with_listener(self, listener: TcpListener, balancer: PingBalancer) -> io::Result<()> {
         let bypass_client = Client::builder()
             .http1_preserve_header_case(true)
             .http1_title_case_headers(true)
@@ -63,70 +131,80 @@ impl Http {
 
         let context = self.context.clone();
         let proxy_client_cache = self.proxy_client_cache.clone();
+        let forward_headers = self.forward_headers.clone();
+        let allowed_clients = self.allowed_clients.clone();
+        let max_request_body = self.max_request_body;
+        let body_start_timeout = self.body_start_timeout;
+        let auth = self.auth.clone();
         let make_service = make_service_fn(|socket: &AddrStream| {
             let client_addr = socket.remote_addr();
+            let allowed = allowed_clients.check_allowed(&client_addr.ip());
             let balancer = balancer.clone();
             let bypass_client = bypass_client.clone();
             let context = context.clone();
             let proxy_client_cache = proxy_client_cache.clone();
+            let forward_headers = forward_headers.clone();
+            let auth = auth.clone();
+            // Counted until hyper drops the service, after the connection is closed
+            let gauge = context.metrics().map(|m| m.track_http_connection());
+            // Logs of requests of the connection are prefixed with its id
+            let conn_id = context.next_conn_id();
 
             async move {
-                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                if !allowed {
+                    warn!(
+                        "[c{}] http client {} is not in allowed_clients, access denied",
+                        conn_id, client_addr
+                    );
+                    // Connection will be closed without serving
+                    return Err(io::Error::new(ErrorKind::PermissionDenied, "client is not allowed"));
+                }
+
+                Ok::<_, io::Error>(service_fn(move |req: Request<Body>| {
+                    let _ = &gauge;
                     HttpDispatcher::new(
                         context.clone(),
+                        conn_id,
                         req,
                         balancer.clone(),
                         client_addr,
                         bypass_client.clone(),
                         proxy_client_cache.clone(),
+                        forward_headers.clone(),
+                        max_request_body,
+                        body_start_timeout,
+                        auth.clone(),
                     )
                     .dispatch()
                 }))
             }
         });
 
-        let bind_result = match *client_config {
-            ServerAddr::SocketAddr(sa) => TcpListener::bind_with_opts(&sa, self.context.accept_opts().clone()).await,
-            ServerAddr::DomainName(ref dname, port) => lookup_then!(self.context.context_ref(), dname, port, |addr| {
-                TcpListener::bind_with_opts(&addr, self.context.accept_opts().clone()).await
-            })
-            .map(|(_, b)| b),
-        };
-
-        let server = match bind_result {
-            Ok(listener) => {
-                let listener = listener.into_inner().into_std()?;
-                let builder = match Server::from_tcp(listener) {
-                    Ok(builder) => builder,
-                    Err(err) => {
-                        error!("hyper server from std::net::TcpListener error: {}", err);
-                        let err = io::Error::new(ErrorKind::InvalidInput, err);
-                        return Err(err);
-                    }
-                };
-
-                builder
-                    .http1_only(true) // HTTP Proxy protocol only defined in HTTP 1.x
-                    .http1_preserve_header_case(true)
-                    .http1_title_case_headers(true)
-                    .tcp_sleep_on_accept_errors(true)
-                    .tcp_keepalive(
-                        self.context
-                            .accept_opts()
-                            .tcp
-                            .keepalive
-                            .or(Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT)),
-                    )
-                    .tcp_nodelay(self.context.accept_opts().tcp.nodelay)
-                    .serve(make_service)
-            }
+        let listener = listener.into_inner().into_std()?;
+        let builder = match Server::from_tcp(listener) {
+            Ok(builder) => builder,
             Err(err) => {
-                error!("hyper server bind error: {}", err);
+                error!("hyper server from std::net::TcpListener error: {}", err);
                 let err = io::Error::new(ErrorKind::InvalidInput, err);
                 return Err(err);
             }
         };
 
+        let server = builder
+            .http1_only(true) // HTTP Proxy protocol only defined in HTTP 1.x
+            .http1_preserve_header_case(true)
+            .http1_title_case_headers(true)
+            .tcp_sleep_on_accept_errors(true)
+            .tcp_keepalive(
+                self.context
+                    .accept_opts()
+                    .tcp
+                    .keepalive
+                    .or(Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT)),
+            )
+            .tcp_nodelay(self.context.accept_opts().tcp.nodelay)
+            .serve(make_service);
+
         info!("shadowsocks HTTP listening on {}", server.local_addr());
 
         if let Err(err) = server.await {
@@ -139,3 +217,1015 @@ impl Http {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use async_trait::async_trait;
+    use hyper::header::{HeaderName, HeaderValue};
+    use shadowsocks::{
+        config::{Mode, ServerConfig, ServerType},
+        context::Context,
+        crypto::CipherKind,
+        dns_resolver::{DnsResolve, DnsResolver},
+        relay::socks5::Address,
+        ProxyListener,
+    };
+    use tokio::{
+        io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener as TokioTcpListener, TcpStream},
+        time::{self, Duration},
+    };
+
+    use crate::{
+        local::{loadbalancing::PingBalancerBuilder, metrics::LocalMetrics},
+        test_utils::bind_listener,
+    };
+
+    use super::*;
+
+    async fn read_request_head(stream: &mut TcpStream) -> String {
+        let mut buffer = Vec::new();
+        while !buffer.ends_with(b"\r\n\r\n") {
+            let mut b = [0u8; 1];
+            if stream.read(&mut b).await.unwrap() == 0 {
+                break;
+            }
+            buffer.push(b[0]);
+        }
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[tokio::test]
+    async fn http_forward_headers() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let mut forward_headers = HeaderMap::new();
+        forward_headers.insert(
+            HeaderName::from_static("x-auth-token"),
+            HeaderValue::from_static("s3cr3t"),
+        );
+
+        let mut server = Http::with_context(context);
+        server.set_forward_headers(forward_headers);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let request = format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nX-Auth-Token: from-client\r\nProxy-Authorization: Basic Zm9vOmJhcg==\r\n\r\n",
+            target_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        let forwarded = read_request_head(&mut target).await.to_ascii_lowercase();
+
+        assert!(forwarded.contains("\r\nx-auth-token: s3cr3t\r\n"), "{}", forwarded);
+        assert!(!forwarded.contains("from-client"), "{}", forwarded);
+        assert!(!forwarded.contains("proxy-authorization"), "{}", forwarded);
+
+        target
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = [0u8; 15];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK");
+    }
+
+    // Resolver failing to resolve any names
+    struct FailingResolver;
+
+    #[async_trait]
+    impl DnsResolve for FailingResolver {
+        async fn resolve(&self, _addr: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Err(io::Error::new(io::ErrorKind::NotFound, "unknown host"))
+        }
+    }
+
+    #[tokio::test]
+    async fn http_unresolvable_target() {
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(FailingResolver)));
+        let context = Arc::new(context);
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        client
+            .write_all(b"GET http://unknown.invalid/ HTTP/1.1\r\nHost: unknown.invalid\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = [0u8; 12];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 502");
+    }
+
+    #[tokio::test]
+    async fn http_connection_close() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let request = format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+            target_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        let forwarded = read_request_head(&mut target).await.to_ascii_lowercase();
+        assert!(forwarded.contains("\r\nconnection: close\r\n"), "{}", forwarded);
+
+        // Target keeps its connection open, the client's connection should still end right after the response
+        target
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        time::timeout(Duration::from_secs(1), client.read_to_end(&mut response))
+            .await
+            .expect("connection is still open after Connection: close")
+            .unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.to_ascii_lowercase().contains("\r\nconnection: close\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn http_max_conns_per_host_response_body() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_max_conns_per_host(1);
+        let context = Arc::new(context);
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let request = format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr);
+
+        let mut first = TcpStream::connect(proxy_addr).await.unwrap();
+        first.write_all(request.as_bytes()).await.unwrap();
+
+        // Response body is still being sent
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        read_request_head(&mut target).await;
+        target
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nab")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"ab") {
+            let mut buf = [0u8; 64];
+            let n = first.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0);
+            response.extend_from_slice(&buf[..n]);
+        }
+
+        let mut second = TcpStream::connect(proxy_addr).await.unwrap();
+        second.write_all(request.as_bytes()).await.unwrap();
+        let mut response = [0u8; 12];
+        second.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 429");
+
+        // Slot is released after the body is finished
+        target.write_all(b"cd").await.unwrap();
+        drop(target);
+        let mut rest = [0u8; 2];
+        first.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"cd");
+
+        let mut third = TcpStream::connect(proxy_addr).await.unwrap();
+        third.write_all(request.as_bytes()).await.unwrap();
+        let (mut target, _) = time::timeout(Duration::from_secs(1), target_listener.accept())
+            .await
+            .expect("slot isn't released")
+            .unwrap();
+        read_request_head(&mut target).await;
+    }
+
+    #[tokio::test]
+    async fn http_keep_alive_different_hosts() {
+        let first_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let first_addr = first_listener.local_addr().unwrap();
+        let second_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second_addr = second_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let request = format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", first_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut first, _) = first_listener.accept().await.unwrap();
+        let forwarded = read_request_head(&mut first).await.to_ascii_lowercase();
+        assert!(
+            forwarded.contains(&format!("\r\nhost: {}\r\n", first_addr)),
+            "{}",
+            forwarded
+        );
+        first
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst")
+            .await
+            .unwrap();
+
+        let mut response = [0u8; 15];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK");
+        let head = read_request_head(&mut client).await;
+        assert!(head.ends_with("\r\n\r\n"), "{}", head);
+        let mut body = [0u8; 5];
+        client.read_exact(&mut body).await.unwrap();
+        assert_eq!(&body, b"first");
+
+        // Same client connection, another host, with Host left from the previous request
+        let request = format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {1}\r\nConnection: close\r\n\r\n",
+            second_addr, first_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut second, _) = second_listener.accept().await.unwrap();
+        let forwarded = read_request_head(&mut second).await.to_ascii_lowercase();
+        assert!(
+            forwarded.contains(&format!("\r\nhost: {}\r\n", second_addr)),
+            "{}",
+            forwarded
+        );
+        second
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsecond")
+            .await
+            .unwrap();
+
+        // The first host's connection isn't used for the second request
+        let mut buffer = [0u8; 1];
+        let reused = time::timeout(Duration::from_millis(100), first.read(&mut buffer)).await;
+        assert!(reused.is_err(), "first host received {:?}", reused);
+
+        let mut response = Vec::new();
+        time::timeout(Duration::from_secs(1), client.read_to_end(&mut response))
+            .await
+            .expect("connection is still open after Connection: close")
+            .unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nsecond"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn http_response_over_content_length() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let request = format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        // Target sends more than Content-Length, which looks like the head of another response
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        read_request_head(&mut target).await;
+        target
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokHTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\ngarbage",
+            )
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\nok") {
+            let mut b = [0u8; 1];
+            assert_eq!(client.read(&mut b).await.unwrap(), 1, "{}", response);
+            response.push(b[0] as char);
+        }
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        // The connection with trailing bytes is closed instead of being reused
+        let mut buffer = [0u8; 1];
+        let n = time::timeout(Duration::from_secs(1), target.read(&mut buffer))
+            .await
+            .expect("connection with trailing bytes is still open")
+            .unwrap();
+        assert_eq!(n, 0);
+
+        // The next request is sent through a new connection, without the trailing bytes
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        read_request_head(&mut target).await;
+        target
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\nhello") {
+            let mut b = [0u8; 1];
+            assert_eq!(client.read(&mut b).await.unwrap(), 1, "{}", response);
+            response.push(b[0] as char);
+        }
+        assert!(!response.contains("garbage"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn http_allowed_clients() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        // Listeners sharing the same context, with their own allowed clients
+        let start_proxy = |listener: TcpListener, allowed_clients: &str| {
+            let proxy_addr = listener.local_addr().unwrap();
+            let mut server = Http::with_context(context.clone());
+            server.set_allowed_clients(AllowedClients::new(vec![allowed_clients.parse().unwrap()]));
+            let balancer = balancer.clone();
+            tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+            proxy_addr
+        };
+        let trusted_proxy_addr = start_proxy(bind_listener().await, "127.0.0.0/8");
+        let restricted_proxy_addr = start_proxy(bind_listener().await, "10.0.0.0/8");
+
+        let request = format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+            target_addr
+        );
+
+        // Denied, closed without any response
+        let mut client = TcpStream::connect(restricted_proxy_addr).await.unwrap();
+        let _ = client.write_all(request.as_bytes()).await;
+        let mut response = Vec::new();
+        let _ = time::timeout(Duration::from_secs(1), client.read_to_end(&mut response))
+            .await
+            .expect("denied connection is still open");
+        assert!(response.is_empty(), "{}", String::from_utf8_lossy(&response));
+
+        // Allowed
+        let mut client = TcpStream::connect(trusted_proxy_addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        read_request_head(&mut target).await;
+        target
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = [0u8; 15];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn http_request_smuggling() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let vectors = [
+            "Content-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+            "Content-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+            "Content-Length: 5, 6\r\n\r\nhello!",
+            "X-Folded: first\r\n second\r\nContent-Length: 5\r\n\r\nhello",
+        ];
+
+        for vector in vectors {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+            let request = format!(
+                "POST http://{0}/ HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n{1}",
+                target_addr, vector
+            );
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = Vec::new();
+            time::timeout(Duration::from_secs(1), client.read_to_end(&mut response))
+                .await
+                .expect("rejected connection is still open")
+                .unwrap();
+            let response = String::from_utf8(response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 "), "{:?}: {}", vector, response);
+        }
+
+        // Upstream is never contacted
+        let accepted = time::timeout(Duration::from_millis(100), target_listener.accept()).await;
+        assert!(accepted.is_err(), "upstream contacted");
+    }
+
+    #[tokio::test]
+    async fn http_max_request_body_content_length() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let mut server = Http::with_context(context);
+        server.set_max_request_body(8);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let request = format!(
+            "POST http://{0}/ HTTP/1.1\r\nHost: {0}\r\nContent-Length: 16\r\n\r\n0123456789abcdef",
+            target_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let response = read_request_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+        // Upstream is never contacted
+        let accepted = time::timeout(Duration::from_millis(100), target_listener.accept()).await;
+        assert!(accepted.is_err(), "upstream contacted");
+    }
+
+    // Read a chunked body, returns its decoded content
+    async fn read_chunked_body(stream: &mut TcpStream) -> Vec<u8> {
+        let mut body = Vec::new();
+        loop {
+            let size_line = read_line(stream).await;
+            let size = usize::from_str_radix(size_line.split(';').next().unwrap().trim(), 16).unwrap();
+            if size == 0 {
+                // No trailers
+                assert_eq!(read_line(stream).await, "");
+                return body;
+            }
+
+            let mut chunk = vec![0u8; size];
+            stream.read_exact(&mut chunk).await.unwrap();
+            body.extend_from_slice(&chunk);
+            assert_eq!(read_line(stream).await, "");
+        }
+    }
+
+    async fn read_line(stream: &mut TcpStream) -> String {
+        let mut buffer = Vec::new();
+        while !buffer.ends_with(b"\r\n") {
+            let mut b = [0u8; 1];
+            stream.read_exact(&mut b).await.unwrap();
+            buffer.push(b[0]);
+        }
+        buffer.truncate(buffer.len() - 2);
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[tokio::test]
+    async fn http_chunked_request_body() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let request = format!(
+            "POST http://{0}/upload HTTP/1.1\r\nHost: {0}\r\nTransfer-Encoding: chunked\r\n\r\n",
+            target_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.write_all(b"4\r\n0123\r\n").await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        let forwarded = read_request_head(&mut target).await.to_ascii_lowercase();
+        assert!(forwarded.starts_with("post /upload http/1.1\r\n"), "{}", forwarded);
+        assert!(
+            forwarded.contains("\r\ntransfer-encoding: chunked\r\n"),
+            "{}",
+            forwarded
+        );
+        assert!(!forwarded.contains("content-length"), "{}", forwarded);
+
+        // Chunk extensions and sizes in upper case
+        client
+            .write_all(b"C;name=value\r\n456789ABCDEF\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_chunked_body(&mut target).await, b"0123456789ABCDEF");
+
+        target
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_request_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        // The client connection serves the next request after the terminating chunk
+        let request = format!("GET http://{0}/next HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let forwarded = read_request_head(&mut target).await;
+        assert!(forwarded.starts_with("GET /next HTTP/1.1\r\n"), "{}", forwarded);
+        target.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+        let response = read_request_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn http_max_request_body_chunked() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let mut server = Http::with_context(context);
+        server.set_max_request_body(8);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let request = format!(
+            "POST http://{0}/ HTTP/1.1\r\nHost: {0}\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n0123\r\n",
+            target_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        let forwarded = read_request_head(&mut target).await;
+        assert!(forwarded.starts_with("POST / HTTP/1.1\r\n"), "{}", forwarded);
+
+        // Crosses the limit in the middle of the body
+        client.write_all(b"c\r\n456789abcdef\r\n0\r\n\r\n").await.unwrap();
+
+        // Forwarded request is aborted before the body's terminating chunk
+        let mut forwarded_body = Vec::new();
+        time::timeout(Duration::from_secs(1), target.read_to_end(&mut forwarded_body))
+            .await
+            .expect("forwarded request is not aborted")
+            .unwrap();
+        let forwarded_body = String::from_utf8(forwarded_body).unwrap();
+        assert!(!forwarded_body.contains("456789abcdef"), "{}", forwarded_body);
+        assert!(!forwarded_body.ends_with("0\r\n\r\n"), "{}", forwarded_body);
+
+        let response = read_request_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn http_body_start_timeout() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let mut server = Http::with_context(context);
+        server.set_body_start_timeout(Duration::from_millis(200));
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        // Stalls after headers
+        let request = format!(
+            "POST http://{0}/ HTTP/1.1\r\nHost: {0}\r\nContent-Length: 16\r\n\r\n",
+            target_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        let forwarded = read_request_head(&mut target).await;
+        assert!(forwarded.starts_with("POST / HTTP/1.1\r\n"), "{}", forwarded);
+
+        // Forwarded request is aborted
+        let mut forwarded_body = Vec::new();
+        time::timeout(Duration::from_secs(1), target.read_to_end(&mut forwarded_body))
+            .await
+            .expect("forwarded request is not aborted")
+            .unwrap();
+        assert!(forwarded_body.is_empty(), "{:?}", forwarded_body);
+
+        // Client is answered and dropped
+        let mut response = Vec::new();
+        time::timeout(Duration::from_secs(1), client.read_to_end(&mut response))
+            .await
+            .expect("stalled client's connection is still open")
+            .unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn http_proxy_authorization() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let mut auth = Socks5AuthConfig::new();
+        auth.passwd.add_user("foo", "bar");
+
+        let mut server = Http::with_context(context);
+        server.set_auth(auth);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        // "foo:bar" and "foo:baz"
+        let rejected_requests = [
+            format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr),
+            format!(
+                "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nProxy-Authorization: Basic Zm9vOmJheg==\r\n\r\n",
+                target_addr
+            ),
+            format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr),
+            format!(
+                "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nProxy-Authorization: Basic Zm9vOmJheg==\r\n\r\n",
+                target_addr
+            ),
+        ];
+        for request in rejected_requests {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let response = read_request_head(&mut client).await;
+            assert!(response.starts_with("HTTP/1.1 407 "), "{:?}: {}", request, response);
+            assert!(
+                response.contains("\r\nProxy-Authenticate: Basic realm=\"shadowsocks\"\r\n"),
+                "{}",
+                response
+            );
+        }
+
+        // Upstream is never contacted
+        let accepted = time::timeout(Duration::from_millis(100), target_listener.accept()).await;
+        assert!(accepted.is_err(), "upstream contacted");
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nProxy-Authorization: Basic Zm9vOmJhcg==\r\n\r\n",
+            target_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        // Credential is not forwarded to upstream
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        let forwarded = read_request_head(&mut target).await;
+        assert!(forwarded.starts_with("GET / HTTP/1.1\r\n"), "{}", forwarded);
+        assert!(
+            !forwarded.to_lowercase().contains("proxy-authorization"),
+            "{}",
+            forwarded
+        );
+    }
+
+    #[tokio::test]
+    async fn http_servers_removed() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            SocketAddr::from(([127, 0, 0, 1], 8388)),
+            "password",
+            CipherKind::AES_128_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+        balancer.reset_servers(Vec::new()).await.unwrap();
+
+        let server = Http::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let requests = [
+            format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr),
+            format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr),
+        ];
+        for request in requests {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let response = read_request_head(&mut client).await;
+            assert!(response.starts_with("HTTP/1.1 502 "), "{:?}: {}", request, response);
+        }
+
+        // Target is not connected directly either
+        let accepted = time::timeout(Duration::from_millis(100), target_listener.accept()).await;
+        assert!(accepted.is_err(), "target contacted");
+    }
+
+    #[tokio::test]
+    async fn http_malformed_request() {
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let requests = [
+            "GARBAGE\r\n\r\n",
+            "GET http://example.com/ HTTP/1.1 trailing\r\nHost: example.com\r\n\r\n",
+            "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nBad Header: value\r\n\r\n",
+            "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nNo-Colon\r\n\r\n",
+        ];
+
+        for request in requests {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            // Responded with 400 and closed, instead of being dropped silently
+            let mut response = Vec::new();
+            time::timeout(Duration::from_secs(1), client.read_to_end(&mut response))
+                .await
+                .expect("malformed request's connection is still open")
+                .unwrap();
+            let response = String::from_utf8(response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 "), "{:?}: {}", request, response);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_draining_refuses_requests() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context.clone());
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let request = format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        read_request_head(&mut target).await;
+        assert_eq!(context.active_connections(), 1);
+
+        target
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_request_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        // The next request on the keep-alive connection is refused
+        context.set_draining();
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let response = read_request_head(&mut client).await.to_ascii_lowercase();
+        assert!(response.starts_with("http/1.1 503 "), "{}", response);
+        assert!(response.contains("\r\nconnection: close\r\n"), "{}", response);
+        assert_eq!(context.active_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn http_destination_bytes_recorded() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_metrics(Arc::new(LocalMetrics::with_destination_stats()));
+        let context = Arc::new(context);
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Http::with_context(context.clone());
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", target_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        let forwarded = read_request_head(&mut target).await;
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        target.write_all(response).await.unwrap();
+        let head = read_request_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+
+        // Relayed by the HTTP client, the connection is still kept in its pool
+        let destinations = context.metrics().unwrap().destination_stats().unwrap().snapshot();
+        let bytes = destinations[&target_addr.to_string()];
+        assert_eq!(bytes.tx, forwarded.len() as u64);
+        assert_eq!(bytes.rx, response.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn http_reload_grace_closes_pooled_connections() {
+        let target_listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        // Server relays connections to the target, probes are closed
+        let svr_listener = bind_listener().await;
+        let svr_cfg = ServerConfig::new(svr_listener.local_addr().unwrap(), "password", CipherKind::AES_128_GCM);
+        let svr_listener =
+            ProxyListener::from_listener(Context::new_shared(ServerType::Server), svr_listener, &svr_cfg);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = svr_listener.accept().await {
+                if stream.handshake().await.ok() != Some(Address::SocketAddress(target_addr)) {
+                    continue;
+                }
+                tokio::spawn(async move {
+                    let mut target = TcpStream::connect(target_addr).await.unwrap();
+                    let _ = copy_bidirectional(&mut stream, &mut target).await;
+                });
+            }
+        });
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let grace = Duration::from_millis(200);
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.reload_grace(grace);
+        builder.add_server(svr_cfg.clone());
+        let balancer = builder.build().await.unwrap();
+
+        let server = Http::with_context(context);
+        {
+            let balancer = balancer.clone();
+            tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+        }
+
+        let request = format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+            target_addr
+        );
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        // Target keeps the connection alive, it is kept in the client's pool
+        let (mut pooled, _) = target_listener.accept().await.unwrap();
+        read_request_head(&mut pooled).await;
+        pooled
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.ends_with(b"\r\n\r\nok"));
+
+        // Reloaded with the same address, the pooled connection is closed after the grace
+        balancer.reset_servers(vec![svr_cfg]).await.unwrap();
+        let mut buffer = [0u8; 1];
+        assert!(time::timeout(grace / 2, pooled.read(&mut buffer)).await.is_err());
+        let n = time::timeout(grace * 5, pooled.read(&mut buffer))
+            .await
+            .expect("pooled connection of the old server isn't closed after grace")
+            .unwrap();
+        assert_eq!(n, 0);
+
+        // Requests go through a new connection of the new server
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let (mut target, _) = time::timeout(Duration::from_secs(1), target_listener.accept())
+            .await
+            .expect("new connection isn't made")
+            .unwrap();
+        read_request_head(&mut target).await;
+        target
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+}
@@ -8,6 +8,8 @@ use shadowsocks::relay::socks5::Address;
 pub fn authority_addr(scheme_str: Option<&str>, authority: &Authority) -> Option<Address> {
     // RFC7230 indicates that we should ignore userinfo
     // https://tools.ietf.org/html/rfc7230#section-5.3.3
+    //
+    // `Authority::host` strips userinfo, and keeps the brackets of IPv6 address
 
     // Check if URI has port
     let port = match authority.port_u16() {
@@ -23,6 +25,10 @@ pub fn authority_addr(scheme_str: Option<&str>, authority: &Authority) -> Option
     };
 
     let host_str = authority.host();
+    if host_str.is_empty() {
+        // Authority without host, like `user@:80`
+        return None;
+    }
 
     // RFC3986 indicates that IPv6 address should be wrapped in [ and ]
     // https://tools.ietf.org/html/rfc3986#section-3.2.2
@@ -53,3 +59,46 @@ pub fn host_addr(uri: &Uri) -> Option<Address> {
         Some(authority) => authority_addr(uri.scheme_str(), authority),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn uri_addr(uri: &str) -> Option<Address> {
+        host_addr(&uri.parse::<Uri>().unwrap())
+    }
+
+    #[test]
+    fn authority_with_userinfo() {
+        assert_eq!(
+            uri_addr("user@example.com:8080"),
+            Some(Address::DomainNameAddress("example.com".to_owned(), 8080))
+        );
+        assert_eq!(
+            uri_addr("user:pass@[::1]:443"),
+            Some(Address::SocketAddress("[::1]:443".parse().unwrap()))
+        );
+        assert_eq!(
+            uri_addr("http://user:p@ss@127.0.0.1/index.html"),
+            Some(Address::SocketAddress("127.0.0.1:80".parse().unwrap()))
+        );
+        assert_eq!(uri_addr("user@:80"), None);
+    }
+
+    #[test]
+    fn authority_default_port() {
+        assert_eq!(
+            uri_addr("example.com"),
+            Some(Address::DomainNameAddress("example.com".to_owned(), 80))
+        );
+        assert_eq!(
+            uri_addr("https://example.com/"),
+            Some(Address::DomainNameAddress("example.com".to_owned(), 443))
+        );
+        assert_eq!(
+            uri_addr("[::1]"),
+            Some(Address::SocketAddress("[::1]:80".parse().unwrap()))
+        );
+        assert_eq!(uri_addr("ftp://example.com/"), None);
+    }
+}
@@ -0,0 +1,163 @@
+//! Load balancer choosing the server with the lowest recent throughput
+
+use std::{
+    fmt::{self, Debug},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{server_data::ServerIdent, LoadBalancer};
+
+/// Interval of recalculating servers' throughput when it is the balancer's strategy
+pub const BANDWIDTH_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+struct BandwidthSample {
+    // Total bytes transferred through the server when it was sampled
+    bytes: u64,
+    // Bytes per second since the previous sample
+    bandwidth: u64,
+}
+
+struct BandwidthSnapshot {
+    samples: Vec<BandwidthSample>,
+    sampled_at: Instant,
+}
+
+/// Balancer that steers new connections toward the server with the lowest recent throughput
+///
+/// Throughput of each server is calculated from its flow statistic by calling `update` periodically.
+/// Servers with the same throughput are ordered by their TCP / UDP scores.
+pub struct BandwidthBalancer {
+    servers: Vec<Arc<ServerIdent>>,
+    snapshot: Mutex<BandwidthSnapshot>,
+}
+
+impl BandwidthBalancer {
+    /// Create a balancer with `servers`
+    pub fn new(servers: Vec<Arc<ServerIdent>>) -> BandwidthBalancer {
+        assert!(!servers.is_empty(), "no available server");
+
+        let samples = servers
+            .iter()
+            .map(|server| BandwidthSample {
+                bytes: server_bytes(server),
+                bandwidth: 0,
+            })
+            .collect();
+
+        BandwidthBalancer {
+            servers,
+            snapshot: Mutex::new(BandwidthSnapshot {
+                samples,
+                sampled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Get the servers
+    pub fn servers(&self) -> &[Arc<ServerIdent>] {
+        &self.servers
+    }
+
+    /// Take a snapshot of servers' flow statistic and recalculate their throughput
+    pub fn update(&self) {
+        self.update_at(Instant::now());
+    }
+
+    fn update_at(&self, now: Instant) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+
+        let elapsed_ms = now.saturating_duration_since(snapshot.sampled_at).as_millis() as u64;
+        if elapsed_ms == 0 {
+            return;
+        }
+
+        for (server, sample) in self.servers.iter().zip(snapshot.samples.iter_mut()) {
+            let bytes = server_bytes(server);
+            sample.bandwidth = bytes.saturating_sub(sample.bytes) * 1000 / elapsed_ms;
+            sample.bytes = bytes;
+        }
+        snapshot.sampled_at = now;
+    }
+
+    /// Recent throughput of each server in bytes per second, in the same order of `servers`
+    pub fn bandwidths(&self) -> Vec<u64> {
+        let snapshot = self.snapshot.lock().unwrap();
+        snapshot.samples.iter().map(|s| s.bandwidth).collect()
+    }
+
+    fn least_loaded_server<F>(&self, score: F) -> Arc<ServerIdent>
+    where
+        F: Fn(&ServerIdent) -> u32,
+    {
+        let snapshot = self.snapshot.lock().unwrap();
+
+        let (server, _) = self
+            .servers
+            .iter()
+            .zip(snapshot.samples.iter())
+            .min_by_key(|(server, sample)| (sample.bandwidth, score(server)))
+            .expect("no available server");
+        server.clone()
+    }
+}
+
+impl LoadBalancer for BandwidthBalancer {
+    fn best_tcp_server(&self) -> Arc<ServerIdent> {
+        self.least_loaded_server(|server| server.tcp_score().score())
+    }
+
+    fn best_udp_server(&self) -> Arc<ServerIdent> {
+        self.least_loaded_server(|server| server.udp_score().score())
+    }
+}
+
+impl Debug for BandwidthBalancer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BandwidthBalancer")
+            .field("servers", &self.servers)
+            .field("bandwidths", &self.bandwidths())
+            .finish()
+    }
+}
+
+#[inline]
+fn server_bytes(server: &ServerIdent) -> u64 {
+    let flow_stat = server.flow_stat();
+    flow_stat.tx().wrapping_add(flow_stat.rx())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::weighted_servers;
+
+    use super::*;
+
+    #[test]
+    fn bandwidth_balancer_least_loaded() {
+        let servers = weighted_servers(&[1, 1, 1]);
+
+        let balancer = BandwidthBalancer::new(servers.clone());
+        let start = balancer.snapshot.lock().unwrap().sampled_at;
+
+        // 1s of synthetic traffic
+        servers[0].flow_stat().incr_tx(300_000);
+        servers[1].flow_stat().incr_rx(100_000);
+        servers[2].flow_stat().incr_tx(50_000);
+        servers[2].flow_stat().incr_rx(150_000);
+        balancer.update_at(start + Duration::from_secs(1));
+
+        assert_eq!(balancer.bandwidths(), [300_000, 100_000, 200_000]);
+        assert!(Arc::ptr_eq(&balancer.best_tcp_server(), &servers[1]));
+
+        // The next second, server 1 becomes the busiest one
+        servers[0].flow_stat().incr_rx(20_000);
+        servers[1].flow_stat().incr_rx(500_000);
+        servers[2].flow_stat().incr_tx(10_000);
+        balancer.update_at(start + Duration::from_secs(2));
+
+        assert_eq!(balancer.bandwidths(), [20_000, 500_000, 10_000]);
+        assert!(Arc::ptr_eq(&balancer.best_tcp_server(), &servers[2]));
+        assert!(Arc::ptr_eq(&balancer.best_udp_server(), &servers[2]));
+    }
+}
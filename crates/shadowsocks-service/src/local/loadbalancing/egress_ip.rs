@@ -0,0 +1,370 @@
+//! Probing egress IP addresses of servers with an IP-echo endpoint
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use byte_string::ByteStr;
+use log::{debug, info, warn};
+use shadowsocks::relay::{socks5::Address, tcprelay::proxy_stream::ProxyClientStream};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
+};
+
+use crate::local::context::ServiceContext;
+
+use super::server_data::ServerIdent;
+
+const MAXIMUM_ECHO_RESPONSE_SIZE: u64 = 4096;
+
+/// Probes the egress IP address of servers
+///
+/// Requests `http://<addr>/` through the server, the endpoint should respond the client's IP address as the plain
+/// text body, like `https://api.ipify.org`. Only plain HTTP is supported, endpoints requiring TLS couldn't be probed.
+///
+/// Probed addresses are exported in metrics as `shadowsocks_local_server_egress_ip_info`.
+#[derive(Debug, Clone)]
+pub struct EgressIpProbe {
+    addr: Address,
+    interval: Option<Duration>,
+    max_redirects: usize,
+}
+
+impl EgressIpProbe {
+    /// Create a probe requesting the IP-echo endpoint `addr`
+    ///
+    /// Servers are probed once when they are loaded, then every `interval` if it is set
+    pub fn new(addr: Address, interval: Option<Duration>) -> EgressIpProbe {
+        EgressIpProbe {
+            addr,
+            interval,
+            max_redirects: 0,
+        }
+    }
+
+    /// Interval between each probing
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Set maximum redirects followed in each probing, redirects are not followed by default
+    ///
+    /// Only plain HTTP locations can be followed.
+    pub fn set_max_redirects(&mut self, max_redirects: usize) {
+        self.max_redirects = max_redirects;
+    }
+
+    /// Maximum redirects followed in each probing
+    pub fn max_redirects(&self) -> usize {
+        self.max_redirects
+    }
+
+    /// Probe `server` and record its egress IP address
+    pub async fn probe_server(&self, context: &ServiceContext, server: &ServerIdent, timeout: Duration) {
+        let svr_cfg = server.server_config();
+
+        match time::timeout(timeout, self.request_egress_ip(context, server)).await {
+            Ok(Ok(ip)) => {
+                if server.egress_ip() != Some(ip) {
+                    info!("server {} egress IP {}", svr_cfg.addr(), ip);
+                }
+                server.set_egress_ip(Some(ip));
+            }
+            Ok(Err(err)) => {
+                warn!(
+                    "failed to probe egress IP of server {} with {}, error: {}",
+                    svr_cfg.addr(),
+                    self.addr,
+                    err
+                );
+            }
+            Err(..) => {
+                warn!(
+                    "failed to probe egress IP of server {} with {}, timeout",
+                    svr_cfg.addr(),
+                    self.addr
+                );
+            }
+        }
+    }
+
+    async fn request_egress_ip(&self, context: &ServiceContext, server: &ServerIdent) -> io::Result<IpAddr> {
+        let mut addr = self.addr.clone();
+        let mut path = "/".to_owned();
+        let mut redirects = 0;
+
+        loop {
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept: */*\r\n\r\n",
+                path, addr
+            );
+
+            let mut stream = ProxyClientStream::connect_with_opts(
+                context.context(),
+                server.server_config(),
+                &addr,
+                context.connect_opts_ref(),
+            )
+            .await?;
+            stream.write_all(request.as_bytes()).await?;
+
+            let mut response = Vec::new();
+            stream
+                .take(MAXIMUM_ECHO_RESPONSE_SIZE)
+                .read_to_end(&mut response)
+                .await?;
+
+            match parse_echo_response(&response) {
+                Some(EchoResponse::Ip(ip)) => return Ok(ip),
+                Some(EchoResponse::Redirect(location)) => {
+                    if redirects >= self.max_redirects {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            format!(
+                                "IP-echo endpoint redirected to {} after {} redirects, max_redirects: {}",
+                                location, redirects, self.max_redirects
+                            ),
+                        ));
+                    }
+                    redirects += 1;
+
+                    let (raddr, rpath) = redirect_target(&addr, &location).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("IP-echo endpoint redirected to unsupported location {}", location),
+                        )
+                    })?;
+                    debug!("IP-echo endpoint {}{} redirected to {}{}", addr, path, raddr, rpath);
+                    addr = raddr;
+                    path = rpath;
+                }
+                None => {
+                    debug!(
+                        "unexpected response from IP-echo endpoint {}, {:?}",
+                        addr,
+                        ByteStr::new(&response)
+                    );
+
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected response from IP-echo endpoint",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+enum EchoResponse {
+    Ip(IpAddr),
+    /// Location of a redirection
+    Redirect(String),
+}
+
+fn parse_echo_response(response: &[u8]) -> Option<EchoResponse> {
+    let response = std::str::from_utf8(response).ok()?;
+    let (head, body) = response.split_once("\r\n\r\n")?;
+
+    let mut lines = head.lines();
+    let status_line = lines.next()?;
+    let mut status = status_line.split_whitespace().skip(1);
+    match status.next()? {
+        "200" => body.trim().parse().ok().map(EchoResponse::Ip),
+        "301" | "302" | "303" | "307" | "308" => lines.find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("location") {
+                Some(EchoResponse::Redirect(value.trim().to_owned()))
+            } else {
+                None
+            }
+        }),
+        _ => None,
+    }
+}
+
+/// Address and path of a redirection to `location` from `addr`
+///
+/// `location` could be an absolute `http` URL, or an absolute path on `addr`.
+fn redirect_target(addr: &Address, location: &str) -> Option<(Address, String)> {
+    if let Some(url) = location.strip_prefix("http://") {
+        let (authority, path) = match url.find('/') {
+            Some(pos) => (&url[..pos], &url[pos..]),
+            None => (url, "/"),
+        };
+        let raddr = match authority.parse::<Address>() {
+            Ok(a) => a,
+            // IPv6 literal without port
+            Err(..) => Address::SocketAddress(SocketAddr::new(
+                authority.trim_start_matches('[').trim_end_matches(']').parse().ok()?,
+                80,
+            )),
+        };
+        Some((raddr, path.to_owned()))
+    } else if location.starts_with('/') && !location.starts_with("//") {
+        Some((addr.clone(), location.to_owned()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{SocketAddr, TcpListener as StdTcpListener},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use shadowsocks::{
+        config::{ServerConfig, ServerType},
+        context::Context,
+        crypto::CipherKind,
+        relay::tcprelay::proxy_listener::ProxyListener,
+    };
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::test_utils::bind_listener;
+
+    use super::*;
+
+    /// IP-echo endpoint responding the client's IP address
+    async fn start_echo_endpoint() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, peer_addr) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+
+                    let body = peer_addr.ip().to_string();
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    /// Endpoint redirecting all requests to `location`, counts requests in `requests`
+    async fn start_redirect_endpoint(location: &'static str, requests: Arc<AtomicUsize>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                requests.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+
+                    let response = format!(
+                        "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        location
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    /// Shadowsocks server relaying to the requested target
+    async fn start_server() -> ServerConfig {
+        let listener = bind_listener().await;
+        let svr_cfg = ServerConfig::new(listener.local_addr().unwrap(), "password", CipherKind::AES_128_GCM);
+        let context = Context::new_shared(ServerType::Server);
+        let listener = ProxyListener::from_listener(context, listener, &svr_cfg);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let target_addr = match stream.handshake().await.unwrap() {
+                        Address::SocketAddress(addr) => addr,
+                        Address::DomainNameAddress(..) => unreachable!(),
+                    };
+                    let mut remote = TcpStream::connect(target_addr).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut remote).await;
+                });
+            }
+        });
+
+        svr_cfg
+    }
+
+    #[tokio::test]
+    async fn egress_ip_probe() {
+        let echo_addr = start_echo_endpoint().await;
+
+        let reachable = {
+            let svr_cfg = start_server().await;
+            ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10))
+        };
+
+        let unreachable = {
+            // Nothing is listening on the server's address
+            let server_addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            let svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_128_GCM);
+            ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10))
+        };
+
+        let context = ServiceContext::new();
+        let probe = EgressIpProbe::new(Address::SocketAddress(echo_addr), None);
+
+        probe.probe_server(&context, &reachable, Duration::from_secs(5)).await;
+        probe.probe_server(&context, &unreachable, Duration::from_secs(5)).await;
+
+        assert_eq!(reachable.egress_ip(), Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(unreachable.egress_ip(), None);
+    }
+
+    #[tokio::test]
+    async fn egress_ip_probe_max_redirects() {
+        let echo_addr = start_echo_endpoint().await;
+
+        let server = {
+            let svr_cfg = start_server().await;
+            ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10))
+        };
+        let context = ServiceContext::new();
+
+        // Redirected to the IP-echo endpoint
+        let location = Box::leak(format!("http://{}/ip", echo_addr).into_boxed_str());
+        let redirect_addr = start_redirect_endpoint(location, Arc::new(AtomicUsize::new(0))).await;
+
+        let probe = EgressIpProbe::new(Address::SocketAddress(redirect_addr), None);
+        probe.probe_server(&context, &server, Duration::from_secs(5)).await;
+        assert_eq!(server.egress_ip(), None);
+
+        let mut probe = EgressIpProbe::new(Address::SocketAddress(redirect_addr), None);
+        probe.set_max_redirects(1);
+        probe.probe_server(&context, &server, Duration::from_secs(5)).await;
+        assert_eq!(server.egress_ip(), Some("127.0.0.1".parse().unwrap()));
+
+        // Redirect loop is aborted after following `max_redirects` redirects
+        server.set_egress_ip(None);
+        let requests = Arc::new(AtomicUsize::new(0));
+        let loop_addr = start_redirect_endpoint("/", requests.clone()).await;
+
+        let mut probe = EgressIpProbe::new(Address::SocketAddress(loop_addr), None);
+        probe.set_max_redirects(3);
+        probe.probe_server(&context, &server, Duration::from_secs(5)).await;
+        assert_eq!(server.egress_ip(), None);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,102 @@
+//! Load balancer choosing the server with the fewest open connections
+
+use std::{
+    fmt::{self, Debug},
+    sync::Arc,
+};
+
+use super::{server_data::ServerIdent, LoadBalancer};
+
+/// Balancer that steers new connections toward the server with the fewest open TCP connections
+///
+/// Connections are counted in each server's `ServerIdent::tcp_connections` while proxied streams are alive.
+/// Servers with the same count are ordered by their positions in `servers`.
+pub struct LeastConnectionsBalancer {
+    servers: Vec<Arc<ServerIdent>>,
+}
+
+impl LeastConnectionsBalancer {
+    /// Create a balancer with `servers`
+    pub fn new(servers: Vec<Arc<ServerIdent>>) -> LeastConnectionsBalancer {
+        assert!(!servers.is_empty(), "no available server");
+
+        LeastConnectionsBalancer { servers }
+    }
+
+    /// Get the servers
+    pub fn servers(&self) -> &[Arc<ServerIdent>] {
+        &self.servers
+    }
+
+    /// Open TCP connections of each server, in the same order of `servers`
+    pub fn connections(&self) -> Vec<usize> {
+        self.servers.iter().map(|server| server.tcp_connections()).collect()
+    }
+
+    fn least_connections_server(&self) -> Arc<ServerIdent> {
+        let (_, server) = self
+            .servers
+            .iter()
+            .enumerate()
+            .min_by_key(|(idx, server)| (server.tcp_connections(), *idx))
+            .expect("no available server");
+        server.clone()
+    }
+}
+
+impl LoadBalancer for LeastConnectionsBalancer {
+    fn best_tcp_server(&self) -> Arc<ServerIdent> {
+        self.least_connections_server()
+    }
+
+    fn best_udp_server(&self) -> Arc<ServerIdent> {
+        self.least_connections_server()
+    }
+}
+
+impl Debug for LeastConnectionsBalancer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LeastConnectionsBalancer")
+            .field("servers", &self.servers)
+            .field("connections", &self.connections())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::weighted_servers;
+
+    use super::*;
+
+    #[test]
+    fn least_connections_balancer_pick() {
+        let servers = weighted_servers(&[1, 1, 1]);
+
+        let balancer = LeastConnectionsBalancer::new(servers.clone());
+
+        // Ties are broken by index, so connections are spread in order
+        let connect = || {
+            let server = balancer.best_tcp_server();
+            let gauge = server.track_tcp_connection();
+            (server, gauge)
+        };
+        let (s0, c0) = connect();
+        let (s1, c1) = connect();
+        let (s2, c2) = connect();
+        let (_, c3) = connect();
+        assert!(Arc::ptr_eq(&s0, &servers[0]));
+        assert!(Arc::ptr_eq(&s1, &servers[1]));
+        assert!(Arc::ptr_eq(&s2, &servers[2]));
+        assert_eq!(balancer.connections(), [2, 1, 1]);
+
+        // Server 1 becomes the least busy one after its connection finishes
+        drop(c1);
+        assert_eq!(balancer.connections(), [2, 0, 1]);
+        assert!(Arc::ptr_eq(&balancer.best_tcp_server(), &servers[1]));
+        assert!(Arc::ptr_eq(&balancer.best_udp_server(), &servers[1]));
+
+        drop((c0, c2, c3));
+        assert_eq!(balancer.connections(), [0, 0, 0]);
+    }
+}
@@ -1,10 +1,31 @@
 //! Load balancer
 
+use std::sync::Arc;
+
 pub use self::{
+    bandwidth_balancer::BandwidthBalancer,
+    egress_ip::EgressIpProbe,
+    least_connections_balancer::LeastConnectionsBalancer,
     ping_balancer::{PingBalancer, PingBalancerBuilder, ServerType},
-    server_data::{ServerIdent, ServerScore},
+    server_data::{AdaptiveConnectTimeout, ServerIdent, ServerScore},
+    weighted_random_balancer::WeightedRandomBalancer,
+    weighted_round_robin_balancer::WeightedRoundRobinBalancer,
 };
 
+pub mod bandwidth_balancer;
+pub mod egress_ip;
+pub mod least_connections_balancer;
 pub mod ping_balancer;
 pub mod server_data;
 pub mod server_stat;
+pub mod weighted_random_balancer;
+pub mod weighted_round_robin_balancer;
+
+/// Strategy of choosing servers for new connections
+pub trait LoadBalancer {
+    /// Pick the best TCP server
+    fn best_tcp_server(&self) -> Arc<ServerIdent>;
+
+    /// Pick the best UDP server
+    fn best_udp_server(&self) -> Arc<ServerIdent>;
+}
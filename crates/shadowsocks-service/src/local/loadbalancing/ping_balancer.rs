@@ -7,7 +7,7 @@ use std::{
     iter::Iterator,
     net::{Ipv4Addr, SocketAddr},
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
     time::{Duration, Instant},
@@ -35,11 +35,17 @@ use tokio::{
     time,
 };
 
-use crate::local::context::ServiceContext;
+use crate::{config::BalancerStrategy, local::context::ServiceContext};
 
 use super::{
+    bandwidth_balancer::{BandwidthBalancer, BANDWIDTH_SAMPLE_INTERVAL},
+    egress_ip::EgressIpProbe,
+    least_connections_balancer::LeastConnectionsBalancer,
     server_data::ServerIdent,
     server_stat::{Score, DEFAULT_CHECK_INTERVAL_SEC, DEFAULT_CHECK_TIMEOUT_SEC},
+    weighted_random_balancer::WeightedRandomBalancer,
+    weighted_round_robin_balancer::WeightedRoundRobinBalancer,
+    LoadBalancer,
 };
 
 const EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW: u32 = 67;
@@ -60,6 +66,61 @@ impl fmt::Display for ServerType {
     }
 }
 
+/// Reason of a server being skipped when choosing a TCP server for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SkipReason {
+    /// Server's address is blocked by `[outbound_block_list]` of ACL
+    Forbidden,
+    /// Server is down after failing to connect, until its cooldown expires
+    Unreachable,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SkipReason::Forbidden => f.write_str("forbidden"),
+            SkipReason::Unreachable => f.write_str("unreachable"),
+        }
+    }
+}
+
+/// TCP server chosen for a connection, and the servers skipped before it
+struct TcpServerChoice<'a> {
+    servers: &'a [Arc<ServerIdent>],
+    best_idx: usize,
+    skipped: Vec<(usize, SkipReason)>,
+}
+
+impl TcpServerChoice<'_> {
+    fn server(&self) -> Arc<ServerIdent> {
+        self.servers[self.best_idx].clone()
+    }
+}
+
+impl fmt::Display for TcpServerChoice<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "chose TCP server {}, skipped {} servers",
+            ServerConfigFormatter::new(self.servers[self.best_idx].server_config()),
+            self.skipped.len()
+        )?;
+
+        for (i, (idx, reason)) in self.skipped.iter().enumerate() {
+            let sep = if i == 0 { ": " } else { ", " };
+            write!(
+                f,
+                "{}{} ({})",
+                sep,
+                ServerConfigFormatter::new(self.servers[*idx].server_config()),
+                reason
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
 /// Build a `PingBalancer`
 pub struct PingBalancerBuilder {
     servers: Vec<Arc<ServerIdent>>,
@@ -68,6 +129,9 @@ pub struct PingBalancerBuilder {
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
+    reload_grace: Option<Duration>,
+    egress_ip_probe: Option<EgressIpProbe>,
+    strategy: BalancerStrategy,
 }
 
 impl PingBalancerBuilder {
@@ -79,6 +143,9 @@ impl PingBalancerBuilder {
             max_server_rtt: Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC),
             check_interval: Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC),
             check_best_interval: None,
+            reload_grace: None,
+            egress_ip_probe: None,
+            strategy: BalancerStrategy::default(),
         }
     }
 
@@ -103,6 +170,21 @@ impl PingBalancerBuilder {
         self.check_best_interval = Some(intv);
     }
 
+    /// Close connections of the old servers after `grace` when servers are reset
+    pub fn reload_grace(&mut self, grace: Duration) {
+        self.reload_grace = Some(grace);
+    }
+
+    /// Probe servers' egress IP address with an IP-echo endpoint
+    pub fn egress_ip_probe(&mut self, probe: EgressIpProbe) {
+        self.egress_ip_probe = Some(probe);
+    }
+
+    /// Strategy of choosing servers, servers are still probed for failing over from the servers that are down
+    pub fn strategy(&mut self, strategy: BalancerStrategy) {
+        self.strategy = strategy;
+    }
+
     fn find_best_idx(servers: &[Arc<ServerIdent>], mode: Mode) -> (usize, usize) {
         if servers.is_empty() {
             trace!("init without any TCP and UDP servers");
@@ -171,6 +253,7 @@ impl PingBalancerBuilder {
             }
         }
 
+        let had_servers = !self.servers.is_empty();
         let (shared_context, task_abortable) = PingBalancerContext::new(
             self.servers,
             self.context,
@@ -178,6 +261,8 @@ impl PingBalancerBuilder {
             self.max_server_rtt,
             self.check_interval,
             self.check_best_interval,
+            self.egress_ip_probe,
+            self.strategy,
         )
         .await?;
 
@@ -185,6 +270,8 @@ impl PingBalancerBuilder {
             inner: Arc::new(PingBalancerInner {
                 context: ArcSwap::new(shared_context),
                 task_abortable: SpinMutex::new(task_abortable),
+                reload_grace: self.reload_grace,
+                had_servers: AtomicBool::new(had_servers),
             }),
         })
     }
@@ -193,6 +280,8 @@ impl PingBalancerBuilder {
 struct PingBalancerContextTask {
     checker_abortable: JoinHandle<()>,
     plugin_abortable: Option<JoinHandle<()>>,
+    egress_ip_abortable: Option<JoinHandle<()>>,
+    strategy_abortable: Option<JoinHandle<()>>,
 }
 
 impl Drop for PingBalancerContextTask {
@@ -201,6 +290,52 @@ impl Drop for PingBalancerContextTask {
         if let Some(ref p) = self.plugin_abortable {
             p.abort();
         }
+        if let Some(ref p) = self.egress_ip_abortable {
+            p.abort();
+        }
+        if let Some(ref p) = self.strategy_abortable {
+            p.abort();
+        }
+    }
+}
+
+// Balancer choosing servers instead of the probing scores
+enum StrategyBalancer {
+    Bandwidth(BandwidthBalancer),
+    LeastConnections(LeastConnectionsBalancer),
+    WeightedRoundRobin(WeightedRoundRobinBalancer),
+    WeightedRandom(WeightedRandomBalancer),
+}
+
+impl StrategyBalancer {
+    // `None` for the `Ping` strategy
+    fn new(strategy: BalancerStrategy, servers: &[Arc<ServerIdent>]) -> Option<StrategyBalancer> {
+        if servers.is_empty() {
+            return None;
+        }
+
+        match strategy {
+            BalancerStrategy::Ping => None,
+            BalancerStrategy::Bandwidth => Some(StrategyBalancer::Bandwidth(BandwidthBalancer::new(servers.to_vec()))),
+            BalancerStrategy::LeastConnections => Some(StrategyBalancer::LeastConnections(
+                LeastConnectionsBalancer::new(servers.to_vec()),
+            )),
+            BalancerStrategy::WeightedRoundRobin => Some(StrategyBalancer::WeightedRoundRobin(
+                WeightedRoundRobinBalancer::new(servers.to_vec()),
+            )),
+            BalancerStrategy::WeightedRandom => Some(StrategyBalancer::WeightedRandom(WeightedRandomBalancer::new(
+                servers.to_vec(),
+            ))),
+        }
+    }
+
+    fn balancer(&self) -> &dyn LoadBalancer {
+        match *self {
+            StrategyBalancer::Bandwidth(ref b) => b,
+            StrategyBalancer::LeastConnections(ref b) => b,
+            StrategyBalancer::WeightedRoundRobin(ref b) => b,
+            StrategyBalancer::WeightedRandom(ref b) => b,
+        }
     }
 }
 
@@ -214,17 +349,96 @@ struct PingBalancerContext {
     check_interval: Duration,
     check_best_interval: Option<Duration>,
     best_task_notify: Notify,
+    egress_ip_probe: Option<EgressIpProbe>,
+    strategy: BalancerStrategy,
+    strategy_balancer: Option<StrategyBalancer>,
 }
 
 impl PingBalancerContext {
     fn best_tcp_server(&self) -> Arc<ServerIdent> {
+        self.choose_tcp_server().server()
+    }
+
+    /// Choose the TCP server for a new connection, skipping servers that are forbidden or down
+    ///
+    /// The best server of the strategy is still chosen if all servers are skipped.
+    fn choose_tcp_server(&self) -> TcpServerChoice<'_> {
         assert!(!self.is_empty(), "no available server");
-        self.servers[self.best_tcp_idx.load(Ordering::Relaxed)].clone()
+        let best_idx = match self.strategy_balancer {
+            Some(ref b) => {
+                let best = b.balancer().best_tcp_server();
+                self.servers
+                    .iter()
+                    .position(|server| Arc::ptr_eq(server, &best))
+                    .expect("server of strategy balancer")
+            }
+            None => self.best_tcp_idx.load(Ordering::Relaxed),
+        };
+
+        let acl = self.context.acl();
+        let skip_reason = |server: &ServerIdent| {
+            if matches!(acl, Some(ref acl) if acl.check_server_blocked(server.server_config().addr())) {
+                Some(SkipReason::Forbidden)
+            } else if server.is_tcp_down() {
+                Some(SkipReason::Unreachable)
+            } else {
+                None
+            }
+        };
+
+        let best_reason = match skip_reason(&self.servers[best_idx]) {
+            None => {
+                return TcpServerChoice {
+                    servers: &self.servers,
+                    best_idx,
+                    skipped: Vec::new(),
+                }
+            }
+            Some(reason) => reason,
+        };
+
+        // Fail over to the best of the others, until the server is allowed or its cooldown expires
+        let mut skipped = vec![(best_idx, best_reason)];
+        let mut other_idx = None;
+        for (idx, server) in self.servers.iter().enumerate() {
+            if idx == best_idx || !PingBalancerContext::check_server_tcp_enabled(server.server_config()) {
+                continue;
+            }
+            match skip_reason(server) {
+                Some(reason) => skipped.push((idx, reason)),
+                None => {
+                    let score = server.tcp_score().score();
+                    match other_idx {
+                        Some((_, best_score)) if best_score <= score => {}
+                        _ => other_idx = Some((idx, score)),
+                    }
+                }
+            }
+        }
+
+        match other_idx {
+            Some((idx, _)) => TcpServerChoice {
+                servers: &self.servers,
+                best_idx: idx,
+                skipped,
+            },
+            None => {
+                skipped.remove(0);
+                TcpServerChoice {
+                    servers: &self.servers,
+                    best_idx,
+                    skipped,
+                }
+            }
+        }
     }
 
     fn best_udp_server(&self) -> Arc<ServerIdent> {
         assert!(!self.is_empty(), "no available server");
-        self.servers[self.best_udp_idx.load(Ordering::Relaxed)].clone()
+        match self.strategy_balancer {
+            Some(ref b) => b.balancer().best_udp_server(),
+            None => self.servers[self.best_udp_idx.load(Ordering::Relaxed)].clone(),
+        }
     }
 
     #[inline]
@@ -234,6 +448,7 @@ impl PingBalancerContext {
 }
 
 impl PingBalancerContext {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new(
         mut servers: Vec<Arc<ServerIdent>>,
         context: Arc<ServiceContext>,
@@ -241,6 +456,8 @@ impl PingBalancerContext {
         max_server_rtt: Duration,
         check_interval: Duration,
         check_best_interval: Option<Duration>,
+        egress_ip_probe: Option<EgressIpProbe>,
+        strategy: BalancerStrategy,
     ) -> io::Result<(Arc<PingBalancerContext>, PingBalancerContextTask)> {
         let plugin_abortable = if mode.enable_tcp() {
             // Start plugins for TCP proxies
@@ -306,6 +523,7 @@ impl PingBalancerContext {
         };
 
         let (best_tcp_idx, best_udp_idx) = PingBalancerBuilder::find_best_idx(&servers, mode);
+        let strategy_balancer = StrategyBalancer::new(strategy, &servers);
 
         let balancer_context = PingBalancerContext {
             servers,
@@ -317,6 +535,9 @@ impl PingBalancerContext {
             check_interval,
             check_best_interval,
             best_task_notify: Notify::new(),
+            egress_ip_probe,
+            strategy,
+            strategy_balancer,
         };
 
         balancer_context.init_score().await;
@@ -328,11 +549,31 @@ impl PingBalancerContext {
             tokio::spawn(async move { shared_context.checker_task().await })
         };
 
+        let egress_ip_abortable = if shared_context.egress_ip_probe.is_some() {
+            let shared_context = shared_context.clone();
+            Some(tokio::spawn(async move { shared_context.egress_ip_task().await }))
+        } else {
+            None
+        };
+
+        let strategy_abortable = match shared_context.strategy_balancer {
+            Some(StrategyBalancer::Bandwidth(..)) => {
+                let shared_context = shared_context.clone();
+                Some(tokio::spawn(async move { shared_context.bandwidth_task().await }))
+            }
+            Some(StrategyBalancer::LeastConnections(..))
+            | Some(StrategyBalancer::WeightedRoundRobin(..))
+            | Some(StrategyBalancer::WeightedRandom(..))
+            | None => None,
+        };
+
         Ok((
             shared_context,
             PingBalancerContextTask {
                 checker_abortable,
                 plugin_abortable,
+                egress_ip_abortable,
+                strategy_abortable,
             },
         ))
     }
@@ -352,6 +593,25 @@ impl PingBalancerContext {
         svr_cfg.mode().enable_udp() && svr_cfg.weight().udp_weight() > 0.0
     }
 
+    /// Index of the server with the lowest score
+    fn choose_best_server(servers: &[Arc<ServerIdent>], server_type: ServerType) -> usize {
+        let server_score = |server: &Arc<ServerIdent>| match server_type {
+            ServerType::Tcp => server.tcp_score().score(),
+            ServerType::Udp => server.udp_score().score(),
+        };
+
+        let mut best_idx = 0;
+        let mut best_score = u32::MAX;
+        for (idx, server) in servers.iter().enumerate() {
+            let score = server_score(server);
+            if score < best_score {
+                best_idx = idx;
+                best_score = score;
+            }
+        }
+        best_idx
+    }
+
     fn probing_required(&self) -> bool {
         if self.servers.is_empty() {
             return false;
@@ -381,7 +641,42 @@ impl PingBalancerContext {
         }
     }
 
+    /// Probe egress IP address of servers serving TCP, once or periodically
+    async fn egress_ip_task(self: Arc<Self>) {
+        let probe = match self.egress_ip_probe {
+            Some(ref probe) => probe,
+            None => return,
+        };
+
+        loop {
+            let mut vfut = Vec::with_capacity(self.servers.len());
+            for server in self.servers.iter() {
+                if self.mode.enable_tcp() && PingBalancerContext::check_server_tcp_enabled(server.server_config()) {
+                    vfut.push(probe.probe_server(&self.context, server, self.max_server_rtt));
+                }
+            }
+            future::join_all(vfut).await;
+
+            match probe.interval() {
+                Some(interval) => time::sleep(interval).await,
+                None => break,
+            }
+        }
+    }
+
     /// Dummy task that will do nothing if there only have one server in the balancer
+    async fn bandwidth_task(self: Arc<Self>) {
+        if let Some(StrategyBalancer::Bandwidth(ref balancer)) = self.strategy_balancer {
+            let start = time::Instant::now() + BANDWIDTH_SAMPLE_INTERVAL;
+            let mut interval = time::interval_at(start, BANDWIDTH_SAMPLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                balancer.update();
+                trace!("server bandwidths updated, {:?}", balancer.bandwidths());
+            }
+        }
+    }
+
     async fn checker_task_dummy(self: Arc<Self>) {
         future::pending().await
     }
@@ -441,15 +736,7 @@ impl PingBalancerContext {
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = PingBalancerContext::choose_best_server(servers, ServerType::Tcp);
             self.best_tcp_idx.store(best_idx, Ordering::Release);
 
             if first_run {
@@ -476,15 +763,7 @@ impl PingBalancerContext {
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = PingBalancerContext::choose_best_server(servers, ServerType::Udp);
             self.best_udp_idx.store(best_idx, Ordering::Release);
 
             if first_run {
@@ -556,15 +835,7 @@ impl PingBalancerContext {
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = PingBalancerContext::choose_best_server(servers, ServerType::Tcp);
             self.best_tcp_idx.store(best_idx, Ordering::Release);
 
             if best_idx != old_best_idx {
@@ -586,15 +857,7 @@ impl PingBalancerContext {
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = PingBalancerContext::choose_best_server(servers, ServerType::Udp);
             self.best_udp_idx.store(best_idx, Ordering::Release);
 
             if best_idx != old_best_idx {
@@ -675,6 +938,9 @@ impl PingBalancerContext {
 struct PingBalancerInner {
     context: ArcSwap<PingBalancerContext>,
     task_abortable: SpinMutex<PingBalancerContextTask>,
+    reload_grace: Option<Duration>,
+    // Balancers without servers bypass all connections, unless their servers were removed by reloading
+    had_servers: AtomicBool,
 }
 
 impl Drop for PingBalancerInner {
@@ -715,6 +981,55 @@ impl PingBalancer {
         context.is_empty()
     }
 
+    /// Check if all servers were removed by `reset_servers`
+    ///
+    /// Connections shouldn't be bypassed in this case, they would have been proxied before the servers were removed.
+    pub fn is_servers_removed(&self) -> bool {
+        self.is_empty() && self.inner.had_servers.load(Ordering::Acquire)
+    }
+
+    /// Pick the best TCP server for connection `conn_id`, `None` if it doesn't have any server so connections are
+    /// bypassed
+    ///
+    /// Servers skipped before the chosen one are logged with the reasons. Fails if all servers were removed by
+    /// `reset_servers`, until servers are added back.
+    pub fn pick_tcp_server(&self, conn_id: usize) -> io::Result<Option<Arc<ServerIdent>>> {
+        let context = self.inner.context.load();
+        if !context.is_empty() {
+            let choice = context.choose_tcp_server();
+            if !choice.skipped.is_empty() {
+                debug!("[c{}] {}", conn_id, choice);
+            }
+            return Ok(Some(choice.server()));
+        }
+
+        if self.inner.had_servers.load(Ordering::Acquire) {
+            error!("no available server, all servers were removed by reloading, connection rejected");
+            return Err(io::Error::new(io::ErrorKind::NotFound, "all servers were removed"));
+        }
+
+        Ok(None)
+    }
+
+    /// Pick the best TCP server other than `failed`, for connections failing over from it
+    ///
+    /// Servers that are forbidden or down are skipped, `None` if there isn't any other server.
+    pub fn pick_tcp_server_except(&self, failed: &ServerIdent) -> Option<Arc<ServerIdent>> {
+        let context = self.inner.context.load();
+        let acl = context.context.acl();
+        context
+            .servers
+            .iter()
+            .filter(|server| {
+                !std::ptr::eq(server.as_ref(), failed)
+                    && PingBalancerContext::check_server_tcp_enabled(server.server_config())
+                    && !server.is_tcp_down()
+                    && !matches!(acl, Some(ref acl) if acl.check_server_blocked(server.server_config().addr()))
+            })
+            .min_by_key(|server| server.tcp_score().score())
+            .cloned()
+    }
+
     /// Get the server list
     pub fn servers(&self) -> PingServerIter<'_> {
         let context = self.inner.context.load();
@@ -747,6 +1062,8 @@ impl PingBalancer {
             old_context.max_server_rtt,
             old_context.check_interval,
             old_context.check_best_interval,
+            old_context.egress_ip_probe.clone(),
+            old_context.strategy,
         )
         .await?;
 
@@ -757,17 +1074,52 @@ impl PingBalancer {
         }
 
         // Replace with the new context
+        if shared_context.is_empty() {
+            if self.inner.had_servers.load(Ordering::Acquire) {
+                error!("servers are reset to empty, connections will be rejected until servers are added back");
+            }
+        } else {
+            self.inner.had_servers.store(true, Ordering::Release);
+        }
         self.inner.context.store(shared_context);
 
+        // Connections of the old servers are kept for a grace period, then closed to move to the new servers
+        if let Some(grace) = self.inner.reload_grace {
+            let old_servers = old_context.servers.clone();
+            tokio::spawn(async move {
+                time::sleep(grace).await;
+
+                debug!(
+                    "closing connections of {} old servers after reload grace {:?}",
+                    old_servers.len(),
+                    grace
+                );
+                for server in old_servers {
+                    server.retire();
+                }
+            });
+        }
+
         Ok(())
     }
 }
 
+impl LoadBalancer for PingBalancer {
+    fn best_tcp_server(&self) -> Arc<ServerIdent> {
+        PingBalancer::best_tcp_server(self)
+    }
+
+    fn best_udp_server(&self) -> Arc<ServerIdent> {
+        PingBalancer::best_udp_server(self)
+    }
+}
+
 impl Debug for PingBalancer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let context = self.inner.context.load();
 
         f.debug_struct("PingBalancer")
+            .field("strategy", &context.strategy)
             .field("servers", &context.servers)
             .field("best_tcp_idx", &context.best_tcp_idx.load(Ordering::Relaxed))
             .field("best_udp_idx", &context.best_udp_idx.load(Ordering::Relaxed))
@@ -1028,3 +1380,286 @@ impl<'a> Iterator for PingServerIter<'a> {
         self.iter.next().map(AsRef::as_ref)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{fs, net::SocketAddr, process};
+
+    use log::LevelFilter;
+    use shadowsocks::{config::ServerWeight, crypto::CipherKind, relay::socks5::Address};
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+        task::JoinHandle,
+    };
+
+    use crate::{
+        acl::AccessControl,
+        local::utils::establish_tcp_tunnel,
+        test_utils::{capture_logs, captured_conn_logs, ProxiedStream},
+    };
+
+    use super::*;
+
+    // Returns the tunnel task, client's stream and remote's stream
+    async fn start_tunnel(
+        context: Arc<ServiceContext>,
+        server: Arc<ServerIdent>,
+    ) -> (JoinHandle<io::Result<()>>, DuplexStream, DuplexStream) {
+        let (mut plain, mut client) = duplex(1024);
+        let (shadow, remote) = duplex(1024);
+
+        let tunnel = tokio::spawn(async move {
+            let peer_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+            let target_addr = Address::DomainNameAddress("www.example.com".to_owned(), 443);
+            let mut shadow = ProxiedStream(shadow);
+            establish_tcp_tunnel(&context, 0, &server, &mut plain, &mut shadow, peer_addr, &target_addr).await
+        });
+
+        // Client sends first, tunnel starts relaying immediately
+        client.write_all(b"hello").await.unwrap();
+
+        (tunnel, client, remote)
+    }
+
+    #[tokio::test]
+    async fn pick_tcp_server_skip_summary() {
+        capture_logs(LevelFilter::Debug);
+
+        let acl_path = std::env::temp_dir().join(format!("shadowsocks-skip-summary-{}.acl", process::id()));
+        fs::write(&acl_path, "[proxy_all]\n[outbound_block_list]\n127.0.0.2/32\n").unwrap();
+        let acl = AccessControl::load_from_file(&acl_path).unwrap();
+        fs::remove_file(&acl_path).unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_acl(acl);
+        let context = Arc::new(context);
+
+        let mut builder = PingBalancerBuilder::new(context, Mode::TcpOnly);
+        for (ip, port) in [([127, 0, 0, 2], 8388), ([127, 0, 0, 1], 8389), ([127, 0, 0, 3], 8390)] {
+            builder.add_server(ServerConfig::new(
+                SocketAddr::from((ip, port)),
+                "password",
+                CipherKind::AES_128_GCM,
+            ));
+        }
+        let balancer = builder.build().await.unwrap();
+
+        // The best server is forbidden by ACL, the next one is down
+        let context = balancer.inner.context.load();
+        context.best_tcp_idx.store(0, Ordering::Release);
+        context.servers[1].tcp_score().push_score(Score::Latency(10)).await;
+        context.servers[1].mark_tcp_down(Duration::from_secs(60));
+        context.servers[2].tcp_score().push_score(Score::Latency(100)).await;
+
+        let server = balancer.pick_tcp_server(7).unwrap().unwrap();
+        assert_eq!(server.server_config().addr().port(), 8390);
+
+        let summary = "chose TCP server 127.0.0.3:8390, skipped 2 servers: 127.0.0.2:8388 (forbidden), 127.0.0.1:8389 \
+                       (unreachable)";
+        let logs = captured_conn_logs(7);
+        assert!(logs.iter().any(|(message, _)| message == summary), "{:?}", logs);
+
+        // Connections failing over from the chosen server skip them too
+        assert!(balancer.pick_tcp_server_except(&server).is_none());
+    }
+
+    #[tokio::test]
+    async fn best_tcp_server_skips_down_servers() {
+        let context = Arc::new(ServiceContext::new());
+
+        let mut builder = PingBalancerBuilder::new(context, Mode::TcpOnly);
+        for port in [8388, 8389] {
+            builder.add_server(ServerConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                "password",
+                CipherKind::AES_128_GCM,
+            ));
+        }
+        let balancer = builder.build().await.unwrap();
+
+        let first = balancer.best_tcp_server();
+        first.mark_tcp_down(Duration::from_secs(60));
+        let second = balancer.best_tcp_server();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(!second.is_tcp_down());
+
+        // All servers are down, one of them is still chosen
+        second.mark_tcp_down(Duration::from_secs(60));
+        assert!(balancer.best_tcp_server().is_tcp_down());
+    }
+
+    #[tokio::test]
+    async fn strategy_bandwidth() {
+        let context = Arc::new(ServiceContext::new());
+
+        let mut builder = PingBalancerBuilder::new(context, Mode::TcpOnly);
+        builder.strategy(BalancerStrategy::Bandwidth);
+        for port in [8388, 8389] {
+            builder.add_server(ServerConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                "password",
+                CipherKind::AES_128_GCM,
+            ));
+        }
+        let balancer = builder.build().await.unwrap();
+
+        let update = || {
+            let context = balancer.inner.context.load();
+            match context.strategy_balancer {
+                Some(StrategyBalancer::Bandwidth(ref b)) => b.update(),
+                _ => panic!("bandwidth strategy isn't enabled"),
+            }
+        };
+        let best_tcp_port = || balancer.best_tcp_server().server_config().addr().port();
+
+        let servers = balancer.servers().collect::<Vec<_>>();
+        servers[0].flow_stat().incr_tx(1_000_000);
+        time::sleep(Duration::from_millis(10)).await;
+        update();
+        assert_eq!(best_tcp_port(), 8389);
+
+        servers[1].flow_stat().incr_rx(10_000_000);
+        time::sleep(Duration::from_millis(10)).await;
+        update();
+        assert_eq!(best_tcp_port(), 8388);
+
+        // Servers that are down are still skipped
+        servers[0].mark_tcp_down(Duration::from_secs(60));
+        assert_eq!(best_tcp_port(), 8389);
+    }
+
+    #[tokio::test]
+    async fn strategy_least_connections() {
+        let context = Arc::new(ServiceContext::new());
+
+        let mut builder = PingBalancerBuilder::new(context, Mode::TcpOnly);
+        builder.strategy(BalancerStrategy::LeastConnections);
+        for port in [8388, 8389] {
+            builder.add_server(ServerConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                "password",
+                CipherKind::AES_128_GCM,
+            ));
+        }
+        let balancer = builder.build().await.unwrap();
+        let best_tcp_port = || balancer.best_tcp_server().server_config().addr().port();
+
+        // Connections counted by proxied streams steer new ones to the other server
+        let c0 = balancer.best_tcp_server().track_tcp_connection();
+        assert_eq!(best_tcp_port(), 8389);
+        let _c1 = balancer.best_tcp_server().track_tcp_connection();
+        let _c2 = balancer.best_tcp_server().track_tcp_connection();
+        assert_eq!(best_tcp_port(), 8389);
+
+        // Ties are broken by the servers' order
+        drop(c0);
+        assert_eq!(best_tcp_port(), 8388);
+    }
+
+    #[tokio::test]
+    async fn strategy_weighted_round_robin() {
+        let context = Arc::new(ServiceContext::new());
+
+        let mut builder = PingBalancerBuilder::new(context, Mode::TcpOnly);
+        builder.strategy(BalancerStrategy::WeightedRoundRobin);
+        for (port, w) in [(8388, 3), (8389, 1)] {
+            let mut svr_cfg = ServerConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                "password",
+                CipherKind::AES_128_GCM,
+            );
+            let mut weight = ServerWeight::new();
+            weight.set_round_robin_weight(w);
+            svr_cfg.set_weight(weight);
+            builder.add_server(svr_cfg);
+        }
+        let balancer = builder.build().await.unwrap();
+
+        let ports = (0..4)
+            .map(|_| balancer.best_tcp_server().server_config().addr().port())
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [8388, 8388, 8389, 8388]);
+    }
+
+    #[tokio::test]
+    async fn servers_removed_by_reloading() {
+        let context = Arc::new(ServiceContext::new());
+        let server = ServerConfig::new(
+            SocketAddr::from(([127, 0, 0, 1], 8388)),
+            "password",
+            CipherKind::AES_128_GCM,
+        );
+
+        // Balancers configured without servers bypass connections
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        assert!(balancer.pick_tcp_server(0).unwrap().is_none());
+        assert!(!balancer.is_servers_removed());
+
+        let mut builder = PingBalancerBuilder::new(context, Mode::TcpOnly);
+        builder.add_server(server.clone());
+        let balancer = builder.build().await.unwrap();
+        assert!(balancer.pick_tcp_server(0).unwrap().is_some());
+
+        // Connections fail instead of being bypassed or panicking
+        balancer.reset_servers(Vec::new()).await.unwrap();
+        assert!(balancer.is_servers_removed());
+        assert!(balancer.pick_tcp_server(0).is_err());
+
+        balancer.reset_servers(vec![server]).await.unwrap();
+        assert!(!balancer.is_servers_removed());
+        let picked = balancer.pick_tcp_server(0).unwrap().unwrap();
+        assert_eq!(picked.server_config().addr().to_string(), "127.0.0.1:8388");
+    }
+
+    #[tokio::test]
+    async fn reload_grace_closes_old_connections() {
+        let context = Arc::new(ServiceContext::new());
+        let grace = Duration::from_millis(200);
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.reload_grace(grace);
+        builder.add_server(ServerConfig::new(
+            SocketAddr::from(([127, 0, 0, 1], 8388)),
+            "password",
+            CipherKind::AES_128_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        let old_server = balancer.best_tcp_server();
+        let (old_tunnel, mut old_client, _old_remote) = start_tunnel(context.clone(), old_server.clone()).await;
+
+        balancer
+            .reset_servers(vec![ServerConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], 8389)),
+                "password",
+                CipherKind::AES_128_GCM,
+            )])
+            .await
+            .unwrap();
+
+        let new_server = balancer.best_tcp_server();
+        assert!(!Arc::ptr_eq(&old_server, &new_server));
+        let (mut new_tunnel, _new_client, _new_remote) = start_tunnel(context.clone(), new_server.clone()).await;
+
+        // Old connection is still alive in the grace period
+        assert!(!old_server.is_retired());
+
+        // and closed after the grace
+        time::timeout(grace * 5, old_tunnel)
+            .await
+            .expect("old connection should be closed after grace")
+            .unwrap()
+            .unwrap();
+        assert!(old_server.is_retired());
+
+        let mut buf = [0u8; 16];
+        assert_eq!(old_client.read(&mut buf).await.unwrap(), 0);
+
+        // New connection persists
+        assert!(!new_server.is_retired());
+        assert!(time::timeout(grace, &mut new_tunnel).await.is_err());
+    }
+}
@@ -2,19 +2,50 @@
 
 use std::{
     fmt::{self, Debug},
-    sync::atomic::{AtomicU32, Ordering},
-    time::Duration,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use shadowsocks::ServerConfig;
-use tokio::sync::Mutex;
+use spin::Mutex as SpinMutex;
+use tokio::sync::{watch, Mutex};
+
+use crate::{local::metrics::ConnectionGauge, net::FlowStat};
 
 use super::server_stat::{Score, ServerStat};
 
+/// Default minimum of the tuned connect timeout
+pub const DEFAULT_CONNECT_TIMEOUT_FLOOR: Duration = Duration::from_secs(1);
+
+/// Connect timeout tuned by servers' observed RTT
+///
+/// Timeout is `rtt * rtt_factor + floor`, servers without observed RTT use their configured `timeout` instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveConnectTimeout {
+    /// Multiplier of the observed RTT
+    pub rtt_factor: u32,
+    /// Minimum timeout
+    pub floor: Duration,
+}
+
+impl AdaptiveConnectTimeout {
+    /// Connect timeout of a server with `rtt`
+    pub fn timeout(&self, rtt: Duration) -> Duration {
+        rtt * self.rtt_factor + self.floor
+    }
+}
+
 /// Server's statistic score
 pub struct ServerScore {
     stat_data: Mutex<ServerStat>,
     score: AtomicU32,
+    latest_errored: AtomicBool,
+    // Median of observed latency in millisec, 0 if not observed yet
+    rtt: AtomicU32,
 }
 
 impl ServerScore {
@@ -26,6 +57,8 @@ impl ServerScore {
         ServerScore {
             stat_data: Mutex::new(ServerStat::new(user_weight, max_server_rtt, check_window)),
             score: AtomicU32::new(u32::MAX),
+            latest_errored: AtomicBool::new(false),
+            rtt: AtomicU32::new(0),
         }
     }
 
@@ -36,14 +69,34 @@ impl ServerScore {
 
     /// Append a `Score` into statistic and recalculate score of the server
     pub async fn push_score(&self, score: Score) -> u32 {
-        let updated_score = {
+        let (updated_score, rtt) = {
             let mut stat = self.stat_data.lock().await;
-            stat.push_score(score)
+            let updated_score = stat.push_score(score);
+            (updated_score, stat.observed_rtt())
         };
         self.score.store(updated_score, Ordering::Release);
+        if let Some(rtt) = rtt {
+            // 0 is reserved for not observed
+            self.rtt.store(rtt.max(1), Ordering::Release);
+        }
+        let errored = matches!(score, Score::Errored);
+        self.latest_errored.store(errored, Ordering::Release);
         updated_score
     }
 
+    /// Median of the observed latency, `None` if no latency was observed yet
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Acquire) {
+            0 => None,
+            rtt => Some(Duration::from_millis(rtt as u64)),
+        }
+    }
+
+    /// Check if the latest request of this server failed
+    pub fn latest_errored(&self) -> bool {
+        self.latest_errored.load(Ordering::Acquire)
+    }
+
     /// Report request failure of this server, which will eventually records an `Errored` score
     pub async fn report_failure(&self) -> u32 {
         self.push_score(Score::Errored).await
@@ -57,20 +110,35 @@ impl Debug for ServerScore {
 }
 
 /// Identifer for a server
-#[derive(Debug)]
 pub struct ServerIdent {
     tcp_score: ServerScore,
     udp_score: ServerScore,
+    flow_stat: Arc<FlowStat>,
+    tcp_connections: Arc<AtomicUsize>,
+    tcp_failures: AtomicUsize,
     svr_cfg: ServerConfig,
+    egress_ip: SpinMutex<Option<IpAddr>>,
+    tcp_down_until: SpinMutex<Option<Instant>>,
+    retire_tx: watch::Sender<bool>,
+    retire_rx: watch::Receiver<bool>,
 }
 
 impl ServerIdent {
     /// Create a `ServerIdent`
     pub fn new(svr_cfg: ServerConfig, max_server_rtt: Duration, check_window: Duration) -> ServerIdent {
+        let (retire_tx, retire_rx) = watch::channel(false);
+
         ServerIdent {
             tcp_score: ServerScore::new(svr_cfg.weight().tcp_weight(), max_server_rtt, check_window),
             udp_score: ServerScore::new(svr_cfg.weight().udp_weight(), max_server_rtt, check_window),
+            flow_stat: Arc::new(FlowStat::new()),
+            tcp_connections: Arc::new(AtomicUsize::new(0)),
+            tcp_failures: AtomicUsize::new(0),
             svr_cfg,
+            egress_ip: SpinMutex::new(None),
+            tcp_down_until: SpinMutex::new(None),
+            retire_tx,
+            retire_rx,
         }
     }
 
@@ -89,4 +157,153 @@ impl ServerIdent {
     pub fn udp_score(&self) -> &ServerScore {
         &self.udp_score
     }
+
+    /// Timeout of connecting to this server
+    ///
+    /// With `adaptive`, it is tuned by the observed TCP latency, otherwise it is the server's configured `timeout`
+    pub fn connect_timeout(&self, adaptive: Option<&AdaptiveConnectTimeout>) -> Option<Duration> {
+        match (adaptive, self.tcp_score.rtt()) {
+            (Some(adaptive), Some(rtt)) => Some(adaptive.timeout(rtt)),
+            _ => self.svr_cfg.timeout(),
+        }
+    }
+
+    /// Get cloned flow statistic of TCP connections proxied through this server
+    pub fn flow_stat(&self) -> Arc<FlowStat> {
+        self.flow_stat.clone()
+    }
+
+    /// Open TCP connections to this server
+    pub fn tcp_connections(&self) -> usize {
+        self.tcp_connections.load(Ordering::Acquire)
+    }
+
+    /// Count an open TCP connection to this server until the returned guard is dropped
+    pub fn track_tcp_connection(&self) -> ConnectionGauge {
+        ConnectionGauge::new(self.tcp_connections.clone())
+    }
+
+    /// Same as `track_tcp_connection`, but `None` if the server's `max_tcp_connections` has been reached
+    pub fn try_track_tcp_connection(&self) -> Option<ConnectionGauge> {
+        match self.svr_cfg.max_tcp_connections() {
+            Some(max) => ConnectionGauge::try_new(self.tcp_connections.clone(), max),
+            None => Some(self.track_tcp_connection()),
+        }
+    }
+
+    /// Failures of connecting to this server since it was created
+    pub fn tcp_failures(&self) -> usize {
+        self.tcp_failures.load(Ordering::Acquire)
+    }
+
+    /// Count a failure of connecting to this server
+    pub fn report_tcp_failure(&self) {
+        self.tcp_failures.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Egress IP address of this server reported by the IP-echo endpoint, `None` if it wasn't probed yet
+    pub fn egress_ip(&self) -> Option<IpAddr> {
+        *self.egress_ip.lock()
+    }
+
+    /// Record egress IP address of this server
+    pub fn set_egress_ip(&self, ip: Option<IpAddr>) {
+        *self.egress_ip.lock() = ip;
+    }
+
+    /// Mark this server down for TCP in `cooldown`, balancers will choose the other servers until it expires
+    pub fn mark_tcp_down(&self, cooldown: Duration) {
+        *self.tcp_down_until.lock() = Some(Instant::now() + cooldown);
+    }
+
+    /// Check if this server is down for TCP, marked by `mark_tcp_down` and the cooldown hasn't expired yet
+    pub fn is_tcp_down(&self) -> bool {
+        matches!(*self.tcp_down_until.lock(), Some(until) if until > Instant::now())
+    }
+
+    /// Retire this server, connections relaying through it will be closed
+    pub fn retire(&self) {
+        let _ = self.retire_tx.send(true);
+    }
+
+    /// Check if this server has been retired
+    pub fn is_retired(&self) -> bool {
+        *self.retire_rx.borrow()
+    }
+
+    /// Wait until this server is retired
+    pub async fn retired(&self) {
+        let mut retire_rx = self.retire_rx.clone();
+        while !*retire_rx.borrow() {
+            // Sender is owned by `self`, it won't be closed
+            if retire_rx.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Debug for ServerIdent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerIdent")
+            .field("tcp_score", &self.tcp_score)
+            .field("udp_score", &self.udp_score)
+            .field("tx", &self.flow_stat.tx())
+            .field("rx", &self.flow_stat.rx())
+            .field("svr_cfg", &self.svr_cfg)
+            .field("egress_ip", &self.egress_ip())
+            .field("tcp_down", &self.is_tcp_down())
+            .field("retired", &self.is_retired())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use shadowsocks::crypto::CipherKind;
+
+    use super::*;
+    use crate::local::loadbalancing::server_stat::Score;
+
+    #[tokio::test]
+    async fn adaptive_connect_timeout() {
+        let new_server = |port| {
+            let mut svr_cfg = ServerConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                "password",
+                CipherKind::AES_128_GCM,
+            );
+            svr_cfg.set_timeout(Duration::from_secs(5));
+            ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(30))
+        };
+
+        let fast = new_server(8388);
+        let slow = new_server(8389);
+
+        let adaptive = AdaptiveConnectTimeout {
+            rtt_factor: 4,
+            floor: Duration::from_secs(1),
+        };
+
+        // No latency was observed yet
+        assert_eq!(fast.connect_timeout(Some(&adaptive)), Some(Duration::from_secs(5)));
+        assert_eq!(slow.connect_timeout(Some(&adaptive)), Some(Duration::from_secs(5)));
+
+        for _ in 0..5 {
+            fast.tcp_score().push_score(Score::Latency(50)).await;
+            slow.tcp_score().push_score(Score::Latency(1500)).await;
+        }
+        slow.tcp_score().report_failure().await;
+
+        let fast_timeout = fast.connect_timeout(Some(&adaptive)).unwrap();
+        let slow_timeout = slow.connect_timeout(Some(&adaptive)).unwrap();
+        assert_eq!(fast_timeout, Duration::from_millis(1200));
+        assert_eq!(slow_timeout, Duration::from_millis(7000));
+        assert!(slow_timeout > fast_timeout);
+
+        // Configured timeout is used without adaptive timeout
+        assert_eq!(slow.connect_timeout(None), Some(Duration::from_secs(5)));
+    }
 }
@@ -100,6 +100,16 @@ impl ServerStat {
         (score * 10000.0) as u32
     }
 
+    /// Median of latency in the checking window (in millisec), `None` if no latency was observed
+    pub fn observed_rtt(&self) -> Option<u32> {
+        let observed = self.latency_queue.iter().any(|(s, _)| matches!(*s, Score::Latency(..)));
+        if observed {
+            Some(self.rtt)
+        } else {
+            None
+        }
+    }
+
     pub fn push_score(&mut self, score: Score) -> u32 {
         let now = Instant::now();
 
@@ -0,0 +1,187 @@
+//! Load balancer picking servers randomly by weights, which are reduced after failures
+
+use std::{
+    fmt::{self, Debug},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{server_data::ServerIdent, LoadBalancer};
+
+/// Default period for servers to recover half of their reduced weights
+pub const DEFAULT_FAILURE_HALF_LIFE: Duration = Duration::from_secs(30);
+
+// Failures of a server, decaying by half in every half-life
+#[derive(Clone, Copy)]
+struct FailureDecay {
+    failures: f64,
+    updated_at: Instant,
+    // `ServerIdent::tcp_failures` that have been counted
+    reported: usize,
+}
+
+impl FailureDecay {
+    fn failures_at(&self, now: Instant, half_life: Duration) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.failures * 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64())
+    }
+}
+
+/// Balancer that picks servers randomly, in proportion to their effective weights
+///
+/// Effective weight of a server is its configured weight divided by `1 + failures`. Failures are counted in each
+/// server's `ServerIdent::tcp_failures` by connections, and decay by half in every half-life, so failed servers are
+/// chosen less often for a while and recover their shares gradually.
+pub struct WeightedRandomBalancer {
+    servers: Vec<Arc<ServerIdent>>,
+    weights: Vec<f64>,
+    failures: Mutex<Vec<FailureDecay>>,
+    half_life: Duration,
+}
+
+impl WeightedRandomBalancer {
+    /// Create a balancer with `servers`, weights are recovered in `DEFAULT_FAILURE_HALF_LIFE`
+    pub fn new(servers: Vec<Arc<ServerIdent>>) -> WeightedRandomBalancer {
+        WeightedRandomBalancer::with_half_life(servers, DEFAULT_FAILURE_HALF_LIFE)
+    }
+
+    /// Create a balancer with `servers`, failures decay by half in every `half_life`
+    pub fn with_half_life(servers: Vec<Arc<ServerIdent>>, half_life: Duration) -> WeightedRandomBalancer {
+        assert!(!servers.is_empty(), "no available server");
+        assert!(!half_life.is_zero(), "half_life must be > 0");
+
+        let weights = servers
+            .iter()
+            .map(|server| server.server_config().weight().round_robin_weight() as f64)
+            .collect();
+
+        let now = Instant::now();
+        let failures = servers
+            .iter()
+            .map(|server| FailureDecay {
+                failures: 0.0,
+                updated_at: now,
+                reported: server.tcp_failures(),
+            })
+            .collect();
+
+        WeightedRandomBalancer {
+            servers,
+            weights,
+            failures: Mutex::new(failures),
+            half_life,
+        }
+    }
+
+    /// Get the servers
+    pub fn servers(&self) -> &[Arc<ServerIdent>] {
+        &self.servers
+    }
+
+    /// Effective weights of servers, in the same order of `servers`
+    pub fn effective_weights(&self) -> Vec<f64> {
+        self.effective_weights_at(Instant::now())
+    }
+
+    fn effective_weights_at(&self, now: Instant) -> Vec<f64> {
+        let mut failures = self.failures.lock().unwrap();
+
+        // Failures reported since the last pick are counted from now on
+        for (server, decay) in self.servers.iter().zip(failures.iter_mut()) {
+            let reported = server.tcp_failures();
+            if reported > decay.reported {
+                decay.failures = decay.failures_at(now, self.half_life) + (reported - decay.reported) as f64;
+                decay.updated_at = now;
+                decay.reported = reported;
+            }
+        }
+
+        self.weights
+            .iter()
+            .zip(failures.iter())
+            .map(|(weight, decay)| weight / (1.0 + decay.failures_at(now, self.half_life)))
+            .collect()
+    }
+
+    // `point` is a random number in `[0, 1)`
+    fn pick_server_at(&self, now: Instant, point: f64) -> Arc<ServerIdent> {
+        let weights = self.effective_weights_at(now);
+        let total = weights.iter().sum::<f64>();
+
+        let mut point = point * total;
+        for (server, weight) in self.servers.iter().zip(weights.iter()) {
+            if point < *weight {
+                return server.clone();
+            }
+            point -= weight;
+        }
+
+        // Rounding errors
+        self.servers[self.servers.len() - 1].clone()
+    }
+}
+
+impl LoadBalancer for WeightedRandomBalancer {
+    fn best_tcp_server(&self) -> Arc<ServerIdent> {
+        self.pick_server_at(Instant::now(), rand::random::<f64>())
+    }
+
+    fn best_udp_server(&self) -> Arc<ServerIdent> {
+        self.pick_server_at(Instant::now(), rand::random::<f64>())
+    }
+}
+
+impl Debug for WeightedRandomBalancer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WeightedRandomBalancer")
+            .field("servers", &self.servers)
+            .field("effective_weights", &self.effective_weights())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::weighted_servers;
+
+    use super::*;
+
+    // Picks of each server with points evenly spread in [0, 1)
+    fn pick_shares(balancer: &WeightedRandomBalancer, now: Instant) -> Vec<usize> {
+        let mut shares = vec![0; balancer.servers().len()];
+        for i in 0..1000 {
+            let server = balancer.pick_server_at(now, i as f64 / 1000.0);
+            let idx = balancer.servers().iter().position(|s| Arc::ptr_eq(s, &server)).unwrap();
+            shares[idx] += 1;
+        }
+        shares
+    }
+
+    #[test]
+    fn weighted_random_balancer_failure_decay() {
+        let servers = weighted_servers(&[1, 1]);
+
+        let half_life = Duration::from_secs(10);
+        let balancer = WeightedRandomBalancer::with_half_life(servers.clone(), half_life);
+        let start = Instant::now();
+
+        assert_eq!(pick_shares(&balancer, start), [500, 500]);
+
+        // 3 failures reduce the weight to 1 / 4
+        for _ in 0..3 {
+            servers[0].report_tcp_failure();
+        }
+        assert_eq!(pick_shares(&balancer, start), [200, 800]);
+
+        // Recovers gradually, 1.5 failures left after a half-life
+        let shares = pick_shares(&balancer, start + half_life);
+        assert_eq!(shares, [286, 714]);
+
+        let shares = pick_shares(&balancer, start + half_life * 10);
+        assert!(shares[0] >= 495, "{:?}", shares);
+
+        // The other server is never affected
+        let weights = balancer.effective_weights_at(start);
+        assert_eq!(weights[1], 1.0);
+    }
+}
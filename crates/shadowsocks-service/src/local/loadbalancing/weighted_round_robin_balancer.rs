@@ -0,0 +1,122 @@
+//! Load balancer picking servers in proportion to their weights
+
+use std::{
+    fmt::{self, Debug},
+    sync::{Arc, Mutex},
+};
+
+use super::{server_data::ServerIdent, LoadBalancer};
+
+/// Balancer that picks servers in turn, in proportion to their round-robin weights
+///
+/// Uses the smooth weighted round-robin algorithm, which interleaves picks of different servers
+/// instead of picking the heaviest one repeatedly. TCP and UDP picks are counted separately.
+pub struct WeightedRoundRobinBalancer {
+    servers: Vec<Arc<ServerIdent>>,
+    weights: Vec<i64>,
+    tcp_current: Mutex<Vec<i64>>,
+    udp_current: Mutex<Vec<i64>>,
+}
+
+impl WeightedRoundRobinBalancer {
+    /// Create a balancer with `servers`
+    pub fn new(servers: Vec<Arc<ServerIdent>>) -> WeightedRoundRobinBalancer {
+        assert!(!servers.is_empty(), "no available server");
+
+        let weights = servers
+            .iter()
+            .map(|server| server.server_config().weight().round_robin_weight() as i64)
+            .collect::<Vec<_>>();
+        let current = vec![0; servers.len()];
+
+        WeightedRoundRobinBalancer {
+            servers,
+            weights,
+            tcp_current: Mutex::new(current.clone()),
+            udp_current: Mutex::new(current),
+        }
+    }
+
+    /// Get the servers
+    pub fn servers(&self) -> &[Arc<ServerIdent>] {
+        &self.servers
+    }
+
+    fn pick_server(&self, current: &Mutex<Vec<i64>>) -> Arc<ServerIdent> {
+        let mut current = current.lock().unwrap();
+
+        let mut total = 0;
+        let mut picked = 0;
+        for (idx, weight) in self.weights.iter().enumerate() {
+            current[idx] += weight;
+            total += weight;
+            if current[idx] > current[picked] {
+                picked = idx;
+            }
+        }
+        current[picked] -= total;
+
+        self.servers[picked].clone()
+    }
+}
+
+impl LoadBalancer for WeightedRoundRobinBalancer {
+    fn best_tcp_server(&self) -> Arc<ServerIdent> {
+        self.pick_server(&self.tcp_current)
+    }
+
+    fn best_udp_server(&self) -> Arc<ServerIdent> {
+        self.pick_server(&self.udp_current)
+    }
+}
+
+impl Debug for WeightedRoundRobinBalancer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WeightedRoundRobinBalancer")
+            .field("servers", &self.servers)
+            .field("weights", &self.weights)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::weighted_servers;
+
+    use super::*;
+
+    #[test]
+    fn weighted_round_robin_balancer_distribution() {
+        let servers = weighted_servers(&[3, 1]);
+        let balancer = WeightedRoundRobinBalancer::new(servers.clone());
+
+        let mut picks = [0usize; 2];
+        for _ in 0..400 {
+            let server = balancer.best_tcp_server();
+            let idx = servers.iter().position(|s| Arc::ptr_eq(s, &server)).unwrap();
+            picks[idx] += 1;
+        }
+        assert_eq!(picks, [300, 100]);
+
+        // Picks are interleaved, a:a:b:a with weights 3:1
+        let order = (0..4)
+            .map(|_| {
+                let server = balancer.best_udp_server();
+                servers.iter().position(|s| Arc::ptr_eq(s, &server)).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(order, [0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn weighted_round_robin_balancer_default_weight() {
+        let servers = weighted_servers(&[1, 1, 1]);
+        let balancer = WeightedRoundRobinBalancer::new(servers.clone());
+
+        for round in 0..3 {
+            for server in servers.iter() {
+                assert!(Arc::ptr_eq(&balancer.best_tcp_server(), server), "round {}", round);
+            }
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Prometheus metrics of local server
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+use shadowsocks::relay::socks5::Address;
+
+#[cfg(feature = "local-metrics")]
+pub use self::server::MetricsServer;
+pub use crate::net::ConnectionGauge;
+
+#[cfg(feature = "local-metrics")]
+mod server;
+
+/// Connection counters of local server
+///
+/// Only counted if it is set to `ServiceContext` by `set_metrics`.
+#[derive(Debug, Default)]
+pub struct LocalMetrics {
+    tcp_connections: Arc<AtomicUsize>,
+    http_connections: Arc<AtomicUsize>,
+    socks_unsupported_version: AtomicU64,
+    destinations: Option<DestinationStats>,
+}
+
+impl LocalMetrics {
+    /// Create counters starting from zero
+    pub fn new() -> LocalMetrics {
+        LocalMetrics::default()
+    }
+
+    /// Create counters starting from zero, also accounting bytes relayed to each destination
+    pub fn with_destination_stats() -> LocalMetrics {
+        LocalMetrics {
+            destinations: Some(DestinationStats::new()),
+            ..LocalMetrics::default()
+        }
+    }
+
+    /// Bytes relayed to each destination, `None` if it is not enabled
+    pub fn destination_stats(&self) -> Option<&DestinationStats> {
+        self.destinations.as_ref()
+    }
+
+    /// Active TCP tunnels, both proxied and bypassed
+    pub fn tcp_connections(&self) -> usize {
+        self.tcp_connections.load(Ordering::Acquire)
+    }
+
+    /// Active client connections of HTTP local servers
+    pub fn http_connections(&self) -> usize {
+        self.http_connections.load(Ordering::Acquire)
+    }
+
+    /// Connections closed because of an unsupported SOCKS version
+    pub fn socks_unsupported_version(&self) -> u64 {
+        self.socks_unsupported_version.load(Ordering::Relaxed)
+    }
+
+    /// Count a connection closed because of an unsupported SOCKS version
+    pub fn incr_socks_unsupported_version(&self) {
+        self.socks_unsupported_version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an active TCP tunnel until the returned guard is dropped
+    pub fn track_tcp_connection(&self) -> ConnectionGauge {
+        ConnectionGauge::new(self.tcp_connections.clone())
+    }
+
+    /// Count an active HTTP client connection until the returned guard is dropped
+    pub fn track_http_connection(&self) -> ConnectionGauge {
+        ConnectionGauge::new(self.http_connections.clone())
+    }
+}
+
+/// Maximum destinations kept in `DestinationStats`
+pub const DESTINATION_STATS_MAX_ENTRIES: usize = 4096;
+
+/// Destinations accounted after `DestinationStats` is full are summed up in this entry
+pub const DESTINATION_STATS_OTHERS: &str = "others";
+
+/// Bytes relayed to a destination
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DestinationBytes {
+    /// Bytes sent to the destination
+    pub tx: u64,
+    /// Bytes received from the destination
+    pub rx: u64,
+}
+
+/// Bytes relayed to each destination, accumulated since the last `take`
+///
+/// Destinations are counted as they were requested by clients, not the addresses they were resolved to. After the
+/// table is full, new destinations are summed up in `DESTINATION_STATS_OTHERS` until it is reset.
+#[derive(Debug)]
+pub struct DestinationStats {
+    table: Mutex<HashMap<String, DestinationBytes>>,
+    max_entries: usize,
+}
+
+impl Default for DestinationStats {
+    fn default() -> DestinationStats {
+        DestinationStats::with_max_entries(DESTINATION_STATS_MAX_ENTRIES)
+    }
+}
+
+impl DestinationStats {
+    /// Create an empty table
+    pub fn new() -> DestinationStats {
+        DestinationStats::default()
+    }
+
+    /// Create an empty table keeping at most `max_entries` destinations
+    pub fn with_max_entries(max_entries: usize) -> DestinationStats {
+        DestinationStats {
+            table: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    /// Account bytes relayed to `addr`
+    pub fn record(&self, addr: &Address, tx: u64, rx: u64) {
+        if tx == 0 && rx == 0 {
+            return;
+        }
+
+        let key = destination_key(addr);
+
+        let mut table = self.table.lock().unwrap();
+        let bytes = if table.contains_key(&key) || table.len() < self.max_entries {
+            table.entry(key).or_default()
+        } else {
+            // The entry of others is kept even if it is over `max_entries`
+            table.entry(DESTINATION_STATS_OTHERS.to_owned()).or_default()
+        };
+        bytes.tx += tx;
+        bytes.rx += rx;
+    }
+
+    /// Copy of the table, sorted by destinations
+    pub fn snapshot(&self) -> BTreeMap<String, DestinationBytes> {
+        let table = self.table.lock().unwrap();
+        table.iter().map(|(k, v)| (k.clone(), *v)).collect()
+    }
+
+    /// Snapshot of the table, and reset it
+    pub fn take(&self) -> BTreeMap<String, DestinationBytes> {
+        let table = std::mem::take(&mut *self.table.lock().unwrap());
+        table.into_iter().collect()
+    }
+}
+
+/// Key of a destination in `DestinationStats`
+///
+/// Domain names are case insensitive and may be fully qualified, and IP addresses may be sent as domain names,
+/// or as IPv4-mapped IPv6 addresses. They are counted as the same destination.
+fn destination_key(addr: &Address) -> String {
+    let (ip, port) = match *addr {
+        Address::SocketAddress(ref sa) => (sa.ip(), sa.port()),
+        Address::DomainNameAddress(ref dname, port) => {
+            let dname = dname.trim_end_matches('.');
+            match dname.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+                Ok(ip) => (ip, port),
+                Err(..) => return format!("{}:{}", dname.to_ascii_lowercase(), port),
+            }
+        }
+    };
+
+    let ip = match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        ip => ip,
+    };
+    SocketAddr::new(ip, port).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn destination_stats_normalized() {
+        let stats = DestinationStats::new();
+        stats.record(&Address::DomainNameAddress("Example.COM.".to_owned(), 443), 10, 20);
+        stats.record(&Address::DomainNameAddress("example.com".to_owned(), 443), 1, 2);
+        stats.record(&Address::DomainNameAddress("example.com".to_owned(), 80), 3, 4);
+        stats.record(&Address::SocketAddress("[::ffff:1.2.3.4]:80".parse().unwrap()), 5, 6);
+        stats.record(&Address::DomainNameAddress("1.2.3.4".to_owned(), 80), 7, 8);
+        stats.record(&Address::DomainNameAddress("[::1]".to_owned(), 80), 9, 10);
+        stats.record(&Address::SocketAddress("[::1]:80".parse().unwrap()), 11, 12);
+        stats.record(&Address::DomainNameAddress("idle.example.com".to_owned(), 443), 0, 0);
+
+        assert_eq!(
+            stats.snapshot().into_iter().collect::<Vec<_>>(),
+            vec![
+                ("1.2.3.4:80".to_owned(), DestinationBytes { tx: 12, rx: 14 }),
+                ("[::1]:80".to_owned(), DestinationBytes { tx: 20, rx: 22 }),
+                ("example.com:443".to_owned(), DestinationBytes { tx: 11, rx: 22 }),
+                ("example.com:80".to_owned(), DestinationBytes { tx: 3, rx: 4 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn destination_stats_capped() {
+        let stats = DestinationStats::with_max_entries(2);
+        stats.record(&Address::DomainNameAddress("a.example.com".to_owned(), 443), 1, 2);
+        stats.record(&Address::DomainNameAddress("b.example.com".to_owned(), 443), 3, 4);
+        stats.record(&Address::DomainNameAddress("c.example.com".to_owned(), 443), 5, 6);
+        stats.record(&Address::DomainNameAddress("d.example.com".to_owned(), 443), 7, 8);
+        stats.record(&Address::DomainNameAddress("a.example.com".to_owned(), 443), 9, 10);
+
+        assert_eq!(
+            stats.take().into_iter().collect::<Vec<_>>(),
+            vec![
+                ("a.example.com:443".to_owned(), DestinationBytes { tx: 10, rx: 12 }),
+                ("b.example.com:443".to_owned(), DestinationBytes { tx: 3, rx: 4 }),
+                (DESTINATION_STATS_OTHERS.to_owned(), DestinationBytes { tx: 12, rx: 14 }),
+            ]
+        );
+
+        // Destinations are kept again after reset
+        stats.record(&Address::DomainNameAddress("c.example.com".to_owned(), 443), 1, 1);
+        assert!(stats.snapshot().contains_key("c.example.com:443"));
+    }
+}
@@ -0,0 +1,451 @@
+//! HTTP server of Prometheus metrics
+
+use std::{convert::Infallible, fmt::Write, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    server::conn::Http,
+    service::service_fn,
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+use log::{debug, error, info, trace};
+use shadowsocks::{config::ServerAddr, lookup_then, net::TcpListener};
+use tokio::time;
+
+use crate::local::{
+    context::ServiceContext,
+    loadbalancing::{PingBalancer, ServerIdent},
+};
+
+use super::LocalMetrics;
+
+/// Buffer of each client, the minimum allowed by hyper
+const MAXIMUM_REQUEST_BUFFER_SIZE: usize = 8192;
+/// Time allowed for each client to send a request and receive the response
+const METRICS_CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// HTTP server exposing metrics in Prometheus' text format on `/metrics`
+///
+/// Bytes relayed to each destination are served in JSON on `/destinations` if they are accounted,
+/// `DELETE /destinations` resets them after responding.
+pub struct MetricsServer {
+    context: Arc<ServiceContext>,
+    balancers: Vec<PingBalancer>,
+}
+
+impl MetricsServer {
+    /// Create a server reporting `context` and servers in `balancer`
+    ///
+    /// Connection counters are reported as 0 if `context` has no metrics set.
+    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer) -> MetricsServer {
+        MetricsServer {
+            context,
+            balancers: vec![balancer],
+        }
+    }
+
+    /// Report servers in `balancer` too, like balancers of local servers pinned to specific servers
+    ///
+    /// Servers are labeled with `balancer`, the order it was added. The one passed to `new` is `0`.
+    pub fn add_balancer(&mut self, balancer: PingBalancer) {
+        self.balancers.push(balancer);
+    }
+
+    /// Run server
+    pub async fn run(self, metrics_addr: &ServerAddr) -> io::Result<()> {
+        let listener = match *metrics_addr {
+            ServerAddr::SocketAddr(ref saddr) => TcpListener::bind_with_opts(saddr, self.context.accept_opts()).await?,
+            ServerAddr::DomainName(ref dname, port) => lookup_then!(self.context.context_ref(), dname, port, |addr| {
+                TcpListener::bind_with_opts(&addr, self.context.accept_opts()).await
+            })
+            .map(|(_, l)| l)?,
+        };
+
+        self.run_with_listener(listener).await
+    }
+
+    /// Run server on a bound listener
+    pub async fn run_with_listener(self, listener: TcpListener) -> io::Result<()> {
+        info!("shadowsocks metrics listening on {}", listener.local_addr()?);
+
+        let mut http = Http::new();
+        http.http1_only(true)
+            .http1_keep_alive(false)
+            .http1_title_case_headers(true)
+            .max_buf_size(MAXIMUM_REQUEST_BUFFER_SIZE);
+
+        let server = Arc::new(self);
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("metrics accept failed with error: {}", err);
+                    continue;
+                }
+            };
+
+            let server = server.clone();
+            let service = service_fn(move |req: Request<Body>| {
+                let response = server.handle_request(&req, peer_addr);
+                async move { Ok::<_, Infallible>(response) }
+            });
+
+            // Clients sending nothing, or too slow, are closed after a while
+            let conn = http.serve_connection(stream, service);
+            tokio::spawn(async move {
+                match time::timeout(METRICS_CLIENT_TIMEOUT, conn).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => debug!("metrics client {} failed with error: {}", peer_addr, err),
+                    Err(..) => debug!("metrics client {} timed out", peer_addr),
+                }
+            });
+        }
+    }
+
+    fn handle_request(&self, req: &Request<Body>, peer_addr: SocketAddr) -> Response<Body> {
+        trace!("metrics client {} {} {}", peer_addr, req.method(), req.uri());
+
+        let destinations = self.context.metrics().and_then(LocalMetrics::destination_stats);
+        let (content_type, body) = match (req.method(), req.uri().path(), destinations) {
+            (&Method::GET, "/metrics", _) => (PROMETHEUS_CONTENT_TYPE, self.render()),
+            // Snapshot of bytes relayed to each destination
+            (&Method::GET, "/destinations", Some(destinations)) => {
+                (JSON_CONTENT_TYPE, json5::to_string(&destinations.snapshot()).unwrap())
+            }
+            // Snapshot, and then reset, for reports of each period
+            (&Method::DELETE, "/destinations", Some(destinations)) => {
+                (JSON_CONTENT_TYPE, json5::to_string(&destinations.take()).unwrap())
+            }
+            _ => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                return response;
+            }
+        };
+
+        let mut response = Response::new(Body::from(body));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        response
+    }
+
+    /// Metrics in Prometheus' text format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        let flow_stat = self.context.flow_stat_ref();
+        let bypassed_flow_stat = self.context.bypassed_flow_stat_ref();
+        output.push_str("# HELP shadowsocks_local_bytes_total Bytes relayed through servers, or bypassed them\n");
+        output.push_str("# TYPE shadowsocks_local_bytes_total counter\n");
+        let _ = writeln!(
+            output,
+            "shadowsocks_local_bytes_total{{direction=\"local_to_remote\"}} {}",
+            flow_stat.tx().wrapping_add(bypassed_flow_stat.tx())
+        );
+        let _ = writeln!(
+            output,
+            "shadowsocks_local_bytes_total{{direction=\"remote_to_local\"}} {}",
+            flow_stat.rx().wrapping_add(bypassed_flow_stat.rx())
+        );
+
+        let (tcp_connections, http_connections, socks_unsupported_version) = match self.context.metrics() {
+            Some(metrics) => (
+                metrics.tcp_connections(),
+                metrics.http_connections(),
+                metrics.socks_unsupported_version(),
+            ),
+            None => (0, 0, 0),
+        };
+        output.push_str("# HELP shadowsocks_local_tcp_connections Active TCP tunnels\n");
+        output.push_str("# TYPE shadowsocks_local_tcp_connections gauge\n");
+        let _ = writeln!(output, "shadowsocks_local_tcp_connections {}", tcp_connections);
+        output.push_str("# HELP shadowsocks_local_http_connections Active client connections of HTTP servers\n");
+        output.push_str("# TYPE shadowsocks_local_http_connections gauge\n");
+        let _ = writeln!(output, "shadowsocks_local_http_connections {}", http_connections);
+        output.push_str(
+            "# HELP shadowsocks_local_socks_unsupported_version_total Connections closed because of an unsupported SOCKS version\n",
+        );
+        output.push_str("# TYPE shadowsocks_local_socks_unsupported_version_total counter\n");
+        let _ = writeln!(
+            output,
+            "shadowsocks_local_socks_unsupported_version_total {}",
+            socks_unsupported_version
+        );
+
+        output.push_str("# HELP shadowsocks_local_server_tcp_connections Open TCP connections to each server\n");
+        output.push_str("# TYPE shadowsocks_local_server_tcp_connections gauge\n");
+        for (balancer_index, balancer) in self.balancers.iter().enumerate() {
+            for (index, server) in balancer.servers().enumerate() {
+                let _ = writeln!(
+                    output,
+                    "shadowsocks_local_server_tcp_connections{{{}}} {}",
+                    server_labels(balancer_index, index, server),
+                    server.tcp_connections()
+                );
+            }
+        }
+
+        output.push_str("# HELP shadowsocks_local_server_bytes_total Bytes relayed through each server\n");
+        output.push_str("# TYPE shadowsocks_local_server_bytes_total counter\n");
+        for (balancer_index, balancer) in self.balancers.iter().enumerate() {
+            for (index, server) in balancer.servers().enumerate() {
+                let labels = server_labels(balancer_index, index, server);
+                let flow_stat = server.flow_stat();
+                let _ = writeln!(
+                    output,
+                    "shadowsocks_local_server_bytes_total{{{},direction=\"local_to_remote\"}} {}",
+                    labels,
+                    flow_stat.tx()
+                );
+                let _ = writeln!(
+                    output,
+                    "shadowsocks_local_server_bytes_total{{{},direction=\"remote_to_local\"}} {}",
+                    labels,
+                    flow_stat.rx()
+                );
+            }
+        }
+
+        output.push_str("# HELP shadowsocks_local_server_egress_ip_info Egress IP address of each server probed\n");
+        output.push_str("# TYPE shadowsocks_local_server_egress_ip_info gauge\n");
+        for (balancer_index, balancer) in self.balancers.iter().enumerate() {
+            for (index, server) in balancer.servers().enumerate() {
+                if let Some(egress_ip) = server.egress_ip() {
+                    let _ = writeln!(
+                        output,
+                        "shadowsocks_local_server_egress_ip_info{{{},egress_ip=\"{}\"}} 1",
+                        server_labels(balancer_index, index, server),
+                        egress_ip
+                    );
+                }
+            }
+        }
+
+        output
+    }
+}
+
+/// Labels identifying a server, servers with the same address are told apart by `index` in the balancer, and `tag`,
+/// the remarks of the server
+fn server_labels(balancer_index: usize, index: usize, server: &ServerIdent) -> String {
+    let svr_cfg = server.server_config();
+    format!(
+        "balancer=\"{}\",index=\"{}\",server=\"{}\",tag=\"{}\"",
+        balancer_index,
+        index,
+        escape_label_value(&svr_cfg.addr().to_string()),
+        escape_label_value(svr_cfg.remarks().unwrap_or_default())
+    )
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::{
+        config::{Mode, ServerConfig},
+        crypto::CipherKind,
+        relay::socks5::Address,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use crate::{local::loadbalancing::PingBalancerBuilder, test_utils::bind_listener};
+
+    use super::*;
+
+    async fn request(metrics_addr: SocketAddr, method: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(metrics_addr).await.unwrap();
+
+        let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        response
+    }
+
+    async fn scrape(metrics_addr: SocketAddr) -> String {
+        request(metrics_addr, "GET", "/metrics").await
+    }
+
+    #[tokio::test]
+    async fn metrics_reports_connections() {
+        let mut context = ServiceContext::new();
+        context.set_metrics(Arc::new(LocalMetrics::new()));
+        let context = Arc::new(context);
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        let mut svr_cfg = ServerConfig::new(
+            SocketAddr::from(([127, 0, 0, 1], 8388)),
+            "password",
+            CipherKind::AES_128_GCM,
+        );
+        svr_cfg.set_remarks("jp");
+        builder.add_server(svr_cfg);
+        let balancer = builder.build().await.unwrap();
+
+        let listener = bind_listener().await;
+        let metrics_addr = listener.local_addr().unwrap();
+        let server = MetricsServer::new(context.clone(), balancer.clone());
+        tokio::spawn(async move { server.run_with_listener(listener).await });
+
+        let server = balancer.best_tcp_server();
+        server.set_egress_ip(Some("1.2.3.4".parse().unwrap()));
+        let tcp_gauge = context.metrics().unwrap().track_tcp_connection();
+        let server_gauge = server.track_tcp_connection();
+        let http_gauge = context.metrics().unwrap().track_http_connection();
+        context.flow_stat_ref().incr_tx(100);
+        context.flow_stat_ref().incr_rx(200);
+        context.bypassed_flow_stat_ref().incr_tx(10);
+        context.bypassed_flow_stat_ref().incr_rx(20);
+
+        let response = scrape(metrics_addr).await;
+        assert!(
+            response.contains("\nshadowsocks_local_tcp_connections 1\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\nshadowsocks_local_http_connections 1\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response
+                .contains("\nshadowsocks_local_server_tcp_connections{balancer=\"0\",index=\"0\",server=\"127.0.0.1:8388\",tag=\"jp\"} 1\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\nshadowsocks_local_bytes_total{direction=\"local_to_remote\"} 110\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\nshadowsocks_local_bytes_total{direction=\"remote_to_local\"} 220\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains(
+                "\nshadowsocks_local_server_egress_ip_info{balancer=\"0\",index=\"0\",server=\"127.0.0.1:8388\",tag=\"jp\",egress_ip=\"1.2.3.4\"} 1\n"
+            ),
+            "{}",
+            response
+        );
+
+        drop(tcp_gauge);
+        drop(server_gauge);
+        drop(http_gauge);
+
+        let response = scrape(metrics_addr).await;
+        assert!(
+            response.contains("\nshadowsocks_local_tcp_connections 0\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\nshadowsocks_local_http_connections 0\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response
+                .contains("\nshadowsocks_local_server_tcp_connections{balancer=\"0\",index=\"0\",server=\"127.0.0.1:8388\",tag=\"jp\"} 0\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_serves_destinations() {
+        let mut context = ServiceContext::new();
+        context.set_metrics(Arc::new(LocalMetrics::with_destination_stats()));
+        let context = Arc::new(context);
+
+        let builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        let balancer = builder.build().await.unwrap();
+
+        let listener = bind_listener().await;
+        let metrics_addr = listener.local_addr().unwrap();
+        let server = MetricsServer::new(context.clone(), balancer);
+        tokio::spawn(async move { server.run_with_listener(listener).await });
+
+        let destinations = context.metrics().unwrap().destination_stats().unwrap();
+        destinations.record(&Address::DomainNameAddress("example.com".to_owned(), 443), 100, 200);
+        destinations.record(&Address::SocketAddress("1.2.3.4:80".parse().unwrap()), 1, 2);
+
+        let expected = r#"{"1.2.3.4:80":{"tx":1,"rx":2},"example.com:443":{"tx":100,"rx":200}}"#;
+        let response = request(metrics_addr, "GET", "/destinations").await;
+        assert!(
+            response.contains("\r\nContent-Type: application/json\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with(expected), "{}", response);
+
+        // Snapshot is kept until it is reset
+        let response = request(metrics_addr, "DELETE", "/destinations").await;
+        assert!(response.ends_with(expected), "{}", response);
+        let response = request(metrics_addr, "GET", "/destinations").await;
+        assert!(response.ends_with("\r\n\r\n{}"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn metrics_servers_with_same_address() {
+        let context = Arc::new(ServiceContext::new());
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        for password in ["password1", "password2"] {
+            builder.add_server(ServerConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], 8388)),
+                password,
+                CipherKind::AES_128_GCM,
+            ));
+        }
+        let balancer = builder.build().await.unwrap();
+
+        let output = MetricsServer::new(context, balancer).render();
+        for index in 0..2 {
+            let series = format!(
+                "\nshadowsocks_local_server_tcp_connections{{balancer=\"0\",index=\"{}\",server=\"127.0.0.1:8388\",tag=\"\"}} 0\n",
+                index
+            );
+            assert!(output.contains(&series), "{}", output);
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_idle_client_closed() {
+        time::pause();
+
+        let listener = bind_listener().await;
+        let metrics_addr = listener.local_addr().unwrap();
+        let builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        let server = MetricsServer::new(Arc::new(ServiceContext::new()), builder.build().await.unwrap());
+        tokio::spawn(async move { server.run_with_listener(listener).await });
+
+        // Client sending an incomplete request head
+        let mut stream = TcpStream::connect(metrics_addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n").await.unwrap();
+
+        let mut buffer = Vec::new();
+        let n = time::timeout(METRICS_CLIENT_TIMEOUT * 2, stream.read_to_end(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+}
@@ -6,29 +6,40 @@ use std::{
     future::Future,
     io::{self, ErrorKind},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
     time::Duration,
 };
 
 use futures::{future, ready};
-use log::trace;
+use log::{debug, error, trace, warn};
 use shadowsocks::{
-    config::Mode,
+    config::{Mode, ServerAddr, ServerConfig},
     net::{AcceptOpts, ConnectOpts},
 };
-use tokio::task::JoinHandle;
+use tokio::{task::JoinHandle, time};
 
 #[cfg(feature = "local-flow-stat")]
 use crate::net::FlowStat;
 use crate::{
-    config::{Config, ConfigType, ProtocolType},
+    config::{BalancerConfig, Config, ConfigType, ProtocolType},
     dns::build_dns_resolver,
 };
 
 use self::{
     context::ServiceContext,
-    loadbalancing::{PingBalancer, PingBalancerBuilder},
+    loadbalancing::{
+        server_data::DEFAULT_CONNECT_TIMEOUT_FLOOR,
+        AdaptiveConnectTimeout,
+        EgressIpProbe,
+        PingBalancer,
+        PingBalancerBuilder,
+    },
+    metrics::LocalMetrics,
+    net::AllowedClients,
 };
 
 pub mod context;
@@ -37,10 +48,13 @@ pub mod dns;
 #[cfg(feature = "local-http")]
 pub mod http;
 pub mod loadbalancing;
+pub mod metrics;
 pub mod net;
 #[cfg(feature = "local-redir")]
 pub mod redir;
+pub mod relay_error;
 pub mod socks;
+pub mod trace_span;
 #[cfg(feature = "local-tun")]
 pub mod tun;
 #[cfg(feature = "local-tunnel")]
@@ -52,6 +66,12 @@ pub mod utils;
 /// This is borrowed from Go's `net` library's default setting
 pub(crate) const LOCAL_DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(15);
 
+/// Default time waiting for active connections when shutting down
+pub const LOCAL_DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Maximum time waiting for servers' hostnames resolved ahead on startup
+const DNS_WARM_UP_TIMEOUT: Duration = Duration::from_secs(5);
+
 struct ServerHandle(JoinHandle<io::Result<()>>);
 
 impl Drop for ServerHandle {
@@ -73,10 +93,72 @@ impl Future for ServerHandle {
     }
 }
 
+/// Spawn a local server's listener
+///
+/// Failure of a listener is logged and the others keep running, unless it was the last one running.
+fn spawn_listener<F>(listeners: &Arc<AtomicUsize>, name: String, fut: F) -> ServerHandle
+where
+    F: Future<Output = io::Result<()>> + Send + 'static,
+{
+    listeners.fetch_add(1, Ordering::AcqRel);
+    let listeners = listeners.clone();
+
+    ServerHandle(tokio::spawn(async move {
+        let err = match fut.await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+
+        if listeners.fetch_sub(1, Ordering::AcqRel) == 1 {
+            return Err(err);
+        }
+
+        error!("{} exited with error: {}, other local servers keep running", name, err);
+        future::pending().await
+    }))
+}
+
+/// Balancer of a local server pinned to servers with specific remarks
+#[derive(Clone)]
+pub struct PinnedBalancer {
+    remarks: Vec<String>,
+    balancer: PingBalancer,
+}
+
+impl PinnedBalancer {
+    /// Remarks of servers that the balancer is pinned to
+    pub fn remarks(&self) -> &[String] {
+        &self.remarks
+    }
+
+    /// Get the balancer
+    pub fn balancer(&self) -> &PingBalancer {
+        &self.balancer
+    }
+
+    /// Reset servers of the balancer with those in `servers` it is pinned to
+    pub async fn reset_servers(&self, servers: &[ServerConfig]) -> io::Result<()> {
+        self.balancer
+            .reset_servers(pinned_servers(servers, &self.remarks))
+            .await
+    }
+}
+
+/// Servers in `servers` with any of `remarks`
+fn pinned_servers(servers: &[ServerConfig], remarks: &[String]) -> Vec<ServerConfig> {
+    servers
+        .iter()
+        .filter(|svr| remarks.iter().any(|r| svr.remarks() == Some(r.as_str())))
+        .cloned()
+        .collect()
+}
+
 /// Local Server instance
 pub struct Server {
     vfut: Vec<ServerHandle>,
     balancer: PingBalancer,
+    pinned_balancers: Vec<PinnedBalancer>,
+    context: Arc<ServiceContext>,
 }
 
 impl Server {
@@ -97,16 +179,36 @@ impl Server {
         res
     }
 
+    /// Refuse new requests, and wait until active connections are finished
+    ///
+    /// Servers keep running while draining, so handshakes are answered and UDP associations are still served. They
+    /// are closed after all connections are finished, or `grace_period` elapsed.
+    pub async fn shutdown(self, grace_period: Duration) {
+        self.context.drain(grace_period).await;
+    }
+
     /// Get the internal server balancer
     pub fn server_balancer(&self) -> &PingBalancer {
         &self.balancer
     }
+
+    /// Get balancers of local servers pinned to specific servers
+    pub fn pinned_balancers(&self) -> &[PinnedBalancer] {
+        &self.pinned_balancers
+    }
+
+    /// Get the shared service context
+    pub fn server_context(&self) -> &Arc<ServiceContext> {
+        &self.context
+    }
 }
 
 /// Starts a shadowsocks local server
-pub async fn create(config: Config) -> io::Result<Server> {
+pub async fn create(mut config: Config) -> io::Result<Server> {
     assert!(config.config_type == ConfigType::Local && !config.local.is_empty());
 
+    config.apply_default_timeout();
+
     trace!("{:?}", config);
 
     // Warning for Stream Ciphers
@@ -145,10 +247,16 @@ pub async fn create(config: Config) -> io::Result<Server> {
     connect_opts.tcp.nodelay = config.no_delay;
     connect_opts.tcp.fastopen = config.fast_open;
     connect_opts.tcp.keepalive = config.keep_alive.or(Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT));
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        connect_opts.tcp.congestion = config.tcp_congestion.clone();
+        connect_opts.tcp.user_timeout = config.tcp_user_timeout;
+    }
     context.set_connect_opts(connect_opts);
 
     let mut accept_opts = AcceptOpts {
         ipv6_only: config.ipv6_only,
+        backlog: config.listen_backlog,
         ..Default::default()
     };
     accept_opts.tcp.send_buffer_size = config.inbound_send_buffer_size;
@@ -156,8 +264,57 @@ pub async fn create(config: Config) -> io::Result<Server> {
     accept_opts.tcp.nodelay = config.no_delay;
     accept_opts.tcp.fastopen = config.fast_open;
     accept_opts.tcp.keepalive = config.keep_alive.or(Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT));
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        accept_opts.tcp.congestion = config.tcp_congestion.clone();
+        accept_opts.tcp.user_timeout = config.tcp_user_timeout;
+    }
     context.set_accept_opts(accept_opts);
 
+    context.set_outbound_proxy_protocol(config.outbound_proxy_protocol);
+
+    if let Some(max_conns_per_host) = config.max_conns_per_host {
+        context.set_max_conns_per_host(max_conns_per_host);
+    }
+    if let Some(bytes_per_sec) = config.rate_limit_bps {
+        context.set_rate_limit(bytes_per_sec);
+    }
+    if let Some(bytes_per_sec) = config.global_rate_limit_bps {
+        context.set_global_rate_limit(bytes_per_sec);
+    }
+    if let Some(pool) = config.worker_pool {
+        context.set_worker_pool(pool);
+    }
+    if let Some(udp_max_datagram) = config.udp_max_datagram {
+        context.set_udp_max_datagram_size(udp_max_datagram);
+    }
+    context.set_udp_oversized_datagram_policy(config.udp_oversized_datagram);
+    if let Some(connect_retries) = config.connect_retries {
+        context.set_connect_retries(connect_retries);
+    }
+    if config.connect_read_ahead {
+        context.set_connect_read_ahead(config.connect_read_ahead);
+    }
+    if let Some(threshold) = config.slow_connection_threshold {
+        context.set_slow_connection_threshold(threshold);
+    }
+    if let Some(timeout) = config.idle_timeout {
+        context.set_idle_timeout(timeout);
+    }
+    if let Some(rtt_factor) = config.balancer.connect_timeout_rtt_factor {
+        context.set_adaptive_connect_timeout(AdaptiveConnectTimeout {
+            rtt_factor,
+            floor: config
+                .balancer
+                .connect_timeout_floor
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT_FLOOR),
+        });
+    }
+    context.set_server_failure_policy(config.balancer.server_failure_policy);
+    if let Some(cooldown) = config.balancer.server_down_cooldown {
+        context.set_server_down_cooldown(cooldown);
+    }
+
     if let Some(resolver) = build_dns_resolver(config.dns, config.ipv6_first, context.connect_opts_ref()).await {
         context.set_dns_resolver(Arc::new(resolver));
     }
@@ -166,18 +323,51 @@ pub async fn create(config: Config) -> io::Result<Server> {
         context.set_ipv6_first(config.ipv6_first);
     }
 
+    if let Some(max) = config.max_pending_dns_resolutions {
+        context.set_max_pending_dns_resolutions(max);
+    }
+
+    context.set_direct_connect_strategy(config.direct_connect_strategy);
+
     if let Some(acl) = config.acl {
         context.set_acl(acl);
     }
 
     context.set_security_config(&config.security);
 
+    if config.metrics_addr.is_some() {
+        let metrics = if config.destination_stats {
+            LocalMetrics::with_destination_stats()
+        } else {
+            LocalMetrics::new()
+        };
+        context.set_metrics(Arc::new(metrics));
+    }
+
     assert!(!config.local.is_empty(), "no valid local server configuration");
 
+    // Resolve servers' hostnames before accepting any connections
+    warm_up_dns_cache(&context, &config.server, DNS_WARM_UP_TIMEOUT).await;
+
     let context = Arc::new(context);
 
     let mut vfut = Vec::new();
 
+    // Local servers pinned to a subset of servers have their own balancers
+    let mut pinned_balancers = Vec::with_capacity(config.local.len());
+    for local in &config.local {
+        let pinned_balancer = if local.servers.is_empty() {
+            None
+        } else {
+            let servers = pinned_servers(&config.server, &local.servers);
+            Some(PinnedBalancer {
+                remarks: local.servers.clone(),
+                balancer: create_balancer(&context, &config.balancer, local.mode, servers).await?,
+            })
+        };
+        pinned_balancers.push(pinned_balancer);
+    }
+
     // Create a service balancer for choosing between multiple servers
     let balancer = {
         let mut mode = Mode::TcpOnly;
@@ -186,26 +376,7 @@ pub async fn create(config: Config) -> io::Result<Server> {
             mode = mode.merge(local.mode);
         }
 
-        let mut balancer_builder = PingBalancerBuilder::new(context.clone(), mode);
-
-        // max_server_rtt have to be set before add_server
-        if let Some(rtt) = config.balancer.max_server_rtt {
-            balancer_builder.max_server_rtt(rtt);
-        }
-
-        if let Some(intv) = config.balancer.check_interval {
-            balancer_builder.check_interval(intv);
-        }
-
-        if let Some(intv) = config.balancer.check_best_interval {
-            balancer_builder.check_best_interval(intv);
-        }
-
-        for server in config.server {
-            balancer_builder.add_server(server);
-        }
-
-        balancer_builder.build().await?
+        create_balancer(&context, &config.balancer, mode, config.server).await?
     };
 
     #[cfg(feature = "local-flow-stat")]
@@ -216,8 +387,25 @@ pub async fn create(config: Config) -> io::Result<Server> {
         vfut.push(ServerHandle(tokio::spawn(report_fut)));
     }
 
-    for local_config in config.local {
-        let balancer = balancer.clone();
+    #[cfg(feature = "local-metrics")]
+    if let Some(metrics_addr) = config.metrics_addr {
+        use self::metrics::MetricsServer;
+
+        let mut server = MetricsServer::new(context.clone(), balancer.clone());
+        for pinned_balancer in pinned_balancers.iter().flatten() {
+            server.add_balancer(pinned_balancer.balancer.clone());
+        }
+        vfut.push(ServerHandle(tokio::spawn(
+            async move { server.run(&metrics_addr).await },
+        )));
+    }
+
+    let listeners = Arc::new(AtomicUsize::new(0));
+    for (local_config, pinned_balancer) in config.local.into_iter().zip(pinned_balancers.iter()) {
+        let balancer = match pinned_balancer {
+            Some(pinned_balancer) => pinned_balancer.balancer.clone(),
+            None => balancer.clone(),
+        };
 
         match local_config.protocol {
             ProtocolType::Socks => {
@@ -231,6 +419,19 @@ pub async fn create(config: Config) -> io::Result<Server> {
                 let mut server = Socks::with_context(context.clone());
                 server.set_mode(local_config.mode);
                 server.set_socks5_auth(local_config.socks5_auth);
+                if let Some(timeout) = local_config.socks5_request_timeout {
+                    server.set_socks5_request_timeout(timeout);
+                }
+                if local_config.socks5_lazy_connect {
+                    server.set_socks5_lazy_connect(true);
+                }
+                #[cfg(feature = "local-socks5-bind")]
+                if let Some(timeout) = local_config.socks5_bind_timeout {
+                    server.set_socks5_bind_timeout(timeout);
+                }
+                if !local_config.allowed_clients.is_empty() {
+                    server.set_allowed_clients(AllowedClients::new(local_config.allowed_clients));
+                }
 
                 if let Some(c) = config.udp_max_associations {
                     server.set_udp_capacity(c);
@@ -242,9 +443,10 @@ pub async fn create(config: Config) -> io::Result<Server> {
                     server.set_udp_bind_addr(b.clone());
                 }
 
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                let name = format!("socks server {}", client_addr);
+                vfut.push(spawn_listener(&listeners, name, async move {
                     server.run(&client_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-tunnel")]
             ProtocolType::Tunnel => {
@@ -268,9 +470,10 @@ pub async fn create(config: Config) -> io::Result<Server> {
                 server.set_mode(local_config.mode);
 
                 let udp_addr = local_config.udp_addr.unwrap_or_else(|| client_addr.clone());
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                let name = format!("tunnel server {}", client_addr);
+                vfut.push(spawn_listener(&listeners, name, async move {
                     server.run(&client_addr, &udp_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-http")]
             ProtocolType::Http => {
@@ -281,10 +484,38 @@ pub async fn create(config: Config) -> io::Result<Server> {
                     None => return Err(io::Error::new(ErrorKind::Other, "http requires local address")),
                 };
 
-                let server = Http::with_context(context.clone());
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                let mut server = Http::with_context(context.clone());
+
+                if !local_config.http_forward_headers.is_empty() {
+                    use hyper::{
+                        header::{HeaderName, HeaderValue},
+                        HeaderMap,
+                    };
+
+                    let mut forward_headers = HeaderMap::new();
+                    for (name, value) in local_config.http_forward_headers {
+                        let name = HeaderName::from_bytes(name.as_bytes())
+                            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+                        let value = HeaderValue::from_str(&value)
+                            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+                        forward_headers.append(name, value);
+                    }
+                    server.set_forward_headers(forward_headers);
+                }
+                if let Some(max_request_body) = local_config.http_max_request_body {
+                    server.set_max_request_body(max_request_body);
+                }
+                if let Some(body_start_timeout) = local_config.http_body_start_timeout {
+                    server.set_body_start_timeout(body_start_timeout);
+                }
+                server.set_auth(local_config.http_auth);
+                if !local_config.allowed_clients.is_empty() {
+                    server.set_allowed_clients(AllowedClients::new(local_config.allowed_clients));
+                }
+                let name = format!("http server {}", client_addr);
+                vfut.push(spawn_listener(&listeners, name, async move {
                     server.run(&client_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-redir")]
             ProtocolType::Redir => {
@@ -307,9 +538,10 @@ pub async fn create(config: Config) -> io::Result<Server> {
                 server.set_udp_redir(local_config.udp_redir);
 
                 let udp_addr = local_config.udp_addr.unwrap_or_else(|| client_addr.clone());
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                let name = format!("redir server {}", client_addr);
+                vfut.push(spawn_listener(&listeners, name, async move {
                     server.run(&client_addr, &udp_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-dns")]
             ProtocolType::Dns => {
@@ -327,10 +559,12 @@ pub async fn create(config: Config) -> io::Result<Server> {
                     Dns::with_context(context.clone(), local_addr.clone(), remote_addr.clone())
                 };
                 server.set_mode(local_config.mode);
+                server.set_sinkhole_hosts(&local_config.dns_sinkhole_hosts)?;
 
-                vfut.push(ServerHandle(tokio::spawn(async move {
+                let name = format!("dns server {}", client_addr);
+                vfut.push(spawn_listener(&listeners, name, async move {
                     server.run(&client_addr, balancer).await
-                })));
+                }));
             }
             #[cfg(feature = "local-tun")]
             ProtocolType::Tun => {
@@ -405,20 +639,95 @@ pub async fn create(config: Config) -> io::Result<Server> {
                     }
                 }
                 let server = builder.build().await?;
-                vfut.push(ServerHandle(tokio::spawn(async move { server.run().await })));
+                vfut.push(spawn_listener(&listeners, "tun server".to_owned(), async move {
+                    server.run().await
+                }));
             }
         }
     }
 
-    Ok(Server { vfut, balancer })
+    Ok(Server {
+        vfut,
+        balancer,
+        pinned_balancers: pinned_balancers.into_iter().flatten().collect(),
+        context,
+    })
+}
+
+/// Create a balancer choosing between `servers`
+async fn create_balancer(
+    context: &Arc<ServiceContext>,
+    config: &BalancerConfig,
+    mode: Mode,
+    servers: Vec<ServerConfig>,
+) -> io::Result<PingBalancer> {
+    let mut balancer_builder = PingBalancerBuilder::new(context.clone(), mode);
+    balancer_builder.strategy(config.strategy);
+
+    // max_server_rtt have to be set before add_server
+    if let Some(rtt) = config.max_server_rtt {
+        balancer_builder.max_server_rtt(rtt);
+    }
+
+    if let Some(intv) = config.check_interval {
+        balancer_builder.check_interval(intv);
+    }
+
+    if let Some(intv) = config.check_best_interval {
+        balancer_builder.check_best_interval(intv);
+    }
+
+    if let Some(grace) = config.reload_grace {
+        balancer_builder.reload_grace(grace);
+    }
+
+    if let Some(ref addr) = config.egress_ip_probe {
+        let mut probe = EgressIpProbe::new(addr.into(), config.egress_ip_probe_interval);
+        if let Some(max_redirects) = config.egress_ip_probe_max_redirects {
+            probe.set_max_redirects(max_redirects);
+        }
+        balancer_builder.egress_ip_probe(probe);
+    }
+
+    for server in servers {
+        balancer_builder.add_server(server);
+    }
+
+    balancer_builder.build().await
+}
+
+/// Resolve hostnames of `servers` ahead, so that DNS resolvers with cache won't have to resolve them on the first connections
+///
+/// Failures are logged and will be resolved again when connecting. Startup won't wait for more than `timeout`.
+async fn warm_up_dns_cache(context: &ServiceContext, servers: &[ServerConfig], timeout: Duration) {
+    let resolve_futs = servers.iter().filter_map(|svr_cfg| match *svr_cfg.external_addr() {
+        ServerAddr::SocketAddr(..) => None,
+        ServerAddr::DomainName(ref dname, port) => Some(async move {
+            match context.context_ref().dns_resolve(dname, port).await {
+                Ok(addrs) => {
+                    let addrs = addrs.collect::<Vec<_>>();
+                    debug!("warmed up DNS cache for server {}:{} => {:?}", dname, port, addrs);
+                }
+                Err(err) => {
+                    warn!("failed to resolve server {}:{} ahead, error: {}", dname, port, err);
+                }
+            }
+        }),
+    });
+
+    if time::timeout(timeout, future::join_all(resolve_futs)).await.is_err() {
+        warn!(
+            "resolving servers ahead didn't finish in {:?}, continue starting",
+            timeout
+        );
+    }
 }
 
 #[cfg(feature = "local-flow-stat")]
 async fn flow_report_task(stat_path: PathBuf, flow_stat: Arc<FlowStat>) -> io::Result<()> {
     use std::slice;
 
-    use log::debug;
-    use tokio::{io::AsyncWriteExt, net::UnixStream, time};
+    use tokio::{io::AsyncWriteExt, net::UnixStream};
 
     // Android's flow statistic report RPC
     let timeout = Duration::from_secs(1);
@@ -460,3 +769,170 @@ async fn flow_report_task(stat_path: PathBuf, flow_stat: Arc<FlowStat>) -> io::R
 pub async fn run(config: Config) -> io::Result<()> {
     create(config).await?.wait_until_exit().await
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io::ErrorKind,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use async_trait::async_trait;
+    use shadowsocks::{
+        crypto::CipherKind,
+        dns_resolver::{DnsResolve, DnsResolver},
+    };
+    use tokio::net::TcpStream;
+
+    use crate::{config::LocalConfig, test_utils::bind_listener};
+
+    use super::*;
+
+    // Resolver caching every resolved names, resolves everything except `*.invalid` to 127.0.0.1, never finishes
+    // resolving `*.hang`
+    #[derive(Clone, Default)]
+    struct CachingResolver {
+        cache: Arc<Mutex<HashMap<String, Vec<SocketAddr>>>>,
+    }
+
+    #[async_trait]
+    impl DnsResolve for CachingResolver {
+        async fn resolve(&self, addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            if addr.ends_with(".invalid") {
+                return Err(io::Error::new(ErrorKind::NotFound, "unknown host"));
+            }
+            if addr.ends_with(".hang") {
+                future::pending::<()>().await;
+            }
+
+            let addrs = vec![SocketAddr::from(([127, 0, 0, 1], port))];
+            self.cache.lock().unwrap().insert(addr.to_owned(), addrs.clone());
+            Ok(addrs)
+        }
+    }
+
+    #[tokio::test]
+    async fn warm_up_servers_dns_cache() {
+        let resolver = CachingResolver::default();
+
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(resolver.clone())));
+
+        let servers = [
+            ServerConfig::new(("a.example.com", 8388), "password", CipherKind::AES_128_GCM),
+            ServerConfig::new(("b.example.com", 8389), "password", CipherKind::AES_128_GCM),
+            ServerConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], 8390)),
+                "password",
+                CipherKind::AES_128_GCM,
+            ),
+            // Failures won't stop the others
+            ServerConfig::new(("c.invalid", 8391), "password", CipherKind::AES_128_GCM),
+        ];
+
+        warm_up_dns_cache(&context, &servers, DNS_WARM_UP_TIMEOUT).await;
+
+        let cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache["a.example.com"], [SocketAddr::from(([127, 0, 0, 1], 8388))]);
+        assert_eq!(cache["b.example.com"], [SocketAddr::from(([127, 0, 0, 1], 8389))]);
+    }
+
+    #[tokio::test]
+    async fn warm_up_dns_cache_timeout() {
+        let resolver = CachingResolver::default();
+
+        let mut context = ServiceContext::new();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(resolver.clone())));
+
+        let servers = [
+            ServerConfig::new(("a.example.com", 8388), "password", CipherKind::AES_128_GCM),
+            ServerConfig::new(("b.hang", 8389), "password", CipherKind::AES_128_GCM),
+        ];
+
+        // Startup continues while resolving `b.hang`
+        time::timeout(
+            Duration::from_secs(5),
+            warm_up_dns_cache(&context, &servers, Duration::from_millis(100)),
+        )
+        .await
+        .unwrap();
+
+        let cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache["a.example.com"], [SocketAddr::from(([127, 0, 0, 1], 8388))]);
+    }
+
+    fn socks_config(local_addrs: &[SocketAddr]) -> Config {
+        let mut config = Config::new(ConfigType::Local);
+        config.local = local_addrs
+            .iter()
+            .map(|addr| LocalConfig::new_with_addr(ServerAddr::from(*addr), ProtocolType::Socks))
+            .collect();
+        config.server = vec![ServerConfig::new(
+            ("127.0.0.1", 8388),
+            "password",
+            CipherKind::AES_128_GCM,
+        )];
+        config
+    }
+
+    #[tokio::test]
+    async fn listener_failure_keeps_others_running() {
+        let occupied = bind_listener().await;
+        let free_addr = bind_listener().await.local_addr().unwrap();
+
+        let config = socks_config(&[occupied.local_addr().unwrap(), free_addr]);
+        let server = Server::create(config).await.unwrap();
+
+        // The other listener is still accepting connections
+        let exit = server.wait_until_exit();
+        tokio::pin!(exit);
+        assert!(time::timeout(Duration::from_millis(200), &mut exit).await.is_err());
+        TcpStream::connect(free_addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn listener_failures_exit() {
+        let occupied = bind_listener().await;
+
+        let config = socks_config(&[occupied.local_addr().unwrap()]);
+        let server = Server::create(config).await.unwrap();
+
+        let result = time::timeout(Duration::from_secs(5), server.wait_until_exit())
+            .await
+            .unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::AddrInUse);
+    }
+
+    #[tokio::test]
+    async fn pinned_balancer_reset_servers() {
+        let free_addr = bind_listener().await.local_addr().unwrap();
+
+        let server_config = |port: u16, remarks: &str| {
+            let mut svr_cfg = ServerConfig::new(("127.0.0.1", port), "password", CipherKind::AES_128_GCM);
+            svr_cfg.set_remarks(remarks);
+            svr_cfg
+        };
+
+        let mut config = socks_config(&[free_addr]);
+        config.local[0].servers = vec!["a".to_owned()];
+        config.server = vec![server_config(8388, "a"), server_config(8389, "b")];
+        let server = Server::create(config).await.unwrap();
+
+        let pinned_balancer = &server.pinned_balancers()[0];
+        pinned_balancer
+            .reset_servers(&[server_config(8390, "a"), server_config(8391, "b")])
+            .await
+            .unwrap();
+
+        let ports = pinned_balancer
+            .balancer()
+            .servers()
+            .map(|server| server.server_config().addr().port())
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [8390]);
+    }
+}
@@ -0,0 +1,43 @@
+//! Clients allowed to connect to a local server
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Networks of clients allowed to connect to a local server
+///
+/// All clients are allowed if it is empty.
+#[derive(Debug, Clone, Default)]
+pub struct AllowedClients {
+    networks: Vec<IpNet>,
+}
+
+impl AllowedClients {
+    /// Create with networks of allowed clients
+    pub fn new(networks: Vec<IpNet>) -> AllowedClients {
+        AllowedClients { networks }
+    }
+
+    /// Check if no network is configured, which allows all clients
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    /// Check if client `addr` is allowed
+    pub fn check_allowed(&self, addr: &IpAddr) -> bool {
+        if self.networks.is_empty() {
+            return true;
+        }
+
+        // IPv4 clients of dual-stack listeners are accepted with IPv4-mapped IPv6 addresses
+        let addr = match *addr {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => *addr,
+            },
+            IpAddr::V4(..) => *addr,
+        };
+
+        self.networks.iter().any(|n| n.contains(&addr))
+    }
+}
@@ -0,0 +1,113 @@
+//! Limiter of concurrent connections per destination host
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use shadowsocks::relay::socks5::Address;
+
+/// Limits the number of simultaneous connections to each destination host
+#[derive(Debug)]
+pub struct HostConnectionLimiter {
+    max_conns_per_host: usize,
+    conns: Mutex<HashMap<String, usize>>,
+}
+
+impl HostConnectionLimiter {
+    /// Create a limiter allowing at most `max_conns_per_host` connections to a host
+    pub fn new(max_conns_per_host: usize) -> HostConnectionLimiter {
+        HostConnectionLimiter {
+            max_conns_per_host,
+            conns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Maximum connections allowed to a host
+    pub fn max_conns_per_host(&self) -> usize {
+        self.max_conns_per_host
+    }
+
+    /// Try to acquire a connection slot to host of `addr`
+    ///
+    /// Returns `None` if there are already `max_conns_per_host` connections to that host.
+    /// The slot will be released after the returned guard is dropped.
+    pub fn acquire(self: &Arc<Self>, addr: &Address) -> Option<HostConnectionGuard> {
+        let host = match *addr {
+            Address::SocketAddress(ref sa) => sa.ip().to_string(),
+            Address::DomainNameAddress(ref dname, ..) => dname.to_ascii_lowercase(),
+        };
+
+        let mut conns = self.conns.lock().unwrap();
+        let count = conns.get(&host).copied().unwrap_or(0);
+        if count >= self.max_conns_per_host {
+            return None;
+        }
+        conns.insert(host.clone(), count + 1);
+
+        Some(HostConnectionGuard {
+            limiter: self.clone(),
+            host,
+        })
+    }
+
+    /// Number of hosts that currently have connections
+    pub fn host_count(&self) -> usize {
+        self.conns.lock().unwrap().len()
+    }
+
+    fn release(&self, host: &str) {
+        let mut conns = self.conns.lock().unwrap();
+        if let Some(count) = conns.get_mut(host) {
+            *count -= 1;
+            if *count == 0 {
+                conns.remove(host);
+            }
+        }
+    }
+}
+
+/// A connection slot acquired from `HostConnectionLimiter`, released when dropped
+#[derive(Debug)]
+pub struct HostConnectionGuard {
+    limiter: Arc<HostConnectionLimiter>,
+    host: String,
+}
+
+impl Drop for HostConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.release(&self.host);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limit_conns_per_host() {
+        let limiter = Arc::new(HostConnectionLimiter::new(2));
+
+        let example = Address::DomainNameAddress("www.example.com".to_owned(), 443);
+        let other = Address::DomainNameAddress("www.example.org".to_owned(), 443);
+
+        let g1 = limiter.acquire(&example).expect("first connection");
+        let g2 = limiter.acquire(&example).expect("second connection");
+        assert!(
+            limiter.acquire(&example).is_none(),
+            "third connection should be throttled"
+        );
+
+        // Other hosts are unaffected
+        let g3 = limiter.acquire(&other).expect("other host");
+
+        // Slot is available again after one of the connections is closed
+        drop(g1);
+        let g4 = limiter.acquire(&example).expect("connection after release");
+
+        drop(g2);
+        drop(g3);
+        drop(g4);
+        assert_eq!(limiter.host_count(), 0);
+    }
+}
@@ -1,9 +1,15 @@
 //! Shadowsocks Local Network Utilities
 
 pub use self::{
+    allowed_clients::AllowedClients,
+    host_limiter::{HostConnectionGuard, HostConnectionLimiter},
+    rate_limiter::{acquire_datagram, RateLimitedStream, RateLimiter},
     tcp::{auto_proxy_io::AutoProxyIo, auto_proxy_stream::AutoProxyClientStream},
     udp::{UdpAssociationManager, UdpInboundWrite},
 };
 
+mod allowed_clients;
+mod host_limiter;
+mod rate_limiter;
 mod tcp;
 pub(crate) mod udp;
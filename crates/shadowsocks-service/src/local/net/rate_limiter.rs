@@ -0,0 +1,323 @@
+//! Bandwidth limiter of TCP tunnels and UDP associations
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::ready;
+use pin_project::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant, Sleep},
+};
+
+/// Maximum bytes transferred at once after waiting for the bucket
+const MAX_QUANTUM: u64 = 16 * 1024;
+
+/// Token bucket limiting bytes transferred per second
+///
+/// The bucket holds at most 1 second of bytes, so idle connections could burst for a while. Transfers reserve a
+/// quantum of bytes before they start, and the bucket goes into debt if it is empty. Reservations are then served in
+/// order, which keeps connections sharing the same bucket taking turns.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    quantum: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `bytes_per_sec` bytes per second
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        assert!(bytes_per_sec > 0, "bytes_per_sec must be > 0");
+
+        RateLimiter {
+            bytes_per_sec,
+            quantum: (bytes_per_sec / 16).clamp(1, MAX_QUANTUM),
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Bytes allowed per second
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Reserve a quantum of bytes, returns the reserved bytes and the duration to wait before using them
+    fn reserve(&self) -> (u64, Duration) {
+        (self.quantum, self.take(self.quantum))
+    }
+
+    /// Take `n` bytes from the bucket, returns the duration to wait before using them
+    fn take(&self, n: u64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.bytes_per_sec as f64).min(self.bytes_per_sec as f64);
+        bucket.updated_at = now;
+
+        bucket.tokens -= n as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec as f64)
+        }
+    }
+
+    /// Give back bytes reserved but never used
+    fn release(&self, n: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens = (bucket.tokens + n as f64).min(self.bytes_per_sec as f64);
+    }
+}
+
+/// Wait until a datagram of `n` bytes is allowed by all of `limiters`
+///
+/// Datagrams can't be split, the buckets go into debt for the whole datagram.
+pub async fn acquire_datagram(limiters: &[Arc<RateLimiter>], n: usize) {
+    let wait = limiters
+        .iter()
+        .map(|limiter| limiter.take(n as u64))
+        .max()
+        .unwrap_or(Duration::ZERO);
+    if !wait.is_zero() {
+        time::sleep(wait).await;
+    }
+}
+
+/// Bytes reserved from `RateLimiter`s for one direction of a stream
+struct Reservation {
+    limiters: Vec<Arc<RateLimiter>>,
+    reserved: Vec<u64>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Reservation {
+    fn new(limiters: Vec<Arc<RateLimiter>>) -> Reservation {
+        let reserved = vec![0; limiters.len()];
+        Reservation {
+            limiters,
+            reserved,
+            sleep: None,
+        }
+    }
+
+    /// Wait until bytes are reserved from all limiters, and returns the least of them
+    fn poll_available(&mut self, cx: &mut Context<'_>) -> Poll<usize> {
+        if self.sleep.is_none() {
+            let mut wait = Duration::ZERO;
+            for (limiter, reserved) in self.limiters.iter().zip(self.reserved.iter_mut()) {
+                if *reserved == 0 {
+                    let (n, w) = limiter.reserve();
+                    *reserved = n;
+                    wait = wait.max(w);
+                }
+            }
+
+            if !wait.is_zero() {
+                self.sleep = Some(Box::pin(time::sleep(wait)));
+            }
+        }
+
+        if let Some(ref mut sleep) = self.sleep {
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+        }
+
+        let available = self.reserved.iter().copied().min().unwrap_or(0);
+        Poll::Ready(available as usize)
+    }
+
+    /// Use `n` bytes of the reservation
+    fn consume(&mut self, n: usize) {
+        for reserved in self.reserved.iter_mut() {
+            *reserved -= n as u64;
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        for (limiter, reserved) in self.limiters.iter().zip(self.reserved.iter()) {
+            if *reserved > 0 {
+                limiter.release(*reserved);
+            }
+        }
+    }
+}
+
+/// Stream with reads and writes limited by `RateLimiter`s
+#[pin_project]
+pub struct RateLimitedStream<S> {
+    #[pin]
+    stream: S,
+    limited: bool,
+    read: Reservation,
+    write: Reservation,
+}
+
+impl<S> RateLimitedStream<S> {
+    /// Limit `stream` by all of `limiters`, it is not limited if `limiters` is empty
+    pub fn new(stream: S, limiters: Vec<Arc<RateLimiter>>) -> RateLimitedStream<S> {
+        RateLimitedStream {
+            stream,
+            limited: !limiters.is_empty(),
+            read: Reservation::new(limiters.clone()),
+            write: Reservation::new(limiters),
+        }
+    }
+
+    /// Get a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> AsyncRead for RateLimitedStream<S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+
+        if !*this.limited {
+            return this.stream.poll_read(cx, buf);
+        }
+
+        let available = ready!(this.read.poll_available(cx));
+
+        let mut limited = buf.take(available);
+        ready!(this.stream.poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+
+        // Bytes were read into the unfilled part of `buf` through `limited`
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+
+        this.read.consume(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for RateLimitedStream<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+
+        if !*this.limited {
+            return this.stream.poll_write(cx, buf);
+        }
+
+        let available = ready!(this.write.poll_available(cx));
+
+        let n = ready!(this.stream.poll_write(cx, &buf[..buf.len().min(available)]))?;
+        this.write.consume(n);
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limiter_shared_fairly() {
+        const BYTES_PER_SEC: u64 = 100_000;
+
+        let limiter = Arc::new(RateLimiter::new(BYTES_PER_SEC));
+        // Drain the initial burst, so only the refill rate counts
+        limiter.bucket.lock().unwrap().tokens = 0.0;
+
+        let counters = [Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))];
+        let mut tasks = Vec::new();
+        for counter in counters.iter() {
+            let (mut remote, local) = duplex(64 * 1024);
+            tasks.push(tokio::spawn(async move {
+                let buffer = [0u8; 4096];
+                while remote.write_all(&buffer).await.is_ok() {}
+            }));
+
+            let mut local = RateLimitedStream::new(local, vec![limiter.clone()]);
+            let counter = counter.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut buffer = [0u8; 4096];
+                while let Ok(n) = local.read(&mut buffer).await {
+                    counter.fetch_add(n, Ordering::Relaxed);
+                }
+            }));
+        }
+
+        // Time is paused, it advances only when all tasks are waiting
+        let elapsed = 1.0;
+        time::sleep(Duration::from_secs_f64(elapsed)).await;
+        for task in tasks {
+            task.abort();
+        }
+
+        let first = counters[0].load(Ordering::Relaxed) as f64;
+        let second = counters[1].load(Ordering::Relaxed) as f64;
+        let total = first + second;
+
+        // Leave room for one quantum of each stream
+        let limit = elapsed * BYTES_PER_SEC as f64 + 2.0 * limiter.quantum as f64;
+        assert!(
+            total <= limit,
+            "transferred {} bytes, expected at most {}",
+            total,
+            limit
+        );
+        assert!(
+            total >= elapsed * BYTES_PER_SEC as f64 - 2.0 * limiter.quantum as f64,
+            "transferred only {} bytes",
+            total
+        );
+
+        let ratio = first / second;
+        assert!((0.5..=2.0).contains(&ratio), "unfair shares {} and {}", first, second);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limiter_datagrams() {
+        const BYTES_PER_SEC: u64 = 10_000;
+
+        let limiters = [Arc::new(RateLimiter::new(BYTES_PER_SEC))];
+
+        // The initial burst is 1 second of bytes, then datagrams wait for the refill
+        let start = Instant::now();
+        for _ in 0..20 {
+            acquire_datagram(&limiters, 1000).await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -1,32 +1,50 @@
 //! A `ProxyStream` that bypasses or proxies data through proxy server automatically
 
 use std::{
-    io::{self, IoSlice},
+    future::Future,
+    io::{self, ErrorKind, IoSlice},
     net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{self, Poll},
+    time::Instant,
 };
 
+use log::{debug, trace, warn};
 use pin_project::pin_project;
+#[cfg(feature = "stream-compression")]
+use shadowsocks::relay::tcprelay::compress_stream::CompressedStream;
 use shadowsocks::{
+    config::{ServerAddr, ServerConfig},
     net::TcpStream,
     relay::{socks5::Address, tcprelay::proxy_stream::ProxyClientStream},
 };
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time,
+};
 
 use crate::{
+    acl::DEFAULT_RULE_NAME,
+    config::ServerFailurePolicy,
     local::{context::ServiceContext, loadbalancing::ServerIdent},
-    net::MonProxyStream,
+    net::{utils::is_fd_exhausted, ConnectionGauge, MonProxyStream},
 };
 
-use super::auto_proxy_io::AutoProxyIo;
+use super::{
+    auto_proxy_io::AutoProxyIo,
+    proxy_protocol::{make_proxy_protocol_v1_header, PROXY_PROTOCOL_V1_UNKNOWN_HEADER},
+};
 
 /// Unified stream for bypassed and proxied connections
+///
+/// Proxied streams are counted in their servers' `tcp_connections` until they are dropped.
 #[allow(clippy::large_enum_variant)]
 #[pin_project(project = AutoProxyClientStreamProj)]
 pub enum AutoProxyClientStream {
     Proxied(#[pin] ProxyClientStream<MonProxyStream<TcpStream>>),
+    #[cfg(feature = "stream-compression")]
+    ProxiedCompressed(#[pin] CompressedStream<ProxyClientStream<MonProxyStream<TcpStream>>>),
     Bypassed(#[pin] TcpStream),
 }
 
@@ -40,11 +58,46 @@ impl AutoProxyClientStream {
     where
         A: Into<Address>,
     {
-        let addr = addr.into();
-        if context.check_target_bypassed(&addr).await {
+        AutoProxyClientStream::connect_inner(context, server, addr.into(), None).await
+    }
+
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg` for client `peer_addr`
+    pub async fn connect_with_peer<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+        peer_addr: SocketAddr,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        AutoProxyClientStream::connect_inner(context, server, addr.into(), Some(peer_addr)).await
+    }
+
+    async fn connect_inner(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: Address,
+        peer_addr: Option<SocketAddr>,
+    ) -> io::Result<AutoProxyClientStream> {
+        let bypassed = match context.check_target_acl(&addr).await {
+            None => false,
+            Some(decision) => {
+                debug!(
+                    "ACL rule \"{}\" matched {} -> {}, {}",
+                    decision.rule,
+                    peer_addr.map_or_else(|| "-".to_owned(), |a| a.to_string()),
+                    addr,
+                    if decision.bypassed { "bypassed" } else { "proxied" }
+                );
+                decision.bypassed
+            }
+        };
+
+        if bypassed {
             AutoProxyClientStream::connect_bypassed(context, addr).await
         } else {
-            AutoProxyClientStream::connect_proxied(context, server, addr).await
+            AutoProxyClientStream::connect_proxied_inner(context, server, addr, peer_addr).await
         }
     }
 
@@ -55,9 +108,15 @@ impl AutoProxyClientStream {
     {
         // Connect directly.
         let addr = addr.into();
-        let stream =
-            TcpStream::connect_remote_with_opts(context.context_ref(), &addr, context.connect_opts_ref()).await?;
-        Ok(AutoProxyClientStream::Bypassed(stream))
+        match TcpStream::connect_remote_with_opts(context.context_ref(), &addr, context.connect_opts_ref()).await {
+            Ok(stream) => Ok(AutoProxyClientStream::Bypassed(stream)),
+            Err(err) => {
+                if is_fd_exhausted(&err) {
+                    context.report_fd_exhausted(&err);
+                }
+                Err(err)
+            }
+        }
     }
 
     /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`
@@ -69,28 +128,218 @@ impl AutoProxyClientStream {
     where
         A: Into<Address>,
     {
-        let flow_stat = context.flow_stat();
-        let stream = match ProxyClientStream::connect_with_opts_map(
-            context.context(),
-            server.server_config(),
-            addr,
-            context.connect_opts_ref(),
-            |stream| MonProxyStream::from_stream(stream, flow_stat),
-        )
-        .await
-        {
-            Ok(s) => s,
-            Err(err) => {
-                server.tcp_score().report_failure().await;
+        AutoProxyClientStream::connect_proxied_inner(context, server, addr.into(), None).await
+    }
+
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg` for client `peer_addr`
+    ///
+    /// If outbound PROXY protocol is enabled, `peer_addr` will be sent to the server before the shadowsocks handshake.
+    /// Connections made without `peer_addr` will send an UNKNOWN header instead.
+    pub async fn connect_proxied_with_peer<A>(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: A,
+        peer_addr: SocketAddr,
+    ) -> io::Result<AutoProxyClientStream>
+    where
+        A: Into<Address>,
+    {
+        AutoProxyClientStream::connect_proxied_inner(context, server, addr.into(), Some(peer_addr)).await
+    }
+
+    async fn connect_proxied_inner(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: Address,
+        peer_addr: Option<SocketAddr>,
+    ) -> io::Result<AutoProxyClientStream> {
+        // Balancers choose servers in cooldown only if all servers are down
+        if server.is_tcp_down() {
+            let err = io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("server {} is down", server.server_config().addr()),
+            );
+            return Err(err);
+        }
+
+        // Counted before connecting, so connections in progress are limited too
+        let gauge = match server.try_track_tcp_connection() {
+            Some(g) => g,
+            None => {
+                let err = io::Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "too many connections to server {}, max_tcp_connections: {}",
+                        server.server_config().addr(),
+                        server.server_config().max_tcp_connections().unwrap_or_default()
+                    ),
+                );
                 return Err(err);
             }
         };
+
+        let mut stream = AutoProxyClientStream::connect_server_with_policy(context, server, addr, peer_addr).await?;
+        stream.set_server_gauge(gauge);
+        Ok(stream)
+    }
+
+    /// Connect to `server`, failures are handled by the `ServerFailurePolicy`
+    async fn connect_server_with_policy(
+        context: Arc<ServiceContext>,
+        server: &ServerIdent,
+        addr: Address,
+        peer_addr: Option<SocketAddr>,
+    ) -> io::Result<AutoProxyClientStream> {
+        let err = match AutoProxyClientStream::connect_server(&context, server, &addr, peer_addr).await {
+            Ok(s) => return Ok(s),
+            Err(err) => err,
+        };
+
+        if !report_server_failure(&context, server, &addr, &err) {
+            return Err(err);
+        }
+
+        match context.server_failure_policy() {
+            ServerFailurePolicy::Reject => Err(err),
+            ServerFailurePolicy::Panic => panic!(
+                "failed to connect to server {} for {}, error: {}",
+                server.server_config().addr(),
+                addr,
+                err
+            ),
+            ServerFailurePolicy::FallbackDirect => {
+                if is_target_proxy_ruled(&context, &addr).await {
+                    debug!(
+                        "failed to connect to server {}, {} is required to be proxied by ACL, error: {}",
+                        server.server_config().addr(),
+                        addr,
+                        err
+                    );
+                    return Err(err);
+                }
+
+                warn!(
+                    "failed to connect to server {}, connecting {} directly, error: {}",
+                    server.server_config().addr(),
+                    addr,
+                    err
+                );
+                AutoProxyClientStream::connect_bypassed(context, addr).await
+            }
+            ServerFailurePolicy::RetryAfter { delay, retries } => {
+                let mut err = err;
+                for retry in 1..=retries {
+                    debug!(
+                        "failed to connect to server {} for {}, retrying {}/{} after {:?}, error: {}",
+                        server.server_config().addr(),
+                        addr,
+                        retry,
+                        retries,
+                        delay,
+                        err
+                    );
+                    time::sleep(delay).await;
+
+                    err = match AutoProxyClientStream::connect_server(&context, server, &addr, peer_addr).await {
+                        Ok(s) => return Ok(s),
+                        Err(err) => err,
+                    };
+                    if !report_server_failure(&context, server, &addr, &err) {
+                        break;
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn connect_server(
+        context: &ServiceContext,
+        server: &ServerIdent,
+        addr: &Address,
+        peer_addr: Option<SocketAddr>,
+    ) -> io::Result<AutoProxyClientStream> {
+        let start = Instant::now();
+        let max_retries = max_connect_retries(context, server);
+        let mut stream = connect_with_retries(server, max_retries, || {
+            let flow_stat = context.flow_stat();
+            let server_flow_stat = server.flow_stat();
+            ProxyClientStream::connect_with_opts_timeout_map(
+                context.context(),
+                server.server_config(),
+                addr.clone(),
+                context.connect_opts_ref(),
+                server.connect_timeout(context.adaptive_connect_timeout()),
+                |stream| MonProxyStream::from_stream_with_server(stream, flow_stat, server_flow_stat),
+            )
+        })
+        .await?;
+
+        if let Some(threshold) = context.slow_connection_threshold() {
+            let elapsed = start.elapsed();
+            if elapsed > threshold {
+                warn!(
+                    "slow connection {} -> {} through server {}, connecting took {:?}",
+                    peer_addr.map_or_else(|| "-".to_owned(), |a| a.to_string()),
+                    addr,
+                    server.server_config().addr(),
+                    elapsed
+                );
+            }
+        }
+
+        if context.outbound_proxy_protocol() {
+            // PROXY protocol header must be sent before the shadowsocks handshake,
+            // which will be sent along with the first data packet.
+            let remote_stream = stream.get_mut().get_mut();
+
+            // Connections without client's address, or the remote address couldn't be retrieved,
+            // are still relayed with an UNKNOWN header, instead of being dropped.
+            let remote_addr = proxy_protocol_destination(context, remote_stream, server.server_config()).await;
+            let header = match (peer_addr, remote_addr) {
+                (Some(peer_addr), Ok(remote_addr)) => make_proxy_protocol_v1_header(peer_addr, remote_addr),
+                (None, ..) => PROXY_PROTOCOL_V1_UNKNOWN_HEADER.to_owned(),
+                (Some(peer_addr), Err(err)) => {
+                    debug!(
+                        "failed to get address of remote {} for client {}, error: {}",
+                        server.server_config().addr(),
+                        peer_addr,
+                        err
+                    );
+                    PROXY_PROTOCOL_V1_UNKNOWN_HEADER.to_owned()
+                }
+            };
+            trace!(
+                "sending PROXY protocol header {:?} to {}",
+                header,
+                server.server_config().addr()
+            );
+            remote_stream.write_all(header.as_bytes()).await?;
+        }
+
+        #[cfg(feature = "stream-compression")]
+        if server.server_config().compression() {
+            return Ok(AutoProxyClientStream::ProxiedCompressed(CompressedStream::new(stream)));
+        }
+
         Ok(AutoProxyClientStream::Proxied(stream))
     }
 
+    // Bypassed streams are not connections to the server, the gauge is dropped
+    fn set_server_gauge(&mut self, gauge: ConnectionGauge) {
+        match *self {
+            AutoProxyClientStream::Proxied(ref mut s) => s.get_mut().set_server_gauge(gauge),
+            #[cfg(feature = "stream-compression")]
+            AutoProxyClientStream::ProxiedCompressed(ref mut s) => s.get_mut().get_mut().set_server_gauge(gauge),
+            AutoProxyClientStream::Bypassed(..) => {}
+        }
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         match *self {
             AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().local_addr(),
+            #[cfg(feature = "stream-compression")]
+            AutoProxyClientStream::ProxiedCompressed(ref s) => s.get_ref().get_ref().get_ref().local_addr(),
             AutoProxyClientStream::Bypassed(ref s) => s.local_addr(),
         }
     }
@@ -98,14 +347,120 @@ impl AutoProxyClientStream {
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
         match *self {
             AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().set_nodelay(nodelay),
+            #[cfg(feature = "stream-compression")]
+            AutoProxyClientStream::ProxiedCompressed(ref s) => s.get_ref().get_ref().get_ref().set_nodelay(nodelay),
             AutoProxyClientStream::Bypassed(ref s) => s.set_nodelay(nodelay),
         }
     }
 }
 
+/// Retries after failing to connect to `server`, falls back to the global default if the server doesn't have its own
+fn max_connect_retries(context: &ServiceContext, server: &ServerIdent) -> usize {
+    server
+        .server_config()
+        .max_retries()
+        .unwrap_or_else(|| context.connect_retries())
+}
+
+/// Handle a failure of connecting to `server`, returns `false` if it isn't the server's fault
+///
+/// Exhausted file descriptors pause local servers from accepting, servers are not marked down for them.
+fn report_server_failure(context: &ServiceContext, server: &ServerIdent, addr: &Address, err: &io::Error) -> bool {
+    if is_fd_exhausted(err) {
+        context.report_fd_exhausted(err);
+        return false;
+    }
+
+    if let Some(cooldown) = context.server_down_cooldown() {
+        warn!(
+            "server {} is down for {:?}, failed to connect for {}, error: {}",
+            server.server_config().addr(),
+            cooldown,
+            addr,
+            err
+        );
+        server.mark_tcp_down(cooldown);
+    }
+
+    true
+}
+
+/// Check if `addr` is required to be proxied by a rule of ACL, it must never be connected directly
+async fn is_target_proxy_ruled(context: &ServiceContext, addr: &Address) -> bool {
+    match context.acl() {
+        None => false,
+        Some(acl) => {
+            let decision = acl.check_target(context.context_ref(), addr).await;
+            !decision.bypassed && decision.rule != DEFAULT_RULE_NAME
+        }
+    }
+}
+
+async fn connect_with_retries<F, Fut, S>(server: &ServerIdent, max_retries: usize, mut connect: F) -> io::Result<S>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    let mut retries = 0;
+    loop {
+        match connect().await {
+            Ok(s) => return Ok(s),
+            // Neither the server's fault, nor could it be fixed by retrying immediately
+            Err(err) if is_fd_exhausted(&err) => return Err(err),
+            Err(err) => {
+                server.tcp_score().report_failure().await;
+                server.report_tcp_failure();
+
+                if retries >= max_retries {
+                    return Err(err);
+                }
+                retries += 1;
+
+                debug!(
+                    "failed to connect to {}, retrying {}/{}, error: {}",
+                    server.server_config().addr(),
+                    retries,
+                    max_retries,
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Destination address of the PROXY protocol header sent to the server
+///
+/// Streams of servers with a plugin or fronted by a HTTP proxy are connected to the local plugin or the proxy, the
+/// server's address is sent instead of their peer addresses.
+async fn proxy_protocol_destination(
+    context: &ServiceContext,
+    stream: &TcpStream,
+    svr_cfg: &ServerConfig,
+) -> io::Result<SocketAddr> {
+    if svr_cfg.plugin().is_none() && svr_cfg.http_fronting().is_none() {
+        return stream.peer_addr();
+    }
+
+    match *svr_cfg.addr() {
+        ServerAddr::SocketAddr(sa) => Ok(sa),
+        ServerAddr::DomainName(ref dname, port) => match context.context_ref().dns_resolve(dname, port).await?.next() {
+            Some(sa) => Ok(sa),
+            None => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("dns resolved {}:{} without any addresses", dname, port),
+            )),
+        },
+    }
+}
+
 impl AutoProxyIo for AutoProxyClientStream {
     fn is_proxied(&self) -> bool {
-        matches!(*self, AutoProxyClientStream::Proxied(..))
+        match *self {
+            AutoProxyClientStream::Proxied(..) => true,
+            #[cfg(feature = "stream-compression")]
+            AutoProxyClientStream::ProxiedCompressed(..) => true,
+            AutoProxyClientStream::Bypassed(..) => false,
+        }
     }
 }
 
@@ -113,6 +468,8 @@ impl AsyncRead for AutoProxyClientStream {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_read(cx, buf),
+            #[cfg(feature = "stream-compression")]
+            AutoProxyClientStreamProj::ProxiedCompressed(s) => s.poll_read(cx, buf),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_read(cx, buf),
         }
     }
@@ -122,6 +479,8 @@ impl AsyncWrite for AutoProxyClientStream {
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_write(cx, buf),
+            #[cfg(feature = "stream-compression")]
+            AutoProxyClientStreamProj::ProxiedCompressed(s) => s.poll_write(cx, buf),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_write(cx, buf),
         }
     }
@@ -129,6 +488,8 @@ impl AsyncWrite for AutoProxyClientStream {
     fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_flush(cx),
+            #[cfg(feature = "stream-compression")]
+            AutoProxyClientStreamProj::ProxiedCompressed(s) => s.poll_flush(cx),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_flush(cx),
         }
     }
@@ -136,6 +497,8 @@ impl AsyncWrite for AutoProxyClientStream {
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_shutdown(cx),
+            #[cfg(feature = "stream-compression")]
+            AutoProxyClientStreamProj::ProxiedCompressed(s) => s.poll_shutdown(cx),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_shutdown(cx),
         }
     }
@@ -147,6 +510,8 @@ impl AsyncWrite for AutoProxyClientStream {
     ) -> Poll<io::Result<usize>> {
         match self.project() {
             AutoProxyClientStreamProj::Proxied(s) => s.poll_write_vectored(cx, bufs),
+            #[cfg(feature = "stream-compression")]
+            AutoProxyClientStreamProj::ProxiedCompressed(s) => s.poll_write_vectored(cx, bufs),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_write_vectored(cx, bufs),
         }
     }
@@ -157,3 +522,297 @@ impl From<ProxyClientStream<MonProxyStream<TcpStream>>> for AutoProxyClientStrea
         AutoProxyClientStream::Proxied(s)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::ErrorKind,
+        net::{SocketAddr, TcpListener as StdTcpListener},
+        process,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use shadowsocks::{
+        config::{ServerAddr, ServerConfig},
+        crypto::CipherKind,
+    };
+    use tokio::net::TcpListener;
+
+    use crate::acl::AccessControl;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn per_server_connect_retries() {
+        let mut context = ServiceContext::new();
+        context.set_connect_retries(1);
+
+        let new_server = |port: u16, max_retries: Option<usize>| {
+            let mut svr_cfg = ServerConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                "password",
+                CipherKind::AES_128_GCM,
+            );
+            if let Some(max_retries) = max_retries {
+                svr_cfg.set_max_retries(max_retries);
+            }
+            ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10))
+        };
+
+        // (server, expected attempts)
+        let servers = [
+            (new_server(8388, Some(3)), 4),
+            (new_server(8389, Some(0)), 1),
+            (new_server(8390, None), 2),
+        ];
+
+        for (server, expected_attempts) in servers.iter() {
+            let attempts = AtomicUsize::new(0);
+            let max_retries = max_connect_retries(&context, server);
+            let result: io::Result<()> = connect_with_retries(server, max_retries, || async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err(io::Error::from(ErrorKind::ConnectionRefused))
+            })
+            .await;
+
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::ConnectionRefused);
+            assert_eq!(
+                attempts.load(Ordering::Relaxed),
+                *expected_attempts,
+                "{}",
+                server.server_config().addr()
+            );
+        }
+    }
+
+    fn unreachable_server() -> ServerIdent {
+        // Nothing is listening on the server's address
+        let server_addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_128_GCM);
+        ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn fd_exhausted_not_server_failure() {
+        let mut context = ServiceContext::new();
+        context.set_connect_retries(3);
+        context.set_server_down_cooldown(Duration::from_secs(10));
+        context.set_fd_exhausted_pause(Duration::from_millis(200));
+        let context = Arc::new(context);
+
+        let server = unreachable_server();
+        let target_addr = Address::SocketAddress("127.0.0.1:80".parse().unwrap());
+
+        // Connector fails as the process has run out of file descriptors
+        let attempts = AtomicUsize::new(0);
+        let max_retries = max_connect_retries(&context, &server);
+        let err = connect_with_retries(&server, max_retries, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err::<(), _>(io::Error::from_raw_os_error(libc::EMFILE))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(attempts.load(Ordering::Relaxed), 1, "retried on EMFILE");
+
+        assert!(!report_server_failure(&context, &server, &target_addr, &err));
+        assert!(!server.is_tcp_down());
+        assert!(!server.tcp_score().latest_errored());
+        assert_eq!(server.tcp_failures(), 0);
+        assert!(context.is_accept_paused());
+
+        // Accepting is resumed after the pause
+        time::timeout(Duration::from_secs(1), context.accept_resumed())
+            .await
+            .expect("accepting is still paused");
+        assert!(!context.is_accept_paused());
+
+        // The other errors are still the server's fault
+        let err = io::Error::from(ErrorKind::ConnectionRefused);
+        assert!(report_server_failure(&context, &server, &target_addr, &err));
+        assert!(server.is_tcp_down());
+        assert!(!context.is_accept_paused());
+    }
+
+    #[tokio::test]
+    async fn server_failure_reject() {
+        let mut context = ServiceContext::new();
+        context.set_server_failure_policy(ServerFailurePolicy::Reject);
+        let context = Arc::new(context);
+
+        let server = unreachable_server();
+        let target_addr = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
+
+        let result = AutoProxyClientStream::connect_proxied(context, &server, target_addr).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::ConnectionRefused);
+
+        // Counted for the weighted random balancer
+        assert_eq!(server.tcp_failures(), 1);
+    }
+
+    #[tokio::test]
+    async fn server_down_cooldown() {
+        let cooldown = Duration::from_millis(300);
+
+        let mut context = ServiceContext::new();
+        context.set_server_down_cooldown(cooldown);
+        let context = Arc::new(context);
+
+        let server = unreachable_server();
+        let server_addr = match server.server_config().addr() {
+            ServerAddr::SocketAddr(sa) => *sa,
+            ServerAddr::DomainName(..) => unreachable!(),
+        };
+        let target_addr = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
+
+        let result = AutoProxyClientStream::connect_proxied(context.clone(), &server, target_addr).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::ConnectionRefused);
+        assert!(server.is_tcp_down());
+
+        // Refused without connecting until the cooldown expires, even if the server is up again
+        let listener = TcpListener::bind(server_addr).await.unwrap();
+        let result = AutoProxyClientStream::connect_proxied(context.clone(), &server, target_addr).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::ConnectionRefused);
+        let accepted = time::timeout(Duration::from_millis(50), listener.accept()).await;
+        assert!(accepted.is_err(), "server contacted in cooldown");
+
+        time::sleep(cooldown).await;
+        assert!(!server.is_tcp_down());
+        let stream = AutoProxyClientStream::connect_proxied(context, &server, target_addr)
+            .await
+            .unwrap();
+        assert!(stream.is_proxied());
+        listener.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn server_max_tcp_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut svr_cfg = ServerConfig::new(listener.local_addr().unwrap(), "password", CipherKind::AES_128_GCM);
+        svr_cfg.set_max_tcp_connections(2);
+        let server = ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10));
+
+        let context = Arc::new(ServiceContext::new());
+        let target_addr = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
+
+        let s1 = AutoProxyClientStream::connect_proxied(context.clone(), &server, target_addr)
+            .await
+            .unwrap();
+        assert_eq!(server.tcp_connections(), 1);
+        let s2 = AutoProxyClientStream::connect_proxied(context.clone(), &server, target_addr)
+            .await
+            .unwrap();
+        assert_eq!(server.tcp_connections(), 2);
+        listener.accept().await.unwrap();
+        listener.accept().await.unwrap();
+
+        // Refused without connecting when the cap is reached
+        let result = AutoProxyClientStream::connect_proxied(context.clone(), &server, target_addr).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::Other);
+        let accepted = time::timeout(Duration::from_millis(50), listener.accept()).await;
+        assert!(accepted.is_err(), "server contacted over max_tcp_connections");
+        assert_eq!(server.tcp_connections(), 2);
+
+        drop(s1);
+        assert_eq!(server.tcp_connections(), 1);
+        let s3 = AutoProxyClientStream::connect_proxied(context, &server, target_addr)
+            .await
+            .unwrap();
+        assert_eq!(server.tcp_connections(), 2);
+
+        drop(s2);
+        drop(s3);
+        assert_eq!(server.tcp_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn server_failure_retry_after() {
+        let delay = Duration::from_millis(200);
+
+        let mut context = ServiceContext::new();
+        context.set_server_failure_policy(ServerFailurePolicy::RetryAfter { delay, retries: 3 });
+        let context = Arc::new(context);
+
+        let server = unreachable_server();
+        let server_addr = match server.server_config().addr() {
+            ServerAddr::SocketAddr(sa) => *sa,
+            ServerAddr::DomainName(..) => unreachable!(),
+        };
+        let target_addr = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
+
+        let start = Instant::now();
+        let client = tokio::spawn(async move {
+            AutoProxyClientStream::connect_proxied(context, &server, target_addr)
+                .await
+                .map(|s| s.is_proxied())
+        });
+
+        // Server is up after the first retry
+        time::sleep(delay * 3 / 2).await;
+        let listener = TcpListener::bind(server_addr).await.unwrap();
+
+        assert!(client.await.unwrap().unwrap());
+        assert!(start.elapsed() >= delay * 2);
+        listener.accept().await.unwrap();
+
+        // Fails after all retries
+        let mut context = ServiceContext::new();
+        context.set_server_failure_policy(ServerFailurePolicy::RetryAfter {
+            delay: Duration::from_millis(10),
+            retries: 2,
+        });
+        let context = Arc::new(context);
+
+        let server = unreachable_server();
+        let start = Instant::now();
+        let result = AutoProxyClientStream::connect_proxied(context, &server, target_addr).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::ConnectionRefused);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn server_failure_fallback_direct() {
+        let mut context = ServiceContext::new();
+        context.set_server_failure_policy(ServerFailurePolicy::FallbackDirect);
+        let context = Arc::new(context);
+
+        let server = unreachable_server();
+
+        let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let stream = AutoProxyClientStream::connect_proxied(context, &server, target_addr)
+            .await
+            .unwrap();
+        assert!(!stream.is_proxied());
+
+        let (_, peer_addr) = target_listener.accept().await.unwrap();
+        assert_eq!(peer_addr, stream.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn server_failure_fallback_direct_acl() {
+        let acl_path = std::env::temp_dir().join(format!("shadowsocks-fallback-direct-{}.acl", process::id()));
+        fs::write(&acl_path, "[bypass_all]\n[proxy_list]\n127.0.0.0/8\n").unwrap();
+        let acl = AccessControl::load_from_file(&acl_path).unwrap();
+        fs::remove_file(&acl_path).unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_server_failure_policy(ServerFailurePolicy::FallbackDirect);
+        context.set_acl(acl);
+        let context = Arc::new(context);
+
+        let server = unreachable_server();
+
+        // Targets required to be proxied are never connected directly
+        let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+
+        let result = AutoProxyClientStream::connect_proxied(context, &server, target_addr).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::ConnectionRefused);
+        let accepted = time::timeout(Duration::from_millis(50), target_listener.accept()).await;
+        assert!(accepted.is_err(), "target connected directly");
+    }
+}
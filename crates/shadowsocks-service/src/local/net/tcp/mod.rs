@@ -1,2 +1,3 @@
 pub mod auto_proxy_io;
 pub mod auto_proxy_stream;
+mod proxy_protocol;
@@ -0,0 +1,190 @@
+//! PROXY protocol header for outbound connections
+//!
+//! <https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt>
+
+use std::net::{IpAddr, SocketAddr};
+
+/// PROXY protocol version 1 header for connections whose addresses are unknown
+pub const PROXY_PROTOCOL_V1_UNKNOWN_HEADER: &str = "PROXY UNKNOWN\r\n";
+
+/// Make a PROXY protocol version 1 (human-readable) header
+///
+/// `src_addr` is the client's address, `dst_addr` is the address that the client connected to
+pub fn make_proxy_protocol_v1_header(src_addr: SocketAddr, dst_addr: SocketAddr) -> String {
+    // Both addresses must be in the same family, otherwise use IPv4-mapped IPv6 addresses
+    let (src_ip, dst_ip) = match (src_addr.ip(), dst_addr.ip()) {
+        (IpAddr::V4(src), IpAddr::V6(dst)) => (IpAddr::V6(src.to_ipv6_mapped()), IpAddr::V6(dst)),
+        (IpAddr::V6(src), IpAddr::V4(dst)) => (IpAddr::V6(src), IpAddr::V6(dst.to_ipv6_mapped())),
+        (src, dst) => (src, dst),
+    };
+
+    let protocol = match src_ip {
+        IpAddr::V4(..) => "TCP4",
+        IpAddr::V6(..) => "TCP6",
+    };
+
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        protocol,
+        src_ip,
+        dst_ip,
+        src_addr.port(),
+        dst_addr.port()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use shadowsocks::{config::ServerConfig, crypto::CipherKind, plugin::PluginConfig};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use crate::local::{context::ServiceContext, loadbalancing::ServerIdent, net::AutoProxyClientStream};
+
+    use super::*;
+
+    #[test]
+    fn proxy_protocol_v1_header() {
+        let src = "192.168.1.2:56324".parse::<SocketAddr>().unwrap();
+        let dst = "10.0.0.1:8388".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            make_proxy_protocol_v1_header(src, dst),
+            "PROXY TCP4 192.168.1.2 10.0.0.1 56324 8388\r\n"
+        );
+
+        let dst = "[2001:db8::1]:8388".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            make_proxy_protocol_v1_header(src, dst),
+            "PROXY TCP6 ::ffff:192.168.1.2 2001:db8::1 56324 8388\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_v1_before_handshake() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_outbound_proxy_protocol(true);
+        let context = Arc::new(context);
+
+        let svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_128_GCM);
+        let server = ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10));
+
+        let client_addr = "192.168.1.2:56324".parse::<SocketAddr>().unwrap();
+        let target_addr = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream =
+                AutoProxyClientStream::connect_proxied_with_peer(context, &server, target_addr, client_addr)
+                    .await
+                    .unwrap();
+            stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let mut header = String::new();
+        reader.read_line(&mut header).await.unwrap();
+        assert_eq!(
+            header,
+            format!("PROXY TCP4 192.168.1.2 127.0.0.1 56324 {}\r\n", server_addr.port())
+        );
+
+        // Shadowsocks handshake follows right after the header, starting with the salt
+        let mut salt = [0u8; 16];
+        reader.read_exact(&mut salt).await.unwrap();
+
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_v1_unknown_peer() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_outbound_proxy_protocol(true);
+        let context = Arc::new(context);
+
+        let svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_128_GCM);
+        let server = ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10));
+
+        let target_addr = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
+
+        // Connection without a client address, like those made by the HTTP client
+        let client = tokio::spawn(async move {
+            let mut stream = AutoProxyClientStream::connect_proxied(context, &server, target_addr)
+                .await
+                .unwrap();
+            stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let mut header = String::new();
+        reader.read_line(&mut header).await.unwrap();
+        assert_eq!(header, PROXY_PROTOCOL_V1_UNKNOWN_HEADER);
+
+        // Handshake still proceeds
+        let mut salt = [0u8; 16];
+        reader.read_exact(&mut salt).await.unwrap();
+
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_v1_through_plugin() {
+        // Listening as the local plugin
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let plugin_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_outbound_proxy_protocol(true);
+        let context = Arc::new(context);
+
+        let server_addr = "10.0.0.1:8388".parse::<SocketAddr>().unwrap();
+        let mut svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_128_GCM);
+        svr_cfg.set_plugin(PluginConfig {
+            plugin: "v2ray-plugin".to_owned(),
+            plugin_opts: None,
+            plugin_args: Vec::new(),
+        });
+        svr_cfg.set_plugin_addr(plugin_addr.into());
+        let server = ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10));
+
+        let client_addr = "192.168.1.2:56324".parse::<SocketAddr>().unwrap();
+        let target_addr = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream =
+                AutoProxyClientStream::connect_proxied_with_peer(context, &server, target_addr, client_addr)
+                    .await
+                    .unwrap();
+            stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+
+        // Destination is the server behind the plugin, not the plugin itself
+        let mut header = String::new();
+        reader.read_line(&mut header).await.unwrap();
+        assert_eq!(header, "PROXY TCP4 192.168.1.2 10.0.0.1 56324 8388\r\n");
+
+        client.await.unwrap();
+    }
+}
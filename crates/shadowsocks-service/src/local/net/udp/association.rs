@@ -12,7 +12,7 @@ use std::{
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::future;
-use log::{debug, error, trace, warn};
+use log::{debug, error, trace, warn, Level};
 use lru_time_cache::LruCache;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use tokio::{sync::mpsc, task::JoinHandle, time};
@@ -27,9 +27,16 @@ use shadowsocks::{
 };
 
 use crate::{
-    local::{context::ServiceContext, loadbalancing::PingBalancer},
+    config::UdpOversizedDatagramPolicy,
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        metrics::ConnectionGauge,
+        net::{acquire_datagram, RateLimiter},
+    },
     net::{
         packet_window::PacketWindowFilter,
+        relay_event::RelayEvent,
         MonProxySocket,
         UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
         UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
@@ -105,6 +112,11 @@ where
             return assoc.try_send((target_addr, Bytes::copy_from_slice(data)));
         }
 
+        if self.context.is_draining() {
+            debug!("udp association for {} refused, shutting down", peer_addr);
+            return Ok(());
+        }
+
         let assoc = UdpAssociation::new(
             self.context.clone(),
             peer_addr,
@@ -114,7 +126,8 @@ where
             self.server_session_expire_duration,
         );
 
-        debug!("created udp association for {}", peer_addr);
+        RelayEvent::udp("established", peer_addr)
+            .log(Level::Debug, format_args!("created udp association for {}", peer_addr));
 
         assoc.try_send((target_addr, Bytes::copy_from_slice(data)))?;
         self.assoc_map.insert(peer_addr, assoc);
@@ -140,6 +153,8 @@ where
     assoc_handle: JoinHandle<()>,
     sender: mpsc::Sender<(Address, Bytes)>,
     writer: PhantomData<W>,
+    // Shutting down waits until the association expires
+    _active: ConnectionGauge,
 }
 
 impl<W> Drop for UdpAssociation<W>
@@ -163,6 +178,7 @@ where
         respond_writer: W,
         server_session_expire_duration: Duration,
     ) -> UdpAssociation<W> {
+        let active = context.track_active_connection();
         let (assoc_handle, sender) = UdpAssociationContext::create(
             context,
             peer_addr,
@@ -175,6 +191,7 @@ where
             assoc_handle,
             sender,
             writer: PhantomData,
+            _active: active,
         }
     }
 
@@ -222,6 +239,7 @@ where
     client_packet_id: u64,
     server_session: Option<ServerSessionContext>,
     server_session_expire_duration: Duration,
+    rate_limiters: Vec<Arc<RateLimiter>>,
 }
 
 impl<W> Drop for UdpAssociationContext<W>
@@ -229,7 +247,10 @@ where
     W: UdpInboundWrite + Send + Sync + Unpin + 'static,
 {
     fn drop(&mut self) {
-        debug!("udp association for {} is closed", self.peer_addr);
+        RelayEvent::udp("closed", self.peer_addr).log(
+            Level::Debug,
+            format_args!("udp association for {} is closed", self.peer_addr),
+        );
     }
 }
 
@@ -259,6 +280,9 @@ where
         // being OOM.
         let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
 
+        // Each association is limited like a tunnel
+        let rate_limiters = context.tunnel_rate_limiters();
+
         let mut assoc = UdpAssociationContext {
             context,
             peer_addr,
@@ -275,6 +299,7 @@ where
             client_packet_id: 0,
             server_session: None,
             server_session_expire_duration,
+            rate_limiters,
         };
         let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
 
@@ -312,6 +337,7 @@ where
                         }
                     };
 
+                    self.context.bypassed_flow_stat_ref().incr_rx(n as u64);
                     let addr = Address::from(addr);
                     self.send_received_respond_packet(&addr, &bypassed_ipv4_buffer[..n], true).await;
                 }
@@ -327,6 +353,7 @@ where
                         }
                     };
 
+                    self.context.bypassed_flow_stat_ref().incr_rx(n as u64);
                     let addr = Address::from(addr);
                     self.send_received_respond_packet(&addr, &bypassed_ipv6_buffer[..n], true).await;
                 }
@@ -420,6 +447,17 @@ where
     }
 
     async fn dispatch_received_packet(&mut self, target_addr: &Address, data: &[u8]) {
+        // Packets were proxied before servers were removed, don't send them directly
+        if self.balancer.is_servers_removed() {
+            error!(
+                "udp relay {} -> {} with {} bytes dropped, all servers were removed by reloading",
+                self.peer_addr,
+                target_addr,
+                data.len()
+            );
+            return;
+        }
+
         // Check if target should be bypassed. If so, send packets directly.
         let bypassed = self.balancer.is_empty() || self.context.check_target_bypassed(target_addr).await;
 
@@ -431,6 +469,62 @@ where
             data.len()
         );
 
+        // udp_max_datagram limits packets sent, which includes the header and the encryption of proxied packets
+        let overhead = if bypassed {
+            0
+        } else {
+            match self.proxied_socket().await {
+                Ok(socket) => socket.get_ref().client_packet_len(target_addr, 0),
+                Err(err) => {
+                    error!(
+                        "udp relay {} -> {} (proxied) with {} bytes, error: {}",
+                        self.peer_addr,
+                        target_addr,
+                        data.len(),
+                        err
+                    );
+                    return;
+                }
+            }
+        };
+
+        let max_datagram_size = self.context.udp_max_datagram_size();
+        let max_payload_size = max_datagram_size.saturating_sub(overhead);
+        if data.len() <= max_payload_size {
+            self.send_received_packet(target_addr, data, bypassed).await;
+            return;
+        }
+
+        match self.context.udp_oversized_datagram_policy() {
+            UdpOversizedDatagramPolicy::Split if max_payload_size > 0 => {
+                debug!(
+                    "udp client {} outbound {} datagram with {} bytes split, packet of {} bytes exceeds udp_max_datagram {} bytes",
+                    self.peer_addr,
+                    target_addr,
+                    data.len(),
+                    data.len() + overhead,
+                    max_datagram_size
+                );
+                for part in data.chunks(max_payload_size) {
+                    self.send_received_packet(target_addr, part, bypassed).await;
+                }
+            }
+            _ => {
+                warn!(
+                    "udp client {} outbound {} datagram with {} bytes dropped, packet of {} bytes exceeds udp_max_datagram {} bytes",
+                    self.peer_addr,
+                    target_addr,
+                    data.len(),
+                    data.len() + overhead,
+                    max_datagram_size
+                );
+            }
+        }
+    }
+
+    async fn send_received_packet(&mut self, target_addr: &Address, data: &[u8], bypassed: bool) {
+        acquire_datagram(&self.rate_limiters, data.len()).await;
+
         if bypassed {
             if let Err(err) = self.dispatch_received_bypassed_packet(target_addr, data).await {
                 error!(
@@ -521,6 +615,7 @@ where
         }
 
         let n = socket.send_to(data, target_addr).await?;
+        self.context.bypassed_flow_stat_ref().incr_tx(n as u64);
         if n != data.len() {
             warn!(
                 "{} -> {} sent {} bytes != expected {} bytes",
@@ -534,6 +629,25 @@ where
         Ok(())
     }
 
+    async fn proxied_socket(&mut self) -> io::Result<&mut MonProxySocket> {
+        match self.proxied_socket {
+            Some(ref mut socket) => Ok(socket),
+            None => {
+                // Create a new connection to proxy server
+
+                let server = self.balancer.best_udp_server();
+                let svr_cfg = server.server_config();
+
+                let socket =
+                    ProxySocket::connect_with_opts(self.context.context(), svr_cfg, self.context.connect_opts_ref())
+                        .await?;
+                let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
+
+                Ok(self.proxied_socket.insert(socket))
+            }
+        }
+    }
+
     async fn dispatch_received_proxied_packet(&mut self, target_addr: &Address, data: &[u8]) -> io::Result<()> {
         // Increase Packet ID before send
         self.client_packet_id = match self.client_packet_id.checked_add(1) {
@@ -561,28 +675,12 @@ where
             }
         };
 
-        let socket = match self.proxied_socket {
-            Some(ref mut socket) => socket,
-            None => {
-                // Create a new connection to proxy server
-
-                let server = self.balancer.best_udp_server();
-                let svr_cfg = server.server_config();
-
-                let socket =
-                    ProxySocket::connect_with_opts(self.context.context(), svr_cfg, self.context.connect_opts_ref())
-                        .await?;
-                let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
-
-                self.proxied_socket.insert(socket)
-            }
-        };
-
         let control = UdpSocketControlData {
             client_session_id: self.client_session_id,
             server_session_id: 0,
             packet_id: self.client_packet_id,
         };
+        let socket = self.proxied_socket().await?;
 
         match socket.send_with_ctrl(target_addr, &control, data).await {
             Ok(..) => return Ok(()),
@@ -615,6 +713,8 @@ where
         // Keep association alive in map
         self.keepalive_flag = true;
 
+        acquire_datagram(&self.rate_limiters, data.len()).await;
+
         // Send back to client
         if let Err(err) = self.respond_writer.send_to(self.peer_addr, addr, data).await {
             warn!(
@@ -636,3 +736,64 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use shadowsocks::config::Mode;
+    use tokio::net::UdpSocket;
+
+    use crate::local::loadbalancing::PingBalancerBuilder;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct DiscardWriter;
+
+    #[async_trait]
+    impl UdpInboundWrite for DiscardWriter {
+        async fn send_to(&self, _: SocketAddr, _: &Address, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn send_oversized_datagram(policy: UdpOversizedDatagramPolicy) -> Vec<usize> {
+        let target = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target_addr = Address::from(target.local_addr().unwrap());
+
+        let mut context = ServiceContext::new();
+        context.set_udp_max_datagram_size(1024);
+        context.set_udp_oversized_datagram_policy(policy);
+        let context = Arc::new(context);
+
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly)
+            .build()
+            .await
+            .unwrap();
+        let (mut manager, ..) = UdpAssociationManager::new(context, DiscardWriter, None, None, balancer);
+
+        let peer_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 10000));
+        manager.send_to(peer_addr, target_addr, &[0u8; 2500]).await.unwrap();
+
+        let mut received = Vec::new();
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        while let Ok(r) = time::timeout(Duration::from_millis(200), target.recv_from(&mut buffer)).await {
+            let (n, ..) = r.unwrap();
+            received.push(n);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn udp_oversized_datagram() {
+        assert_eq!(
+            send_oversized_datagram(UdpOversizedDatagramPolicy::Reject).await,
+            Vec::<usize>::new()
+        );
+        assert_eq!(
+            send_oversized_datagram(UdpOversizedDatagramPolicy::Split).await,
+            vec![1024, 1024, 452]
+        );
+    }
+}
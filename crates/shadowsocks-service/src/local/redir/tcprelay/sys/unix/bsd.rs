@@ -5,7 +5,7 @@ use std::{
 
 use async_trait::async_trait;
 use log::warn;
-use shadowsocks::net::{is_dual_stack_addr, set_tcp_fastopen, AcceptOpts};
+use shadowsocks::net::{is_dual_stack_addr, set_tcp_fastopen, AcceptOpts, DEFAULT_LISTEN_BACKLOG};
 use socket2::Protocol;
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
 
@@ -74,8 +74,7 @@ impl TcpListenerRedirExt for TcpListener {
 
         socket.bind(addr)?;
 
-        // mio's default backlog is 1024
-        let listener = socket.listen(1024)?;
+        let listener = socket.listen(accept_opts.backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG))?;
 
         if accept_opts.tcp.fastopen {
             set_tcp_fastopen(&listener)?;
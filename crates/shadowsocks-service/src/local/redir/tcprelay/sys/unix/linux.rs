@@ -7,7 +7,7 @@ use std::{
 
 use async_trait::async_trait;
 use log::warn;
-use shadowsocks::net::{is_dual_stack_addr, set_tcp_fastopen, AcceptOpts};
+use shadowsocks::net::{is_dual_stack_addr, set_tcp_fastopen, AcceptOpts, DEFAULT_LISTEN_BACKLOG};
 use socket2::SockAddr;
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
 
@@ -52,8 +52,7 @@ impl TcpListenerRedirExt for TcpListener {
 
                 socket.bind(addr)?;
 
-                // mio's default backlog is 1024
-                let listener = socket.listen(1024)?;
+                let listener = socket.listen(accept_opts.backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG))?;
 
                 if accept_opts.tcp.fastopen {
                     set_tcp_fastopen(&listener)?;
@@ -184,8 +183,7 @@ async fn create_redir_listener(addr: SocketAddr, accept_opts: AcceptOpts) -> io:
     // bind, listen as original
     socket.bind(addr)?;
 
-    // listen backlogs = 1024 as mio's default
-    let listener = socket.listen(1024)?;
+    let listener = socket.listen(accept_opts.backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG))?;
 
     if accept_opts.tcp.fastopen {
         set_tcp_fastopen(&listener)?;
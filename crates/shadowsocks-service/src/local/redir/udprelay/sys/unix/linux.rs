@@ -273,3 +273,35 @@ fn recv_dest_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Sock
         ))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recv_original_destination() {
+        // IP_TRANSPARENT requires CAP_NET_ADMIN, but IP_RECVORIGDSTADDR alone reports the destination of
+        // datagrams that are not redirected, which is the socket's own address
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let enable: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_IP,
+                libc::IP_RECVORIGDSTADDR,
+                &enable as *const _ as *const _,
+                mem::size_of_val(&enable) as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0, "{}", Error::last_os_error());
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"hello", socket.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0u8; 64];
+        let (n, src, dst) = recv_dest_from(&socket, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(src, client.local_addr().unwrap());
+        assert_eq!(dst, socket.local_addr().unwrap());
+    }
+}
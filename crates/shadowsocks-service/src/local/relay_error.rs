@@ -0,0 +1,112 @@
+//! Relay errors reported to embedders
+
+use std::{fmt, io, net::SocketAddr};
+
+use shadowsocks::relay::socks5::Address;
+
+/// Stage of a connection where the error occurs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayErrorKind {
+    /// Failed to connect to the target or the proxy server
+    Connect,
+    /// Failed when relaying data between the client and the remote
+    Relay,
+}
+
+impl fmt::Display for RelayErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RelayErrorKind::Connect => f.write_str("connect"),
+            RelayErrorKind::Relay => f.write_str("relay"),
+        }
+    }
+}
+
+/// Context of a relay error
+#[derive(Debug)]
+pub struct ErrorContext {
+    /// Stage where the error occurs
+    pub kind: RelayErrorKind,
+    /// Client's address
+    pub peer_addr: SocketAddr,
+    /// Target address that the client requested
+    pub target_addr: Address,
+    /// The error
+    pub error: io::Error,
+}
+
+/// Receiver of relay errors
+///
+/// Errors are reported from the local servers' handlers and relay loops, in addition to logs.
+pub trait RelayErrorSink: Send + Sync {
+    /// Called when connection `conn_id` fails with `err`. Does nothing by default
+    fn on_error(&self, conn_id: usize, err: ErrorContext) {
+        let _ = (conn_id, err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::ErrorKind,
+        net::TcpListener as StdTcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use shadowsocks::config::Mode;
+
+    use crate::{
+        local::{
+            context::ServiceContext,
+            loadbalancing::PingBalancerBuilder,
+            socks::{client::Socks5TcpClient, server::Socks},
+        },
+        test_utils::bind_listener,
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        errors: Mutex<Vec<(usize, ErrorContext)>>,
+    }
+
+    impl RelayErrorSink for RecordingSink {
+        fn on_error(&self, conn_id: usize, err: ErrorContext) {
+            self.errors.lock().unwrap().push((conn_id, err));
+        }
+    }
+
+    #[tokio::test]
+    async fn report_connect_error() {
+        // Nothing is listening on the target address
+        let target_addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let sink = Arc::new(RecordingSink::default());
+
+        let mut context = ServiceContext::new();
+        context.set_error_sink(sink.clone());
+        let context = Arc::new(context);
+
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Socks::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let result = Socks5TcpClient::connect(target_addr, proxy_addr).await;
+        assert!(result.is_err(), "connect to {} should fail", target_addr);
+
+        let errors = sink.errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+
+        let (_, ref err) = errors[0];
+        assert_eq!(err.kind, RelayErrorKind::Connect);
+        assert_eq!(err.target_addr, Address::SocketAddress(target_addr));
+        assert_eq!(err.error.kind(), ErrorKind::ConnectionRefused);
+    }
+}
@@ -3,15 +3,18 @@
 use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use futures::{future, FutureExt};
-use log::{error, info};
+use log::{debug, error, info, warn};
 use shadowsocks::{config::Mode, lookup_then, net::TcpListener as ShadowTcpListener, ServerAddr};
-use tokio::{net::TcpStream, time};
+use tokio::{io::AsyncWriteExt, net::TcpStream, time};
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+use crate::{
+    local::{context::ServiceContext, loadbalancing::PingBalancer, net::AllowedClients},
+    net::WorkerPool,
+};
 
 #[cfg(feature = "local-socks4")]
 use self::socks4::Socks4TcpHandler;
-use self::socks5::{Socks5TcpHandler, Socks5UdpServer};
+use self::socks5::{Socks5TcpHandler, Socks5UdpServer, UdpAssociateClients, UdpRelayAddrs};
 
 use super::config::Socks5AuthConfig;
 
@@ -19,6 +22,12 @@ use super::config::Socks5AuthConfig;
 mod socks4;
 mod socks5;
 
+/// Default timeout of waiting for the incoming peer of SOCKS5 BIND
+const SOCKS5_DEFAULT_BIND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Maximum bytes discarded before closing a client with an unsupported SOCKS version
+const MAXIMUM_DISCARD_SIZE: usize = 4096;
+
 /// SOCKS4/4a, SOCKS5 Local Server
 pub struct Socks {
     context: Arc<ServiceContext>,
@@ -27,6 +36,10 @@ pub struct Socks {
     udp_capacity: Option<usize>,
     udp_bind_addr: Option<ServerAddr>,
     socks5_auth: Arc<Socks5AuthConfig>,
+    socks5_request_timeout: Option<Duration>,
+    socks5_lazy_connect: bool,
+    socks5_bind_timeout: Duration,
+    allowed_clients: AllowedClients,
 }
 
 impl Default for Socks {
@@ -51,6 +64,10 @@ impl Socks {
             udp_capacity: None,
             udp_bind_addr: None,
             socks5_auth: Arc::new(Socks5AuthConfig::default()),
+            socks5_request_timeout: None,
+            socks5_lazy_connect: false,
+            socks5_bind_timeout: SOCKS5_DEFAULT_BIND_TIMEOUT,
+            allowed_clients: AllowedClients::default(),
         }
     }
 
@@ -82,50 +99,194 @@ impl Socks {
         self.socks5_auth = Arc::new(p);
     }
 
+    /// Set timeout of reading SOCKS5 request after the method negotiation
+    ///
+    /// Clients which don't send their requests in time are disconnected.
+    pub fn set_socks5_request_timeout(&mut self, timeout: Duration) {
+        self.socks5_request_timeout = Some(timeout);
+    }
+
+    /// Set whether SOCKS5 CONNECT replies success before connecting to the target
+    ///
+    /// Target is connected after the client sends its first byte, and connect failures close the client's
+    /// connection instead of replying an error. It doesn't work with protocols that servers speak first.
+    /// Disabled by default.
+    pub fn set_socks5_lazy_connect(&mut self, lazy_connect: bool) {
+        self.socks5_lazy_connect = lazy_connect;
+    }
+
+    /// Set timeout of waiting for the incoming peer of SOCKS5 BIND
+    ///
+    /// When it expires, the second reply is a failure and the listener is closed. 120 seconds by default.
+    pub fn set_socks5_bind_timeout(&mut self, timeout: Duration) {
+        self.socks5_bind_timeout = timeout;
+    }
+
+    /// Set clients allowed to connect, all clients are allowed by default
+    pub fn set_allowed_clients(&mut self, allowed_clients: AllowedClients) {
+        self.allowed_clients = allowed_clients;
+    }
+
     /// Start serving
     pub async fn run(self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
+        self.run_with_opt_listener(client_config, None, balancer).await
+    }
+
+    /// Start serving on a bound TCP listener, UDP relay binds to the same address
+    pub async fn run_with_listener(self, listener: ShadowTcpListener, balancer: PingBalancer) -> io::Result<()> {
+        let client_config = ServerAddr::from(listener.local_addr()?);
+        self.run_with_opt_listener(&client_config, Some(listener), balancer)
+            .await
+    }
+
+    async fn run_with_opt_listener(
+        self,
+        client_config: &ServerAddr,
+        listener: Option<ShadowTcpListener>,
+        balancer: PingBalancer,
+    ) -> io::Result<()> {
         let mut vfut = Vec::new();
 
+        // NOTE: SOCKS 5 RFC requires TCP handshake for UDP ASSOCIATE command
+        // But here we can start a standalone UDP SOCKS 5 relay server in `udp_only` mode, for special use cases
+        let associate_clients = if self.mode.enable_tcp() && self.mode.enable_udp() {
+            Some(Arc::new(UdpAssociateClients::new()))
+        } else {
+            None
+        };
+
+        let udp_server = if self.mode.enable_udp() {
+            let mut server = Socks5UdpServer::new(self.context.clone(), self.udp_expiry_duration, self.udp_capacity);
+            if let Some(ref clients) = associate_clients {
+                server.set_associate_clients(clients.clone());
+            }
+            server.set_allowed_clients(self.allowed_clients.clone());
+
+            let udp_bind_addr = self.udp_bind_addr.as_ref().unwrap_or(client_config);
+            let socket = server.bind(udp_bind_addr).await?;
+            let other_family_socket = if self.mode.enable_tcp() {
+                server.bind_other_family(socket.local_addr()?).await
+            } else {
+                None
+            };
+            Some((Arc::new(server), socket, other_family_socket))
+        } else {
+            None
+        };
+
+        // If UDP is enabled, SOCK5 UDP_ASSOCIATE command will let client to send requests to the bound address
+        let udp_bind_addr = match udp_server {
+            Some((_, ref socket, ref other_family_socket)) => {
+                let mut addrs = UdpRelayAddrs::new(ServerAddr::from(socket.local_addr()?));
+                if let Some(ref other_family_socket) = *other_family_socket {
+                    addrs.other_family_addr = Some(other_family_socket.local_addr()?);
+                }
+                Some(Arc::new(addrs))
+            }
+            None => self.udp_bind_addr.clone().map(|a| Arc::new(UdpRelayAddrs::new(a))),
+        };
+
         if self.mode.enable_tcp() {
-            vfut.push(self.run_tcp_server(client_config, balancer.clone()).boxed());
+            vfut.push(
+                self.run_tcp_server(
+                    client_config,
+                    listener,
+                    balancer.clone(),
+                    udp_bind_addr,
+                    associate_clients,
+                )
+                .boxed(),
+            );
         }
 
-        if self.mode.enable_udp() {
-            // NOTE: SOCKS 5 RFC requires TCP handshake for UDP ASSOCIATE command
-            // But here we can start a standalone UDP SOCKS 5 relay server, for special use cases
-
-            vfut.push(self.run_udp_server(client_config, balancer).boxed());
+        if let Some((server, socket, other_family_socket)) = udp_server {
+            if let Some(other_family_socket) = other_family_socket {
+                let server = server.clone();
+                let balancer = balancer.clone();
+                vfut.push(async move { server.run(other_family_socket, balancer).await }.boxed());
+            }
+            vfut.push(async move { server.run(socket, balancer).await }.boxed());
         }
 
         let (res, ..) = future::select_all(vfut).await;
         res
     }
 
-    async fn run_tcp_server(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let listener = match *client_config {
-            ServerAddr::SocketAddr(ref saddr) => {
-                ShadowTcpListener::bind_with_opts(saddr, self.context.accept_opts()).await?
-            }
-            ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(self.context.context_ref(), dname, port, |addr| {
-                    ShadowTcpListener::bind_with_opts(&addr, self.context.accept_opts()).await
-                })?
-                .1
-            }
+    async fn run_tcp_server(
+        &self,
+        client_config: &ServerAddr,
+        listener: Option<ShadowTcpListener>,
+        balancer: PingBalancer,
+        udp_bind_addr: Option<Arc<UdpRelayAddrs>>,
+        associate_clients: Option<Arc<UdpAssociateClients>>,
+    ) -> io::Result<()> {
+        let listener = match listener {
+            Some(listener) => listener,
+            None => match *client_config {
+                ServerAddr::SocketAddr(ref saddr) => {
+                    ShadowTcpListener::bind_with_opts(saddr, self.context.accept_opts()).await?
+                }
+                ServerAddr::DomainName(ref dname, port) => {
+                    lookup_then!(self.context.context_ref(), dname, port, |addr| {
+                        ShadowTcpListener::bind_with_opts(&addr, self.context.accept_opts()).await
+                    })?
+                    .1
+                }
+            },
         };
 
         info!("shadowsocks socks TCP listening on {}", listener.local_addr()?);
 
-        // If UDP is enabled, SOCK5 UDP_ASSOCIATE command will let client to send requests to this address
-        let udp_bind_addr = if self.mode.enable_udp() {
-            let udp_bind_addr = self.udp_bind_addr.as_ref().unwrap_or(client_config);
-            let udp_bind_addr = Arc::new(udp_bind_addr.clone());
-            Some(udp_bind_addr)
-        } else {
-            self.udp_bind_addr.clone().map(Arc::new)
+        let handler = {
+            let context = self.context.clone();
+            let mode = self.mode;
+            let socks5_auth = self.socks5_auth.clone();
+            let socks5_request_timeout = self.socks5_request_timeout;
+            let socks5_lazy_connect = self.socks5_lazy_connect;
+            let socks5_bind_timeout = self.socks5_bind_timeout;
+
+            move |(stream, peer_addr): (TcpStream, SocketAddr)| {
+                let balancer = balancer.clone();
+                let context = context.clone();
+                let udp_bind_addr = udp_bind_addr.clone();
+                let associate_clients = associate_clients.clone();
+                let socks5_auth = socks5_auth.clone();
+
+                // Logs of the connection are prefixed with its id
+                let conn_id = context.next_conn_id();
+
+                async move {
+                    if let Err(err) = Socks::handle_tcp_client(
+                        context,
+                        conn_id,
+                        udp_bind_addr,
+                        associate_clients,
+                        stream,
+                        balancer,
+                        peer_addr,
+                        mode,
+                        socks5_auth,
+                        socks5_request_timeout,
+                        socks5_lazy_connect,
+                        socks5_bind_timeout,
+                    )
+                    .await
+                    {
+                        error!("[c{}] socks5 tcp client handler error: {}", conn_id, err);
+                    }
+                }
+            }
         };
 
+        let worker_pool = self
+            .context
+            .worker_pool()
+            .map(|config| WorkerPool::new(config, handler.clone()));
+
         loop {
+            // Paused for a while after file descriptors were exhausted
+            self.context.accept_resumed().await;
+
             let (stream, peer_addr) = match listener.accept().await {
                 Ok(s) => s,
                 Err(err) => {
@@ -135,78 +296,229 @@ impl Socks {
                 }
             };
 
-            let balancer = balancer.clone();
-            let context = self.context.clone();
-            let udp_bind_addr = udp_bind_addr.clone();
-            let mode = self.mode;
-            let socks5_auth = self.socks5_auth.clone();
+            if !self.allowed_clients.check_allowed(&peer_addr.ip()) {
+                warn!("socks client {} is not in allowed_clients, access denied", peer_addr);
+                continue;
+            }
 
-            tokio::spawn(async move {
-                if let Err(err) =
-                    Socks::handle_tcp_client(context, udp_bind_addr, stream, balancer, peer_addr, mode, socks5_auth)
-                        .await
-                {
-                    error!("socks5 tcp client handler error: {}", err);
+            match worker_pool {
+                Some(ref pool) => {
+                    if let Err(err) = pool.dispatch((stream, peer_addr)) {
+                        warn!("socks server rejected client {}, error: {}", peer_addr, err);
+                    }
+                }
+                None => {
+                    tokio::spawn(handler((stream, peer_addr)));
                 }
-            });
+            }
         }
     }
 
     #[cfg(feature = "local-socks4")]
+    #[allow(clippy::too_many_arguments)]
     async fn handle_tcp_client(
         context: Arc<ServiceContext>,
-        udp_bind_addr: Option<Arc<ServerAddr>>,
+        conn_id: usize,
+        udp_bind_addr: Option<Arc<UdpRelayAddrs>>,
+        associate_clients: Option<Arc<UdpAssociateClients>>,
         stream: TcpStream,
         balancer: PingBalancer,
         peer_addr: SocketAddr,
         mode: Mode,
         socks5_auth: Arc<Socks5AuthConfig>,
+        socks5_request_timeout: Option<Duration>,
+        socks5_lazy_connect: bool,
+        socks5_bind_timeout: Duration,
     ) -> io::Result<()> {
-        use std::io::ErrorKind;
-
-        let mut version_buffer = [0u8; 1];
-        let n = stream.peek(&mut version_buffer).await?;
-        if n == 0 {
-            return Err(ErrorKind::UnexpectedEof.into());
-        }
-
-        match version_buffer[0] {
+        match Socks::peek_version(&stream).await? {
             0x04 => {
-                let handler = Socks4TcpHandler::new(context, balancer, mode);
+                let handler = Socks4TcpHandler::new(context, conn_id, balancer, mode);
                 handler.handle_socks4_client(stream, peer_addr).await
             }
 
             0x05 => {
-                let handler = Socks5TcpHandler::new(context, udp_bind_addr, balancer, mode, socks5_auth);
+                let handler = Socks5TcpHandler::new(
+                    context,
+                    conn_id,
+                    udp_bind_addr,
+                    associate_clients,
+                    balancer,
+                    mode,
+                    socks5_auth,
+                    socks5_request_timeout,
+                    socks5_lazy_connect,
+                    socks5_bind_timeout,
+                );
                 handler.handle_socks5_client(stream, peer_addr).await
             }
 
             version => {
-                error!("unsupported socks version {:x}", version);
-                let err = io::Error::new(ErrorKind::Other, "unsupported socks version");
-                Err(err)
+                Socks::close_unsupported_version(&context, conn_id, stream, peer_addr, version).await;
+                Ok(())
             }
         }
     }
 
     #[cfg(not(feature = "local-socks4"))]
+    #[allow(clippy::too_many_arguments)]
     async fn handle_tcp_client(
         context: Arc<ServiceContext>,
-        udp_bind_addr: Option<Arc<ServerAddr>>,
+        conn_id: usize,
+        udp_bind_addr: Option<Arc<UdpRelayAddrs>>,
+        associate_clients: Option<Arc<UdpAssociateClients>>,
         stream: TcpStream,
         balancer: PingBalancer,
         peer_addr: SocketAddr,
         mode: Mode,
         socks5_auth: Arc<Socks5AuthConfig>,
+        socks5_request_timeout: Option<Duration>,
+        socks5_lazy_connect: bool,
+        socks5_bind_timeout: Duration,
     ) -> io::Result<()> {
-        let handler = Socks5TcpHandler::new(context, udp_bind_addr, balancer, mode, socks5_auth);
+        let version = Socks::peek_version(&stream).await?;
+        if version != 0x05 {
+            Socks::close_unsupported_version(&context, conn_id, stream, peer_addr, version).await;
+            return Ok(());
+        }
+
+        let handler = Socks5TcpHandler::new(
+            context,
+            conn_id,
+            udp_bind_addr,
+            associate_clients,
+            balancer,
+            mode,
+            socks5_auth,
+            socks5_request_timeout,
+            socks5_lazy_connect,
+            socks5_bind_timeout,
+        );
         handler.handle_socks5_client(stream, peer_addr).await
     }
 
-    async fn run_udp_server(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
-        let server = Socks5UdpServer::new(self.context.clone(), self.udp_expiry_duration, self.udp_capacity);
+    async fn peek_version(stream: &TcpStream) -> io::Result<u8> {
+        let mut version_buffer = [0u8; 1];
+        let n = stream.peek(&mut version_buffer).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(version_buffer[0])
+    }
+
+    /// Close a client speaking an unknown protocol without any reply
+    ///
+    /// Bytes already received are discarded first, so the client sees a FIN instead of a RST.
+    async fn close_unsupported_version(
+        context: &ServiceContext,
+        conn_id: usize,
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        version: u8,
+    ) {
+        debug!(
+            "[c{}] closed connection from {} with unsupported socks version {:#04x}",
+            conn_id, peer_addr, version
+        );
+        if let Some(metrics) = context.metrics() {
+            metrics.incr_socks_unsupported_version();
+        }
+
+        let mut buffer = [0u8; 512];
+        let mut discarded = 0;
+        while discarded < MAXIMUM_DISCARD_SIZE {
+            match stream.try_read(&mut buffer) {
+                Ok(0) | Err(..) => break,
+                Ok(n) => discarded += n,
+            }
+        }
+        let _ = stream.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::relay::socks5::{self, HandshakeRequest, HandshakeResponse};
+    use tokio::io::AsyncReadExt;
+
+    use crate::{
+        local::{loadbalancing::PingBalancerBuilder, metrics::LocalMetrics},
+        test_utils::bind_listener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tcp_allowed_clients() {
+        let start_proxy = |allowed_clients: &str| {
+            let allowed_clients = AllowedClients::new(vec![allowed_clients.parse().unwrap()]);
+            async move {
+                let listener = bind_listener().await;
+                let proxy_addr = listener.local_addr().unwrap();
+
+                let context = Arc::new(ServiceContext::new());
+                let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+                    .build()
+                    .await
+                    .unwrap();
+                let mut server = Socks::with_context(context);
+                server.set_allowed_clients(allowed_clients);
+                tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+                proxy_addr
+            }
+        };
+
+        let proxy_addr = start_proxy("127.0.0.0/8").await;
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE])
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+        // Closed without any response
+        let proxy_addr = start_proxy("10.0.0.0/8").await;
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE])
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        let mut buffer = [0u8; 16];
+        let n = time::timeout(Duration::from_secs(5), stream.read(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+    }
 
-        let udp_bind_addr = self.udp_bind_addr.as_ref().unwrap_or(client_config);
-        server.run(udp_bind_addr, balancer).await
+    #[tokio::test]
+    async fn unsupported_version_closed_quietly() {
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_metrics(Arc::new(LocalMetrics::new()));
+        let context = Arc::new(context);
+
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let server = Socks::with_context(context.clone());
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        // SOCKS3 doesn't exist, may be a scanner
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream.write_all(&[0x03, 0x01, 0x00, 0x50]).await.unwrap();
+
+        // Closed without any reply
+        let mut buf = Vec::new();
+        let n = time::timeout(Duration::from_secs(2), stream.read_to_end(&mut buf))
+            .await
+            .expect("connection isn't closed")
+            .expect("connection isn't closed gracefully");
+        assert_eq!(n, 0, "replied {:?}", buf);
+
+        assert_eq!(context.metrics().unwrap().socks_unsupported_version(), 1);
     }
 }
@@ -17,7 +17,9 @@ use crate::local::{
     context::ServiceContext,
     loadbalancing::PingBalancer,
     net::AutoProxyClientStream,
-    utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+    relay_error::RelayErrorKind,
+    trace_span::SpanEventKind,
+    utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed, establish_tcp_tunnel_with_failover},
 };
 
 use crate::local::socks::socks4::{
@@ -31,20 +33,26 @@ use crate::local::socks::socks4::{
 
 pub struct Socks4TcpHandler {
     context: Arc<ServiceContext>,
+    conn_id: usize,
     balancer: PingBalancer,
     mode: Mode,
 }
 
 impl Socks4TcpHandler {
-    pub fn new(context: Arc<ServiceContext>, balancer: PingBalancer, mode: Mode) -> Socks4TcpHandler {
+    /// Create a handler of the accepted connection `conn_id`
+    pub fn new(context: Arc<ServiceContext>, conn_id: usize, balancer: PingBalancer, mode: Mode) -> Socks4TcpHandler {
         Socks4TcpHandler {
             context,
+            conn_id,
             balancer,
             mode,
         }
     }
 
     pub async fn handle_socks4_client(self, stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+        // Shutting down waits for handshakes
+        let _active = self.context.track_active_connection();
+
         // 1. Handshake
 
         // NOTE: Wraps it with BufReader for reading NULL terminated information in HandshakeRequest
@@ -52,25 +60,36 @@ impl Socks4TcpHandler {
         let handshake_req = match HandshakeRequest::read_from(&mut s).await {
             Ok(r) => r,
             Err(Socks4Error::IoError(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
-                trace!("socks4 handshake early eof. peer: {}", peer_addr);
+                trace!("[c{}] socks4 handshake early eof. peer: {}", self.conn_id, peer_addr);
+                return Ok(());
+            }
+            Err(Socks4Error::UnsupportedCommand(cd)) => {
+                warn!(
+                    "[c{}] socks4 command {:#x} is not supported, peer: {}",
+                    self.conn_id, cd, peer_addr
+                );
+
+                let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedOrFailed);
+                handshake_rsp.write_to(&mut s).await?;
+
                 return Ok(());
             }
             Err(err) => {
-                error!("socks4 handshake error: {}", err);
+                error!("[c{}] socks4 handshake error: {}", self.conn_id, err);
                 return Err(err.into());
             }
         };
 
-        trace!("socks4 {:?} peer: {}", handshake_req, peer_addr);
+        trace!("[c{}] socks4 {:?} peer: {}", self.conn_id, handshake_req, peer_addr);
 
         match handshake_req.cd {
             Command::Connect => {
-                debug!("CONNECT {}", handshake_req.dst);
+                debug!("[c{}] CONNECT {}", self.conn_id, handshake_req.dst);
 
                 self.handle_socks4_connect(s, peer_addr, handshake_req.dst).await
             }
             Command::Bind => {
-                warn!("BIND is not supported");
+                warn!("[c{}] BIND is not supported", self.conn_id);
 
                 let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedOrFailed);
                 handshake_rsp.write_to(&mut s).await?;
@@ -87,7 +106,19 @@ impl Socks4TcpHandler {
         target_addr: Address,
     ) -> io::Result<()> {
         if !self.mode.enable_tcp() {
-            warn!("TCP CONNECT is disabled");
+            warn!("[c{}] TCP CONNECT is disabled", self.conn_id);
+
+            let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedOrFailed);
+            handshake_rsp.write_to(&mut stream).await?;
+
+            return Ok(());
+        }
+
+        if self.context.is_draining() {
+            debug!(
+                "[c{}] socks4 CONNECT {} refused, shutting down, peer: {}",
+                self.conn_id, target_addr, peer_addr
+            );
 
             let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedOrFailed);
             handshake_rsp.write_to(&mut stream).await?;
@@ -96,29 +127,55 @@ impl Socks4TcpHandler {
         }
 
         let target_addr = target_addr.into();
-        let mut server_opt = None;
-        let server_result = if self.balancer.is_empty() {
-            AutoProxyClientStream::connect_bypassed(self.context, &target_addr).await
-        } else {
-            let server = self.balancer.best_tcp_server();
+        let conn_id = self.conn_id;
+
+        // Hold the slot until the tunnel finishes
+        let _host_guard = match self.context.acquire_host_connection(&target_addr) {
+            Ok(g) => g,
+            Err(err) => {
+                warn!("[c{}] socks4 client {} rejected, {}", self.conn_id, peer_addr, err);
+
+                let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedOrFailed);
+                handshake_rsp.write_to(&mut stream).await?;
+
+                return Ok(());
+            }
+        };
 
-            let r = AutoProxyClientStream::connect(self.context, &server, &target_addr).await;
-            server_opt = Some(server);
+        self.context
+            .emit_span_event(conn_id, SpanEventKind::ConnectStart, peer_addr, &target_addr);
 
-            r
+        let mut server_opt = None;
+        let server_result = match self.balancer.pick_tcp_server(conn_id) {
+            Ok(None) => AutoProxyClientStream::connect_bypassed(self.context.clone(), &target_addr).await,
+            Ok(Some(server)) => {
+                let r =
+                    AutoProxyClientStream::connect_with_peer(self.context.clone(), &server, &target_addr, peer_addr)
+                        .await;
+                server_opt = Some(server);
+
+                r
+            }
+            Err(err) => Err(err),
         };
 
+        self.context
+            .emit_span_event(conn_id, SpanEventKind::ConnectEnd, peer_addr, &target_addr);
+
         let mut remote = match server_result {
             Ok(remote) => {
                 // Tell the client that we are ready
                 let handshake_rsp = HandshakeResponse::new(ResultCode::RequestGranted);
                 handshake_rsp.write_to(&mut stream).await?;
 
-                trace!("sent header: {:?}", handshake_rsp);
+                trace!("[c{}] sent header: {:?}", self.conn_id, handshake_rsp);
 
                 remote
             }
             Err(err) => {
+                self.context
+                    .report_relay_error(conn_id, RelayErrorKind::Connect, peer_addr, &target_addr, &err);
+
                 let result_code = match err.kind() {
                     ErrorKind::ConnectionRefused => ResultCode::RequestRejectedCannotConnect,
                     ErrorKind::ConnectionAborted => ResultCode::RequestRejectedCannotConnect,
@@ -134,7 +191,8 @@ impl Socks4TcpHandler {
 
         // NOTE: Transfer all buffered data before unwrap, or these data will be lost
         let buffer = stream.buffer();
-        if !buffer.is_empty() {
+        let early_data = !buffer.is_empty();
+        if early_data {
             remote.write_all(buffer).await?;
         }
 
@@ -142,11 +200,85 @@ impl Socks4TcpHandler {
         let mut stream = stream.into_inner();
 
         match server_opt {
+            Some(server) if !early_data => {
+                establish_tcp_tunnel_with_failover(
+                    &self.context,
+                    conn_id,
+                    &self.balancer,
+                    &server,
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &target_addr,
+                )
+                .await
+            }
+            // Buffered data has been written to the server, the tunnel can't be failed over
             Some(server) => {
-                let svr_cfg = server.server_config();
-                establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+                establish_tcp_tunnel(
+                    &self.context,
+                    conn_id,
+                    &server,
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &target_addr,
+                )
+                .await
             }
-            None => establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, &target_addr).await,
+            None => {
+                establish_tcp_tunnel_bypassed(
+                    &self.context,
+                    conn_id,
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &target_addr,
+                )
+                .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use crate::{
+        local::{loadbalancing::PingBalancerBuilder, socks::server::Socks},
+        test_utils::bind_listener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reject_non_connect_commands() {
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let server = Socks::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        // BIND, and an unknown command 0x03
+        for cd in [0x02, 0x03] {
+            let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+
+            // VN CD DSTPORT DSTIP USERID NULL
+            stream
+                .write_all(&[0x04, cd, 0x00, 0x50, 127, 0, 0, 1, b'u', 0x00])
+                .await
+                .unwrap();
+
+            let mut rsp = [0u8; 8];
+            stream.read_exact(&mut rsp).await.unwrap();
+            assert_eq!(rsp[0], 0x00);
+            assert_eq!(rsp[1], 0x5B, "command {:#x}", cd);
         }
     }
 }
@@ -1,6 +1,11 @@
 //! SOCKS5 Local Server
 
-pub use self::{tcprelay::Socks5TcpHandler, udprelay::Socks5UdpServer};
+pub use self::{
+    tcprelay::Socks5TcpHandler,
+    udp_clients::UdpAssociateClients,
+    udprelay::{Socks5UdpServer, UdpRelayAddrs},
+};
 
 mod tcprelay;
+mod udp_clients;
 mod udprelay;
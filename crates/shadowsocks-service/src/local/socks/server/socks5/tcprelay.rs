@@ -2,9 +2,10 @@
 
 use std::{
     io::{self, ErrorKind},
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     str,
     sync::Arc,
+    time::Duration,
 };
 
 use log::{debug, error, trace, warn};
@@ -25,41 +26,68 @@ use shadowsocks::{
     },
     ServerAddr,
 };
-use tokio::net::TcpStream;
+#[cfg(feature = "local-socks5-bind")]
+use tokio::net::TcpListener;
+use tokio::{net::TcpStream, time};
 
 use crate::{
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
         net::AutoProxyClientStream,
+        relay_error::RelayErrorKind,
         socks::config::Socks5AuthConfig,
-        utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+        trace_span::SpanEventKind,
+        utils::{establish_tcp_tunnel_bypassed, establish_tcp_tunnel_with_failover},
     },
-    net::utils::ignore_until_end,
+    net::{utils::ignore_until_end, worker_pool::release_worker},
 };
 
+use super::{UdpAssociateClients, UdpRelayAddrs};
+
+/// Time waiting for the first byte of lazy connected clients, unless `idle_timeout` is configured
+const LAZY_CONNECT_DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct Socks5TcpHandler {
     context: Arc<ServiceContext>,
-    udp_bind_addr: Option<Arc<ServerAddr>>,
+    conn_id: usize,
+    udp_bind_addr: Option<Arc<UdpRelayAddrs>>,
+    associate_clients: Option<Arc<UdpAssociateClients>>,
     balancer: PingBalancer,
     mode: Mode,
     auth: Arc<Socks5AuthConfig>,
+    request_timeout: Option<Duration>,
+    lazy_connect: bool,
+    #[cfg_attr(not(feature = "local-socks5-bind"), allow(dead_code))]
+    bind_timeout: Duration,
 }
 
 impl Socks5TcpHandler {
+    /// Create a handler of the accepted connection `conn_id`
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         context: Arc<ServiceContext>,
-        udp_bind_addr: Option<Arc<ServerAddr>>,
+        conn_id: usize,
+        udp_bind_addr: Option<Arc<UdpRelayAddrs>>,
+        associate_clients: Option<Arc<UdpAssociateClients>>,
         balancer: PingBalancer,
         mode: Mode,
         auth: Arc<Socks5AuthConfig>,
+        request_timeout: Option<Duration>,
+        lazy_connect: bool,
+        bind_timeout: Duration,
     ) -> Socks5TcpHandler {
         Socks5TcpHandler {
             context,
+            conn_id,
             udp_bind_addr,
+            associate_clients,
             balancer,
             mode,
             auth,
+            request_timeout,
+            lazy_connect,
+            bind_timeout,
         }
     }
 
@@ -72,24 +100,24 @@ impl Socks5TcpHandler {
             match *method {
                 socks5::SOCKS5_AUTH_METHOD_PASSWORD => {
                     let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_PASSWORD);
-                    trace!("reply handshake {:?}", resp);
+                    trace!("[c{}] reply handshake {:?}", self.conn_id, resp);
                     resp.write_to(stream).await?;
 
                     return self.check_auth_password(stream).await;
                 }
                 socks5::SOCKS5_AUTH_METHOD_NONE => {
                     if !allow_none {
-                        trace!("none authentication method is not allowed");
+                        trace!("[c{}] none authentication method is not allowed", self.conn_id);
                     } else {
                         let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NONE);
-                        trace!("reply handshake {:?}", resp);
+                        trace!("[c{}] reply handshake {:?}", self.conn_id, resp);
                         resp.write_to(stream).await?;
 
                         return Ok(());
                     }
                 }
                 _ => {
-                    trace!("unsupported authentication method {}", method);
+                    trace!("[c{}] unsupported authentication method {}", self.conn_id, method);
                 }
             }
         }
@@ -97,7 +125,7 @@ impl Socks5TcpHandler {
         let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
         resp.write_to(stream).await?;
 
-        trace!("reply handshake {:?}", resp);
+        trace!("[c{}] reply handshake {:?}", self.conn_id, resp);
 
         Err(Error::new(
             ErrorKind::Other,
@@ -153,7 +181,8 @@ impl Socks5TcpHandler {
 
         if self.auth.passwd.check_user(user_name, password) {
             trace!(
-                "socks5 authenticated with Username/Password method, user: {}, password: {}",
+                "[c{}] socks5 authenticated with Username/Password method, user: {}, password: {}",
+                self.conn_id,
                 user_name,
                 password
             );
@@ -167,8 +196,8 @@ impl Socks5TcpHandler {
             rsp.write_to(stream).await?;
 
             error!(
-                "socks5 rejected Username/Password user: {}, password: {}",
-                user_name, password
+                "[c{}] socks5 rejected Username/Password user: {}, password: {}",
+                self.conn_id, user_name, password
             );
 
             Err(Error::new(
@@ -182,52 +211,105 @@ impl Socks5TcpHandler {
     }
 
     pub async fn handle_socks5_client(self, mut stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+        // Shutting down waits for handshakes, and for the UDP ASSOCIATE control connections
+        let _active = self.context.track_active_connection();
+
         // 1. Handshake
 
         let handshake_req = match HandshakeRequest::read_from(&mut stream).await {
             Ok(r) => r,
             Err(Socks5Error::IoError(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
-                trace!("socks5 handshake early eof. peer: {}", peer_addr);
+                trace!("[c{}] socks5 handshake early eof. peer: {}", self.conn_id, peer_addr);
+                return Ok(());
+            }
+            Err(Socks5Error::IoError(ref err)) if err.kind() == ErrorKind::InvalidData => {
+                debug!(
+                    "[c{}] socks5 handshake rejected, {}, peer: {}",
+                    self.conn_id, err, peer_addr
+                );
+                let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+                let _ = resp.write_to(&mut stream).await;
                 return Ok(());
             }
             Err(err) => {
-                error!("socks5 handshake error: {}", err);
+                error!("[c{}] socks5 handshake error: {}", self.conn_id, err);
                 return Err(err.into());
             }
         };
 
-        trace!("socks5 {:?}", handshake_req);
+        trace!("[c{}] socks5 {:?}", self.conn_id, handshake_req);
+
+        let handshake_req = dedup_handshake_methods(handshake_req, peer_addr);
         self.check_auth(&mut stream, &handshake_req).await?;
 
         // 2. Fetch headers
-        let header = match TcpRequestHeader::read_from(&mut stream).await {
+        //
+        // Clients may send the request header right after the greeting, without waiting for the method selection
+        // reply. Messages are read with `read_exact` directly from the stream, so those early bytes are kept in the
+        // socket's buffer until they are read here. The same applies to the payload following the request header.
+        let header_result = match self.request_timeout {
+            None => TcpRequestHeader::read_from(&mut stream).await,
+            Some(timeout) => match time::timeout(timeout, TcpRequestHeader::read_from(&mut stream)).await {
+                Ok(r) => r,
+                Err(..) => {
+                    debug!(
+                        "[c{}] socks5 request is not received in {:?} after handshake, peer: {}",
+                        self.conn_id, timeout, peer_addr
+                    );
+                    return Ok(());
+                }
+            },
+        };
+
+        let header = match header_result {
             Ok(h) => h,
             Err(err) => {
-                error!("failed to get TcpRequestHeader: {}, peer: {}", err, peer_addr);
+                error!(
+                    "[c{}] failed to get TcpRequestHeader: {}, peer: {}",
+                    self.conn_id, err, peer_addr
+                );
                 let rh = TcpResponseHeader::new(err.as_reply(), Address::SocketAddress(peer_addr));
                 rh.write_to(&mut stream).await?;
                 return Err(err.into());
             }
         };
 
-        trace!("socks5 {:?} peer: {}", header, peer_addr);
+        trace!("[c{}] socks5 {:?} peer: {}", self.conn_id, header, peer_addr);
 
         let addr = header.address;
 
+        // Connections accepted before shutting down are answered instead of being dropped silently
+        if self.context.is_draining() {
+            debug!(
+                "[c{}] socks5 {:?} {} refused, shutting down, peer: {}",
+                self.conn_id, header.command, addr, peer_addr
+            );
+            let rh = TcpResponseHeader::new(socks5::Reply::GeneralFailure, addr);
+            rh.write_to(&mut stream).await?;
+            return Ok(());
+        }
+
         // 3. Handle Command
         match header.command {
             Command::TcpConnect => {
-                debug!("CONNECT {}", addr);
+                debug!("[c{}] CONNECT {}", self.conn_id, addr);
 
                 self.handle_tcp_connect(stream, peer_addr, addr).await
             }
             Command::UdpAssociate => {
-                debug!("UDP ASSOCIATE from {}", addr);
+                debug!("[c{}] UDP ASSOCIATE from {}", self.conn_id, addr);
+
+                self.handle_udp_associate(stream, peer_addr, addr).await
+            }
+            #[cfg(feature = "local-socks5-bind")]
+            Command::TcpBind => {
+                debug!("[c{}] BIND {}", self.conn_id, addr);
 
-                self.handle_udp_associate(stream, addr).await
+                self.handle_tcp_bind(stream, peer_addr, addr).await
             }
+            #[cfg(not(feature = "local-socks5-bind"))]
             Command::TcpBind => {
-                warn!("BIND is not supported");
+                warn!("[c{}] BIND is not supported", self.conn_id);
                 let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
                 rh.write_to(&mut stream).await?;
 
@@ -236,6 +318,182 @@ impl Socks5TcpHandler {
         }
     }
 
+    /// Handle BIND by listening on the address which the client connected to
+    ///
+    /// The first reply carries the listening address, and the second one carries the address of the incoming peer.
+    /// Peers connect to this host directly, the shadowsocks protocol cannot listen on the server. So BIND is only
+    /// allowed for `DST.ADDR` bypassed by the ACL, and only the peers connecting from `DST.ADDR` are accepted.
+    #[cfg(feature = "local-socks5-bind")]
+    async fn handle_tcp_bind(self, mut stream: TcpStream, peer_addr: SocketAddr, bind_req: Address) -> io::Result<()> {
+        if !self.mode.enable_tcp() {
+            warn!("[c{}] TCP BIND is disabled", self.conn_id);
+
+            let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, bind_req);
+            rh.write_to(&mut stream).await?;
+
+            return Ok(());
+        }
+
+        // Accepting peers locally exposes this host's address, which is only acceptable for bypassed targets
+        if !self.context.check_target_bypassed(&bind_req).await {
+            warn!(
+                "[c{}] socks5 BIND {} rejected, peers of proxied targets cannot be accepted through the server, client: {}",
+                self.conn_id, bind_req, peer_addr
+            );
+
+            let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, bind_req);
+            rh.write_to(&mut stream).await?;
+
+            return Ok(());
+        }
+
+        let expected_ips = match self.resolve_bind_peer_ips(&bind_req).await {
+            Ok(ips) => ips,
+            Err(err) => {
+                warn!(
+                    "[c{}] socks5 BIND failed to resolve {}, error: {}",
+                    self.conn_id, bind_req, err
+                );
+
+                let rh = TcpResponseHeader::new(socks5::Reply::HostUnreachable, bind_req);
+                rh.write_to(&mut stream).await?;
+
+                return Ok(());
+            }
+        };
+
+        let listen_addr = SocketAddr::new(stream.local_addr()?.ip(), 0);
+        let listener = match TcpListener::bind(listen_addr).await {
+            Ok(l) => l,
+            Err(err) => {
+                error!(
+                    "[c{}] socks5 BIND failed to listen on {}, error: {}",
+                    self.conn_id, listen_addr, err
+                );
+
+                let dummy_address = reply_bind_addr(&bind_req, SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+                let rh = TcpResponseHeader::new(socks5::Reply::GeneralFailure, Address::SocketAddress(dummy_address));
+                rh.write_to(&mut stream).await?;
+
+                return Err(err);
+            }
+        };
+
+        // 1st reply, where the peer should connect to
+        let bind_addr = reply_bind_addr(&bind_req, listener.local_addr()?);
+        let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(bind_addr));
+        rh.write_to(&mut stream).await?;
+
+        trace!(
+            "[c{}] socks5 BIND listening on {}, peer: {}",
+            self.conn_id,
+            bind_addr,
+            peer_addr
+        );
+
+        // Accepts the first peer connecting from DST.ADDR, others are closed immediately
+        let accept_peer = async {
+            loop {
+                let (remote, remote_addr) = listener.accept().await?;
+                if expected_ips.contains(&remote_addr.ip().to_canonical()) {
+                    return Ok((remote, remote_addr));
+                }
+
+                warn!(
+                    "[c{}] socks5 BIND {} rejected peer {}, expecting {}",
+                    self.conn_id, bind_addr, remote_addr, bind_req
+                );
+            }
+        };
+
+        // Wait for the peer. Stops listening if the client leaves first, or the peer doesn't come in time
+        let mut buf = [0u8; 1];
+        let wait_peer = async {
+            tokio::pin!(accept_peer);
+            tokio::select! {
+                r = &mut accept_peer => Some(r),
+                r = stream.peek(&mut buf) => match r {
+                    Ok(0) | Err(..) => None,
+                    // Data before the 2nd reply is kept in the socket for the relay
+                    Ok(..) => Some(accept_peer.await),
+                },
+            }
+        };
+
+        let accept_result: io::Result<(TcpStream, SocketAddr)> = match time::timeout(self.bind_timeout, wait_peer).await
+        {
+            Ok(Some(r)) => r,
+            Ok(None) => {
+                debug!(
+                    "[c{}] socks5 BIND client {} closed before peer connected",
+                    self.conn_id, peer_addr
+                );
+                return Ok(());
+            }
+            Err(..) => {
+                debug!(
+                    "[c{}] socks5 BIND {} no peer connected in {:?}, peer: {}",
+                    self.conn_id, bind_addr, self.bind_timeout, peer_addr
+                );
+                drop(listener);
+
+                let rh = TcpResponseHeader::new(socks5::Reply::TtlExpired, Address::SocketAddress(bind_addr));
+                rh.write_to(&mut stream).await?;
+
+                return Ok(());
+            }
+        };
+        drop(listener);
+
+        let (mut remote, remote_addr) = match accept_result {
+            Ok(r) => r,
+            Err(err) => {
+                error!(
+                    "[c{}] socks5 BIND failed to accept on {}, error: {}",
+                    self.conn_id, bind_addr, err
+                );
+
+                let rh = TcpResponseHeader::new(socks5::Reply::GeneralFailure, Address::SocketAddress(bind_addr));
+                rh.write_to(&mut stream).await?;
+
+                return Err(err);
+            }
+        };
+
+        // 2nd reply, who has connected
+        let remote_addr = Address::SocketAddress(remote_addr);
+        let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, remote_addr.clone());
+        rh.write_to(&mut stream).await?;
+
+        trace!("[c{}] socks5 BIND {} accepted {}", self.conn_id, bind_addr, remote_addr);
+
+        let conn_id = self.conn_id;
+        establish_tcp_tunnel_bypassed(
+            &self.context,
+            conn_id,
+            &mut stream,
+            &mut remote,
+            peer_addr,
+            &remote_addr,
+        )
+        .await
+    }
+
+    /// IPs that BIND peers are allowed to connect from, `DST.ADDR`'s port is ignored
+    #[cfg(feature = "local-socks5-bind")]
+    async fn resolve_bind_peer_ips(&self, bind_req: &Address) -> io::Result<Vec<IpAddr>> {
+        match *bind_req {
+            Address::SocketAddress(ref sa) => Ok(vec![sa.ip().to_canonical()]),
+            Address::DomainNameAddress(ref host, port) => Ok(self
+                .context
+                .context_ref()
+                .dns_resolve(host, port)
+                .await?
+                .map(|sa| sa.ip().to_canonical())
+                .collect()),
+        }
+    }
+
     async fn handle_tcp_connect(
         self,
         mut stream: TcpStream,
@@ -243,7 +501,7 @@ impl Socks5TcpHandler {
         target_addr: Address,
     ) -> io::Result<()> {
         if !self.mode.enable_tcp() {
-            warn!("TCP CONNECT is disabled");
+            warn!("[c{}] TCP CONNECT is disabled", self.conn_id);
 
             let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, target_addr);
             rh.write_to(&mut stream).await?;
@@ -251,37 +509,102 @@ impl Socks5TcpHandler {
             return Ok(());
         }
 
-        let mut server_opt = None;
-        let remote_result = if self.balancer.is_empty() {
-            AutoProxyClientStream::connect_bypassed(self.context.clone(), &target_addr).await
-        } else {
-            let server = self.balancer.best_tcp_server();
+        let conn_id = self.conn_id;
+
+        // Hold the slot until the tunnel finishes
+        let _host_guard = match self.context.acquire_host_connection(&target_addr) {
+            Ok(g) => g,
+            Err(err) => {
+                warn!("[c{}] socks5 client {} rejected, {}", self.conn_id, peer_addr, err);
+
+                let rh = TcpResponseHeader::new(socks5::Reply::ConnectionNotAllowed, target_addr);
+                rh.write_to(&mut stream).await?;
+
+                return Ok(());
+            }
+        };
+
+        if self.lazy_connect {
+            // Tell the client that we are ready before connecting, the bound address is unknown yet
+            let bind_addr = reply_bind_addr(&target_addr, SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+            let header = TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(bind_addr));
+            header.write_to(&mut stream).await?;
+
+            trace!("[c{}] sent header: {:?}", self.conn_id, header);
+
+            // Connect after the client sends its first byte
+            let timeout = self.context.idle_timeout().unwrap_or(LAZY_CONNECT_DEFAULT_TIMEOUT);
+            let mut buffer = [0u8; 1];
+            match time::timeout(timeout, stream.peek(&mut buffer)).await {
+                Ok(Ok(0)) => {
+                    trace!(
+                        "[c{}] socks5 client {} closed before sending anything to {}",
+                        self.conn_id,
+                        peer_addr,
+                        target_addr
+                    );
+                    return Ok(());
+                }
+                Ok(Ok(..)) => {}
+                Ok(Err(err)) => return Err(err),
+                Err(..) => {
+                    debug!(
+                        "[c{}] socks5 client {} sent nothing to {} in {:?}",
+                        self.conn_id, peer_addr, target_addr, timeout
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        self.context
+            .emit_span_event(conn_id, SpanEventKind::ConnectStart, peer_addr, &target_addr);
 
-            let r = AutoProxyClientStream::connect(self.context.clone(), &server, &target_addr).await;
-            server_opt = Some(server);
+        let mut server_opt = None;
+        let remote_result = match self.balancer.pick_tcp_server(conn_id) {
+            Ok(None) => AutoProxyClientStream::connect_bypassed(self.context.clone(), &target_addr).await,
+            Ok(Some(server)) => {
+                let r =
+                    AutoProxyClientStream::connect_with_peer(self.context.clone(), &server, &target_addr, peer_addr)
+                        .await;
+                server_opt = Some(server);
 
-            r
+                r
+            }
+            Err(err) => Err(err),
         };
 
+        self.context
+            .emit_span_event(conn_id, SpanEventKind::ConnectEnd, peer_addr, &target_addr);
+
         let mut remote = match remote_result {
+            Ok(remote) if self.lazy_connect => remote,
             Ok(remote) => {
                 // Tell the client that we are ready
-                let header =
-                    TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(remote.local_addr()?));
+                let bind_addr = reply_bind_addr(&target_addr, remote.local_addr()?);
+                let header = TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(bind_addr));
                 header.write_to(&mut stream).await?;
 
-                trace!("sent header: {:?}", header);
+                trace!("[c{}] sent header: {:?}", self.conn_id, header);
 
                 remote
             }
             Err(err) => {
+                self.context
+                    .report_relay_error(conn_id, RelayErrorKind::Connect, peer_addr, &target_addr, &err);
+
+                if self.lazy_connect {
+                    // Success has been replied, close the client's connection
+                    return Err(err);
+                }
+
                 let reply = match err.kind() {
                     ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
                     ErrorKind::ConnectionAborted => Reply::HostUnreachable,
                     _ => Reply::NetworkUnreachable,
                 };
 
-                let dummy_address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+                let dummy_address = reply_bind_addr(&target_addr, SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
                 let header = TcpResponseHeader::new(reply, Address::SocketAddress(dummy_address));
                 header.write_to(&mut stream).await?;
 
@@ -291,30 +614,66 @@ impl Socks5TcpHandler {
 
         match server_opt {
             Some(server) => {
-                let svr_cfg = server.server_config();
-                establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+                establish_tcp_tunnel_with_failover(
+                    &self.context,
+                    conn_id,
+                    &self.balancer,
+                    &server,
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &target_addr,
+                )
+                .await
+            }
+            None => {
+                establish_tcp_tunnel_bypassed(
+                    &self.context,
+                    conn_id,
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &target_addr,
+                )
+                .await
             }
-            None => establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, &target_addr).await,
         }
     }
 
-    async fn handle_udp_associate(self, mut stream: TcpStream, client_addr: Address) -> io::Result<()> {
+    async fn handle_udp_associate(
+        self,
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        client_addr: Address,
+    ) -> io::Result<()> {
         match self.udp_bind_addr {
             None => {
-                warn!("socks5 udp is disabled");
+                warn!("[c{}] socks5 udp is disabled", self.conn_id);
 
                 let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, client_addr);
                 rh.write_to(&mut stream).await?;
 
                 Ok(())
             }
-            Some(bind_addr) => {
+            Some(relay_addrs) => {
                 // shadowsocks accepts both TCP and UDP from the same address
+                let bind_addr = match relay_addrs.bind_addr {
+                    ServerAddr::SocketAddr(sa) => {
+                        let control_local_addr = stream.local_addr()?;
+                        let sa = select_udp_relay_addr(sa, relay_addrs.other_family_addr, control_local_addr);
+                        Address::SocketAddress(reply_udp_bind_addr(sa, control_local_addr))
+                    }
+                    ref addr => addr.into(),
+                };
+
+                // Datagrams from this client are relayed until the control connection is closed
+                let _client_guard = self.associate_clients.as_ref().map(|c| c.register(peer_addr.ip()));
 
-                let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, bind_addr.as_ref().into());
+                let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, bind_addr);
                 rh.write_to(&mut stream).await?;
 
                 // Hold connection until EOF.
+                release_worker();
                 let _ = ignore_until_end(&mut stream).await;
 
                 Ok(())
@@ -322,3 +681,653 @@ impl Socks5TcpHandler {
         }
     }
 }
+
+/// UDP relay's socket in the same family of the control connection, `udp_addr` if none of them matches
+fn select_udp_relay_addr(
+    udp_addr: SocketAddr,
+    other_family_addr: Option<SocketAddr>,
+    control_local_addr: SocketAddr,
+) -> SocketAddr {
+    let control_is_ipv4 = match control_local_addr.ip() {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().is_some(),
+        IpAddr::V4(..) => true,
+    };
+
+    match other_family_addr {
+        Some(other) if udp_addr.is_ipv4() != control_is_ipv4 && other.is_ipv4() == control_is_ipv4 => other,
+        _ => udp_addr,
+    }
+}
+
+/// UDP relay's address replied to UDP ASSOCIATE
+///
+/// If the UDP socket is bound to an unspecified address, clients should send datagrams to the local address of the
+/// control connection, which is known to be reachable.
+fn reply_udp_bind_addr(udp_addr: SocketAddr, control_local_addr: SocketAddr) -> SocketAddr {
+    if !udp_addr.ip().is_unspecified() {
+        return udp_addr;
+    }
+
+    let local_ip = match control_local_addr.ip() {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        ip => ip,
+    };
+
+    match (udp_addr.ip(), local_ip) {
+        // IPv4 socket cannot be reached with IPv6 address
+        (IpAddr::V4(..), IpAddr::V6(..)) => udp_addr,
+        (_, ip) => SocketAddr::new(ip, udp_addr.port()),
+    }
+}
+
+/// Remove duplicated methods in the greeting, keeping the client's order of preference
+fn dedup_handshake_methods(handshake_req: HandshakeRequest, peer_addr: SocketAddr) -> HandshakeRequest {
+    let mut methods = Vec::with_capacity(handshake_req.methods.len());
+    for method in handshake_req.methods.iter() {
+        if !methods.contains(method) {
+            methods.push(*method);
+        }
+    }
+
+    if methods.len() == handshake_req.methods.len() {
+        return handshake_req;
+    }
+
+    debug!(
+        "socks5 handshake offers duplicated methods {:?}, peer: {}",
+        handshake_req.methods, peer_addr
+    );
+    HandshakeRequest::new(methods)
+}
+
+/// Address replied to clients as `BND.ADDR`, in the same family of the requested address
+///
+/// Some clients reject replies in a different family. IPv4-mapped IPv6 addresses are converted back to IPv4,
+/// otherwise the unspecified address of the requested family is used as a placeholder.
+fn reply_bind_addr(target_addr: &Address, bind_addr: SocketAddr) -> SocketAddr {
+    let ip = match (target_addr, bind_addr.ip()) {
+        (Address::SocketAddress(SocketAddr::V4(..)), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        },
+        (Address::SocketAddress(SocketAddr::V6(..)), IpAddr::V4(ip)) => IpAddr::V6(ip.to_ipv6_mapped()),
+        (_, ip) => ip,
+    };
+    SocketAddr::new(ip, bind_addr.port())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv6Addr, time::Duration};
+
+    use log::LevelFilter;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time,
+    };
+
+    use crate::{
+        local::{loadbalancing::PingBalancerBuilder, socks::server::Socks},
+        test_utils::{bind_listener, capture_logs, captured_conn_logs},
+    };
+
+    use super::*;
+
+    #[test]
+    fn reply_bind_addr_family() {
+        let v4_target = Address::SocketAddress("93.184.216.34:80".parse().unwrap());
+        let v6_target = Address::SocketAddress("[2606:2800:220:1::1]:80".parse().unwrap());
+        let domain_target = Address::DomainNameAddress("example.com".to_owned(), 80);
+
+        // IPv4 request, but connected from an IPv6 socket
+        let v6_bind = "[2001:db8::2]:50000".parse().unwrap();
+        assert_eq!(
+            reply_bind_addr(&v4_target, v6_bind),
+            "0.0.0.0:50000".parse::<SocketAddr>().unwrap()
+        );
+        let mapped_bind = "[::ffff:192.168.1.2]:50000".parse().unwrap();
+        assert_eq!(
+            reply_bind_addr(&v4_target, mapped_bind),
+            "192.168.1.2:50000".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(reply_bind_addr(&v6_target, v6_bind), v6_bind);
+        assert_eq!(reply_bind_addr(&domain_target, v6_bind), v6_bind);
+
+        let v4_bind = "192.168.1.2:50000".parse().unwrap();
+        assert_eq!(reply_bind_addr(&v4_target, v4_bind), v4_bind);
+        assert_eq!(reply_bind_addr(&v6_target, v4_bind), mapped_bind);
+        assert_eq!(
+            reply_bind_addr(&v6_target, SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)),
+            "[::]:0".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_with_early_data() {
+        let target = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let server = Socks::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+
+        // Greeting, request header and payload in one write
+        let mut buf = Vec::new();
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]).write_to_buf(&mut buf);
+        TcpRequestHeader::new(Command::TcpConnect, Address::SocketAddress(target_addr)).write_to_buf(&mut buf);
+        buf.extend_from_slice(b"early data");
+        stream.write_all(&buf).await.unwrap();
+
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+
+        let (mut remote, _) = target.accept().await.unwrap();
+        let mut payload = [0u8; 10];
+        remote.read_exact(&mut payload).await.unwrap();
+        assert_eq!(&payload, b"early data");
+    }
+
+    #[tokio::test]
+    async fn lazy_connect_replies_before_connecting() {
+        let target = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let mut server = Socks::with_context(context);
+        server.set_socks5_lazy_connect(true);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let mut buf = Vec::new();
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]).write_to_buf(&mut buf);
+        TcpRequestHeader::new(Command::TcpConnect, Address::SocketAddress(target_addr)).write_to_buf(&mut buf);
+        stream.write_all(&buf).await.unwrap();
+
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+
+        // Target is not connected until the client sends something
+        let accepted = time::timeout(Duration::from_millis(100), target.accept()).await;
+        assert!(accepted.is_err(), "target connected before the first byte");
+
+        stream.write_all(b"hello").await.unwrap();
+        let (mut remote, _) = target.accept().await.unwrap();
+        let mut payload = [0u8; 5];
+        remote.read_exact(&mut payload).await.unwrap();
+        assert_eq!(&payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn lazy_connect_idle_client_closed() {
+        let target = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_idle_timeout(Duration::from_millis(100));
+        let context = Arc::new(context);
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let mut server = Socks::with_context(context);
+        server.set_socks5_lazy_connect(true);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let mut buf = Vec::new();
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]).write_to_buf(&mut buf);
+        TcpRequestHeader::new(Command::TcpConnect, Address::SocketAddress(target_addr)).write_to_buf(&mut buf);
+        stream.write_all(&buf).await.unwrap();
+
+        HandshakeResponse::read_from(&mut stream).await.unwrap();
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+
+        // Clients sending nothing are disconnected, without connecting to the target
+        let mut buf = [0u8; 1];
+        let n = time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .expect("idle client is still connected")
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+        let accepted = time::timeout(Duration::from_millis(50), target.accept()).await;
+        assert!(accepted.is_err(), "target connected without any data");
+    }
+
+    #[tokio::test]
+    async fn connection_id_in_logs() {
+        capture_logs(LevelFilter::Trace);
+
+        let target = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let proxy_addr = start_socks5_server().await;
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let peer_addr = stream.local_addr().unwrap();
+
+        let mut buf = Vec::new();
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]).write_to_buf(&mut buf);
+        TcpRequestHeader::new(Command::TcpConnect, Address::SocketAddress(target_addr)).write_to_buf(&mut buf);
+        stream.write_all(&buf).await.unwrap();
+
+        HandshakeResponse::read_from(&mut stream).await.unwrap();
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+
+        let (remote, _) = target.accept().await.unwrap();
+        drop(remote);
+        drop(stream);
+
+        // Logs of the handshake and the tunnel are prefixed with the same id, the first one of the server
+        let connect = format!("CONNECT {}", target_addr);
+        let closed = format!("tcp tunnel {} <-> {} (bypassed) closed", peer_addr, target_addr);
+        time::timeout(Duration::from_secs(5), async {
+            loop {
+                let logs = captured_conn_logs(0);
+                if logs.iter().any(|(log, _)| *log == connect) && logs.iter().any(|(log, _)| log.starts_with(&closed)) {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no logs of the connection");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn accept_paused_after_fd_exhausted() {
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_fd_exhausted_pause(Duration::from_millis(300));
+        let context = Arc::new(context);
+        context.report_fd_exhausted(&io::Error::from_raw_os_error(libc::EMFILE));
+
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let server = Socks::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut buf = Vec::new();
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]).write_to_buf(&mut buf);
+        stream.write_all(&buf).await.unwrap();
+
+        // Connection waits in the backlog until the pause ends
+        let rsp = time::timeout(Duration::from_millis(150), HandshakeResponse::read_from(&mut stream)).await;
+        assert!(rsp.is_err(), "accepted while paused");
+
+        let rsp = time::timeout(Duration::from_secs(2), HandshakeResponse::read_from(&mut stream))
+            .await
+            .expect("not accepted after the pause")
+            .unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+    }
+
+    async fn start_socks5_server() -> SocketAddr {
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let server = Socks::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        proxy_addr
+    }
+
+    #[tokio::test]
+    async fn draining_waits_for_handshake() {
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let server = Socks::with_context(context.clone());
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let handshake_req = HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
+        handshake_req.write_to(&mut stream).await.unwrap();
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+        let drain = context.drain(Duration::from_secs(5));
+        tokio::pin!(drain);
+        assert!(time::timeout(Duration::from_millis(200), &mut drain).await.is_err());
+
+        // The request in the middle of the handshake is refused, instead of being dropped
+        let target_addr = Address::SocketAddress("127.0.0.1:80".parse().unwrap());
+        let header = TcpRequestHeader::new(Command::TcpConnect, target_addr);
+        header.write_to(&mut stream).await.unwrap();
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::GeneralFailure), "reply {:?}", rh.reply);
+
+        time::timeout(Duration::from_secs(2), drain)
+            .await
+            .expect("drain didn't finish");
+        assert_eq!(context.active_connections(), 0);
+    }
+
+    #[test]
+    fn dedup_duplicated_methods() {
+        let peer_addr = "127.0.0.1:10000".parse().unwrap();
+
+        let req = HandshakeRequest::new(vec![
+            socks5::SOCKS5_AUTH_METHOD_PASSWORD,
+            socks5::SOCKS5_AUTH_METHOD_NONE,
+            socks5::SOCKS5_AUTH_METHOD_PASSWORD,
+            socks5::SOCKS5_AUTH_METHOD_NONE,
+        ]);
+        let req = dedup_handshake_methods(req, peer_addr);
+        assert_eq!(
+            req.methods,
+            [socks5::SOCKS5_AUTH_METHOD_PASSWORD, socks5::SOCKS5_AUTH_METHOD_NONE]
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_duplicated_methods() {
+        let proxy_addr = start_socks5_server().await;
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+
+        stream.write_all(&[0x05, 3, 0, 0, 0]).await.unwrap();
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+        // The duplicates are not taken as the request header
+        let target = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        TcpRequestHeader::new(
+            Command::TcpConnect,
+            Address::SocketAddress(target.local_addr().unwrap()),
+        )
+        .write_to(&mut stream)
+        .await
+        .unwrap();
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+    }
+
+    #[tokio::test]
+    async fn handshake_length_mismatched() {
+        let proxy_addr = start_socks5_server().await;
+
+        // NMETHODS is 3, but only 1 method is sent
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream.write_all(&[0x05, 3, 0]).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+        // NMETHODS is 0
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream.write_all(&[0x05, 0]).await.unwrap();
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn request_timeout_after_handshake() {
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let mut server = Socks::with_context(context);
+        server.set_socks5_request_timeout(Duration::from_millis(100));
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+
+        // Handshake, then idle without sending the request
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE])
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+        let mut buf = [0u8; 1];
+        let n = time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .expect("idle client is still connected")
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+    }
+
+    #[cfg(feature = "local-socks5-bind")]
+    async fn start_bind_request() -> TcpStream {
+        start_bind_request_with_timeout(None).await
+    }
+
+    #[cfg(feature = "local-socks5-bind")]
+    async fn start_bind_request_with_timeout(bind_timeout: Option<Duration>) -> TcpStream {
+        start_bind_request_with_acl(bind_timeout, "[proxy_all]\n[bypass_list]\n127.0.0.0/8\n").await
+    }
+
+    #[cfg(feature = "local-socks5-bind")]
+    async fn start_bind_request_with_acl(bind_timeout: Option<Duration>, acl: &str) -> TcpStream {
+        use std::{fs, process};
+
+        use crate::acl::AccessControl;
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let acl_path =
+            std::env::temp_dir().join(format!("shadowsocks-bind-{}-{}.acl", process::id(), proxy_addr.port()));
+        fs::write(&acl_path, acl).unwrap();
+        let acl = AccessControl::load_from_file(&acl_path).unwrap();
+        fs::remove_file(&acl_path).unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_acl(acl);
+        let context = Arc::new(context);
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let mut server = Socks::with_context(context);
+        if let Some(timeout) = bind_timeout {
+            server.set_socks5_bind_timeout(timeout);
+        }
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE])
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+        let expected_peer = Address::SocketAddress(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0));
+        TcpRequestHeader::new(Command::TcpBind, expected_peer)
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+
+        stream
+    }
+
+    #[cfg(feature = "local-socks5-bind")]
+    #[tokio::test]
+    async fn bind_replies_and_relay() {
+        let mut stream = start_bind_request().await;
+
+        // 1st reply, before any peer connects
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+        let bind_addr = match rh.address {
+            Address::SocketAddress(a) => a,
+            a => panic!("unexpected bind address {}", a),
+        };
+        assert_ne!(bind_addr.port(), 0);
+
+        let mut buf = [0u8; 1];
+        let r = time::timeout(Duration::from_millis(100), stream.read(&mut buf)).await;
+        assert!(r.is_err(), "2nd reply is sent before peer connects");
+
+        // 2nd reply, after the peer connects
+        let mut remote = TcpStream::connect(bind_addr).await.unwrap();
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+        assert_eq!(rh.address, Address::SocketAddress(remote.local_addr().unwrap()));
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut payload = [0u8; 4];
+        remote.read_exact(&mut payload).await.unwrap();
+        assert_eq!(&payload, b"ping");
+
+        remote.write_all(b"pong").await.unwrap();
+        stream.read_exact(&mut payload).await.unwrap();
+        assert_eq!(&payload, b"pong");
+    }
+
+    #[cfg(feature = "local-socks5-bind")]
+    #[tokio::test]
+    async fn bind_client_closed_before_peer() {
+        let mut stream = start_bind_request().await;
+
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+        let bind_addr = match rh.address {
+            Address::SocketAddress(a) => a,
+            a => panic!("unexpected bind address {}", a),
+        };
+
+        drop(stream);
+
+        // Listener should be closed
+        time::timeout(Duration::from_secs(5), async {
+            while TcpStream::connect(bind_addr).await.is_ok() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("BIND listener is still open");
+    }
+
+    #[cfg(feature = "local-socks5-bind")]
+    #[tokio::test]
+    async fn bind_timeout_without_peer() {
+        let mut stream = start_bind_request_with_timeout(Some(Duration::from_millis(100))).await;
+
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+        let bind_addr = match rh.address {
+            Address::SocketAddress(a) => a,
+            a => panic!("unexpected bind address {}", a),
+        };
+
+        // 2nd reply is a failure, then the client is disconnected
+        let rh = time::timeout(Duration::from_secs(5), TcpResponseHeader::read_from(&mut stream))
+            .await
+            .expect("2nd reply is not sent after the timeout")
+            .unwrap();
+        assert!(matches!(rh.reply, Reply::TtlExpired), "reply {:?}", rh.reply);
+        assert_eq!(rh.address, Address::SocketAddress(bind_addr));
+
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+        // Listener is closed
+        assert!(
+            TcpStream::connect(bind_addr).await.is_err(),
+            "BIND listener is still open"
+        );
+    }
+
+    #[cfg(feature = "local-socks5-bind")]
+    #[tokio::test]
+    async fn bind_proxied_target_rejected() {
+        let mut stream = start_bind_request_with_acl(None, "[bypass_all]\n[proxy_list]\n127.0.0.0/8\n").await;
+
+        // Peers of proxied targets cannot be accepted without exposing this host
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::CommandNotSupported), "reply {:?}", rh.reply);
+    }
+
+    #[cfg(all(feature = "local-socks5-bind", target_os = "linux"))]
+    #[tokio::test]
+    async fn bind_unexpected_peer_rejected() {
+        use tokio::net::TcpSocket;
+
+        let mut stream = start_bind_request().await;
+
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+        let bind_addr = match rh.address {
+            Address::SocketAddress(a) => a,
+            a => panic!("unexpected bind address {}", a),
+        };
+
+        // DST.ADDR is 127.0.0.1, peers from other addresses are closed
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut intruder = socket.connect(bind_addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        let n = time::timeout(Duration::from_secs(5), intruder.read(&mut buf))
+            .await
+            .expect("unexpected peer is not closed")
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+
+        let r = time::timeout(Duration::from_millis(100), stream.read(&mut buf)).await;
+        assert!(r.is_err(), "2nd reply is sent for an unexpected peer");
+
+        // The expected peer is still accepted
+        let remote = TcpStream::connect(bind_addr).await.unwrap();
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+        assert_eq!(rh.address, Address::SocketAddress(remote.local_addr().unwrap()));
+    }
+}
@@ -0,0 +1,65 @@
+//! Clients holding UDP ASSOCIATE control connections
+
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use spin::Mutex as SpinMutex;
+
+/// Addresses of clients which have UDP ASSOCIATE control connections alive
+///
+/// Clients are identified by IP, because the source port of datagrams is unknown until they are sent.
+#[derive(Debug, Default)]
+pub struct UdpAssociateClients {
+    clients: SpinMutex<HashMap<IpAddr, usize>>,
+}
+
+impl UdpAssociateClients {
+    /// Create an empty set of clients
+    pub fn new() -> UdpAssociateClients {
+        UdpAssociateClients::default()
+    }
+
+    /// Register client `ip` until the returned guard is dropped
+    pub fn register(self: &Arc<Self>, ip: IpAddr) -> UdpAssociateGuard {
+        let ip = canonical_ip(ip);
+        *self.clients.lock().entry(ip).or_insert(0) += 1;
+        UdpAssociateGuard {
+            owner: self.clone(),
+            ip,
+        }
+    }
+
+    /// Check if client `ip` has any control connection alive
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.clients.lock().contains_key(&canonical_ip(ip))
+    }
+}
+
+/// Guard of a registered client in `UdpAssociateClients`
+#[derive(Debug)]
+pub struct UdpAssociateGuard {
+    owner: Arc<UdpAssociateClients>,
+    ip: IpAddr,
+}
+
+impl Drop for UdpAssociateGuard {
+    fn drop(&mut self) {
+        let mut clients = self.owner.clients.lock();
+        if let Some(count) = clients.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                clients.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// TCP and UDP listeners may be in different families, IPv4-mapped IPv6 addresses are compared as IPv4
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        IpAddr::V4(..) => ip,
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::{
     io::{self, Cursor},
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
@@ -10,7 +10,7 @@ use std::{
 use async_trait::async_trait;
 use byte_string::ByteStr;
 use bytes::{BufMut, BytesMut};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use shadowsocks::{
     lookup_then,
     net::UdpSocket as ShadowUdpSocket,
@@ -26,11 +26,13 @@ use crate::{
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
-        net::{UdpAssociationManager, UdpInboundWrite},
+        net::{AllowedClients, UdpAssociationManager, UdpInboundWrite},
     },
     net::utils::to_ipv4_mapped,
 };
 
+use super::UdpAssociateClients;
+
 #[derive(Clone)]
 struct Socks5UdpInboundWriter {
     inbound: Arc<UdpSocket>,
@@ -67,10 +69,31 @@ impl UdpInboundWrite for Socks5UdpInboundWriter {
     }
 }
 
+/// Addresses of the UDP relay, replied to UDP ASSOCIATE
+#[derive(Debug, Clone)]
+pub struct UdpRelayAddrs {
+    /// Address that the UDP relay is bound to
+    pub bind_addr: ServerAddr,
+    /// Socket in the other family of `bind_addr`, for clients which cannot reach `bind_addr`
+    pub other_family_addr: Option<SocketAddr>,
+}
+
+impl UdpRelayAddrs {
+    /// Create with the UDP relay's address only
+    pub fn new(bind_addr: ServerAddr) -> UdpRelayAddrs {
+        UdpRelayAddrs {
+            bind_addr,
+            other_family_addr: None,
+        }
+    }
+}
+
 pub struct Socks5UdpServer {
     context: Arc<ServiceContext>,
     time_to_live: Option<Duration>,
     capacity: Option<usize>,
+    associate_clients: Option<Arc<UdpAssociateClients>>,
+    allowed_clients: AllowedClients,
 }
 
 impl Socks5UdpServer {
@@ -83,10 +106,23 @@ impl Socks5UdpServer {
             context,
             time_to_live,
             capacity,
+            associate_clients: None,
+            allowed_clients: AllowedClients::default(),
         }
     }
 
-    pub async fn run(&self, client_config: &ServerAddr, balancer: PingBalancer) -> io::Result<()> {
+    /// Only relay datagrams from clients which have UDP ASSOCIATE control connections alive
+    pub fn set_associate_clients(&mut self, clients: Arc<UdpAssociateClients>) {
+        self.associate_clients = Some(clients);
+    }
+
+    /// Only relay datagrams from clients in `allowed_clients`, all clients are allowed by default
+    pub fn set_allowed_clients(&mut self, allowed_clients: AllowedClients) {
+        self.allowed_clients = allowed_clients;
+    }
+
+    /// Bind the UDP socket that clients send datagrams to
+    pub async fn bind(&self, client_config: &ServerAddr) -> io::Result<UdpSocket> {
         let socket = match *client_config {
             ServerAddr::SocketAddr(ref saddr) => {
                 ShadowUdpSocket::listen_with_opts(saddr, self.context.accept_opts()).await?
@@ -102,6 +138,57 @@ impl Socks5UdpServer {
 
         info!("shadowsocks socks5 UDP listening on {}", socket.local_addr()?);
 
+        Ok(socket)
+    }
+
+    /// Bind a UDP socket in the other family of `bind_addr`, on the same port if it is available
+    ///
+    /// Sockets bound to an unspecified address only receive datagrams of their own family, so clients with control
+    /// connections of the other family couldn't reach them. Not required if `bind_addr` is a specified address, or is
+    /// a dual-stack IPv6 socket.
+    pub async fn bind_other_family(&self, bind_addr: SocketAddr) -> Option<UdpSocket> {
+        if !bind_addr.ip().is_unspecified() {
+            return None;
+        }
+
+        let mut opts = self.context.accept_opts();
+        let other_ip = match bind_addr {
+            SocketAddr::V4(..) => {
+                // Dual-stack socket on the same port conflicts with `bind_addr`
+                opts.ipv6_only = true;
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            }
+            SocketAddr::V6(..) if opts.ipv6_only => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(..) => return None,
+        };
+
+        let other_addr = SocketAddr::new(other_ip, bind_addr.port());
+        let socket = match ShadowUdpSocket::listen_with_opts(&other_addr, opts.clone()).await {
+            Ok(s) => s,
+            Err(err) => {
+                debug!("socks5 UDP failed to listen on {}, error: {}", other_addr, err);
+
+                let other_addr = SocketAddr::new(other_ip, 0);
+                match ShadowUdpSocket::listen_with_opts(&other_addr, opts).await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        warn!("socks5 UDP failed to listen on {}, error: {}", other_addr, err);
+                        return None;
+                    }
+                }
+            }
+        };
+        let socket: UdpSocket = socket.into();
+
+        if let Ok(local_addr) = socket.local_addr() {
+            info!("shadowsocks socks5 UDP listening on {}", local_addr);
+        }
+
+        Some(socket)
+    }
+
+    /// Relay datagrams received on `socket`, which is created by `bind`
+    pub async fn run(&self, socket: UdpSocket, balancer: PingBalancer) -> io::Result<()> {
         let listener = Arc::new(socket);
         let (mut manager, cleanup_interval, mut keepalive_rx) = UdpAssociationManager::new(
             self.context.clone(),
@@ -138,6 +225,18 @@ impl Socks5UdpServer {
                         }
                     };
 
+                    if !self.allowed_clients.check_allowed(&peer_addr.ip()) {
+                        debug!("udp packet from {} dropped, not in allowed_clients", peer_addr);
+                        continue;
+                    }
+
+                    if let Some(ref clients) = self.associate_clients {
+                        if !clients.contains(peer_addr.ip()) {
+                            debug!("udp packet from {} dropped, no UDP ASSOCIATE control connection", peer_addr);
+                            continue;
+                        }
+                    }
+
                     let data = &buffer[..n];
 
                     // PKT = UdpAssociateHeader + PAYLOAD
@@ -178,3 +277,195 @@ impl Socks5UdpServer {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use shadowsocks::{
+        config::Mode,
+        relay::socks5::{
+            self,
+            Command,
+            HandshakeRequest,
+            HandshakeResponse,
+            Reply,
+            TcpRequestHeader,
+            TcpResponseHeader,
+        },
+    };
+    use tokio::net::TcpStream;
+
+    use crate::{
+        local::{loadbalancing::PingBalancerBuilder, socks::server::Socks},
+        test_utils::bind_listener,
+    };
+
+    use super::*;
+
+    async fn udp_associate(proxy_addr: SocketAddr) -> (TcpStream, SocketAddr) {
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE])
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        let rsp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+        assert_eq!(rsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+        let client_addr = Address::SocketAddress(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+        TcpRequestHeader::new(Command::UdpAssociate, client_addr)
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        let rh = TcpResponseHeader::read_from(&mut stream).await.unwrap();
+        assert!(matches!(rh.reply, Reply::Succeeded), "reply {:?}", rh.reply);
+
+        match rh.address {
+            Address::SocketAddress(a) => (stream, a),
+            a => panic!("unexpected UDP relay address {}", a),
+        }
+    }
+
+    async fn echo_through(client: &UdpSocket, relay_addr: SocketAddr, target_addr: SocketAddr) -> Option<Vec<u8>> {
+        let mut packet = BytesMut::new();
+        UdpAssociateHeader::new(0, Address::SocketAddress(target_addr)).write_to_buf(&mut packet);
+        packet.put_slice(b"ping");
+        client.send_to(&packet, relay_addr).await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let n = match time::timeout(Duration::from_millis(500), client.recv(&mut buffer)).await {
+            Ok(r) => r.unwrap(),
+            Err(..) => return None,
+        };
+
+        let mut cur = Cursor::new(&buffer[..n]);
+        let header = UdpAssociateHeader::read_from(&mut cur).await.unwrap();
+        assert_eq!(header.address, Address::SocketAddress(target_addr));
+        Some(buffer[cur.position() as usize..n].to_vec())
+    }
+
+    #[tokio::test]
+    async fn udp_associate_control_connection() {
+        let target = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let (n, peer_addr) = target.recv_from(&mut buffer).await.unwrap();
+                target.send_to(&buffer[..n], peer_addr).await.unwrap();
+            }
+        });
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let context = Arc::new(ServiceContext::new());
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpAndUdp)
+            .build()
+            .await
+            .unwrap();
+        let mut server = Socks::with_context(context);
+        server.set_mode(Mode::TcpAndUdp);
+        // Any port on all interfaces, the reply should tell the real one
+        server.set_udp_bind_addr(ServerAddr::from(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)));
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let (stream, relay_addr) = udp_associate(proxy_addr).await;
+        assert_eq!(relay_addr.ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+        assert_ne!(relay_addr.port(), 0);
+
+        let payload = echo_through(&client, relay_addr, target_addr).await;
+        assert_eq!(payload.as_deref(), Some(&b"ping"[..]));
+
+        // Datagrams are dropped after the control connection is closed
+        drop(stream);
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(echo_through(&client, relay_addr, target_addr).await, None);
+    }
+
+    #[tokio::test]
+    async fn udp_associate_control_connection_family() {
+        let target = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let (n, peer_addr) = target.recv_from(&mut buffer).await.unwrap();
+                target.send_to(&buffer[..n], peer_addr).await.unwrap();
+            }
+        });
+
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let mut context = ServiceContext::new();
+        let mut accept_opts = context.accept_opts();
+        accept_opts.ipv6_only = true;
+        context.set_accept_opts(accept_opts);
+        let context = Arc::new(context);
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpAndUdp)
+            .build()
+            .await
+            .unwrap();
+        let mut server = Socks::with_context(context);
+        server.set_mode(Mode::TcpAndUdp);
+        // IPv6 only, unreachable from IPv4 clients
+        server.set_udp_bind_addr(ServerAddr::from(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)));
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let (_stream, relay_addr) = udp_associate(proxy_addr).await;
+        assert_eq!(relay_addr.ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+        assert_ne!(relay_addr.port(), 0);
+
+        let payload = echo_through(&client, relay_addr, target_addr).await;
+        assert_eq!(payload.as_deref(), Some(&b"ping"[..]));
+    }
+
+    #[tokio::test]
+    async fn udp_only_allowed_clients() {
+        let target = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let (n, peer_addr) = target.recv_from(&mut buffer).await.unwrap();
+                target.send_to(&buffer[..n], peer_addr).await.unwrap();
+            }
+        });
+
+        let start_relay = |allowed_clients: &str| {
+            let allowed_clients = AllowedClients::new(vec![allowed_clients.parse().unwrap()]);
+            async move {
+                let context = Arc::new(ServiceContext::new());
+                let balancer = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly)
+                    .build()
+                    .await
+                    .unwrap();
+                let mut server = Socks5UdpServer::new(context, None, None);
+                server.set_allowed_clients(allowed_clients);
+                let socket = server
+                    .bind(&ServerAddr::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))))
+                    .await
+                    .unwrap();
+                let relay_addr = socket.local_addr().unwrap();
+                tokio::spawn(async move { server.run(socket, balancer).await });
+                relay_addr
+            }
+        };
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let relay_addr = start_relay("127.0.0.0/8").await;
+        let payload = echo_through(&client, relay_addr, target_addr).await;
+        assert_eq!(payload.as_deref(), Some(&b"ping"[..]));
+
+        let relay_addr = start_relay("10.0.0.0/8").await;
+        assert_eq!(echo_through(&client, relay_addr, target_addr).await, None);
+    }
+}
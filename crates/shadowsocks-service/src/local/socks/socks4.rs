@@ -217,7 +217,7 @@ impl HandshakeRequest {
         let command = match Command::from_u8(cd) {
             Some(c) => c,
             None => {
-                return Err(Error::UnsupportedSocksVersion(cd));
+                return Err(Error::UnsupportedCommand(cd));
             }
         };
 
@@ -0,0 +1,148 @@
+//! Per-connection span events exported to embedders
+
+use std::{fmt, net::SocketAddr, time::Instant};
+
+use shadowsocks::relay::socks5::Address;
+
+/// Point in a connection's timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanEventKind {
+    /// Started connecting to the target or the proxy server
+    ConnectStart,
+    /// Finished connecting, successfully or not. Failures are also reported to the error sink
+    ConnectEnd,
+    /// Received the first byte from the remote
+    FirstByte,
+    /// The tunnel between the client and the remote is closed
+    Close,
+}
+
+impl fmt::Display for SpanEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SpanEventKind::ConnectStart => f.write_str("connect_start"),
+            SpanEventKind::ConnectEnd => f.write_str("connect_end"),
+            SpanEventKind::FirstByte => f.write_str("first_byte"),
+            SpanEventKind::Close => f.write_str("close"),
+        }
+    }
+}
+
+/// Span event of a connection
+#[derive(Debug, Clone)]
+pub struct SpanEvent {
+    /// Point in the connection's timeline
+    pub kind: SpanEventKind,
+    /// Client's address
+    pub peer_addr: SocketAddr,
+    /// Target address that the client requested
+    pub target_addr: Address,
+    /// When the event occurs
+    pub time: Instant,
+}
+
+/// Receiver of span events
+///
+/// Events of TCP tunnels are emitted from the local servers' handlers and relay loops, in the order they occur.
+/// Connections failed to connect end with `ConnectEnd`, without `Close`.
+pub trait SpanSink: Send + Sync {
+    /// Called when `event` occurs on connection `conn_id`
+    fn on_span_event(&self, conn_id: usize, event: SpanEvent);
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use shadowsocks::config::Mode;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time,
+    };
+
+    use crate::{
+        local::{
+            context::ServiceContext,
+            loadbalancing::PingBalancerBuilder,
+            socks::{client::Socks5TcpClient, server::Socks},
+        },
+        test_utils::bind_listener,
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Mutex<Vec<(usize, SpanEvent)>>,
+    }
+
+    impl SpanSink for RecordingSink {
+        fn on_span_event(&self, conn_id: usize, event: SpanEvent) {
+            self.events.lock().unwrap().push((conn_id, event));
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_span_events() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let listener = bind_listener().await;
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let sink = Arc::new(RecordingSink::default());
+
+        let mut context = ServiceContext::new();
+        context.set_span_sink(sink.clone());
+        let context = Arc::new(context);
+
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+
+        let server = Socks::with_context(context);
+        tokio::spawn(async move { server.run_with_listener(listener, balancer).await });
+
+        let mut client = Socks5TcpClient::connect(target_addr, proxy_addr).await.unwrap();
+
+        let (mut remote, _) = target.accept().await.unwrap();
+        remote.write_all(b"hello").await.unwrap();
+        let mut buffer = [0u8; 5];
+        client.read_exact(&mut buffer).await.unwrap();
+
+        // Closes the tunnel from both sides
+        drop(remote);
+        drop(client);
+
+        let expected = [
+            SpanEventKind::ConnectStart,
+            SpanEventKind::ConnectEnd,
+            SpanEventKind::FirstByte,
+            SpanEventKind::Close,
+        ];
+        time::timeout(Duration::from_secs(5), async {
+            while sink.events.lock().unwrap().len() < expected.len() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("span events are not emitted");
+
+        let events = sink.events.lock().unwrap();
+        let kinds = events.iter().map(|(_, e)| e.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, expected);
+
+        let conn_id = events[0].0;
+        for (id, event) in events.iter() {
+            assert_eq!(*id, conn_id);
+            assert_eq!(event.target_addr, Address::SocketAddress(target_addr));
+        }
+        for pair in events.windows(2) {
+            assert!(pair[0].1.time <= pair[1].1.time);
+        }
+    }
+}
@@ -13,7 +13,7 @@ use std::{
     time::Duration,
 };
 
-use log::{error, trace};
+use log::{error, trace, warn};
 use shadowsocks::{net::TcpSocketOpts, relay::socks5::Address};
 use smoltcp::{
     iface::{Interface, InterfaceBuilder, Routes, SocketHandle},
@@ -34,7 +34,9 @@ use crate::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
         net::AutoProxyClientStream,
-        utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+        relay_error::RelayErrorKind,
+        trace_span::SpanEventKind,
+        utils::{establish_tcp_tunnel_bypassed, establish_tcp_tunnel_with_failover},
     },
     net::utils::to_ipv4_mapped,
 };
@@ -480,16 +482,61 @@ async fn establish_client_tcp_redir<'a>(
     peer_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
-    if balancer.is_empty() {
-        let mut remote = AutoProxyClientStream::connect_bypassed(context, addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, addr).await;
-    }
+    let conn_id = context.next_conn_id();
+
+    // Hold the slot until the tunnel finishes
+    let _host_guard = match context.acquire_host_connection(addr) {
+        Ok(g) => g,
+        Err(err) => {
+            warn!("tun tcp client {} rejected, {}", peer_addr, err);
+            return Ok(());
+        }
+    };
+    context.emit_span_event(conn_id, SpanEventKind::ConnectStart, peer_addr, addr);
+
+    let server = match balancer.pick_tcp_server(conn_id) {
+        Ok(Some(server)) => server,
+        Ok(None) => {
+            let remote_result = AutoProxyClientStream::connect_bypassed(context.clone(), addr).await;
+            context.emit_span_event(conn_id, SpanEventKind::ConnectEnd, peer_addr, addr);
+
+            let mut remote = match remote_result {
+                Ok(s) => s,
+                Err(err) => {
+                    context.report_relay_error(conn_id, RelayErrorKind::Connect, peer_addr, addr, &err);
+                    return Err(err);
+                }
+            };
+            return establish_tcp_tunnel_bypassed(&context, conn_id, &mut stream, &mut remote, peer_addr, addr).await;
+        }
+        Err(err) => {
+            context.emit_span_event(conn_id, SpanEventKind::ConnectEnd, peer_addr, addr);
+            context.report_relay_error(conn_id, RelayErrorKind::Connect, peer_addr, addr, &err);
+            return Err(err);
+        }
+    };
 
-    let server = balancer.best_tcp_server();
-    let svr_cfg = server.server_config();
+    let remote_result = AutoProxyClientStream::connect_with_peer(context.clone(), &server, addr, peer_addr).await;
+    context.emit_span_event(conn_id, SpanEventKind::ConnectEnd, peer_addr, addr);
 
-    let mut remote = AutoProxyClientStream::connect(context, &server, addr).await?;
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+    let mut remote = match remote_result {
+        Ok(s) => s,
+        Err(err) => {
+            context.report_relay_error(conn_id, RelayErrorKind::Connect, peer_addr, addr, &err);
+            return Err(err);
+        }
+    };
+    establish_tcp_tunnel_with_failover(
+        &context,
+        conn_id,
+        &balancer,
+        &server,
+        &mut stream,
+        &mut remote,
+        peer_addr,
+        addr,
+    )
+    .await
 }
 
 async fn handle_redir_client(
@@ -499,6 +546,8 @@ async fn handle_redir_client(
     peer_addr: SocketAddr,
     mut daddr: SocketAddr,
 ) -> io::Result<()> {
+    let _active = context.track_active_connection();
+
     // Get forward address from socket
     //
     // Try to convert IPv4 mapped IPv6 address for dual-stack mode.
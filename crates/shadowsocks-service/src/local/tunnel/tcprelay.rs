@@ -2,7 +2,7 @@
 
 use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 use shadowsocks::{lookup_then, net::TcpListener as ShadowTcpListener, relay::socks5::Address, ServerAddr};
 use tokio::{net::TcpStream, time};
 
@@ -10,7 +10,9 @@ use crate::local::{
     context::ServiceContext,
     loadbalancing::PingBalancer,
     net::AutoProxyClientStream,
-    utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+    relay_error::RelayErrorKind,
+    trace_span::SpanEventKind,
+    utils::{establish_tcp_tunnel_bypassed, establish_tcp_tunnel_with_failover},
 };
 
 pub async fn run_tcp_tunnel(
@@ -32,6 +34,9 @@ pub async fn run_tcp_tunnel(
     info!("shadowsocks TCP tunnel listening on {}", listener.local_addr()?);
 
     loop {
+        // Paused for a while after file descriptors were exhausted
+        context.accept_resumed().await;
+
         let (stream, peer_addr) = match listener.accept().await {
             Ok(s) => s,
             Err(err) => {
@@ -61,14 +66,50 @@ async fn handle_tcp_client(
     peer_addr: SocketAddr,
     forward_addr: Address,
 ) -> io::Result<()> {
-    if balancer.is_empty() {
-        trace!("establishing tcp tunnel {} <-> {} direct", peer_addr, forward_addr);
+    let conn_id = context.next_conn_id();
+    let _active = context.track_active_connection();
 
-        let mut remote = AutoProxyClientStream::connect_bypassed(context, &forward_addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, &forward_addr).await;
-    }
+    // Hold the slot until the tunnel finishes
+    let _host_guard = match context.acquire_host_connection(&forward_addr) {
+        Ok(g) => g,
+        Err(err) => {
+            warn!("tcp tunnel client {} rejected, {}", peer_addr, err);
+            return Ok(());
+        }
+    };
+    context.emit_span_event(conn_id, SpanEventKind::ConnectStart, peer_addr, &forward_addr);
 
-    let server = balancer.best_tcp_server();
+    let server = match balancer.pick_tcp_server(conn_id) {
+        Ok(Some(server)) => server,
+        Ok(None) => {
+            trace!("establishing tcp tunnel {} <-> {} direct", peer_addr, forward_addr);
+
+            let remote_result = AutoProxyClientStream::connect_bypassed(context.clone(), &forward_addr).await;
+            context.emit_span_event(conn_id, SpanEventKind::ConnectEnd, peer_addr, &forward_addr);
+
+            let mut remote = match remote_result {
+                Ok(s) => s,
+                Err(err) => {
+                    context.report_relay_error(conn_id, RelayErrorKind::Connect, peer_addr, &forward_addr, &err);
+                    return Err(err);
+                }
+            };
+            return establish_tcp_tunnel_bypassed(
+                &context,
+                conn_id,
+                &mut stream,
+                &mut remote,
+                peer_addr,
+                &forward_addr,
+            )
+            .await;
+        }
+        Err(err) => {
+            context.emit_span_event(conn_id, SpanEventKind::ConnectEnd, peer_addr, &forward_addr);
+            context.report_relay_error(conn_id, RelayErrorKind::Connect, peer_addr, &forward_addr, &err);
+            return Err(err);
+        }
+    };
     let svr_cfg = server.server_config();
     trace!(
         "establishing tcp tunnel {} <-> {} through sever {} (outbound: {})",
@@ -78,6 +119,26 @@ async fn handle_tcp_client(
         svr_cfg.addr(),
     );
 
-    let mut remote = AutoProxyClientStream::connect_proxied(context, &server, &forward_addr).await?;
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &forward_addr).await
+    let remote_result =
+        AutoProxyClientStream::connect_proxied_with_peer(context.clone(), &server, &forward_addr, peer_addr).await;
+    context.emit_span_event(conn_id, SpanEventKind::ConnectEnd, peer_addr, &forward_addr);
+
+    let mut remote = match remote_result {
+        Ok(s) => s,
+        Err(err) => {
+            context.report_relay_error(conn_id, RelayErrorKind::Connect, peer_addr, &forward_addr, &err);
+            return Err(err);
+        }
+    };
+    establish_tcp_tunnel_with_failover(
+        &context,
+        conn_id,
+        &balancer,
+        &server,
+        &mut stream,
+        &mut remote,
+        peer_addr,
+        &forward_addr,
+    )
+    .await
 }
@@ -1,96 +1,529 @@
 //! Shadowsocks Local Utilities
 
-use std::{io, net::SocketAddr, time::Duration};
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
 
-use log::{debug, trace};
+use futures::{future::BoxFuture, FutureExt};
+use log::{debug, trace, warn, Level};
 use shadowsocks::{
-    config::ServerConfig,
-    relay::{socks5::Address, tcprelay::utils::copy_encrypted_bidirectional},
+    config::ServerAddr,
+    crypto::CipherKind,
+    relay::{
+        socks5::Address,
+        tcprelay::utils::{
+            copy_encrypted_bidirectional_with_state,
+            CloseInitiator,
+            CopyBidirectionalOpts,
+            CopyBidirectionalState,
+            UndeliveredBytes,
+        },
+    },
 };
 use tokio::{
-    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     time,
 };
 
-use crate::local::net::AutoProxyIo;
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::{PingBalancer, ServerIdent},
+        net::{AutoProxyClientStream, AutoProxyIo, RateLimitedStream},
+        relay_error::RelayErrorKind,
+        trace_span::SpanEventKind,
+    },
+    net::{relay_event::RelayEvent, worker_pool::release_worker, MonProxyStream},
+};
+
+/// Maximum retries of writing the first packet to remote servers on transient errors
+const FIRST_PACKET_WRITE_RETRIES: u32 = 3;
+
+/// `write_all` that retries briefly on transient errors, like the socket's send buffer is full
+///
+/// Bytes that have been written won't be written again.
+async fn write_all_retry_transient<W>(writer: &mut W, mut buf: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut retries = 0;
+    while !buf.is_empty() {
+        match writer.write(buf).await {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => buf = &buf[n..],
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) && retries < FIRST_PACKET_WRITE_RETRIES =>
+            {
+                retries += 1;
+                trace!(
+                    "write first packet failed with transient error: {}, retrying {}/{}",
+                    err,
+                    retries,
+                    FIRST_PACKET_WRITE_RETRIES
+                );
+                time::sleep(Duration::from_millis(10 * retries as u64)).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Stream emits `FirstByte` span event when the first byte is read from it
+///
+/// Streams through remote servers also warn if the first byte arrives later than the slow connection threshold
+/// after data was sent. Time idling before the client sends anything is not counted.
+struct FirstByteTimer<'a, S> {
+    stream: &'a mut S,
+    context: &'a ServiceContext,
+    conn_id: usize,
+    first_byte_arrived: bool,
+    // Time when data was first sent, `None` if nothing has been sent yet
+    waiting_since: Option<Instant>,
+    // `None` for bypassed streams
+    server_addr: Option<&'a ServerAddr>,
+    peer_addr: SocketAddr,
+    target_addr: &'a Address,
+}
+
+impl<S> AsyncRead for FirstByteTimer<'_, S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut *self.stream).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = result {
+            if buf.filled().len() > filled && !self.first_byte_arrived {
+                self.first_byte_arrived = true;
+                self.context
+                    .emit_span_event(self.conn_id, SpanEventKind::FirstByte, self.peer_addr, self.target_addr);
+
+                if let (Some(server_addr), Some(threshold), Some(waiting_since)) = (
+                    self.server_addr,
+                    self.context.slow_connection_threshold(),
+                    self.waiting_since,
+                ) {
+                    let elapsed = waiting_since.elapsed();
+                    if elapsed > threshold {
+                        warn!(
+                            "slow connection {} -> {} through server {}, first byte took {:?}",
+                            self.peer_addr, self.target_addr, server_addr, elapsed
+                        );
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl<S> AsyncWrite for FirstByteTimer<'_, S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut *self.stream).poll_write(cx, buf);
 
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 && !self.first_byte_arrived && self.waiting_since.is_none() {
+                self.waiting_since = Some(Instant::now());
+            }
+        }
+
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Report bytes that were read from one side of a closed tunnel, but never written to the other side
+fn report_undelivered(
+    context: &ServiceContext,
+    undelivered: &UndeliveredBytes,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) {
+    if undelivered.total() == 0 {
+        return;
+    }
+
+    debug!(
+        "tcp tunnel {} <-> {} closed with undelivered L2R {} bytes, R2L {} bytes",
+        peer_addr, target_addr, undelivered.plain_to_encrypted, undelivered.encrypted_to_plain
+    );
+    context.flow_stat_ref().incr_undelivered(undelivered.total());
+}
+
+/// Account bytes relayed to `target_addr`, if destination stats are enabled
+fn record_destination_bytes(context: &ServiceContext, target_addr: &Address, tx: u64, rx: u64) {
+    if let Some(destinations) = context.metrics().and_then(|m| m.destination_stats()) {
+        destinations.record(target_addr, tx, rx);
+    }
+}
+
+/// Side of a tunnel that closed first, `shadow` is the encrypted stream
+fn close_initiator_name(initiator: Option<CloseInitiator>) -> &'static str {
+    match initiator {
+        Some(CloseInitiator::Plain) => "client",
+        Some(CloseInitiator::Encrypted) => "upstream",
+        Some(CloseInitiator::Both) => "both sides",
+        None => "unknown",
+    }
+}
+
+/// Connects a tunnel through another server after its first packet couldn't be written to the failed one,
+/// `None` if there isn't any other server
+type TunnelFailover<'a, S> =
+    Box<dyn FnOnce(&ServerIdent) -> BoxFuture<'a, io::Result<Option<(Arc<ServerIdent>, S)>>> + Send + 'a>;
+
+/// Establish a tunnel through `server`, without failing over to the other servers
+#[cfg(any(feature = "local-socks4", test))]
 pub(crate) async fn establish_tcp_tunnel<P, S>(
-    svr_cfg: &ServerConfig,
+    context: &ServiceContext,
+    conn_id: usize,
+    server: &ServerIdent,
+    plain: &mut P,
+    shadow: &mut S,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
+{
+    establish_tcp_tunnel_inner(context, conn_id, server, plain, shadow, peer_addr, target_addr, None).await
+}
+
+/// Same as `establish_tcp_tunnel`, but fails over to another server of `balancer` if the first packet couldn't be
+/// written to `server`. Nothing has been relayed to the client by then, so the tunnel could be reconnected.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn establish_tcp_tunnel_with_failover<P>(
+    context: &Arc<ServiceContext>,
+    conn_id: usize,
+    balancer: &PingBalancer,
+    server: &ServerIdent,
+    plain: &mut P,
+    shadow: &mut AutoProxyClientStream,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+{
+    let failover_context = context.clone();
+    let failover: TunnelFailover<'_, AutoProxyClientStream> = Box::new(move |failed| {
+        let server = balancer.pick_tcp_server_except(failed);
+        async move {
+            let server = match server {
+                Some(s) => s,
+                None => return Ok(None),
+            };
+            let stream = AutoProxyClientStream::connect_proxied_with_peer(
+                failover_context,
+                &server,
+                target_addr.clone(),
+                peer_addr,
+            )
+            .await?;
+            Ok(Some((server, stream)))
+        }
+        .boxed()
+    });
+
+    establish_tcp_tunnel_inner(
+        context,
+        conn_id,
+        server,
+        plain,
+        shadow,
+        peer_addr,
+        target_addr,
+        Some(failover),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn establish_tcp_tunnel_inner<P, S>(
+    context: &ServiceContext,
+    conn_id: usize,
+    server: &ServerIdent,
     plain: &mut P,
     shadow: &mut S,
     peer_addr: SocketAddr,
     target_addr: &Address,
+    failover: Option<TunnelFailover<'_, S>>,
 ) -> io::Result<()>
 where
     P: AsyncRead + AsyncWrite + Unpin,
     S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
 {
+    // Relaying for the rest of the connection's lifetime
+    release_worker();
+
+    let svr_cfg = server.server_config();
+
     if shadow.is_proxied() {
-        debug!(
-            "established tcp tunnel {} <-> {} through sever {} (outbound: {})",
-            peer_addr,
-            target_addr,
-            svr_cfg.external_addr(),
-            svr_cfg.addr(),
+        let event = RelayEvent::tcp("established", peer_addr, target_addr)
+            .conn_id(conn_id)
+            .server(svr_cfg.addr());
+        event.log(
+            Level::Debug,
+            format_args!(
+                "established tcp tunnel {} <-> {} through sever {} (outbound: {})",
+                peer_addr,
+                target_addr,
+                svr_cfg.external_addr(),
+                svr_cfg.addr(),
+            ),
         );
     } else {
-        return establish_tcp_tunnel_bypassed(plain, shadow, peer_addr, target_addr).await;
+        return establish_tcp_tunnel_bypassed(context, conn_id, plain, shadow, peer_addr, target_addr).await;
     }
 
+    let result = relay_tcp_tunnel_proxied(
+        context,
+        conn_id,
+        server,
+        plain,
+        shadow,
+        peer_addr,
+        target_addr,
+        failover,
+    )
+    .await;
+    context.emit_span_event(conn_id, SpanEventKind::Close, peer_addr, target_addr);
+    result
+}
+
+/// Write the first packet to the server, `first_packet` is empty if the handshake is sent without data
+async fn write_first_packet<S>(
+    shadow: &mut S,
+    first_packet: &[u8],
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    if !first_packet.is_empty() {
+        return write_all_retry_transient(shadow, first_packet).await;
+    }
+
+    shadow.write(&[]).await.map(|_| {
+        trace!(
+            "tcp tunnel {} -> {} (proxied) sent handshake without data",
+            peer_addr,
+            target_addr
+        );
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn relay_tcp_tunnel_proxied<P, S>(
+    context: &ServiceContext,
+    conn_id: usize,
+    server: &ServerIdent,
+    plain: &mut P,
+    shadow: &mut S,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+    failover: Option<TunnelFailover<'_, S>>,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Connections to the server are counted by `AutoProxyClientStream`
+    let _gauge = context.metrics().map(|m| m.track_tcp_connection());
+    let _active = context.track_active_connection();
+
     // https://github.com/shadowsocks/shadowsocks-rust/issues/232
     //
     // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
     //
     // Wait at most 500ms, and then sends handshake packet to remote servers.
+    //
+    // With read-ahead, handshake is sent immediately, so the remote could respond before the client sends anything.
+    let first_packet_len;
+    let mut failed_over = None;
     {
         let mut buffer = [0u8; 8192];
-        match time::timeout(Duration::from_millis(500), plain.read(&mut buffer)).await {
-            Ok(Ok(0)) => {
+        let first_packet = if context.connect_read_ahead() {
+            None
+        } else {
+            time::timeout(Duration::from_millis(500), plain.read(&mut buffer))
+                .await
+                .ok()
+        };
+        let first_packet = match first_packet {
+            Some(Ok(0)) => {
                 // EOF. Just terminate right here.
                 return Ok(());
             }
-            Ok(Ok(n)) => {
-                // Send the first packet.
-                shadow.write_all(&buffer[..n]).await?;
+            Some(Ok(n)) => &buffer[..n],
+            Some(Err(err)) => {
+                context.report_relay_error(conn_id, RelayErrorKind::Relay, peer_addr, target_addr, &err);
+                return Err(err);
             }
-            Ok(Err(err)) => return Err(err),
-            Err(..) => {
-                // Timeout. Send handshake to server.
-                let _ = shadow.write(&[]).await?;
+            // Timeout, or read-ahead. Send handshake to server.
+            None => &[][..],
+        };
 
-                trace!(
-                    "tcp tunnel {} -> {} (proxied) sent handshake without data",
-                    peer_addr,
-                    target_addr
-                );
+        let mut result = write_first_packet(shadow, first_packet, peer_addr, target_addr).await;
+        if let Err(ref err) = result {
+            // Nothing has been relayed to the client yet. Penalize the server,
+            // so the following connections could fail over to the other servers.
+            server.tcp_score().report_failure().await;
+
+            // Transient errors were retried, this one is reconnected through another server
+            if let Some(failover) = failover {
+                match failover(server).await {
+                    Ok(Some((other, mut other_shadow))) => {
+                        debug!(
+                            "[c{}] tcp tunnel {} -> {} failed over from server {} to {}, error: {}",
+                            conn_id,
+                            peer_addr,
+                            target_addr,
+                            server.server_config().addr(),
+                            other.server_config().addr(),
+                            err
+                        );
+                        result = write_first_packet(&mut other_shadow, first_packet, peer_addr, target_addr).await;
+                        if result.is_err() {
+                            other.tcp_score().report_failure().await;
+                        }
+                        failed_over = Some((other, other_shadow));
+                    }
+                    Ok(None) => {}
+                    Err(failover_err) => {
+                        debug!(
+                            "[c{}] tcp tunnel {} -> {} failed to fail over from server {}, error: {}",
+                            conn_id,
+                            peer_addr,
+                            target_addr,
+                            server.server_config().addr(),
+                            failover_err
+                        );
+                    }
+                }
             }
         }
-    }
 
-    match copy_encrypted_bidirectional(svr_cfg.method(), shadow, plain).await {
-        Ok((wn, rn)) => {
-            trace!(
-                "tcp tunnel {} <-> {} (proxied) closed, L2R {} bytes, R2L {} bytes",
-                peer_addr,
-                target_addr,
-                rn,
-                wn
-            );
+        if let Err(err) = result {
+            context.report_relay_error(conn_id, RelayErrorKind::Relay, peer_addr, target_addr, &err);
+            return Err(err);
         }
-        Err(err) => {
-            trace!(
-                "tcp tunnel {} <-> {} (proxied) closed with error: {}",
-                peer_addr,
-                target_addr,
-                err
+        first_packet_len = first_packet.len() as u64;
+    }
+
+    let (server, shadow) = match failed_over {
+        Some((ref other, ref mut other_shadow)) => (other.as_ref(), other_shadow),
+        None => (server, shadow),
+    };
+    let svr_cfg = server.server_config();
+
+    // First byte is expected after data was sent, with the handshake or later
+    let mut shadow = FirstByteTimer {
+        stream: shadow,
+        context,
+        conn_id,
+        first_byte_arrived: false,
+        waiting_since: if first_packet_len > 0 {
+            Some(Instant::now())
+        } else {
+            None
+        },
+        server_addr: Some(svr_cfg.addr()),
+        peer_addr,
+        target_addr,
+    };
+
+    let mut plain = RateLimitedStream::new(plain, context.tunnel_rate_limiters());
+
+    let closed_event = |kind, state: &CopyBidirectionalState| {
+        RelayEvent::tcp(kind, peer_addr, target_addr)
+            .conn_id(conn_id)
+            .server(svr_cfg.addr())
+            .bytes(first_packet_len + state.written_to_encrypted, state.written_to_plain)
+    };
+
+    // Connections will be closed after the server is retired, by reloading servers
+    let copy_opts = CopyBidirectionalOpts {
+        idle_timeout: context.idle_timeout(),
+    };
+    let mut state = CopyBidirectionalState::default();
+    tokio::select! {
+        result = copy_encrypted_bidirectional_with_state(svr_cfg.method(), &mut shadow, &mut plain, &copy_opts, &mut state) => match result {
+            Ok((wn, rn)) => {
+                closed_event("closed", &state).log(
+                    Level::Trace,
+                    format_args!(
+                        "tcp tunnel {} <-> {} (proxied) closed by {}, L2R {} bytes, R2L {} bytes",
+                        peer_addr,
+                        target_addr,
+                        close_initiator_name(state.close_initiator),
+                        rn,
+                        wn
+                    ),
+                );
+            }
+            Err(err) => {
+                closed_event("error", &state).error(&err).log(
+                    Level::Trace,
+                    format_args!(
+                        "tcp tunnel {} <-> {} (proxied) closed with error: {}",
+                        peer_addr,
+                        target_addr,
+                        err
+                    ),
+                );
+                context.report_relay_error(conn_id, RelayErrorKind::Relay, peer_addr, target_addr, &err);
+            }
+        },
+        _ = server.retired() => {
+            closed_event("retired", &state).log(
+                Level::Debug,
+                format_args!(
+                    "tcp tunnel {} <-> {} (proxied) closed because server {} was retired",
+                    peer_addr,
+                    target_addr,
+                    svr_cfg.addr()
+                ),
             );
         }
     }
+    report_undelivered(context, &state.undelivered, peer_addr, target_addr);
+    record_destination_bytes(
+        context,
+        target_addr,
+        first_packet_len + state.written_to_encrypted,
+        state.written_to_plain,
+    );
 
     Ok(())
 }
 
 pub(crate) async fn establish_tcp_tunnel_bypassed<P, S>(
+    context: &ServiceContext,
+    conn_id: usize,
     plain: &mut P,
     shadow: &mut S,
     peer_addr: SocketAddr,
@@ -100,27 +533,510 @@ where
     P: AsyncRead + AsyncWrite + Unpin,
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    debug!("established tcp tunnel {} <-> {} bypassed", peer_addr, target_addr);
+    release_worker();
 
-    match copy_bidirectional(plain, shadow).await {
-        Ok((rn, wn)) => {
-            trace!(
-                "tcp tunnel {} <-> {} (bypassed) closed, L2R {} bytes, R2L {} bytes",
-                peer_addr,
-                target_addr,
-                rn,
-                wn
+    let event = |kind| RelayEvent::tcp(kind, peer_addr, target_addr).conn_id(conn_id);
+    event("established").log(
+        Level::Debug,
+        format_args!("established tcp tunnel {} <-> {} bypassed", peer_addr, target_addr),
+    );
+
+    let _gauge = context.metrics().map(|m| m.track_tcp_connection());
+    let _active = context.track_active_connection();
+
+    let mut shadow = MonProxyStream::from_stream(shadow, context.bypassed_flow_stat());
+    let mut shadow = FirstByteTimer {
+        stream: &mut shadow,
+        context,
+        conn_id,
+        first_byte_arrived: false,
+        waiting_since: None,
+        server_addr: None,
+        peer_addr,
+        target_addr,
+    };
+
+    let mut plain = RateLimitedStream::new(plain, context.tunnel_rate_limiters());
+
+    // Plain streams in both sides, copied as if they were encrypted with the "none" cipher
+    let copy_opts = CopyBidirectionalOpts {
+        idle_timeout: context.idle_timeout(),
+    };
+    let mut state = CopyBidirectionalState::default();
+    match copy_encrypted_bidirectional_with_state(CipherKind::NONE, &mut shadow, &mut plain, &copy_opts, &mut state)
+        .await
+    {
+        Ok((wn, rn)) => {
+            event("closed").bytes(rn, wn).log(
+                Level::Trace,
+                format_args!(
+                    "tcp tunnel {} <-> {} (bypassed) closed by {}, L2R {} bytes, R2L {} bytes",
+                    peer_addr,
+                    target_addr,
+                    close_initiator_name(state.close_initiator),
+                    rn,
+                    wn
+                ),
             );
         }
         Err(err) => {
-            trace!(
-                "tcp tunnel {} <-> {} (bypassed) closed with error: {}",
-                peer_addr,
-                target_addr,
-                err
-            );
+            event("error")
+                .bytes(state.written_to_encrypted, state.written_to_plain)
+                .error(&err)
+                .log(
+                    Level::Trace,
+                    format_args!(
+                        "tcp tunnel {} <-> {} (bypassed) closed with error: {}",
+                        peer_addr, target_addr, err
+                    ),
+                );
+            context.report_relay_error(conn_id, RelayErrorKind::Relay, peer_addr, target_addr, &err);
         }
     }
+    report_undelivered(context, &state.undelivered, peer_addr, target_addr);
+    record_destination_bytes(context, target_addr, state.written_to_encrypted, state.written_to_plain);
+
+    context.emit_span_event(conn_id, SpanEventKind::Close, peer_addr, target_addr);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+    };
+
+    use log::LevelFilter;
+    use shadowsocks::{
+        config::{Mode, ServerConfig, ServerType},
+        context::Context as SsContext,
+        crypto::CipherKind,
+        ProxyListener,
+    };
+    use tokio::{
+        io::{duplex, ReadBuf},
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{
+        local::{
+            loadbalancing::PingBalancerBuilder,
+            metrics::{DestinationBytes, LocalMetrics},
+        },
+        test_utils::{
+            bind_listener,
+            capture_logs,
+            capture_warnings,
+            captured_conn_logs,
+            captured_warnings,
+            ProxiedStream,
+        },
+    };
+
+    // Writer fails with `error_kind` for `failures` times, and then accepts at most 4 bytes each write
+    struct FlakyWriter {
+        error_kind: ErrorKind,
+        failures: usize,
+        written: Vec<u8>,
+    }
+
+    impl AsyncWrite for FlakyWriter {
+        fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Poll::Ready(Err(self.error_kind.into()));
+            }
+
+            let n = buf.len().min(4);
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Remote stream never sends anything, and fails all writes
+    struct BrokenRemote;
+
+    impl AsyncRead for BrokenRemote {
+        fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, _buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for BrokenRemote {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, _buf: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn undelivered_bytes_on_close() {
+        let context = Arc::new(ServiceContext::new());
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+        let target_addr = Address::DomainNameAddress("www.example.com".to_owned(), 80);
+
+        // Tunnel closed normally, everything is delivered
+        let (mut plain, mut client) = duplex(1024);
+        let (mut shadow, mut remote) = duplex(1024);
+        let tunnel = {
+            let context = context.clone();
+            let target_addr = target_addr.clone();
+            tokio::spawn(async move {
+                establish_tcp_tunnel_bypassed(&context, 0, &mut plain, &mut shadow, peer_addr, &target_addr).await
+            })
+        };
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        drop(client);
+        let mut request = Vec::new();
+        remote.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"GET / HTTP/1.1\r\n\r\n");
+        drop(remote);
+        tunnel.await.unwrap().unwrap();
+        assert_eq!(context.flow_stat_ref().undelivered(), 0);
+
+        // Remote failed after the request was read from the client
+        let (mut plain, mut client) = duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        establish_tcp_tunnel_bypassed(&context, 1, &mut plain, &mut BrokenRemote, peer_addr, &target_addr)
+            .await
+            .unwrap();
+        assert_eq!(context.flow_stat_ref().undelivered(), 18);
+    }
+
+    #[tokio::test]
+    async fn destination_bytes_recorded() {
+        let mut context = ServiceContext::new();
+        context.set_metrics(Arc::new(LocalMetrics::with_destination_stats()));
+        let context = Arc::new(context);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 50003));
+        let target_addr = Address::DomainNameAddress("WWW.example.com".to_owned(), 80);
+
+        let (mut plain, mut client) = duplex(1024);
+        let (mut shadow, mut remote) = duplex(1024);
+        let tunnel = {
+            let context = context.clone();
+            tokio::spawn(async move {
+                establish_tcp_tunnel_bypassed(&context, 0, &mut plain, &mut shadow, peer_addr, &target_addr).await
+            })
+        };
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        remote.read_to_end(&mut request).await.unwrap();
+        remote.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        drop(remote);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        tunnel.await.unwrap().unwrap();
+
+        let destinations = context.metrics().unwrap().destination_stats().unwrap();
+        assert_eq!(
+            destinations.snapshot().into_iter().collect::<Vec<_>>(),
+            vec![("www.example.com:80".to_owned(), DestinationBytes { tx: 18, rx: 19 })]
+        );
+    }
+
+    #[tokio::test]
+    async fn close_initiated_by_client() {
+        capture_logs(LevelFilter::Trace);
+
+        let context = Arc::new(ServiceContext::new());
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 50001));
+        let target_addr = Address::DomainNameAddress("close-initiator.example.com".to_owned(), 80);
+
+        let (mut plain, mut client) = duplex(1024);
+        let (mut shadow, mut remote) = duplex(1024);
+        let tunnel = {
+            let context = context.clone();
+            let target_addr = target_addr.clone();
+            tokio::spawn(async move {
+                establish_tcp_tunnel_bypassed(&context, 0, &mut plain, &mut shadow, peer_addr, &target_addr).await
+            })
+        };
+
+        // Client finishes sending first, and remote closes after responding
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        remote.read_to_end(&mut request).await.unwrap();
+        remote.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        drop(remote);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+        tunnel.await.unwrap().unwrap();
+
+        let expected = format!(
+            "tcp tunnel {} <-> {} (bypassed) closed by client",
+            peer_addr, target_addr
+        );
+        let logs = captured_conn_logs(0);
+        let (_, fields) = logs
+            .iter()
+            .find(|(log, _)| log.starts_with(&expected))
+            .unwrap_or_else(|| panic!("no log starts with {:?}", expected));
+
+        // Fields for structured logging
+        assert_eq!(fields["event"], "closed");
+        assert_eq!(fields["proto"], "tcp");
+        assert_eq!(fields["conn_id"], "0");
+        assert_eq!(fields["peer"], peer_addr.to_string());
+        assert_eq!(fields["destination"], target_addr.to_string());
+        assert_eq!(fields["l2r_bytes"], "18");
+        assert_eq!(fields["r2l_bytes"], "19");
+        assert!(!fields.contains_key("server"));
+        assert!(!fields.contains_key("error"));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_closes_tunnel() {
+        let mut context = ServiceContext::new();
+        context.set_idle_timeout(Duration::from_millis(200));
+        let context = Arc::new(context);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 50002));
+        let target_addr = Address::DomainNameAddress("www.example.com".to_owned(), 80);
+
+        let (mut plain, mut client) = duplex(1024);
+        let (mut shadow, mut remote) = duplex(1024);
+        let tunnel = tokio::spawn(async move {
+            establish_tcp_tunnel_bypassed(&context, 0, &mut plain, &mut shadow, peer_addr, &target_addr).await
+        });
+
+        // Transferring data keeps the tunnel open, longer than the idle timeout in total
+        for _ in 0..3 {
+            time::sleep(Duration::from_millis(100)).await;
+            client.write_all(b"ping").await.unwrap();
+            let mut buffer = [0u8; 4];
+            remote.read_exact(&mut buffer).await.unwrap();
+        }
+
+        // Both sides are closed after idling
+        time::timeout(Duration::from_secs(1), tunnel)
+            .await
+            .expect("idle tunnel is still open")
+            .unwrap()
+            .unwrap();
+        let mut buffer = Vec::new();
+        assert_eq!(client.read_to_end(&mut buffer).await.unwrap(), 0);
+        assert_eq!(remote.read_to_end(&mut buffer).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn first_packet_retry_transient() {
+        let mut writer = FlakyWriter {
+            error_kind: ErrorKind::WouldBlock,
+            failures: 2,
+            written: Vec::new(),
+        };
+        write_all_retry_transient(&mut writer, b"GET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(writer.written, b"GET / HTTP/1.1\r\n\r\n");
+
+        // Connection reset is not retried
+        let mut writer = FlakyWriter {
+            error_kind: ErrorKind::ConnectionReset,
+            failures: 1,
+            written: Vec::new(),
+        };
+        let err = write_all_retry_transient(&mut writer, b"GET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        assert!(writer.written.is_empty());
+    }
+
+    #[tokio::test]
+    async fn first_packet_failover() {
+        let context = Arc::new(ServiceContext::new());
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+        let target_addr = Address::SocketAddress(SocketAddr::from(([127, 0, 0, 1], 80)));
+
+        // Connections to the broken server are reset right after they are accepted
+        let broken_listener = bind_listener().await;
+        let broken_addr = broken_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = broken_listener.accept().await {
+                let _ = stream.set_linger(Some(Duration::ZERO));
+            }
+        });
+
+        // The other server sends the first packet of the tunnel back, probes are closed
+        let good_listener = bind_listener().await;
+        let good_cfg = ServerConfig::new(good_listener.local_addr().unwrap(), "password", CipherKind::AES_128_GCM);
+        let good_listener =
+            ProxyListener::from_listener(SsContext::new_shared(ServerType::Server), good_listener, &good_cfg);
+        let (first_packet_tx, mut first_packet_rx) = mpsc::channel(1);
+        {
+            let target_addr = target_addr.clone();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = good_listener.accept().await {
+                    if stream.handshake().await.ok().as_ref() != Some(&target_addr) {
+                        continue;
+                    }
+                    let mut buffer = [0u8; 5];
+                    stream.read_exact(&mut buffer).await.unwrap();
+                    first_packet_tx.send(buffer).await.unwrap();
+                }
+            });
+        }
+
+        let mut builder = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(broken_addr, "password", CipherKind::AES_128_GCM));
+        builder.add_server(good_cfg);
+        let balancer = builder.build().await.unwrap();
+        let broken = balancer.servers().next().unwrap();
+
+        let mut shadow =
+            AutoProxyClientStream::connect_proxied_with_peer(context.clone(), broken, &target_addr, peer_addr)
+                .await
+                .unwrap();
+
+        let (mut plain, mut client) = duplex(1024);
+        let client = tokio::spawn(async move {
+            // Sent after the broken server has reset the connection
+            time::sleep(Duration::from_millis(100)).await;
+            client.write_all(b"hello").await.unwrap();
+
+            let first_packet = time::timeout(Duration::from_secs(5), first_packet_rx.recv())
+                .await
+                .expect("tunnel wasn't failed over")
+                .unwrap();
+            assert_eq!(&first_packet, b"hello");
+        });
+
+        establish_tcp_tunnel_with_failover(
+            &context,
+            0,
+            &balancer,
+            broken,
+            &mut plain,
+            &mut shadow,
+            peer_addr,
+            &target_addr,
+        )
+        .await
+        .unwrap();
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_read_ahead() {
+        let mut context = ServiceContext::new();
+        context.set_connect_read_ahead(true);
+        let context = Arc::new(context);
+
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::from(([127, 0, 0, 1], 8388)),
+            "password",
+            CipherKind::AES_128_GCM,
+        );
+        let server = ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10));
+
+        let (mut plain, mut client) = duplex(1024);
+        let (shadow, mut remote) = duplex(1024);
+
+        tokio::spawn(async move {
+            let peer_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+            let target_addr = Address::DomainNameAddress("ftp.example.com".to_owned(), 21);
+            let mut shadow = ProxiedStream(shadow);
+            establish_tcp_tunnel(&context, 0, &server, &mut plain, &mut shadow, peer_addr, &target_addr).await
+        });
+
+        // Remote speaks first, client sends nothing
+        remote.write_all(b"220 ready\r\n").await.unwrap();
+
+        // Without read-ahead, it would be relayed after waiting 500ms for the client's first packet
+        let mut greeting = [0u8; 11];
+        time::timeout(Duration::from_millis(200), client.read_exact(&mut greeting))
+            .await
+            .expect("greeting should be relayed immediately")
+            .unwrap();
+        assert_eq!(&greeting, b"220 ready\r\n");
+    }
+
+    // Relays a request sent after `idle` to a remote responding after `delay`
+    async fn relay_with_slow_remote(context: Arc<ServiceContext>, target_host: &str, idle: Duration, delay: Duration) {
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::from(([127, 0, 0, 1], 8388)),
+            "password",
+            CipherKind::AES_128_GCM,
+        );
+        let server = ServerIdent::new(svr_cfg, Duration::from_secs(5), Duration::from_secs(10));
+
+        let (mut plain, mut client) = duplex(1024);
+        let (shadow, mut remote) = duplex(1024);
+
+        let target_addr = Address::DomainNameAddress(target_host.to_owned(), 80);
+        tokio::spawn(async move {
+            let peer_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+            let mut shadow = ProxiedStream(shadow);
+            establish_tcp_tunnel(&context, 0, &server, &mut plain, &mut shadow, peer_addr, &target_addr).await
+        });
+
+        time::sleep(idle).await;
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+        let mut request = [0u8; 18];
+        remote.read_exact(&mut request).await.unwrap();
+        time::sleep(delay).await;
+        remote.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+
+        let mut response = [0u8; 17];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn slow_connection_warning() {
+        capture_warnings();
+
+        let mut context = ServiceContext::new();
+        context.set_slow_connection_threshold(Duration::from_millis(100));
+        // Handshakes are sent before clients send anything
+        context.set_connect_read_ahead(true);
+        let context = Arc::new(context);
+
+        relay_with_slow_remote(context.clone(), "fast.example.com", Duration::ZERO, Duration::ZERO).await;
+        relay_with_slow_remote(
+            context.clone(),
+            "slow.example.com",
+            Duration::ZERO,
+            Duration::from_millis(300),
+        )
+        .await;
+        // Idle time before the client sends the request isn't counted
+        relay_with_slow_remote(context, "idle.example.com", Duration::from_millis(300), Duration::ZERO).await;
+
+        let warnings = captured_warnings();
+        let slow_warnings = |host: &str| {
+            warnings
+                .iter()
+                .filter(|w| w.starts_with("slow connection") && w.contains(host))
+                .count()
+        };
+        assert_eq!(slow_warnings("fast.example.com"), 0);
+        assert_eq!(slow_warnings("slow.example.com"), 1);
+        assert_eq!(slow_warnings("idle.example.com"), 0);
+    }
+}
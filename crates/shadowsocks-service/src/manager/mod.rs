@@ -51,9 +51,15 @@ pub async fn run(config: Config) -> io::Result<()> {
     connect_opts.tcp.nodelay = config.no_delay;
     connect_opts.tcp.fastopen = config.fast_open;
     connect_opts.tcp.keepalive = config.keep_alive.or(Some(SERVER_DEFAULT_KEEPALIVE_TIMEOUT));
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        connect_opts.tcp.congestion = config.tcp_congestion.clone();
+        connect_opts.tcp.user_timeout = config.tcp_user_timeout;
+    }
 
     let mut accept_opts = AcceptOpts {
         ipv6_only: config.ipv6_only,
+        backlog: config.listen_backlog,
         ..Default::default()
     };
     accept_opts.tcp.send_buffer_size = config.inbound_send_buffer_size;
@@ -61,6 +67,11 @@ pub async fn run(config: Config) -> io::Result<()> {
     accept_opts.tcp.nodelay = config.no_delay;
     accept_opts.tcp.fastopen = config.fast_open;
     accept_opts.tcp.keepalive = config.keep_alive.or(Some(SERVER_DEFAULT_KEEPALIVE_TIMEOUT));
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        accept_opts.tcp.congestion = config.tcp_congestion.clone();
+        accept_opts.tcp.user_timeout = config.tcp_user_timeout;
+    }
 
     if let Some(resolver) = build_dns_resolver(config.dns, config.ipv6_first, &connect_opts).await {
         manager.set_dns_resolver(Arc::new(resolver));
@@ -73,6 +84,10 @@ pub async fn run(config: Config) -> io::Result<()> {
         manager.set_udp_capacity(c);
     }
 
+    if let Some(s) = config.udp_max_datagram {
+        manager.set_udp_max_datagram_size(s);
+    }
+
     if let Some(d) = config.udp_timeout {
         manager.set_udp_expiry_duration(d);
     }
@@ -16,11 +16,15 @@ use shadowsocks::{
         AddResponse,
         ErrorResponse,
         ListResponse,
+        ManagerMessage,
         ManagerRequest,
         PingResponse,
         RemoveRequest,
         RemoveResponse,
+        ServerTraffic,
         StatRequest,
+        StructuredRequest,
+        StructuredResponse,
     },
     net::{AcceptOpts, ConnectOpts},
     plugin::PluginConfig,
@@ -79,6 +83,7 @@ pub struct Manager {
     accept_opts: AcceptOpts,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_max_datagram_size: Option<usize>,
     acl: Option<Arc<AccessControl>>,
     ipv6_first: bool,
     security: SecurityConfig,
@@ -101,6 +106,7 @@ impl Manager {
             accept_opts: AcceptOpts::default(),
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_max_datagram_size: None,
             acl: None,
             ipv6_first: false,
             security: SecurityConfig::default(),
@@ -128,6 +134,11 @@ impl Manager {
         self.udp_capacity = Some(c);
     }
 
+    /// Set maximum size of UDP datagrams relayed by servers, larger ones will be dropped
+    pub fn set_udp_max_datagram_size(&mut self, s: usize) {
+        self.udp_max_datagram_size = Some(s);
+    }
+
     /// Get the manager's configuration
     pub fn config(&self) -> &ManagerConfig {
         &self.svr_cfg
@@ -170,7 +181,7 @@ impl Manager {
         info!("shadowsocks manager server listening on {}", local_addr);
 
         loop {
-            let (req, peer_addr) = match listener.recv_from().await {
+            let (message, peer_addr) = match listener.recv_message_from().await {
                 Ok(r) => r,
                 Err(err) => {
                     error!("manager recv_from error: {}", err);
@@ -178,7 +189,16 @@ impl Manager {
                 }
             };
 
-            trace!("received {:?} from {:?}", req, peer_addr);
+            trace!("received {:?} from {:?}", message, peer_addr);
+
+            let req = match message {
+                ManagerMessage::Request(req) => req,
+                ManagerMessage::Structured(ref req) => {
+                    let rsp = self.handle_structured(req).await;
+                    let _ = listener.send_to(&rsp, &peer_addr).await;
+                    continue;
+                }
+            };
 
             match req {
                 ManagerRequest::Add(ref req) => match self.handle_add(req).await {
@@ -235,6 +255,10 @@ impl Manager {
             server.set_udp_capacity(c);
         }
 
+        if let Some(s) = self.udp_max_datagram_size {
+            server.set_udp_max_datagram_size(s);
+        }
+
         if let Some(ref acl) = self.acl {
             server.set_acl(acl.clone());
         }
@@ -510,6 +534,39 @@ impl Manager {
         PingResponse { stat }
     }
 
+    async fn handle_structured(&self, req: &StructuredRequest) -> StructuredResponse {
+        match *req {
+            StructuredRequest::Add(ref req) => match self.handle_add(req).await {
+                Ok(AddResponse(ref message)) if message == "ok" => StructuredResponse::Ok,
+                Ok(AddResponse(message)) => StructuredResponse::Error { message },
+                Err(err) => {
+                    error!("add server_port: {} failed, error: {}", req.server_port, err);
+                    StructuredResponse::Error {
+                        message: err.to_string(),
+                    }
+                }
+            },
+            StructuredRequest::Remove(ref req) => {
+                self.handle_remove(req).await;
+                StructuredResponse::Ok
+            }
+            StructuredRequest::List => StructuredResponse::Servers {
+                servers: self.handle_list().await.servers,
+            },
+            StructuredRequest::Stats => {
+                let mut servers = self
+                    .handle_ping()
+                    .await
+                    .stat
+                    .into_iter()
+                    .map(|(server_port, traffic)| ServerTraffic { server_port, traffic })
+                    .collect::<Vec<_>>();
+                servers.sort_unstable_by_key(|s| s.server_port);
+                StructuredResponse::Stats { servers }
+            }
+        }
+    }
+
     #[cfg(not(unix))]
     async fn handle_stat(&self, _: &StatRequest) {}
 
@@ -590,3 +647,75 @@ impl Manager {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, UdpSocket as StdUdpSocket};
+
+    use shadowsocks::{config::ManagerAddr, manager::protocol::ManagerProtocol, ManagerClient};
+    use tokio::{net::UdpSocket, time};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn structured_stats() {
+        let manager_addr = StdUdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server_port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let manager = Manager::new(ManagerConfig::new(ManagerAddr::SocketAddr(manager_addr)));
+        tokio::spawn(manager.run());
+
+        let context = Context::new(ServerType::Local);
+        let mut client = ManagerClient::connect(
+            &context,
+            &ManagerAddr::SocketAddr(manager_addr),
+            &ConnectOpts::default(),
+        )
+        .await
+        .unwrap();
+
+        let add = StructuredRequest::Add(AddRequest {
+            server_port,
+            password: "password".to_owned(),
+            method: Some("aes-128-gcm".to_owned()),
+            no_delay: None,
+            plugin: None,
+            plugin_opts: None,
+            mode: None,
+        });
+        // Wait for the manager to be ready, requests will be refused until then
+        let rsp = loop {
+            match client.structured(&add).await {
+                Ok(rsp) => break rsp,
+                Err(..) => time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        assert!(matches!(rsp, StructuredResponse::Ok), "{:?}", rsp);
+
+        // Response is a length prefixed JSON object
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let request = StructuredRequest::Stats.to_bytes().unwrap();
+        socket.send_to(&request, manager_addr).await.unwrap();
+
+        let mut buf = [0u8; 65536];
+        let n = time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let (len, payload) = buf[..n].split_at(4);
+        assert_eq!(len, (payload.len() as u32).to_be_bytes());
+
+        let expected = format!(
+            r#"{{"type":"stats","servers":[{{"server_port":{},"traffic":0}}]}}"#,
+            server_port
+        );
+        assert_eq!(std::str::from_utf8(payload).unwrap(), expected);
+    }
+}
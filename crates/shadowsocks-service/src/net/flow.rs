@@ -11,6 +11,7 @@ type FlowCounter = std::sync::atomic::AtomicU32;
 pub struct FlowStat {
     tx: FlowCounter,
     rx: FlowCounter,
+    undelivered: FlowCounter,
 }
 
 impl Default for FlowStat {
@@ -18,6 +19,7 @@ impl Default for FlowStat {
         FlowStat {
             tx: FlowCounter::new(0),
             rx: FlowCounter::new(0),
+            undelivered: FlowCounter::new(0),
         }
     }
 }
@@ -47,4 +49,14 @@ impl FlowStat {
     pub fn incr_rx(&self, n: u64) {
         self.rx.fetch_add(n as _, Ordering::AcqRel);
     }
+
+    /// Bytes read by relays but dropped without being written, because connections were closed
+    pub fn undelivered(&self) -> u64 {
+        self.undelivered.load(Ordering::Relaxed) as _
+    }
+
+    /// Increase undelivered bytes
+    pub fn incr_undelivered(&self, n: u64) {
+        self.undelivered.fetch_add(n as _, Ordering::AcqRel);
+    }
 }
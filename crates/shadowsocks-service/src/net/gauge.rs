@@ -0,0 +1,40 @@
+//! Gauges of active connections
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Guard of an active connection counted in a gauge
+#[derive(Debug)]
+pub struct ConnectionGauge {
+    counter: Arc<AtomicUsize>,
+}
+
+impl ConnectionGauge {
+    /// Count a connection in `counter` until it is dropped
+    pub fn new(counter: Arc<AtomicUsize>) -> ConnectionGauge {
+        counter.fetch_add(1, Ordering::AcqRel);
+        ConnectionGauge { counter }
+    }
+
+    /// Count a connection in `counter` until it is dropped, `None` if there are already `max` connections
+    pub fn try_new(counter: Arc<AtomicUsize>, max: usize) -> Option<ConnectionGauge> {
+        counter
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                if n < max {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(ConnectionGauge { counter })
+    }
+}
+
+impl Drop for ConnectionGauge {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -1,12 +1,23 @@
 //! Shadowsocks Service Network Utilities
 
-pub use self::{flow::FlowStat, mon_socket::MonProxySocket, mon_stream::MonProxyStream};
+pub use self::{
+    flow::FlowStat,
+    gauge::ConnectionGauge,
+    mon_socket::MonProxySocket,
+    mon_stream::MonProxyStream,
+    task_backlog::TaskBacklog,
+    worker_pool::{WorkerPool, WorkerPoolConfig},
+};
 
 pub mod flow;
+pub mod gauge;
 pub mod mon_socket;
 pub mod mon_stream;
 pub mod packet_window;
+pub mod relay_event;
+pub mod task_backlog;
 pub mod utils;
+pub mod worker_pool;
 
 /// Packet size for all UDP associations' send queue
 pub const UDP_ASSOCIATION_SEND_CHANNEL_SIZE: usize = 1024;
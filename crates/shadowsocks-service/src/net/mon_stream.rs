@@ -10,7 +10,7 @@ use std::{
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use super::flow::FlowStat;
+use super::{flow::FlowStat, gauge::ConnectionGauge};
 
 /// Monitored `ProxyStream`
 #[pin_project]
@@ -18,12 +18,40 @@ pub struct MonProxyStream<S> {
     #[pin]
     stream: S,
     flow_stat: Arc<FlowStat>,
+    server_flow_stat: Option<Arc<FlowStat>>,
+    server_gauge: Option<ConnectionGauge>,
 }
 
 impl<S> MonProxyStream<S> {
     #[inline]
     pub fn from_stream(stream: S, flow_stat: Arc<FlowStat>) -> MonProxyStream<S> {
-        MonProxyStream { stream, flow_stat }
+        MonProxyStream {
+            stream,
+            flow_stat,
+            server_flow_stat: None,
+            server_gauge: None,
+        }
+    }
+
+    /// Monitored by both `flow_stat` and the statistic of the server that `stream` connects to
+    #[inline]
+    pub fn from_stream_with_server(
+        stream: S,
+        flow_stat: Arc<FlowStat>,
+        server_flow_stat: Arc<FlowStat>,
+    ) -> MonProxyStream<S> {
+        MonProxyStream {
+            stream,
+            flow_stat,
+            server_flow_stat: Some(server_flow_stat),
+            server_gauge: None,
+        }
+    }
+
+    /// Count this stream in the gauge of the server's connections until it is dropped
+    #[inline]
+    pub fn set_server_gauge(&mut self, gauge: ConnectionGauge) {
+        self.server_gauge = Some(gauge);
     }
 
     #[inline]
@@ -54,6 +82,9 @@ where
             Poll::Ready(Ok(())) => {
                 let n = buf.filled().len();
                 this.flow_stat.incr_rx(n as u64);
+                if let Some(server_flow_stat) = this.server_flow_stat {
+                    server_flow_stat.incr_rx(n as u64);
+                }
                 Poll::Ready(Ok(()))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
@@ -72,6 +103,9 @@ where
             Poll::Pending => Poll::Pending,
             Poll::Ready(Ok(n)) => {
                 this.flow_stat.incr_tx(n as u64);
+                if let Some(server_flow_stat) = this.server_flow_stat {
+                    server_flow_stat.incr_tx(n as u64);
+                }
                 Poll::Ready(Ok(n))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
@@ -0,0 +1,132 @@
+//! Events of TCP tunnels and UDP associations
+
+use std::{
+    fmt,
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::{log, log_enabled, Level};
+use shadowsocks::{config::ServerAddr, relay::socks5::Address};
+
+static RELAY_EVENTS_INFO: AtomicBool = AtomicBool::new(false);
+
+/// Log relay events at least at `Info` level
+///
+/// Events are logged at `Debug` or `Trace` level by default. Structured loggers, like the JSON logging format, are
+/// configured for collecting them, so they shouldn't be filtered out with the other debug logs.
+pub fn set_relay_events_info(enabled: bool) {
+    RELAY_EVENTS_INFO.store(enabled, Ordering::Relaxed);
+}
+
+/// Event of a TCP tunnel or an UDP association
+///
+/// Logged as a plain message, prefixed with the connection id if there is one. Fields are also set to the MDC while
+/// logging, so structured encoders, like the JSON logging format, could emit them as fields.
+pub struct RelayEvent<'a> {
+    kind: &'static str,
+    proto: &'static str,
+    conn_id: Option<usize>,
+    peer_addr: SocketAddr,
+    target_addr: Option<&'a Address>,
+    server_addr: Option<&'a ServerAddr>,
+    // L2R and R2L bytes
+    bytes: Option<(u64, u64)>,
+    error: Option<&'a io::Error>,
+}
+
+impl<'a> RelayEvent<'a> {
+    /// Event of a TCP tunnel from `peer_addr` to `target_addr`
+    pub fn tcp(kind: &'static str, peer_addr: SocketAddr, target_addr: &'a Address) -> RelayEvent<'a> {
+        RelayEvent {
+            kind,
+            proto: "tcp",
+            conn_id: None,
+            peer_addr,
+            target_addr: Some(target_addr),
+            server_addr: None,
+            bytes: None,
+            error: None,
+        }
+    }
+
+    /// Event of an UDP association of `peer_addr`, which may relay packets to different targets
+    pub fn udp(kind: &'static str, peer_addr: SocketAddr) -> RelayEvent<'a> {
+        RelayEvent {
+            kind,
+            proto: "udp",
+            conn_id: None,
+            peer_addr,
+            target_addr: None,
+            server_addr: None,
+            bytes: None,
+            error: None,
+        }
+    }
+
+    /// Identifier of the connection
+    pub fn conn_id(mut self, conn_id: usize) -> RelayEvent<'a> {
+        self.conn_id = Some(conn_id);
+        self
+    }
+
+    /// Server relaying the connection, not set for bypassed connections
+    pub fn server(mut self, server_addr: &'a ServerAddr) -> RelayEvent<'a> {
+        self.server_addr = Some(server_addr);
+        self
+    }
+
+    /// Bytes relayed from local to remote, and from remote to local
+    pub fn bytes(mut self, l2r: u64, r2l: u64) -> RelayEvent<'a> {
+        self.bytes = Some((l2r, r2l));
+        self
+    }
+
+    /// Error closing the connection
+    pub fn error(mut self, err: &'a io::Error) -> RelayEvent<'a> {
+        self.error = Some(err);
+        self
+    }
+
+    /// Log the event at `level`, or at `Info` level if `set_relay_events_info` is enabled
+    pub fn log(&self, level: Level, args: fmt::Arguments<'_>) {
+        let level = if RELAY_EVENTS_INFO.load(Ordering::Relaxed) {
+            level.min(Level::Info)
+        } else {
+            level
+        };
+
+        if !log_enabled!(level) {
+            return;
+        }
+
+        let mut fields = vec![
+            ("event", self.kind.to_owned()),
+            ("proto", self.proto.to_owned()),
+            ("peer", self.peer_addr.to_string()),
+        ];
+        if let Some(conn_id) = self.conn_id {
+            fields.push(("conn_id", conn_id.to_string()));
+        }
+        if let Some(target_addr) = self.target_addr {
+            fields.push(("destination", target_addr.to_string()));
+        }
+        if let Some(server_addr) = self.server_addr {
+            fields.push(("server", server_addr.to_string()));
+        }
+        if let Some((l2r, r2l)) = self.bytes {
+            fields.push(("l2r_bytes", l2r.to_string()));
+            fields.push(("r2l_bytes", r2l.to_string()));
+        }
+        if let Some(err) = self.error {
+            fields.push(("error", err.to_string()));
+        }
+
+        let _mdc = log_mdc::extend_scoped(fields);
+        match self.conn_id {
+            Some(conn_id) => log!(level, "[c{}] {}", conn_id, args),
+            None => log!(level, "{}", args),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Backlog of tasks waiting for the scheduler
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Gauge of relay tasks which are spawned but haven't been polled by the scheduler yet
+///
+/// It grows when the runtime is overloaded, which delays relay tasks and times out their clients.
+#[derive(Debug, Default)]
+pub struct TaskBacklog {
+    pending: AtomicUsize,
+}
+
+impl TaskBacklog {
+    /// Create an empty backlog
+    pub fn new() -> TaskBacklog {
+        TaskBacklog::default()
+    }
+
+    /// Number of pending tasks
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Count a new pending task until the returned guard is dropped
+    ///
+    /// The task should drop the guard as soon as it is polled.
+    pub fn enter(self: &Arc<Self>) -> PendingTask {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        PendingTask { backlog: self.clone() }
+    }
+}
+
+/// Guard of a pending task in `TaskBacklog`
+#[derive(Debug)]
+pub struct PendingTask {
+    backlog: Arc<TaskBacklog>,
+}
+
+impl Drop for PendingTask {
+    fn drop(&mut self) {
+        self.backlog.pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -5,6 +5,7 @@ use std::{
     net::{Ipv4Addr, Ipv6Addr},
 };
 
+use cfg_if::cfg_if;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 /// Consumes all data from `reader` and throws away until EOF
@@ -24,6 +25,23 @@ where
     Ok(())
 }
 
+/// Check if `err` is caused by running out of file descriptors of this process or the system
+///
+/// It is a local resource issue, retrying immediately is pointless.
+pub fn is_fd_exhausted(err: &io::Error) -> bool {
+    cfg_if! {
+        if #[cfg(unix)] {
+            matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+        } else if #[cfg(windows)] {
+            // WSAEMFILE
+            err.raw_os_error() == Some(10024)
+        } else {
+            let _ = err;
+            false
+        }
+    }
+}
+
 /// Helper function for converting IPv4 mapped IPv6 address
 ///
 /// This is the same as `Ipv6Addr::to_ipv4_mapped`, but it is still unstable in the current libstd
@@ -0,0 +1,204 @@
+//! Bounded pool of workers handling accepted connections
+
+use std::{
+    cell::Cell,
+    future::Future,
+    io::{self, ErrorKind},
+    panic::AssertUnwindSafe,
+    sync::Arc,
+};
+
+use futures::FutureExt;
+use log::error;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+    Mutex,
+};
+
+tokio::task_local! {
+    // Frees the worker handling the current task
+    static WORKER_RELEASE: Cell<Option<oneshot::Sender<()>>>;
+}
+
+/// Free the worker handling the current connection, before relaying data for the rest of its lifetime
+///
+/// Nothing happens if the connection isn't handled by a `WorkerPool`.
+pub fn release_worker() {
+    if let Ok(Some(release)) = WORKER_RELEASE.try_with(|r| r.take()) {
+        let _ = release.send(());
+    }
+}
+
+/// Configuration of `WorkerPool`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerPoolConfig {
+    /// Number of workers, which is the maximum number of connections being accepted and handshaking simultaneously
+    pub workers: usize,
+    /// Number of accepted connections waiting for a worker, connections are rejected when the queue is full
+    pub queue_size: usize,
+}
+
+/// Fixed number of workers handling items dispatched through a bounded queue
+///
+/// Instead of spawning a task for every accepted connection, accept loops dispatch them to the pool,
+/// which handshakes at most `workers` connections at the same time. Handlers call `release_worker` once they start
+/// relaying, the worker takes the next connection while the handler keeps running in its own task. Bursts of
+/// connections exceeding the queue are rejected, so accept loops never wait for the workers.
+pub struct WorkerPool<T> {
+    sender: mpsc::Sender<T>,
+}
+
+impl<T> WorkerPool<T>
+where
+    T: Send + 'static,
+{
+    /// Create a pool with workers handling items with `handler`
+    ///
+    /// Workers exit after the pool is dropped and the queue is drained.
+    pub fn new<F, Fut>(config: WorkerPoolConfig, handler: F) -> WorkerPool<T>
+    where
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        assert!(config.workers > 0 && config.queue_size > 0);
+
+        let (sender, receiver) = mpsc::channel(config.queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        for _ in 0..config.workers {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                loop {
+                    // Lock is released before handling, so the other workers could take the next one
+                    let item = match receiver.lock().await.recv().await {
+                        Some(item) => item,
+                        None => break,
+                    };
+
+                    let (release_tx, release_rx) = oneshot::channel();
+                    let handling = WORKER_RELEASE.scope(Cell::new(Some(release_tx)), handler(item));
+                    tokio::spawn(async move {
+                        if AssertUnwindSafe(handling).catch_unwind().await.is_err() {
+                            error!("worker pool handler panicked");
+                        }
+                    });
+
+                    // Released by the handler, or it has finished (or panicked) without releasing
+                    let _ = release_rx.await;
+                }
+            });
+        }
+
+        WorkerPool { sender }
+    }
+
+    /// Dispatch `item` to the workers, `item` is dropped if the queue is full
+    pub fn dispatch(&self, item: T) -> io::Result<()> {
+        self.sender.try_send(item).map_err(|err| match err {
+            TrySendError::Full(..) => io::Error::new(ErrorKind::Other, "worker pool queue is full"),
+            TrySendError::Closed(..) => io::Error::new(ErrorKind::Other, "worker pool is closed"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn worker_pool_bounded() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let handled = Arc::new(AtomicUsize::new(0));
+
+        let pool = {
+            let active = active.clone();
+            let max_active = max_active.clone();
+            let handled = handled.clone();
+            WorkerPool::new(
+                WorkerPoolConfig {
+                    workers: 2,
+                    queue_size: 4,
+                },
+                move |_: usize| {
+                    let active = active.clone();
+                    let max_active = max_active.clone();
+                    let handled = handled.clone();
+                    async move {
+                        let n = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_active.fetch_max(n, Ordering::SeqCst);
+                        time::sleep(Duration::from_millis(20)).await;
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        handled.fetch_add(1, Ordering::SeqCst);
+                    }
+                },
+            )
+        };
+
+        // A burst of connections, more than the workers and the queue, the rest of them are rejected
+        let dispatched = (0..16).filter(|i| pool.dispatch(*i).is_ok()).count();
+        assert_eq!(dispatched, 4);
+
+        time::timeout(Duration::from_secs(5), async {
+            while handled.load(Ordering::SeqCst) < dispatched {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(max_active.load(Ordering::SeqCst), 2);
+
+        // Queue is available again
+        pool.dispatch(16).unwrap();
+    }
+
+    #[tokio::test]
+    async fn worker_pool_released_by_relaying() {
+        let relaying = Arc::new(AtomicUsize::new(0));
+
+        let pool = {
+            let relaying = relaying.clone();
+            WorkerPool::new(
+                WorkerPoolConfig {
+                    workers: 1,
+                    queue_size: 4,
+                },
+                move |_: usize| {
+                    let relaying = relaying.clone();
+                    async move {
+                        time::sleep(Duration::from_millis(10)).await;
+
+                        // Handshake is done, relays until the end of the test
+                        release_worker();
+                        relaying.fetch_add(1, Ordering::SeqCst);
+                        time::sleep(Duration::from_secs(60)).await;
+                    }
+                },
+            )
+        };
+
+        // Tunnels are not bounded by the only worker
+        for i in 0..4 {
+            pool.dispatch(i).unwrap();
+        }
+
+        time::timeout(Duration::from_secs(5), async {
+            while relaying.load(Ordering::SeqCst) < 4 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -1,16 +1,20 @@
 //! Shadowsocks Local Server Context
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use shadowsocks::{
-    config::ServerType,
+    config::{ConnectStrategy, ServerType},
     context::{Context, SharedContext},
     dns_resolver::DnsResolver,
     net::ConnectOpts,
     relay::Address,
 };
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+use crate::{
+    acl::AccessControl,
+    config::SecurityConfig,
+    net::{FlowStat, TaskBacklog},
+};
 
 /// Server Service Context
 pub struct ServiceContext {
@@ -22,6 +26,12 @@ pub struct ServiceContext {
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
+
+    // Relay tasks waiting for the scheduler
+    task_backlog: Arc<TaskBacklog>,
+
+    // Tunnels without any bytes transferred in this period are closed
+    idle_timeout: Option<Duration>,
 }
 
 impl Default for ServiceContext {
@@ -31,6 +41,8 @@ impl Default for ServiceContext {
             connect_opts: ConnectOpts::default(),
             acl: None,
             flow_stat: Arc::new(FlowStat::new()),
+            task_backlog: Arc::new(TaskBacklog::new()),
+            idle_timeout: None,
         }
     }
 }
@@ -81,6 +93,23 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Get backlog of relay tasks waiting for the scheduler
+    pub fn task_backlog(&self) -> &Arc<TaskBacklog> {
+        &self.task_backlog
+    }
+
+    /// Set idle timeout of TCP tunnels
+    ///
+    /// Tunnels without any bytes transferred in either direction for `timeout` will be closed.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// Get idle timeout of TCP tunnels
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -114,6 +143,19 @@ impl ServiceContext {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Set the maximum number of DNS resolutions in flight, resolutions beyond it fail immediately
+    pub fn set_max_pending_dns_resolutions(&mut self, max: usize) {
+        let context =
+            Arc::get_mut(&mut self.context).expect("cannot set max_pending_dns_resolutions on a shared context");
+        context.set_max_pending_dns_resolutions(max);
+    }
+
+    /// Set the order of trying resolved addresses when connecting to targets directly
+    pub fn set_direct_connect_strategy(&mut self, strategy: ConnectStrategy) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set direct_connect_strategy on a shared context");
+        context.set_direct_connect_strategy(strategy);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
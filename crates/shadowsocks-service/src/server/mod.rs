@@ -33,10 +33,12 @@ mod udprelay;
 pub(crate) const SERVER_DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(15);
 
 /// Starts a shadowsocks server
-pub async fn run(config: Config) -> io::Result<()> {
+pub async fn run(mut config: Config) -> io::Result<()> {
     assert_eq!(config.config_type, ConfigType::Server);
     assert!(!config.server.is_empty());
 
+    config.apply_default_timeout();
+
     trace!("{:?}", config);
 
     // Warning for Stream Ciphers
@@ -76,9 +78,15 @@ pub async fn run(config: Config) -> io::Result<()> {
     connect_opts.tcp.nodelay = config.no_delay;
     connect_opts.tcp.fastopen = config.fast_open;
     connect_opts.tcp.keepalive = config.keep_alive.or(Some(SERVER_DEFAULT_KEEPALIVE_TIMEOUT));
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        connect_opts.tcp.congestion = config.tcp_congestion.clone();
+        connect_opts.tcp.user_timeout = config.tcp_user_timeout;
+    }
 
     let mut accept_opts = AcceptOpts {
         ipv6_only: config.ipv6_only,
+        backlog: config.listen_backlog,
         ..Default::default()
     };
     accept_opts.tcp.send_buffer_size = config.inbound_send_buffer_size;
@@ -86,6 +94,11 @@ pub async fn run(config: Config) -> io::Result<()> {
     accept_opts.tcp.nodelay = config.no_delay;
     accept_opts.tcp.fastopen = config.fast_open;
     accept_opts.tcp.keepalive = config.keep_alive.or(Some(SERVER_DEFAULT_KEEPALIVE_TIMEOUT));
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        accept_opts.tcp.congestion = config.tcp_congestion.clone();
+        accept_opts.tcp.user_timeout = config.tcp_user_timeout;
+    }
 
     let resolver = build_dns_resolver(config.dns, config.ipv6_first, &connect_opts)
         .await
@@ -106,6 +119,9 @@ pub async fn run(config: Config) -> io::Result<()> {
         if let Some(c) = config.udp_max_associations {
             server.set_udp_capacity(c);
         }
+        if let Some(s) = config.udp_max_datagram {
+            server.set_udp_max_datagram_size(s);
+        }
         if let Some(d) = config.udp_timeout {
             server.set_udp_expiry_duration(d);
         }
@@ -121,10 +137,28 @@ pub async fn run(config: Config) -> io::Result<()> {
             server.set_ipv6_first(config.ipv6_first);
         }
 
+        if let Some(max) = config.max_pending_dns_resolutions {
+            server.set_max_pending_dns_resolutions(max);
+        }
+
+        if let Some(timeout) = config.idle_timeout {
+            server.set_idle_timeout(timeout);
+        }
+
+        server.set_direct_connect_strategy(config.direct_connect_strategy);
+
         if config.worker_count >= 1 {
             server.set_worker_count(config.worker_count);
         }
 
+        if let Some(pool) = config.worker_pool {
+            server.set_worker_pool(pool);
+        }
+
+        if let Some(max) = config.max_pending_tasks {
+            server.set_max_pending_tasks(max);
+        }
+
         server.set_security_config(&config.security);
 
         servers.push(server);
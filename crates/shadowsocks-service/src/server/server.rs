@@ -10,7 +10,7 @@ use std::{
 use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
 use log::{error, trace};
 use shadowsocks::{
-    config::{ManagerAddr, ServerConfig},
+    config::{ConnectStrategy, ManagerAddr, ServerConfig},
     dns_resolver::DnsResolver,
     net::{AcceptOpts, ConnectOpts},
     plugin::{Plugin, PluginMode},
@@ -18,7 +18,11 @@ use shadowsocks::{
 };
 use tokio::time;
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+use crate::{
+    acl::AccessControl,
+    config::SecurityConfig,
+    net::{FlowStat, TaskBacklog, WorkerPoolConfig},
+};
 
 use super::{context::ServiceContext, tcprelay::TcpServer, udprelay::UdpServer};
 
@@ -28,9 +32,12 @@ pub struct Server {
     svr_cfg: ServerConfig,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
+    udp_max_datagram_size: Option<usize>,
     manager_addr: Option<ManagerAddr>,
     accept_opts: AcceptOpts,
     worker_count: usize,
+    worker_pool: Option<WorkerPoolConfig>,
+    max_pending_tasks: Option<usize>,
 }
 
 impl Server {
@@ -46,9 +53,12 @@ impl Server {
             svr_cfg,
             udp_expiry_duration: None,
             udp_capacity: None,
+            udp_max_datagram_size: None,
             manager_addr: None,
             accept_opts: AcceptOpts::default(),
             worker_count: 1,
+            worker_pool: None,
+            max_pending_tasks: None,
         }
     }
 
@@ -78,6 +88,11 @@ impl Server {
         self.udp_capacity = Some(c);
     }
 
+    /// Set maximum size of UDP datagrams relayed by this server, larger ones will be dropped
+    pub fn set_udp_max_datagram_size(&mut self, s: usize) {
+        self.udp_max_datagram_size = Some(s);
+    }
+
     /// Set manager's address to report `stat`
     pub fn set_manager_addr(&mut self, manager_addr: ManagerAddr) {
         self.manager_addr = Some(manager_addr);
@@ -91,11 +106,32 @@ impl Server {
         self.worker_count = worker_count;
     }
 
+    /// Set the pool of workers handling accepted TCP connections, instead of a task for each of them
+    pub fn set_worker_pool(&mut self, config: WorkerPoolConfig) {
+        self.worker_pool = Some(config);
+    }
+
+    /// Set the maximum number of relay tasks waiting for the scheduler, new TCP connections are rejected beyond it
+    pub fn set_max_pending_tasks(&mut self, max: usize) {
+        self.max_pending_tasks = Some(max);
+    }
+
+    /// Get backlog of relay tasks waiting for the scheduler
+    pub fn task_backlog(&self) -> &TaskBacklog {
+        self.context.task_backlog()
+    }
+
     /// Get server's configuration
     pub fn config(&self) -> &ServerConfig {
         &self.svr_cfg
     }
 
+    /// Set idle timeout of TCP tunnels
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set idle_timeout on a shared context");
+        context.set_idle_timeout(timeout);
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -119,6 +155,19 @@ impl Server {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Set the maximum number of DNS resolutions in flight, resolutions beyond it fail immediately
+    pub fn set_max_pending_dns_resolutions(&mut self, max: usize) {
+        let context =
+            Arc::get_mut(&mut self.context).expect("cannot set max_pending_dns_resolutions on a shared context");
+        context.set_max_pending_dns_resolutions(max);
+    }
+
+    /// Set the order of trying resolved addresses when connecting to targets directly
+    pub fn set_direct_connect_strategy(&mut self, strategy: ConnectStrategy) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set direct_connect_strategy on a shared context");
+        context.set_direct_connect_strategy(strategy);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
@@ -174,7 +223,13 @@ impl Server {
     }
 
     async fn run_tcp_server(&self) -> io::Result<()> {
-        let server = TcpServer::new(self.context.clone(), self.accept_opts.clone());
+        let mut server = TcpServer::new(self.context.clone(), self.accept_opts.clone());
+        if let Some(config) = self.worker_pool {
+            server.set_worker_pool(config);
+        }
+        if let Some(max) = self.max_pending_tasks {
+            server.set_max_pending_tasks(max);
+        }
         server.run(&self.svr_cfg).await
     }
 
@@ -187,6 +242,9 @@ impl Server {
             self.accept_opts.clone(),
         );
         server.set_worker_count(self.worker_count);
+        if let Some(s) = self.udp_max_datagram_size {
+            server.set_max_datagram_size(s);
+        }
         server.run(&self.svr_cfg).await
     }
 
@@ -8,43 +8,88 @@ use std::{
     time::Duration,
 };
 
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, info, trace, warn, Level};
+#[cfg(feature = "stream-compression")]
+use shadowsocks::relay::tcprelay::compress_stream::CompressedStream;
 use shadowsocks::{
     crypto::CipherKind,
     net::{AcceptOpts, TcpStream as OutboundTcpStream},
-    relay::tcprelay::{utils::copy_encrypted_bidirectional, ProxyServerStream},
+    relay::{
+        socks5::Address,
+        tcprelay::{
+            utils::{
+                copy_encrypted_bidirectional_with_state,
+                CloseInitiator,
+                CopyBidirectionalOpts,
+                CopyBidirectionalState,
+            },
+            ProxyServerStream,
+        },
+    },
     ProxyListener,
     ServerConfig,
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream as TokioTcpStream,
     time,
 };
 
-use crate::net::{utils::ignore_until_end, MonProxyStream};
+use crate::net::{
+    relay_event::RelayEvent,
+    task_backlog::PendingTask,
+    utils::ignore_until_end,
+    worker_pool::release_worker,
+    MonProxyStream,
+    WorkerPool,
+    WorkerPoolConfig,
+};
 
 use super::context::ServiceContext;
 
 pub struct TcpServer {
     context: Arc<ServiceContext>,
     accept_opts: AcceptOpts,
+    worker_pool: Option<WorkerPoolConfig>,
+    max_pending_tasks: Option<usize>,
 }
 
 impl TcpServer {
     pub fn new(context: Arc<ServiceContext>, accept_opts: AcceptOpts) -> TcpServer {
-        TcpServer { context, accept_opts }
+        TcpServer {
+            context,
+            accept_opts,
+            worker_pool: None,
+            max_pending_tasks: None,
+        }
+    }
+
+    pub fn set_worker_pool(&mut self, config: WorkerPoolConfig) {
+        self.worker_pool = Some(config);
+    }
+
+    pub fn set_max_pending_tasks(&mut self, max: usize) {
+        self.max_pending_tasks = Some(max);
     }
 
     pub async fn run(self, svr_cfg: &ServerConfig) -> io::Result<()> {
-        let listener = ProxyListener::bind_with_opts(self.context.context(), svr_cfg, self.accept_opts).await?;
+        let listener = ProxyListener::bind_with_opts(self.context.context(), svr_cfg, self.accept_opts.clone()).await?;
+
+        self.run_with_listener(svr_cfg, listener).await
+    }
 
+    /// Run server of `svr_cfg` on a bound listener
+    pub async fn run_with_listener(self, svr_cfg: &ServerConfig, listener: ProxyListener) -> io::Result<()> {
         info!(
             "shadowsocks tcp server listening on {}, inbound address {}",
             listener.local_addr().expect("listener.local_addr"),
             svr_cfg.addr()
         );
 
+        let worker_pool = self
+            .worker_pool
+            .map(|config| WorkerPool::new(config, TcpServerClient::serve_logged));
+
         loop {
             let flow_stat = self.context.flow_stat();
 
@@ -63,19 +108,39 @@ impl TcpServer {
                 continue;
             }
 
+            let task_backlog = self.context.task_backlog();
+            if let Some(max) = self.max_pending_tasks {
+                let pending = task_backlog.pending();
+                if pending >= max {
+                    warn!(
+                        "tcp server rejected {}, {} relay tasks are waiting for the scheduler",
+                        peer_addr, pending
+                    );
+                    continue;
+                }
+            }
+
             let client = TcpServerClient {
                 context: self.context.clone(),
                 method: svr_cfg.method(),
+                pending: Some(task_backlog.enter()),
                 peer_addr,
                 stream: local_stream,
                 timeout: svr_cfg.timeout(),
+                #[cfg(feature = "stream-compression")]
+                compression: svr_cfg.compression(),
             };
 
-            tokio::spawn(async move {
-                if let Err(err) = client.serve().await {
-                    debug!("tcp server stream aborted with error: {}", err);
+            match worker_pool {
+                Some(ref pool) => {
+                    if let Err(err) = pool.dispatch(client) {
+                        warn!("tcp server rejected client {}, error: {}", peer_addr, err);
+                    }
+                }
+                None => {
+                    tokio::spawn(client.serve_logged());
                 }
-            });
+            }
         }
     }
 }
@@ -96,13 +161,25 @@ where
 
 struct TcpServerClient {
     context: Arc<ServiceContext>,
+    pending: Option<PendingTask>,
     method: CipherKind,
     peer_addr: SocketAddr,
     stream: ProxyServerStream<MonProxyStream<TokioTcpStream>>,
     timeout: Option<Duration>,
+    #[cfg(feature = "stream-compression")]
+    compression: bool,
 }
 
 impl TcpServerClient {
+    async fn serve_logged(mut self) {
+        // Polled by the scheduler, no longer pending
+        self.pending.take();
+
+        if let Err(err) = self.serve().await {
+            debug!("tcp server stream aborted with error: {}", err);
+        }
+    }
+
     async fn serve(mut self) -> io::Result<()> {
         // let target_addr = match Address::read_from(&mut self.stream).await {
         let target_addr = match timeout_fut(self.timeout, self.stream.handshake()).await {
@@ -154,6 +231,8 @@ impl TcpServerClient {
                 // Note: This will drop all data in the decryption buffer, which is no going back.
                 let mut stream = self.stream.into_inner();
 
+                // Drained for as long as the peer keeps it open, which shouldn't take a worker
+                release_worker();
                 let res = ignore_until_end(&mut stream).await;
 
                 trace!(
@@ -180,7 +259,7 @@ impl TcpServerClient {
             return Ok(());
         }
 
-        let mut remote_stream = match timeout_fut(
+        let remote_stream = match timeout_fut(
             self.timeout,
             OutboundTcpStream::connect_remote_with_opts(
                 self.context.context_ref(),
@@ -200,63 +279,173 @@ impl TcpServerClient {
             }
         };
 
-        // https://github.com/shadowsocks/shadowsocks-rust/issues/232
-        //
-        // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
-        //
-        // Wait at most 500ms, and then sends handshake packet to remote servers.
-        if self.context.connect_opts_ref().tcp.fastopen {
-            let mut buffer = [0u8; 8192];
-            match time::timeout(Duration::from_millis(500), self.stream.read(&mut buffer)).await {
-                Ok(Ok(0)) => {
-                    // EOF. Just terminate right here.
-                    return Ok(());
-                }
-                Ok(Ok(n)) => {
-                    // Send the first packet.
-                    timeout_fut(self.timeout, remote_stream.write_all(&buffer[..n])).await?;
-                }
-                Ok(Err(err)) => return Err(err),
-                Err(..) => {
-                    // Timeout. Send handshake to server.
-                    timeout_fut(self.timeout, remote_stream.write(&[])).await?;
-
-                    trace!(
-                        "tcp tunnel {} -> {} sent TFO connect without data",
-                        self.peer_addr,
-                        target_addr
-                    );
-                }
-            }
+        #[cfg(feature = "stream-compression")]
+        if self.compression {
+            // Data are compressed before being encrypted by the client
+            let mut local_stream = CompressedStream::new(&mut self.stream);
+            return relay_tunnel(
+                &self.context,
+                self.method,
+                self.peer_addr,
+                self.timeout,
+                &mut local_stream,
+                remote_stream,
+                &target_addr,
+            )
+            .await;
         }
 
-        debug!(
-            "established tcp tunnel {} <-> {} with {:?}",
+        relay_tunnel(
+            &self.context,
+            self.method,
             self.peer_addr,
-            target_addr,
-            self.context.connect_opts_ref()
-        );
+            self.timeout,
+            &mut self.stream,
+            remote_stream,
+            &target_addr,
+        )
+        .await
+    }
+}
 
-        match copy_encrypted_bidirectional(self.method, &mut self.stream, &mut remote_stream).await {
-            Ok((rn, wn)) => {
-                trace!(
-                    "tcp tunnel {} <-> {} closed, L2R {} bytes, R2L {} bytes",
-                    self.peer_addr,
-                    target_addr,
-                    rn,
-                    wn
-                );
+/// Relay data between the client's `local_stream` and `remote_stream` connected to `target_addr`
+async fn relay_tunnel<S>(
+    context: &ServiceContext,
+    method: CipherKind,
+    peer_addr: SocketAddr,
+    timeout: Option<Duration>,
+    local_stream: &mut S,
+    mut remote_stream: OutboundTcpStream,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Relaying for the rest of the connection's lifetime
+    release_worker();
+
+    // https://github.com/shadowsocks/shadowsocks-rust/issues/232
+    //
+    // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
+    //
+    // Wait at most 500ms, and then sends handshake packet to remote servers.
+    if context.connect_opts_ref().tcp.fastopen {
+        let mut buffer = [0u8; 8192];
+        match time::timeout(Duration::from_millis(500), local_stream.read(&mut buffer)).await {
+            Ok(Ok(0)) => {
+                // EOF. Just terminate right here.
+                return Ok(());
             }
-            Err(err) => {
+            Ok(Ok(n)) => {
+                // Send the first packet.
+                timeout_fut(timeout, remote_stream.write_all(&buffer[..n])).await?;
+            }
+            Ok(Err(err)) => return Err(err),
+            Err(..) => {
+                // Timeout. Send handshake to server.
+                timeout_fut(timeout, remote_stream.write(&[])).await?;
+
                 trace!(
-                    "tcp tunnel {} <-> {} closed with error: {}",
-                    self.peer_addr,
-                    target_addr,
-                    err
+                    "tcp tunnel {} -> {} sent TFO connect without data",
+                    peer_addr,
+                    target_addr
                 );
             }
         }
+    }
+
+    RelayEvent::tcp("established", peer_addr, target_addr).log(
+        Level::Debug,
+        format_args!(
+            "established tcp tunnel {} <-> {} with {:?}",
+            peer_addr,
+            target_addr,
+            context.connect_opts_ref()
+        ),
+    );
+
+    let copy_opts = CopyBidirectionalOpts {
+        idle_timeout: context.idle_timeout(),
+    };
+    let mut state = CopyBidirectionalState::default();
+    match copy_encrypted_bidirectional_with_state(method, local_stream, &mut remote_stream, &copy_opts, &mut state)
+        .await
+    {
+        Ok((rn, wn)) => {
+            // Encrypted stream is the client
+            let closed_by = match state.close_initiator {
+                Some(CloseInitiator::Encrypted) => "client",
+                Some(CloseInitiator::Plain) => "remote",
+                Some(CloseInitiator::Both) => "both sides",
+                None => "unknown",
+            };
+            RelayEvent::tcp("closed", peer_addr, target_addr).bytes(rn, wn).log(
+                Level::Trace,
+                format_args!(
+                    "tcp tunnel {} <-> {} closed by {}, L2R {} bytes, R2L {} bytes",
+                    peer_addr, target_addr, closed_by, rn, wn
+                ),
+            );
+        }
+        Err(err) => {
+            // Encrypted stream is the client, L2R bytes are written to the plain stream
+            RelayEvent::tcp("error", peer_addr, target_addr)
+                .bytes(state.written_to_plain, state.written_to_encrypted)
+                .error(&err)
+                .log(
+                    Level::Trace,
+                    format_args!(
+                        "tcp tunnel {} <-> {} closed with error: {}",
+                        peer_addr, target_addr, err
+                    ),
+                );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::{config::ServerType, context::Context};
+
+    use crate::test_utils::bind_listener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tcp_max_pending_tasks() {
+        let listener = bind_listener().await;
+        let server_addr = listener.local_addr().unwrap();
+        let svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_128_GCM);
+        let listener = ProxyListener::from_listener(Context::new_shared(ServerType::Server), listener, &svr_cfg);
+
+        let context = Arc::new(ServiceContext::new());
+        let mut server = TcpServer::new(context.clone(), AcceptOpts::default());
+        server.set_max_pending_tasks(2);
+        tokio::spawn(async move { server.run_with_listener(&svr_cfg, listener).await });
+
+        // Simulates relay tasks which are not scheduled yet
+        let backlog = context.task_backlog();
+        let pending = vec![backlog.enter(), backlog.enter()];
+        assert_eq!(backlog.pending(), 2);
+
+        // Rejected, closed immediately
+        let mut client = TokioTcpStream::connect(server_addr).await.unwrap();
+        let mut buffer = [0u8; 1];
+        let n = time::timeout(Duration::from_secs(1), client.read(&mut buffer))
+            .await
+            .expect("rejected connection is still open")
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+
+        drop(pending);
+        assert_eq!(backlog.pending(), 0);
 
-        Ok(())
+        // Accepted, waiting for the handshake
+        let mut client = TokioTcpStream::connect(server_addr).await.unwrap();
+        let r = time::timeout(Duration::from_millis(200), client.read(&mut buffer)).await;
+        assert!(r.is_err(), "accepted connection is closed");
+        assert_eq!(backlog.pending(), 0);
     }
 }
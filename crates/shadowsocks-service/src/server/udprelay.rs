@@ -10,7 +10,7 @@ use std::{
 
 use bytes::Bytes;
 use futures::future;
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, info, trace, warn, Level};
 use lru_time_cache::LruCache;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use shadowsocks::{
@@ -19,7 +19,12 @@ use shadowsocks::{
     net::{AcceptOpts, AddrFamily, UdpSocket as OutboundUdpSocket},
     relay::{
         socks5::Address,
-        udprelay::{options::UdpSocketControlData, ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
+        udprelay::{
+            options::UdpSocketControlData,
+            proxy_socket::DatagramTruncatedError,
+            ProxySocket,
+            MAXIMUM_UDP_PAYLOAD_SIZE,
+        },
     },
     ServerConfig,
 };
@@ -27,6 +32,7 @@ use tokio::{sync::mpsc, task::JoinHandle, time};
 
 use crate::net::{
     packet_window::PacketWindowFilter,
+    relay_event::RelayEvent,
     utils::to_ipv4_mapped,
     MonProxySocket,
     UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
@@ -88,6 +94,7 @@ pub struct UdpServer {
     time_to_live: Duration,
     accept_opts: AcceptOpts,
     worker_count: usize,
+    max_datagram_size: usize,
 }
 
 impl UdpServer {
@@ -130,6 +137,7 @@ impl UdpServer {
             time_to_live,
             accept_opts,
             worker_count: 1,
+            max_datagram_size: crate::DEFAULT_UDP_MAX_DATAGRAM_SIZE,
         }
     }
 
@@ -138,6 +146,12 @@ impl UdpServer {
         self.worker_count = worker_count;
     }
 
+    /// Set maximum size of datagrams received from clients, larger datagrams will be dropped
+    #[inline]
+    pub fn set_max_datagram_size(&mut self, max_datagram_size: usize) {
+        self.max_datagram_size = max_datagram_size;
+    }
+
     pub async fn run(mut self, svr_cfg: &ServerConfig) -> io::Result<()> {
         let socket = ProxySocket::bind_with_opts(self.context.context(), svr_cfg, self.accept_opts.clone()).await?;
 
@@ -166,9 +180,10 @@ impl UdpServer {
                 let otx = otx.clone();
                 let listener = listener.clone();
                 let context = self.context.clone();
+                let buffer_size = self.recv_buffer_size();
 
                 other_receivers.push(tokio::spawn(async move {
-                    let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+                    let mut buffer = vec![0u8; buffer_size];
 
                     loop {
                         let (n, peer_addr, target_addr, control) =
@@ -218,7 +233,7 @@ impl UdpServer {
             }
         }
 
-        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let mut buffer = vec![0u8; self.recv_buffer_size()];
         loop {
             tokio::select! {
                 _ = cleanup_timer.tick() => {
@@ -264,6 +279,11 @@ impl UdpServer {
         }
     }
 
+    /// Receive buffer with 1 more byte than `max_datagram_size`, so larger datagrams could be detected and dropped
+    fn recv_buffer_size(&self) -> usize {
+        (self.max_datagram_size + 1).min(MAXIMUM_UDP_PAYLOAD_SIZE)
+    }
+
     async fn recv_one_packet(
         context: &ServiceContext,
         l: &MonProxySocket,
@@ -272,7 +292,13 @@ impl UdpServer {
         let (n, peer_addr, target_addr, control) = match l.recv_from_with_ctrl(buffer).await {
             Ok(s) => s,
             Err(err) => {
-                error!("udp server recv_from failed with error: {}", err);
+                match err.get_ref().and_then(|e| e.downcast_ref::<DatagramTruncatedError>()) {
+                    Some(err) => warn!(
+                        "udp datagram dropped, exceeds udp_max_datagram {} bytes",
+                        err.buffer_size() - 1
+                    ),
+                    None => error!("udp server recv_from failed with error: {}", err),
+                }
                 return None;
             }
         };
@@ -325,7 +351,8 @@ impl UdpServer {
                     self.keepalive_tx.clone(),
                 );
 
-                debug!("created udp association for {}", peer_addr);
+                RelayEvent::udp("established", peer_addr)
+                    .log(Level::Debug, format_args!("created udp association for {}", peer_addr));
 
                 assoc.try_send((peer_addr, target_addr, data, control))?;
                 m.insert(peer_addr, assoc);
@@ -354,9 +381,12 @@ impl UdpServer {
                     client_session_id,
                 );
 
-                debug!(
-                    "created udp association for {} with session {}",
-                    peer_addr, client_session_id
+                RelayEvent::udp("established", peer_addr).log(
+                    Level::Debug,
+                    format_args!(
+                        "created udp association for {} with session {}",
+                        peer_addr, client_session_id
+                    ),
                 );
 
                 assoc.try_send((peer_addr, target_addr, data, control))?;
@@ -444,7 +474,10 @@ struct UdpAssociationContext {
 
 impl Drop for UdpAssociationContext {
     fn drop(&mut self) {
-        debug!("udp association for {} is closed", self.peer_addr);
+        RelayEvent::udp("closed", self.peer_addr).log(
+            Level::Debug,
+            format_args!("udp association for {} is closed", self.peer_addr),
+        );
     }
 }
 
@@ -800,3 +833,76 @@ impl UdpAssociationContext {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, UdpSocket as StdUdpSocket};
+
+    use shadowsocks::{config::ServerType, context::Context};
+    use tokio::net::UdpSocket;
+
+    use crate::test_utils::{capture_warnings, captured_warnings};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn udp_max_datagram_size() {
+        capture_warnings();
+
+        let target = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target_addr = Address::from(target.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+            loop {
+                let (n, peer_addr) = target.recv_from(&mut buffer).await.unwrap();
+                let _ = target.send_to(&buffer[..n], peer_addr).await;
+            }
+        });
+
+        let server_addr = {
+            let socket = StdUdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            socket.local_addr().unwrap()
+        };
+        let svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_128_GCM);
+
+        let mut server = UdpServer::new(
+            Arc::new(ServiceContext::new()),
+            svr_cfg.method(),
+            None,
+            None,
+            AcceptOpts::default(),
+        );
+        server.set_max_datagram_size(1024);
+        let server_cfg = svr_cfg.clone();
+        tokio::spawn(async move { server.run(&server_cfg).await });
+
+        let client = ProxySocket::connect(Context::new_shared(ServerType::Local), &svr_cfg)
+            .await
+            .unwrap();
+
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+
+        // Wait until the server starts relaying
+        loop {
+            client.send(&target_addr, b"ping").await.unwrap();
+            if let Ok(r) = time::timeout(Duration::from_millis(100), client.recv(&mut buffer)).await {
+                let (n, ..) = r.unwrap();
+                assert_eq!(&buffer[..n], b"ping");
+                break;
+            }
+        }
+
+        // Oversized datagram is dropped, while the following normal one passes
+        client.send(&target_addr, &[0u8; 2048]).await.unwrap();
+        client.send(&target_addr, b"normal").await.unwrap();
+
+        let (n, ..) = time::timeout(Duration::from_secs(5), client.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buffer[..n], b"normal");
+
+        let expected = "udp datagram dropped, exceeds udp_max_datagram 1024 bytes";
+        assert!(captured_warnings().iter().any(|w| w == expected));
+    }
+}
@@ -0,0 +1,197 @@
+//! Utilities shared by tests
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Mutex, Once},
+    thread::{self, ThreadId},
+};
+#[cfg(feature = "local")]
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use shadowsocks::net::{AcceptOpts, TcpListener};
+#[cfg(feature = "local")]
+use shadowsocks::{
+    config::{ServerConfig, ServerWeight},
+    crypto::CipherKind,
+};
+#[cfg(feature = "local")]
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+#[cfg(feature = "local")]
+use crate::local::{loadbalancing::ServerIdent, net::AutoProxyIo};
+
+/// Maximum number of logs kept by the logger, older ones are dropped
+const MAX_CAPTURED_LOGS: usize = 4096;
+
+/// Log captured with the thread it was logged on
+///
+/// `#[tokio::test]` runs each test on its own thread, with a current-thread runtime, so logs of a test could be
+/// told from the ones of other tests running in parallel.
+struct CapturedLog {
+    thread: ThreadId,
+    level: Level,
+    message: String,
+    fields: BTreeMap<String, String>,
+}
+
+/// Logger keeping messages of warnings and errors, and messages of all enabled levels from this crate
+///
+/// Logger could only be set once, tests checking logs should all use this one.
+struct CapturedLogger {
+    logs: Mutex<VecDeque<CapturedLog>>,
+}
+
+impl Log for CapturedLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= Level::Warn || metadata.target().starts_with("shadowsocks_service")
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut fields = BTreeMap::new();
+        log_mdc::iter(|k, v| {
+            fields.insert(k.to_owned(), v.to_owned());
+        });
+
+        let mut logs = self.logs.lock().unwrap();
+        if logs.len() >= MAX_CAPTURED_LOGS {
+            logs.pop_front();
+        }
+        logs.push_back(CapturedLog {
+            thread: thread::current().id(),
+            level: record.level(),
+            message: record.args().to_string(),
+            fields,
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturedLogger = CapturedLogger {
+    logs: Mutex::new(VecDeque::new()),
+};
+
+/// Start capturing warnings
+pub fn capture_warnings() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Warn);
+    });
+}
+
+/// Start capturing warnings, and logs from this crate up to `level`
+///
+/// Levels are enabled globally, so they are kept enabled for the other tests in the process.
+pub fn capture_logs(level: LevelFilter) {
+    capture_warnings();
+    if level > log::max_level() {
+        log::set_max_level(level);
+    }
+}
+
+fn captured<T>(f: impl Fn(&CapturedLog) -> Option<T>) -> Vec<T> {
+    let thread = thread::current().id();
+    LOGGER
+        .logs
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|log| log.thread == thread)
+        .filter_map(f)
+        .collect()
+}
+
+/// Warnings logged by the current test since `capture_warnings`
+pub fn captured_warnings() -> Vec<String> {
+    captured(|log| (log.level <= Level::Warn).then(|| log.message.clone()))
+}
+
+/// Logs of connection `conn_id` logged by the current test, with their MDC fields
+///
+/// Messages are without the `[c{conn_id}] ` prefix.
+pub fn captured_conn_logs(conn_id: usize) -> Vec<(String, BTreeMap<String, String>)> {
+    let prefix = format!("[c{}] ", conn_id);
+    captured(|log| {
+        log.message
+            .strip_prefix(&prefix)
+            .map(|message| (message.to_owned(), log.fields.clone()))
+    })
+}
+
+/// Bind a listener on a random port of 127.0.0.1
+///
+/// Servers running on it accept connections as soon as they are spawned, connecting to them needs no retries.
+pub async fn bind_listener() -> TcpListener {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    TcpListener::bind_with_opts(&addr, AcceptOpts::default()).await.unwrap()
+}
+
+/// Servers of balancers on 127.0.0.1:8388, 127.0.0.1:8389, ..., with round-robin weights `weights`
+#[cfg(feature = "local")]
+pub fn weighted_servers(weights: &[u32]) -> Vec<Arc<ServerIdent>> {
+    weights
+        .iter()
+        .enumerate()
+        .map(|(i, w)| {
+            let mut svr_cfg = ServerConfig::new(
+                SocketAddr::from((Ipv4Addr::LOCALHOST, 8388 + i as u16)),
+                "password",
+                CipherKind::AES_128_GCM,
+            );
+            let mut weight = ServerWeight::new();
+            weight.set_round_robin_weight(*w);
+            svr_cfg.set_weight(weight);
+            Arc::new(ServerIdent::new(
+                svr_cfg,
+                Duration::from_secs(5),
+                Duration::from_secs(10),
+            ))
+        })
+        .collect()
+}
+
+/// Remote stream relaying through a server
+#[cfg(feature = "local")]
+pub struct ProxiedStream(pub DuplexStream);
+
+#[cfg(feature = "local")]
+impl AutoProxyIo for ProxiedStream {
+    fn is_proxied(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "local")]
+impl AsyncRead for ProxiedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "local")]
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -105,6 +105,7 @@ impl FromStr for Mode {
 pub struct ServerWeight {
     tcp_weight: f32,
     udp_weight: f32,
+    round_robin_weight: u32,
 }
 
 impl Default for ServerWeight {
@@ -114,11 +115,12 @@ impl Default for ServerWeight {
 }
 
 impl ServerWeight {
-    /// Creates a default weight for server, which will have 1.0 for both TCP and UDP
+    /// Creates a default weight for server, which will have 1.0 for both TCP and UDP, and 1 for round-robin
     pub fn new() -> ServerWeight {
         ServerWeight {
             tcp_weight: 1.0,
             udp_weight: 1.0,
+            round_robin_weight: 1,
         }
     }
 
@@ -143,6 +145,17 @@ impl ServerWeight {
         assert!((0.0..=1.0).contains(&weight));
         self.udp_weight = weight;
     }
+
+    /// Weight for weighted round-robin and weighted random balancers
+    pub fn round_robin_weight(&self) -> u32 {
+        self.round_robin_weight
+    }
+
+    /// Set weight for weighted round-robin and weighted random balancers, must be > 0
+    pub fn set_round_robin_weight(&mut self, weight: u32) {
+        assert!(weight > 0);
+        self.round_robin_weight = weight;
+    }
 }
 
 /// Configuration for a server
@@ -174,6 +187,60 @@ pub struct ServerConfig {
 
     /// Weight
     weight: ServerWeight,
+
+    /// Maximum retries when connecting to this server
+    max_retries: Option<usize>,
+
+    /// Maximum open TCP connections to this server
+    max_tcp_connections: Option<usize>,
+
+    /// HTTP CONNECT fronting of TCP connections
+    http_fronting: Option<HttpFronting>,
+
+    /// Compress TCP streams
+    #[cfg(feature = "stream-compression")]
+    compression: bool,
+
+    /// `TCP_MAXSEG` of TCP connections to this server
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    tcp_maxseg: Option<u32>,
+}
+
+/// HTTP CONNECT fronting of a server
+///
+/// TCP connections are made to the fronting proxy, for example a CDN's edge, which is asked to tunnel to the
+/// server with a `CONNECT` request before the shadowsocks handshake.
+#[derive(Clone, Debug)]
+pub struct HttpFronting {
+    proxy: ServerAddr,
+    host: Option<String>,
+}
+
+impl HttpFronting {
+    /// Create with the fronting proxy's address
+    pub fn new(proxy: ServerAddr) -> HttpFronting {
+        HttpFronting { proxy, host: None }
+    }
+
+    /// Get the fronting proxy's address
+    pub fn proxy(&self) -> &ServerAddr {
+        &self.proxy
+    }
+
+    /// Get `Host` header of the `CONNECT` request
+    ///
+    /// The server's address is used if it is not set
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// Set `Host` header of the `CONNECT` request
+    pub fn set_host<S>(&mut self, host: S)
+    where
+        S: Into<String>,
+    {
+        self.host = Some(host.into());
+    }
 }
 
 #[cfg(feature = "aead-cipher-2022")]
@@ -233,6 +300,13 @@ impl ServerConfig {
             id: None,
             mode: Mode::TcpAndUdp, // Server serves TCP & UDP by default
             weight: ServerWeight::new(),
+            max_retries: None,
+            max_tcp_connections: None,
+            http_fronting: None,
+            #[cfg(feature = "stream-compression")]
+            compression: false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            tcp_maxseg: None,
         }
     }
 
@@ -359,6 +433,66 @@ impl ServerConfig {
         self.weight = weight;
     }
 
+    /// Get maximum retries when connecting to this server
+    ///
+    /// `None` if it is not set, which means the global default should be used
+    pub fn max_retries(&self) -> Option<usize> {
+        self.max_retries
+    }
+
+    /// Set maximum retries when connecting to this server
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.max_retries = Some(max_retries);
+    }
+
+    /// Get maximum open TCP connections to this server
+    ///
+    /// `None` if it is not set, which means unlimited
+    pub fn max_tcp_connections(&self) -> Option<usize> {
+        self.max_tcp_connections
+    }
+
+    /// Set maximum open TCP connections to this server, new connections fail when it is reached
+    pub fn set_max_tcp_connections(&mut self, max_tcp_connections: usize) {
+        self.max_tcp_connections = Some(max_tcp_connections);
+    }
+
+    /// Get HTTP CONNECT fronting of TCP connections
+    pub fn http_fronting(&self) -> Option<&HttpFronting> {
+        self.http_fronting.as_ref()
+    }
+
+    /// Set HTTP CONNECT fronting of TCP connections
+    pub fn set_http_fronting(&mut self, fronting: HttpFronting) {
+        self.http_fronting = Some(fronting);
+    }
+
+    /// Check if TCP streams are compressed
+    #[cfg(feature = "stream-compression")]
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
+    /// Set compressing TCP streams, both local and server have to enable it
+    #[cfg(feature = "stream-compression")]
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    /// Get `TCP_MAXSEG` of TCP connections to this server
+    ///
+    /// `None` if it is not set, which means the global connect options are used
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn tcp_maxseg(&self) -> Option<u32> {
+        self.tcp_maxseg
+    }
+
+    /// Set `TCP_MAXSEG`, clamping MSS of TCP connections to this server for links with small MTUs
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_tcp_maxseg(&mut self, maxseg: u32) {
+        self.tcp_maxseg = Some(maxseg);
+    }
+
     /// Get URL for QRCode
     /// ```plain
     /// ss:// + base64(method:password@host:port)
@@ -814,6 +948,51 @@ impl From<PathBuf> for ManagerAddr {
     }
 }
 
+/// Order of trying addresses resolved from a hostname when connecting to targets directly
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum ConnectStrategy {
+    /// Try addresses one by one in the order returned by the DNS resolver
+    DnsOrder,
+    /// Try addresses of the preferred family first, IPv4 or IPv6 (`ipv6_first`), one by one
+    FamilyPreference,
+    /// Happy Eyeballs (RFC 8305), try the other family shortly after the preferred one
+    #[default]
+    HappyEyeballs,
+}
+
+impl Display for ConnectStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectStrategy::DnsOrder => f.write_str("dns_order"),
+            ConnectStrategy::FamilyPreference => f.write_str("family_preference"),
+            ConnectStrategy::HappyEyeballs => f.write_str("happy_eyeballs"),
+        }
+    }
+}
+
+/// Error while parsing ConnectStrategy from string
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectStrategyError;
+
+impl Display for ConnectStrategyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid ConnectStrategy")
+    }
+}
+
+impl FromStr for ConnectStrategy {
+    type Err = ConnectStrategyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dns_order" => Ok(ConnectStrategy::DnsOrder),
+            "family_preference" => Ok(ConnectStrategy::FamilyPreference),
+            "happy_eyeballs" => Ok(ConnectStrategy::HappyEyeballs),
+            _ => Err(ConnectStrategyError),
+        }
+    }
+}
+
 /// Policy for handling replay attack requests
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ReplayAttackPolicy {
@@ -4,9 +4,10 @@ use std::{io, net::SocketAddr, sync::Arc};
 
 use byte_string::ByteStr;
 use log::warn;
+use tokio::sync::Semaphore;
 
 use crate::{
-    config::{ReplayAttackPolicy, ServerType},
+    config::{ConnectStrategy, ReplayAttackPolicy, ServerType},
     crypto::{v1::random_iv_or_salt, CipherKind},
     dns_resolver::DnsResolver,
     security::replay::ReplayProtector,
@@ -25,6 +26,13 @@ pub struct Context {
 
     // Connect IPv6 address first
     ipv6_first: bool,
+
+    // Order of trying resolved addresses when connecting to targets
+    direct_connect_strategy: ConnectStrategy,
+
+    // Limits DNS resolutions in flight, resolutions beyond it fail immediately
+    pending_resolutions: Option<Semaphore>,
+    max_pending_resolutions: usize,
 }
 
 /// `Context` for sharing between services
@@ -38,6 +46,9 @@ impl Context {
             replay_policy: ReplayAttackPolicy::Default,
             dns_resolver: Arc::new(DnsResolver::system_resolver()),
             ipv6_first: false,
+            direct_connect_strategy: ConnectStrategy::default(),
+            pending_resolutions: None,
+            max_pending_resolutions: 0,
         }
     }
 
@@ -125,6 +136,36 @@ impl Context {
         }
     }
 
+    /// Check nonce received in a connection against the one sent in the same connection
+    ///
+    /// This only catches nonce reflected back in the same connection, replays across connections are handled by
+    /// `check_nonce_replay`. It is called before decrypting any data. A reflected nonce is always logged as a warning,
+    /// unless the replay attack policy is `Ignore`, and aborts the connection if the policy is `Reject`.
+    pub fn check_nonce_reused(&self, sent_nonce: &[u8], received_nonce: &[u8]) -> io::Result<()> {
+        if sent_nonce.is_empty() || sent_nonce != received_nonce {
+            return Ok(());
+        }
+
+        match self.replay_policy {
+            ReplayAttackPolicy::Ignore => Ok(()),
+            ReplayAttackPolicy::Default | ReplayAttackPolicy::Detect => {
+                warn!(
+                    "detected reused nonce (iv/salt) {:?} in connection",
+                    ByteStr::new(received_nonce)
+                );
+                Ok(())
+            }
+            ReplayAttackPolicy::Reject => {
+                warn!(
+                    "detected reused nonce (iv/salt) {:?} in connection, aborting",
+                    ByteStr::new(received_nonce)
+                );
+                let err = io::Error::new(io::ErrorKind::InvalidData, "detected reused nonce (iv/salt)");
+                Err(err)
+            }
+        }
+    }
+
     /// Set a DNS resolver
     ///
     /// The resolver should be wrapped in an `Arc`, because it could be shared with the other servers
@@ -140,9 +181,39 @@ impl Context {
     /// Resolves DNS address to `SocketAddr`s
     #[allow(clippy::needless_lifetimes)]
     pub async fn dns_resolve<'a>(&self, addr: &'a str, port: u16) -> io::Result<impl Iterator<Item = SocketAddr> + 'a> {
+        let _permit = match self.pending_resolutions {
+            None => None,
+            Some(ref pending) => match pending.try_acquire() {
+                Ok(permit) => Some(permit),
+                Err(..) => {
+                    let err = io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "resolving {}:{}, too many pending DNS resolutions (max {})",
+                            addr, port, self.max_pending_resolutions
+                        ),
+                    );
+                    return Err(err);
+                }
+            },
+        };
+
         self.dns_resolver.resolve(addr, port).await
     }
 
+    /// Set the maximum number of DNS resolutions in flight, 0 for unlimited (default)
+    ///
+    /// Resolutions beyond the limit fail immediately instead of being queued
+    pub fn set_max_pending_dns_resolutions(&mut self, max: usize) {
+        self.pending_resolutions = if max > 0 { Some(Semaphore::new(max)) } else { None };
+        self.max_pending_resolutions = max;
+    }
+
+    /// Get the maximum number of DNS resolutions in flight, 0 for unlimited
+    pub fn max_pending_dns_resolutions(&self) -> usize {
+        self.max_pending_resolutions
+    }
+
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
         self.ipv6_first = ipv6_first;
@@ -153,6 +224,16 @@ impl Context {
         self.ipv6_first
     }
 
+    /// Set the order of trying resolved addresses when connecting to targets
+    pub fn set_direct_connect_strategy(&mut self, strategy: ConnectStrategy) {
+        self.direct_connect_strategy = strategy;
+    }
+
+    /// Order of trying resolved addresses when connecting to targets
+    pub fn direct_connect_strategy(&self) -> ConnectStrategy {
+        self.direct_connect_strategy
+    }
+
     /// Set policy against replay attack
     pub fn set_replay_attack_policy(&mut self, replay_policy: ReplayAttackPolicy) {
         self.replay_policy = replay_policy;
@@ -166,10 +247,17 @@ impl Context {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::ServerType;
-    use crate::context::Context;
+    use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+    use crate::{
+        config::ServerType,
+        context::Context,
+        dns_resolver::{DnsResolve, DnsResolver},
+    };
+    use async_trait::async_trait;
     use byte_string::ByteStr;
     use shadowsocks_crypto::CipherKind;
+    use tokio::{sync::Notify, time};
 
     #[test]
     fn generate_nonce() {
@@ -179,4 +267,61 @@ mod tests {
         println!("generate nonce printable ascii: {:?}", ByteStr::new(&salt));
     }
 
+    // Resolves nothing until it is released
+    struct BlockingResolver {
+        release: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl DnsResolve for BlockingResolver {
+        async fn resolve(&self, _addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            self.release.notified().await;
+            Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))])
+        }
+    }
+
+    #[tokio::test]
+    async fn max_pending_dns_resolutions() {
+        let release = Arc::new(Notify::new());
+
+        let mut context = Context::new(ServerType::Local);
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(BlockingResolver {
+            release: release.clone(),
+        })));
+        context.set_max_pending_dns_resolutions(2);
+        let context = Arc::new(context);
+
+        let pending = (0..2)
+            .map(|_| {
+                let context = context.clone();
+                tokio::spawn(async move { context.dns_resolve("example.com", 80).await.map(|a| a.count()) })
+            })
+            .collect::<Vec<_>>();
+
+        // Let both of them start waiting in the resolver
+        while context.pending_resolutions.as_ref().unwrap().available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // Beyond the limit, fails without waiting for the pending ones
+        let result = time::timeout(Duration::from_secs(1), context.dns_resolve("example.org", 80))
+            .await
+            .expect("resolution beyond the limit should not be queued");
+        assert!(result.is_err());
+
+        release.notify_waiters();
+        for handle in pending {
+            assert_eq!(handle.await.unwrap().unwrap(), 1);
+        }
+
+        // Permits are returned after the pending ones finished
+        release.notify_one();
+        let addrs = context
+            .dns_resolve("example.org", 80)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, [SocketAddr::from(([127, 0, 0, 1], 80))]);
+    }
+
 }
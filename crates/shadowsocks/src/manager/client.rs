@@ -18,6 +18,8 @@ use super::{
         RemoveRequest,
         RemoveResponse,
         StatRequest,
+        StructuredRequest,
+        StructuredResponse,
     },
 };
 
@@ -44,6 +46,8 @@ impl ManagerClient {
 
     impl_command!(remove, RemoveRequest, RemoveResponse);
 
+    impl_command!(structured, StructuredRequest, StructuredResponse);
+
     /// Create a `ManagerDatagram` for sending data to manager
     pub async fn connect(
         context: &Context,
@@ -9,7 +9,7 @@ use crate::{config::ManagerAddr, context::Context, relay::udprelay::MAXIMUM_UDP_
 use super::{
     datagram::{ManagerDatagram, ManagerSocketAddr},
     error::Error,
-    protocol::{ManagerProtocol, ManagerRequest},
+    protocol::{ManagerMessage, ManagerProtocol, ManagerRequest},
 };
 
 /// Manager server Listener
@@ -31,6 +31,13 @@ impl ManagerListener {
         Ok((ManagerRequest::from_bytes(&buf[..n])?, peer_addr))
     }
 
+    /// Receive a text command or a structured request
+    pub async fn recv_message_from(&mut self) -> Result<(ManagerMessage, ManagerSocketAddr), Error> {
+        let mut buf = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        let (n, peer_addr) = self.socket.recv_from(&mut buf).await?;
+        Ok((ManagerMessage::from_bytes(&buf[..n])?, peer_addr))
+    }
+
     pub async fn send_to<P: ManagerProtocol>(&mut self, data: &P, target: &ManagerSocketAddr) -> Result<(), Error> {
         let buf = data.to_bytes()?;
         let n = self.socket.send_to(&buf, target).await?;
@@ -7,7 +7,11 @@ use std::{
     string::ToString,
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{DeserializeOwned, Error as _},
+    Deserialize,
+    Serialize,
+};
 use thiserror::Error;
 
 /// Abstract Manager Protocol
@@ -270,6 +274,101 @@ impl<E: ToString> ManagerProtocol for ErrorResponse<E> {
     }
 }
 
+/// Length of the prefix of structured messages
+const STRUCTURED_LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Check if `buf` is a structured message
+///
+/// Structured messages start with a 4 bytes length, its first byte is always 0 in a datagram,
+/// while text commands start with a command name.
+pub fn is_structured(buf: &[u8]) -> bool {
+    buf.first() == Some(&0)
+}
+
+fn to_structured_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; STRUCTURED_LENGTH_PREFIX_SIZE];
+    serde_json::to_writer(&mut buf, value)?;
+
+    let len = (buf.len() - STRUCTURED_LENGTH_PREFIX_SIZE) as u32;
+    buf[..STRUCTURED_LENGTH_PREFIX_SIZE].copy_from_slice(&len.to_be_bytes());
+    Ok(buf)
+}
+
+fn from_structured_bytes<T: DeserializeOwned>(buf: &[u8]) -> Result<T, Error> {
+    if buf.len() < STRUCTURED_LENGTH_PREFIX_SIZE {
+        let err = serde_json::Error::invalid_length(buf.len(), &"a length prefix of 4 bytes");
+        return Err(Error::JsonError(err));
+    }
+
+    let (len_buf, payload) = buf.split_at(STRUCTURED_LENGTH_PREFIX_SIZE);
+    let mut len = [0u8; STRUCTURED_LENGTH_PREFIX_SIZE];
+    len.copy_from_slice(len_buf);
+    if u32::from_be_bytes(len) as usize != payload.len() {
+        let err = serde_json::Error::invalid_length(payload.len(), &"the length in prefix");
+        return Err(Error::JsonError(err));
+    }
+
+    Ok(serde_json::from_slice(payload)?)
+}
+
+/// Structured request, for tools integrating with the manager
+///
+/// Encoded as a JSON object prefixed with its length (4 bytes, big endian), for example `{"command":"stats"}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum StructuredRequest {
+    /// Starts a server instance
+    Add(AddRequest),
+    /// Deletes an existing server instance
+    Remove(RemoveRequest),
+    /// Lists all current running servers
+    List,
+    /// Lists all servers' statistic data
+    Stats,
+}
+
+impl ManagerProtocol for StructuredRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        from_structured_bytes(buf)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        to_structured_bytes(self)
+    }
+}
+
+/// Traffic statistic of a server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServerTraffic {
+    pub server_port: u16,
+    /// Total bytes transferred
+    pub traffic: u64,
+}
+
+/// Structured response, encoded in the same way as `StructuredRequest`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StructuredResponse {
+    /// Command succeeded
+    Ok,
+    /// Response of `list`
+    Servers { servers: Vec<ServerConfig> },
+    /// Response of `stats`
+    Stats { servers: Vec<ServerTraffic> },
+    /// Command failed
+    Error { message: String },
+}
+
+impl ManagerProtocol for StructuredResponse {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        from_structured_bytes(buf)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        to_structured_bytes(self)
+    }
+}
+
 /// Collections of Manager's request
 #[derive(Debug, Clone)]
 pub enum ManagerRequest {
@@ -347,6 +446,30 @@ impl ManagerProtocol for ManagerRequest {
     }
 }
 
+/// Message received by manager, a text command or a structured request
+#[derive(Debug, Clone)]
+pub enum ManagerMessage {
+    Request(ManagerRequest),
+    Structured(StructuredRequest),
+}
+
+impl ManagerProtocol for ManagerMessage {
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        match *self {
+            ManagerMessage::Request(ref req) => req.to_bytes(),
+            ManagerMessage::Structured(ref req) => req.to_bytes(),
+        }
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<ManagerMessage, Error> {
+        if is_structured(buf) {
+            StructuredRequest::from_bytes(buf).map(ManagerMessage::Structured)
+        } else {
+            ManagerRequest::from_bytes(buf).map(ManagerMessage::Request)
+        }
+    }
+}
+
 /// Manager's Error
 #[derive(Error, Debug)]
 pub enum Error {
@@ -7,7 +7,7 @@ pub use self::sys::uds::{UnixListener, UnixStream};
 pub use self::{
     option::{AcceptOpts, ConnectOpts, TcpSocketOpts},
     sys::{set_tcp_fastopen, socket_bind_dual_stack},
-    tcp::{TcpListener, TcpStream},
+    tcp::{TcpListener, TcpStream, DEFAULT_LISTEN_BACKLOG},
     udp::UdpSocket,
 };
 
@@ -20,6 +20,18 @@ pub struct TcpSocketOpts {
     /// `SO_KEEPALIVE` and sets `TCP_KEEPIDLE`, `TCP_KEEPINTVL` and `TCP_KEEPCNT` respectively,
     /// enables keep-alive messages on connection-oriented sockets
     pub keepalive: Option<Duration>,
+
+    /// `TCP_CONGESTION`, name of the congestion control algorithm, like `bbr` or `cubic`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub congestion: Option<String>,
+
+    /// `TCP_USER_TIMEOUT`, how long transmitted data may remain unacknowledged before the connection is closed
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub user_timeout: Option<Duration>,
+
+    /// `TCP_MAXSEG`, maximum segment size of outbound connections, clamping MSS advertised in SYN
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub maxseg: Option<u32>,
 }
 
 /// Options for connecting to remote server
@@ -60,4 +72,7 @@ pub struct AcceptOpts {
 
     /// Enable IPV6_V6ONLY option for socket
     pub ipv6_only: bool,
+
+    /// Backlog of `listen()` for TCP listeners, `DEFAULT_LISTEN_BACKLOG` by default
+    pub backlog: Option<u32>,
 }
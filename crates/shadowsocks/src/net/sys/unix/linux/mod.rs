@@ -7,6 +7,7 @@ use std::{
     ptr,
     sync::atomic::{AtomicBool, Ordering},
     task::{self, Poll},
+    time::Duration,
 };
 
 use cfg_if::cfg_if;
@@ -35,6 +36,40 @@ pub enum TcpStream {
 
 impl TcpStream {
     pub async fn connect(addr: SocketAddr, opts: &ConnectOpts) -> io::Result<TcpStream> {
+        let socket = TcpStream::new_socket(addr, opts).await?;
+
+        if !opts.tcp.fastopen {
+            // If TFO is not enabled, it just works like a normal TcpStream
+            let stream = socket.connect(addr).await?;
+            set_common_sockopt_after_connect(&stream, opts)?;
+
+            return Ok(TcpStream::Standard(stream));
+        }
+
+        let stream = match TfoStream::connect_with_socket(socket, addr).await {
+            Ok(stream) => stream,
+            Err(err) if is_fastopen_unsupported(&err) => {
+                // Client side TFO may be disabled by `net.ipv4.tcp_fastopen`, the socket has to be created again
+                debug!(
+                    "TFO connect {} failed with error: {}, fallback to normal connect",
+                    addr, err
+                );
+
+                let socket = TcpStream::new_socket(addr, opts).await?;
+                let stream = socket.connect(addr).await?;
+                set_common_sockopt_after_connect(&stream, opts)?;
+
+                return Ok(TcpStream::Standard(stream));
+            }
+            Err(err) => return Err(err),
+        };
+        set_common_sockopt_after_connect(&stream, opts)?;
+
+        Ok(TcpStream::FastOpen(stream))
+    }
+
+    /// Create a socket for connecting to `addr` with all `opts` set
+    async fn new_socket(addr: SocketAddr, opts: &ConnectOpts) -> io::Result<TcpSocket> {
         let socket = match addr {
             SocketAddr::V4(..) => TcpSocket::new_v4()?,
             SocketAddr::V6(..) => TcpSocket::new_v6()?,
@@ -44,7 +79,7 @@ impl TcpStream {
         // This is a workaround for VPNService
         #[cfg(target_os = "android")]
         if !addr.ip().is_loopback() {
-            use std::{io::ErrorKind, time::Duration};
+            use std::io::ErrorKind;
             use tokio::time;
 
             if let Some(ref path) = opts.vpn_protect_path {
@@ -82,20 +117,24 @@ impl TcpStream {
             set_bindtodevice(&socket, iface)?;
         }
 
-        set_common_sockopt_for_connect(addr, &socket, opts)?;
+        // Set TCP_CONGESTION for choosing a congestion control algorithm
+        if let Some(ref congestion) = opts.tcp.congestion {
+            set_tcp_congestion(&socket, congestion)?;
+        }
 
-        if !opts.tcp.fastopen {
-            // If TFO is not enabled, it just works like a normal TcpStream
-            let stream = socket.connect(addr).await?;
-            set_common_sockopt_after_connect(&stream, opts)?;
+        // Set TCP_USER_TIMEOUT for detecting dead peers
+        if let Some(timeout) = opts.tcp.user_timeout {
+            set_tcp_user_timeout(&socket, timeout)?;
+        }
 
-            return Ok(TcpStream::Standard(stream));
+        // Set TCP_MAXSEG before connecting, MSS is advertised in SYN
+        if let Some(maxseg) = opts.tcp.maxseg {
+            set_tcp_maxseg(&socket, maxseg)?;
         }
 
-        let stream = TfoStream::connect_with_socket(socket, addr).await?;
-        set_common_sockopt_after_connect(&stream, opts)?;
+        set_common_sockopt_for_connect(addr, &socket, opts)?;
 
-        Ok(TcpStream::FastOpen(stream))
+        Ok(socket)
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
@@ -168,6 +207,15 @@ impl AsyncWrite for TcpStream {
     }
 }
 
+/// Check if TFO connect failed because it isn't supported or enabled, instead of the connection itself
+fn is_fastopen_unsupported(err: &io::Error) -> bool {
+    // `TCP_FASTOPEN_CONNECT` fails with EOPNOTSUPP if client side TFO is disabled by `net.ipv4.tcp_fastopen`
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOPROTOOPT) | Some(libc::EPROTONOSUPPORT)
+    )
+}
+
 /// Enable `TCP_FASTOPEN`
 ///
 /// `TCP_FASTOPEN` was supported since Linux 3.7
@@ -201,6 +249,81 @@ pub fn set_tcp_fastopen<S: AsRawFd>(socket: &S) -> io::Result<()> {
     Ok(())
 }
 
+/// Set `TCP_CONGESTION`, the TCP congestion control algorithm
+///
+/// Available algorithms could be found in `/proc/sys/net/ipv4/tcp_available_congestion_control`
+pub fn set_tcp_congestion<S: AsRawFd>(socket: &S, algorithm: &str) -> io::Result<()> {
+    let algorithm_bytes = algorithm.as_bytes();
+
+    unsafe {
+        let ret = libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            algorithm_bytes.as_ptr() as *const libc::c_void,
+            algorithm_bytes.len() as libc::socklen_t,
+        );
+
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            error!("set TCP_CONGESTION {} error: {}", algorithm, err);
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Set `TCP_USER_TIMEOUT`, how long transmitted data may remain unacknowledged before the connection is closed
+///
+/// Dead peers are detected faster than keep-alive while there is data in flight
+pub fn set_tcp_user_timeout<S: AsRawFd>(socket: &S, timeout: Duration) -> io::Result<()> {
+    let timeout_ms = timeout.as_millis().min(libc::c_uint::MAX as u128) as libc::c_uint;
+
+    unsafe {
+        let ret = libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_USER_TIMEOUT,
+            &timeout_ms as *const _ as *const libc::c_void,
+            mem::size_of_val(&timeout_ms) as libc::socklen_t,
+        );
+
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            error!("set TCP_USER_TIMEOUT {:?} error: {}", timeout, err);
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Set `TCP_MAXSEG`, the maximum segment size of outgoing TCP packets
+///
+/// It has to be set before connecting to take effect on the MSS advertised to the peer
+pub fn set_tcp_maxseg<S: AsRawFd>(socket: &S, maxseg: u32) -> io::Result<()> {
+    let maxseg = maxseg.min(libc::c_int::MAX as u32) as libc::c_int;
+
+    unsafe {
+        let ret = libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &maxseg as *const _ as *const libc::c_void,
+            mem::size_of_val(&maxseg) as libc::socklen_t,
+        );
+
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            error!("set TCP_MAXSEG {} error: {}", maxseg, err);
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
 /// Disable IP fragmentation
 #[inline]
 pub fn set_disable_ip_fragmentation<S: AsRawFd>(af: AddrFamily, socket: &S) -> io::Result<()> {
@@ -525,3 +648,116 @@ pub fn batch_sendmsg<S: AsRawFd>(sock: &S, msgs: &mut [BatchSendMessage<'_>]) ->
 
     Ok(ret as usize)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Get `TCP_CONGESTION`, the TCP congestion control algorithm
+    fn get_tcp_congestion<S: AsRawFd>(socket: &S) -> io::Result<String> {
+        // TCP_CA_NAME_MAX = 16
+        let mut buffer = [0u8; 16];
+        let mut buffer_len = buffer.len() as libc::socklen_t;
+
+        unsafe {
+            let ret = libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_CONGESTION,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                &mut buffer_len,
+            );
+
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let buffer = &buffer[..buffer_len as usize];
+        let name_len = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+        Ok(String::from_utf8_lossy(&buffer[..name_len]).into_owned())
+    }
+
+    #[test]
+    fn tcp_congestion_loopback() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        // `reno` is built into the kernel and is always allowed for unprivileged users
+        set_tcp_congestion(&stream, "reno").unwrap();
+        assert_eq!(get_tcp_congestion(&stream).unwrap(), "reno");
+    }
+
+    #[test]
+    fn tcp_user_timeout_loopback() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        set_tcp_user_timeout(&stream, Duration::from_secs(30)).unwrap();
+
+        let mut timeout_ms: libc::c_uint = 0;
+        let mut len = mem::size_of_val(&timeout_ms) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_USER_TIMEOUT,
+                &mut timeout_ms as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        assert_eq!(timeout_ms, 30_000);
+    }
+
+    #[tokio::test]
+    async fn tcp_fastopen_connect_loopback() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        // Connects whether client side TFO is enabled or not
+        let mut opts = ConnectOpts::default();
+        opts.tcp.fastopen = true;
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap(), &opts).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 5];
+        accepted.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+    }
+
+    #[test]
+    fn fastopen_unsupported_errors() {
+        assert!(is_fastopen_unsupported(&io::Error::from_raw_os_error(libc::EOPNOTSUPP)));
+        assert!(!is_fastopen_unsupported(&io::Error::from_raw_os_error(
+            libc::ECONNREFUSED
+        )));
+        assert!(!is_fastopen_unsupported(&io::Error::from(io::ErrorKind::TimedOut)));
+    }
+
+    #[tokio::test]
+    async fn tcp_maxseg_loopback() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut opts = ConnectOpts::default();
+        opts.tcp.maxseg = Some(1200);
+        let stream = TcpStream::connect(listener.local_addr().unwrap(), &opts).await.unwrap();
+
+        // MSS of a connected socket is the clamped one minus TCP options, loopback's MTU is much larger
+        let mut maxseg: libc::c_int = 0;
+        let mut len = mem::size_of_val(&maxseg) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_MAXSEG,
+                &mut maxseg as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        assert!(maxseg > 0 && maxseg <= 1200, "TCP_MAXSEG is {}", maxseg);
+    }
+}
@@ -5,7 +5,7 @@ use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 #[cfg(windows)]
 use std::os::windows::io::{AsRawSocket, FromRawSocket, IntoRawSocket, RawSocket};
 use std::{
-    io,
+    io::{self, ErrorKind},
     net::SocketAddr,
     ops::{Deref, DerefMut},
     pin::Pin,
@@ -20,7 +20,7 @@ use tokio::{
     net::{TcpListener as TokioTcpListener, TcpSocket, TcpStream as TokioTcpStream},
 };
 
-use crate::{context::Context, relay::socks5::Address, ServerAddr};
+use crate::{config::ConnectStrategy, context::Context, relay::socks5::Address, ServerAddr};
 
 use super::{
     is_dual_stack_addr,
@@ -29,6 +29,9 @@ use super::{
     ConnectOpts,
 };
 
+/// Default backlog of TCP listeners, the same as mio's
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
 /// TcpStream for outbound connections
 #[pin_project]
 pub struct TcpStream(#[pin] SysTcpStream);
@@ -67,12 +70,33 @@ impl TcpStream {
     ) -> io::Result<TcpStream> {
         let stream = match *addr {
             Address::SocketAddress(ref addr) => SysTcpStream::connect(*addr, opts).await?,
-            Address::DomainNameAddress(ref domain, port) => {
-                lookup_then_connect!(context, domain, port, |addr| {
-                    SysTcpStream::connect(addr, opts).await
-                })?
-                .1
-            }
+            Address::DomainNameAddress(ref domain, port) => match context.direct_connect_strategy() {
+                ConnectStrategy::HappyEyeballs => {
+                    lookup_then_connect!(context, domain, port, |addr| {
+                        SysTcpStream::connect(addr, opts).await
+                    })?
+                    .1
+                }
+                ConnectStrategy::FamilyPreference => {
+                    lookup_then!(context, domain, port, |addr| {
+                        SysTcpStream::connect(addr, opts).await
+                    })?
+                    .1
+                }
+                ConnectStrategy::DnsOrder => {
+                    let mut result = None;
+                    for addr in context.dns_resolve(domain, port).await? {
+                        match SysTcpStream::connect(addr, opts).await {
+                            Ok(s) => {
+                                result = Some(Ok(s));
+                                break;
+                            }
+                            Err(err) => result = Some(Err(err)),
+                        }
+                    }
+                    result.unwrap_or_else(|| Err(io::Error::new(ErrorKind::NotFound, "resolved empty address")))?
+                }
+            },
         };
 
         Ok(TcpStream(stream))
@@ -151,8 +175,7 @@ impl TcpListener {
             socket.bind(*addr)?;
         }
 
-        // mio's default backlog is 1024
-        let inner = socket.listen(1024)?;
+        let inner = socket.listen(accept_opts.backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG))?;
 
         // Enable TFO if supported
         // macos requires TCP_FASTOPEN to be set after listen(), but other platform doesn't have this constraint
@@ -211,6 +234,16 @@ impl From<TcpListener> for TokioTcpListener {
 
 #[cfg(unix)]
 fn setsockopt_with_opt(f: &tokio::net::TcpStream, opts: &AcceptOpts) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(ref congestion) = opts.tcp.congestion {
+        super::sys::set_tcp_congestion(f, congestion)?;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(timeout) = opts.tcp.user_timeout {
+        super::sys::set_tcp_user_timeout(f, timeout)?;
+    }
+
     let socket = unsafe { Socket::from_raw_fd(f.as_raw_fd()) };
 
     macro_rules! try_sockopt {
@@ -313,3 +346,169 @@ impl AsRawSocket for TcpStream {
         self.0.as_raw_socket()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use tokio::net::TcpListener;
+
+    use crate::{
+        config::ServerType,
+        dns_resolver::{DnsResolve, DnsResolver},
+    };
+
+    use super::*;
+
+    // Resolves every hostname to `addrs`, in order
+    struct StaticResolver {
+        addrs: Vec<SocketAddr>,
+    }
+
+    #[async_trait]
+    impl DnsResolve for StaticResolver {
+        async fn resolve(&self, _addr: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.addrs.clone())
+        }
+    }
+
+    async fn connect_with_strategy(
+        strategy: ConnectStrategy,
+        addrs: Vec<SocketAddr>,
+        ipv6_first: bool,
+    ) -> io::Result<SocketAddr> {
+        let mut context = Context::new(ServerType::Local);
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(StaticResolver { addrs })));
+        context.set_ipv6_first(ipv6_first);
+        context.set_direct_connect_strategy(strategy);
+
+        let target = Address::DomainNameAddress("example.com".to_owned(), 80);
+        let stream = TcpStream::connect_remote_with_opts(&context, &target, &ConnectOpts::default()).await?;
+        stream.peer_addr()
+    }
+
+    #[tokio::test]
+    async fn direct_connect_strategy() {
+        let v4_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v6_listener = match TcpListener::bind("[::1]:0").await {
+            Ok(listener) => listener,
+            // Strategies are choosing between address families, nothing to test without IPv6
+            Err(..) => return,
+        };
+        let v4_addr = v4_listener.local_addr().unwrap();
+        let v6_addr = v6_listener.local_addr().unwrap();
+
+        // Nothing is listening on it
+        let closed_v4_addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let addrs = vec![v6_addr, v4_addr];
+
+        // The first address in DNS order, regardless of the family preference
+        let connected = connect_with_strategy(ConnectStrategy::DnsOrder, addrs.clone(), false)
+            .await
+            .unwrap();
+        assert_eq!(connected, v6_addr);
+
+        // Preferred family first
+        let connected = connect_with_strategy(ConnectStrategy::FamilyPreference, addrs.clone(), false)
+            .await
+            .unwrap();
+        assert_eq!(connected, v4_addr);
+        let connected = connect_with_strategy(ConnectStrategy::FamilyPreference, addrs.clone(), true)
+            .await
+            .unwrap();
+        assert_eq!(connected, v6_addr);
+
+        // Preferred family wins the race if it is reachable, otherwise falls back to the other family
+        let connected = connect_with_strategy(ConnectStrategy::HappyEyeballs, addrs.clone(), false)
+            .await
+            .unwrap();
+        assert_eq!(connected, v4_addr);
+        let connected = connect_with_strategy(ConnectStrategy::HappyEyeballs, vec![closed_v4_addr, v6_addr], false)
+            .await
+            .unwrap();
+        assert_eq!(connected, v6_addr);
+
+        // Every strategy tries the next address after failures
+        for strategy in [
+            ConnectStrategy::DnsOrder,
+            ConnectStrategy::FamilyPreference,
+            ConnectStrategy::HappyEyeballs,
+        ] {
+            let connected = connect_with_strategy(strategy, vec![closed_v4_addr, v4_addr], false)
+                .await
+                .unwrap();
+            assert_eq!(connected, v4_addr, "strategy {}", strategy);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn listen_backlog() {
+        use std::mem;
+
+        // Leading fields of Linux's `struct tcp_info`, the kernel copies as much as the buffer's length
+        #[repr(C)]
+        #[derive(Default)]
+        struct TcpInfo {
+            tcpi_state: u8,
+            tcpi_ca_state: u8,
+            tcpi_retransmits: u8,
+            tcpi_probes: u8,
+            tcpi_backoff: u8,
+            tcpi_options: u8,
+            tcpi_wscale: u8,
+            tcpi_flags: u8,
+            tcpi_rto: u32,
+            tcpi_ato: u32,
+            tcpi_snd_mss: u32,
+            tcpi_rcv_mss: u32,
+            tcpi_unacked: u32,
+            tcpi_sacked: u32,
+        }
+
+        // Linux reports the backlog of listening sockets in `tcpi_sacked`
+        fn listener_backlog(fd: RawFd) -> u32 {
+            let mut info = TcpInfo::default();
+            let mut len = mem::size_of::<TcpInfo>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::IPPROTO_TCP,
+                    libc::TCP_INFO,
+                    &mut info as *mut _ as *mut _,
+                    &mut len,
+                )
+            };
+            assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+            info.tcpi_sacked
+        }
+
+        // Backlogs are silently truncated to `net.core.somaxconn`, nothing to compare with if it can't be read
+        let somaxconn = match std::fs::read_to_string("/proc/sys/net/core/somaxconn") {
+            Ok(s) => s.trim().parse::<u32>().unwrap(),
+            Err(..) => return,
+        };
+
+        let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+
+        let listener = super::TcpListener::bind_with_opts(&addr, AcceptOpts::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            listener_backlog(listener.as_raw_fd()),
+            DEFAULT_LISTEN_BACKLOG.min(somaxconn)
+        );
+
+        let accept_opts = AcceptOpts {
+            backlog: Some(128),
+            ..Default::default()
+        };
+        let listener = super::TcpListener::bind_with_opts(&addr, accept_opts).await.unwrap();
+        assert_eq!(listener_backlog(listener.as_raw_fd()), 128.min(somaxconn));
+    }
+}
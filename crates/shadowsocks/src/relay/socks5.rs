@@ -650,8 +650,31 @@ impl HandshakeRequest {
             return Err(Error::UnsupportedSocksVersion(ver));
         }
 
+        if nmet == 0 {
+            let err = io::Error::new(
+                ErrorKind::InvalidData,
+                "handshake doesn't offer any authentication method",
+            );
+            return Err(Error::IoError(err));
+        }
+
+        // Stream closed before all `NMETHODS` bytes arrived
         let mut methods = vec![0u8; nmet as usize];
-        let _ = r.read_exact(&mut methods).await?;
+        let mut received = 0;
+        while received < methods.len() {
+            let n = r.read(&mut methods[received..]).await?;
+            if n == 0 {
+                let err = io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "handshake offers {} authentication methods, but only {} received",
+                        nmet, received
+                    ),
+                );
+                return Err(Error::IoError(err));
+            }
+            received += n;
+        }
 
         Ok(HandshakeRequest { methods })
     }
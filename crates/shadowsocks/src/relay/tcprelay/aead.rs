@@ -68,6 +68,7 @@ pub struct DecryptedReader {
     buffer: BytesMut,
     method: CipherKind,
     salt: Option<Bytes>,
+    sent_salt: Option<Bytes>,
 }
 
 impl DecryptedReader {
@@ -81,6 +82,7 @@ impl DecryptedReader {
                 buffer: BytesMut::with_capacity(method.salt_len()),
                 method,
                 salt: None,
+                sent_salt: None,
             }
         } else {
             DecryptedReader {
@@ -89,6 +91,7 @@ impl DecryptedReader {
                 buffer: BytesMut::with_capacity(2 + method.tag_len()),
                 method,
                 salt: None,
+                sent_salt: None,
             }
         }
     }
@@ -97,6 +100,11 @@ impl DecryptedReader {
         self.salt.as_deref()
     }
 
+    /// Set salt sent in the same connection, received salt will be checked against it
+    pub fn set_sent_salt(&mut self, sent_salt: Bytes) {
+        self.sent_salt = Some(sent_salt);
+    }
+
     /// Attempt to read decrypted data from stream
     pub fn poll_read_decrypted<S>(
         &mut self,
@@ -112,7 +120,7 @@ impl DecryptedReader {
             match self.state {
                 DecryptReadState::WaitSalt { ref key } => {
                     let key = unsafe { &*(key.as_ref() as *const _) };
-                    ready!(self.poll_read_salt(cx, stream, key))?;
+                    ready!(self.poll_read_salt(cx, context, stream, key))?;
 
                     self.buffer.clear();
                     self.state = DecryptReadState::ReadLength;
@@ -153,7 +161,13 @@ impl DecryptedReader {
         }
     }
 
-    fn poll_read_salt<S>(&mut self, cx: &mut task::Context<'_>, stream: &mut S, key: &[u8]) -> Poll<io::Result<()>>
+    fn poll_read_salt<S>(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        context: &Context,
+        stream: &mut S,
+        key: &[u8],
+    ) -> Poll<io::Result<()>>
     where
         S: AsyncRead + Unpin + ?Sized,
     {
@@ -165,6 +179,10 @@ impl DecryptedReader {
         }
 
         let salt = &self.buffer[..salt_len];
+        if let Some(ref sent_salt) = self.sent_salt {
+            context.check_nonce_reused(sent_salt, salt)?;
+        }
+
         // #442 Remember salt in filter after first successful decryption.
         //
         // If we check salt right here will allow attacker to flood our filter and eventually block all of our legitimate clients' requests.
@@ -385,3 +403,63 @@ impl EncryptedWriter {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use futures::future::poll_fn;
+
+    use crate::config::{ServerConfig, ServerType};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn aes_256_gcm_multi_chunk_round_trip() {
+        let method = CipherKind::AES_256_GCM;
+        let svr_cfg = ServerConfig::new(SocketAddr::from(([127, 0, 0, 1], 8388)), "password", method);
+        let key = svr_cfg.key();
+        assert_eq!(key.len(), 32);
+
+        let context = Context::new(ServerType::Local);
+        let mut salt = vec![0u8; method.salt_len()];
+        context.generate_nonce(method, &mut salt, false);
+
+        let payload = (0..MAX_PACKET_SIZE * 3 + 100).map(|i| i as u8).collect::<Vec<u8>>();
+
+        let mut writer = EncryptedWriter::new(method, key, &salt);
+        let mut encrypted = Vec::new();
+        let mut pos = 0;
+        while pos < payload.len() {
+            pos += poll_fn(|cx| writer.poll_write_encrypted(cx, &mut encrypted, &payload[pos..]))
+                .await
+                .unwrap();
+        }
+
+        // salt + 4 chunks of (length + tag, data + tag)
+        let chunks = 4;
+        assert_eq!(
+            encrypted.len(),
+            salt.len() + chunks * (2 + 2 * method.tag_len()) + payload.len()
+        );
+        assert_eq!(&encrypted[..salt.len()], salt.as_slice());
+
+        let mut reader = DecryptedReader::new(method, key);
+        let mut stream = encrypted.as_slice();
+        let mut decrypted = Vec::new();
+        let mut buffer = vec![0u8; 4096];
+        loop {
+            let mut read_buf = ReadBuf::new(&mut buffer);
+            poll_fn(|cx| reader.poll_read_decrypted(cx, &context, &mut stream, &mut read_buf))
+                .await
+                .unwrap();
+            if read_buf.filled().is_empty() {
+                break;
+            }
+            decrypted.extend_from_slice(read_buf.filled());
+        }
+
+        assert_eq!(reader.salt(), Some(salt.as_slice()));
+        assert_eq!(decrypted, payload);
+    }
+}
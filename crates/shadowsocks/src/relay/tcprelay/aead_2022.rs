@@ -92,6 +92,7 @@ pub struct DecryptedReader {
     method: CipherKind,
     salt: Option<Bytes>,
     request_salt: Option<Bytes>,
+    sent_salt: Option<Bytes>,
     data_chunk_count: u64,
 }
 
@@ -108,6 +109,7 @@ impl DecryptedReader {
                 method,
                 salt: None,
                 request_salt: None,
+                sent_salt: None,
                 data_chunk_count: 0,
             }
         } else {
@@ -121,6 +123,7 @@ impl DecryptedReader {
                 method,
                 salt: None,
                 request_salt: None,
+                sent_salt: None,
                 data_chunk_count: 0,
             }
         }
@@ -130,6 +133,11 @@ impl DecryptedReader {
         self.salt.as_deref()
     }
 
+    /// Set salt sent in the same connection, received salt will be checked against it
+    pub fn set_sent_salt(&mut self, sent_salt: Bytes) {
+        self.sent_salt = Some(sent_salt);
+    }
+
     pub fn request_salt(&self) -> Option<&[u8]> {
         match self.request_salt.as_deref() {
             Some(n) => {
@@ -244,6 +252,10 @@ impl DecryptedReader {
 
         trace!("got AEAD salt {:?}", ByteStr::new(salt));
 
+        if let Some(ref sent_salt) = self.sent_salt {
+            context.check_nonce_reused(sent_salt, salt)?;
+        }
+
         let mut cipher = TcpCipher::new(self.method, key, salt);
 
         // Decrypt the header chunk
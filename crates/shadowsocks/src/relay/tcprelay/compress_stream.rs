@@ -0,0 +1,270 @@
+//! Stream compressing data relayed through it with DEFLATE (RFC 1951)
+//!
+//! This is not a part of the shadowsocks protocol. Both local and server have to enable it, it should wrap the
+//! plain side of `ProxyClientStream` and `ProxyServerStream`, because encrypted data couldn't be compressed.
+//!
+//! Nothing is negotiated between local and server. Lengths of compressed data depend on their contents, so
+//! attackers who could inject data into a stream carrying secrets may recover them (CRIME / BREACH).
+
+use std::{
+    io::{self, ErrorKind, Write},
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use flate2::{write::DeflateEncoder, Compression, Decompress, FlushDecompress, Status};
+use futures::ready;
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const COMPRESSED_READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// A stream compresses data written to, and decompresses data read from the underlying `stream`
+///
+/// Every write is flushed with `Z_SYNC_FLUSH`, so the peer could decompress it without waiting for more data.
+#[pin_project]
+pub struct CompressedStream<S> {
+    #[pin]
+    stream: S,
+    // Compressed data are written into the inner `Vec`, waiting to be sent
+    encoder: DeflateEncoder<Vec<u8>>,
+    write_pos: usize,
+    // Length of plain data of the pending compressed data
+    write_consumed: usize,
+    // Compressed data in `read_buf[read_pos..read_len]` are decompressed into buffers of `poll_read`
+    decompress: Decompress,
+    read_pos: usize,
+    read_len: usize,
+    read_buf: Box<[u8]>,
+}
+
+impl<S> CompressedStream<S> {
+    /// Create a `CompressedStream` wrapping `stream`
+    pub fn new(stream: S) -> CompressedStream<S> {
+        CompressedStream {
+            stream,
+            encoder: DeflateEncoder::new(Vec::new(), Compression::fast()),
+            write_pos: 0,
+            write_consumed: 0,
+            decompress: Decompress::new(false),
+            read_pos: 0,
+            read_len: 0,
+            read_buf: vec![0u8; COMPRESSED_READ_BUFFER_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Get reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the `CompressedStream` and returns the underlying stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> CompressedStream<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write_compressed(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+
+        let compressed = this.encoder.get_mut();
+        while *this.write_pos < compressed.len() {
+            let n = ready!(this.stream.as_mut().poll_write(cx, &compressed[*this.write_pos..]))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            *this.write_pos += n;
+        }
+
+        compressed.clear();
+        *this.write_pos = 0;
+        Ok(std::mem::take(this.write_consumed)).into()
+    }
+}
+
+impl<S> AsyncRead for CompressedStream<S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+
+        if buf.remaining() == 0 {
+            return Ok(()).into();
+        }
+
+        loop {
+            // Decompressed data never exceed the caller's buffer, data left are kept in `decompress`
+            let before_in = this.decompress.total_in();
+            let before_out = this.decompress.total_out();
+            let status = this
+                .decompress
+                .decompress(
+                    &this.read_buf[*this.read_pos..*this.read_len],
+                    buf.initialize_unfilled(),
+                    FlushDecompress::Sync,
+                )
+                .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+            let consumed = (this.decompress.total_in() - before_in) as usize;
+            *this.read_pos += consumed;
+            let n = (this.decompress.total_out() - before_out) as usize;
+
+            if n > 0 {
+                buf.advance(n);
+                return Ok(()).into();
+            }
+            if let Status::StreamEnd = status {
+                // Encoder of the peer never finishes the stream
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "unexpected end of compressed stream",
+                ))
+                .into();
+            }
+            if *this.read_pos < *this.read_len {
+                if consumed > 0 {
+                    // Input consumed without output, the rest of it may produce some
+                    continue;
+                }
+                return Err(io::Error::new(ErrorKind::InvalidData, "invalid compressed stream")).into();
+            }
+
+            let mut read_buf = ReadBuf::new(this.read_buf);
+            ready!(this.stream.as_mut().poll_read(cx, &mut read_buf))?;
+
+            let compressed = read_buf.filled().len();
+            if compressed == 0 {
+                // EOF
+                return Ok(()).into();
+            }
+
+            *this.read_pos = 0;
+            *this.read_len = compressed;
+        }
+    }
+}
+
+impl<S> AsyncWrite for CompressedStream<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            // Nothing to compress, but the underlying stream may send its handshake
+            return self.project().stream.poll_write(cx, buf);
+        }
+
+        if self.write_consumed == 0 {
+            let this = self.as_mut().project();
+            this.encoder.write_all(buf)?;
+            this.encoder.flush()?;
+            *this.write_consumed = buf.len();
+        }
+
+        self.poll_write_compressed(cx)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_write_compressed(cx))?;
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_write_compressed(cx))?;
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use flate2::write::DeflateDecoder;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn compressed_stream_round_trip() {
+        let (local, server) = duplex(64 * 1024);
+        let mut local = CompressedStream::new(local);
+        let mut server = CompressedStream::new(server);
+
+        let request = b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n".repeat(100);
+        local.write_all(&request).await.unwrap();
+
+        let mut received = vec![0u8; request.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, request);
+
+        // Respond in several writes, each of them could be decompressed independently
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        for _ in 0..3 {
+            server.write_all(response).await.unwrap();
+
+            let mut received = vec![0u8; response.len()];
+            local.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, response);
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_stream_compresses() {
+        let (local, mut server) = duplex(64 * 1024);
+        let mut local = CompressedStream::new(local);
+
+        let data = vec![b'a'; 8192];
+        local.write_all(&data).await.unwrap();
+        local.shutdown().await.unwrap();
+
+        let mut compressed = Vec::new();
+        server.read_to_end(&mut compressed).await.unwrap();
+        assert!(
+            compressed.len() < data.len() / 10,
+            "compressed {} bytes",
+            compressed.len()
+        );
+
+        let mut decoder = DeflateDecoder::new(Vec::new());
+        decoder.write_all(&compressed).unwrap();
+        assert_eq!(decoder.finish().unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn compressed_stream_bounded_read() {
+        let (mut local, server) = duplex(64 * 1024);
+        let mut server = CompressedStream::new(server);
+
+        // Highly compressible data, decompressed to much more than the compressed read buffer
+        let data = vec![0u8; 4 * 1024 * 1024];
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&data).unwrap();
+        encoder.flush().unwrap();
+        let compressed = encoder.get_ref().clone();
+        assert!(compressed.len() < COMPRESSED_READ_BUFFER_SIZE);
+
+        tokio::spawn(async move {
+            local.write_all(&compressed).await.unwrap();
+            local.shutdown().await.unwrap();
+        });
+
+        let mut received = 0;
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = server.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            assert!(buf[..n].iter().all(|b| *b == 0));
+            received += n;
+        }
+        assert_eq!(received, data.len());
+    }
+}
@@ -94,6 +94,20 @@ impl DecryptedReader {
         }
     }
 
+    /// Set IV (Stream) or Salt (AEAD, AEAD2022) sent in the same connection
+    ///
+    /// Received nonce will be checked against it before decrypting any data.
+    pub fn set_sent_nonce(&mut self, sent_nonce: Bytes) {
+        match *self {
+            #[cfg(feature = "stream-cipher")]
+            DecryptedReader::Stream(ref mut reader) => reader.set_sent_iv(sent_nonce),
+            DecryptedReader::Aead(ref mut reader) => reader.set_sent_salt(sent_nonce),
+            DecryptedReader::None => {}
+            #[cfg(feature = "aead-cipher-2022")]
+            DecryptedReader::Aead2022(ref mut reader) => reader.set_sent_salt(sent_nonce),
+        }
+    }
+
     /// Get received request Salt (AEAD2022)
     pub fn request_nonce(&self) -> Option<&[u8]> {
         match *self {
@@ -239,9 +253,13 @@ impl<S> CryptoStream<S> {
             }
         };
 
+        let iv = Bytes::from(iv);
+        let mut dec = DecryptedReader::new(stream_ty, method, key);
+        dec.set_sent_nonce(iv.clone());
+
         CryptoStream {
             stream,
-            dec: DecryptedReader::new(stream_ty, method, key),
+            dec,
             enc: EncryptedWriter::new(stream_ty, method, key, &iv),
             method,
         }
@@ -519,3 +537,68 @@ where
         Pin::new(&mut self.writer).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use futures::future::poll_fn;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::{ReplayAttackPolicy, ServerConfig, ServerType};
+
+    use super::*;
+
+    async fn read_reflected(method: CipherKind, context: &Context, tampered: bool) -> io::Result<Vec<u8>> {
+        let svr_cfg = ServerConfig::new(SocketAddr::from(([127, 0, 0, 1], 8388)), "password", method);
+        let (local, mut remote) = duplex(4096);
+        let mut stream = CryptoStream::from_stream(context, local, StreamType::Client, method, svr_cfg.key());
+
+        let n = poll_fn(|cx| Pin::new(&mut stream).poll_write_encrypted(cx, b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(n, 5);
+
+        // Send the encrypted data with our own nonce back
+        let mut encrypted = vec![0u8; 4096];
+        let n = remote.read(&mut encrypted).await.unwrap();
+        assert!(encrypted[..n].starts_with(stream.sent_nonce()));
+        if tampered {
+            encrypted[n - 1] ^= 0xFF;
+        }
+        remote.write_all(&encrypted[..n]).await.unwrap();
+
+        let mut buffer = [0u8; 64];
+        let mut read_buf = ReadBuf::new(&mut buffer);
+        poll_fn(|cx| Pin::new(&mut stream).poll_read_decrypted(cx, context, &mut read_buf)).await?;
+        Ok(read_buf.filled().to_vec())
+    }
+
+    #[tokio::test]
+    async fn reflected_nonce_rejected() {
+        #[allow(unused_mut)]
+        let mut methods = vec![CipherKind::AES_128_GCM];
+        #[cfg(feature = "stream-cipher")]
+        methods.push(CipherKind::AES_128_CFB128);
+
+        for method in methods {
+            // Rejected before decrypting, so the tampered data is never authenticated
+            let mut context = Context::new(ServerType::Local);
+            context.set_replay_attack_policy(ReplayAttackPolicy::Reject);
+            let err = read_reflected(method, &context, true).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", method);
+
+            // Not aborted, only warned unless ignored
+            for policy in [
+                ReplayAttackPolicy::Default,
+                ReplayAttackPolicy::Detect,
+                ReplayAttackPolicy::Ignore,
+            ] {
+                let mut context = Context::new(ServerType::Local);
+                context.set_replay_attack_policy(policy);
+                let data = read_reflected(method, &context, false).await.unwrap();
+                assert_eq!(data, b"hello", "{}", method);
+            }
+        }
+    }
+}
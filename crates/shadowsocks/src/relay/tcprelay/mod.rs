@@ -8,6 +8,8 @@ pub use self::{
 mod aead;
 #[cfg(feature = "aead-cipher-2022")]
 mod aead_2022;
+#[cfg(feature = "stream-compression")]
+pub mod compress_stream;
 pub mod crypto_io;
 pub mod proxy_listener;
 pub mod proxy_stream;
@@ -4,6 +4,7 @@ use std::{
     io::{self, ErrorKind},
     pin::Pin,
     task::{self, Poll},
+    time::Duration,
 };
 
 use bytes::{BufMut, BytesMut};
@@ -30,6 +31,8 @@ use crate::{
     },
 };
 
+use super::http_fronting::http_connect;
+
 enum ProxyClientStreamWriteState {
     Connect(Address),
     Connecting(BytesMut),
@@ -111,25 +114,59 @@ where
         A: Into<Address>,
         F: FnOnce(OutboundTcpStream) -> S,
     {
-        let stream = match svr_cfg.timeout() {
-            Some(d) => {
-                match time::timeout(
-                    d,
-                    OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.external_addr(), opts),
-                )
-                .await
-                {
-                    Ok(Ok(s)) => s,
-                    Ok(Err(e)) => return Err(e),
-                    Err(..) => {
-                        return Err(io::Error::new(
-                            ErrorKind::TimedOut,
-                            format!("connect {} timeout", svr_cfg.addr()),
-                        ))
-                    }
+        ProxyClientStream::connect_with_opts_timeout_map(context, svr_cfg, addr, opts, svr_cfg.timeout(), map_fn).await
+    }
+
+    /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`, maps `TcpStream` to customized stream with `map_fn`
+    ///
+    /// Connecting to the server times out after `timeout`, instead of the `svr_cfg`'s timeout
+    pub async fn connect_with_opts_timeout_map<A, F>(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        addr: A,
+        opts: &ConnectOpts,
+        timeout: Option<Duration>,
+        map_fn: F,
+    ) -> io::Result<ProxyClientStream<S>>
+    where
+        A: Into<Address>,
+        F: FnOnce(OutboundTcpStream) -> S,
+    {
+        // Options of this server override the global ones
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let server_opts = svr_cfg.tcp_maxseg().map(|maxseg| {
+            let mut server_opts = opts.clone();
+            server_opts.tcp.maxseg = Some(maxseg);
+            server_opts
+        });
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let opts = server_opts.as_ref().unwrap_or(opts);
+
+        // TCP connections to a fronted server are made to the fronting proxy
+        let connect = async {
+            match svr_cfg.http_fronting() {
+                None => OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.external_addr(), opts).await,
+                Some(fronting) => {
+                    let mut stream =
+                        OutboundTcpStream::connect_server_with_opts(&context, fronting.proxy(), opts).await?;
+                    http_connect(&mut stream, fronting, svr_cfg.external_addr()).await?;
+                    Ok(stream)
                 }
             }
-            None => OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.external_addr(), opts).await?,
+        };
+
+        let stream = match timeout {
+            Some(d) => match time::timeout(d, connect).await {
+                Ok(Ok(s)) => s,
+                Ok(Err(e)) => return Err(e),
+                Err(..) => {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("connect {} timeout", svr_cfg.addr()),
+                    ))
+                }
+            },
+            None => connect.await?,
         };
 
         trace!(
@@ -0,0 +1,59 @@
+//! HTTP CONNECT fronting of shadowsocks' proxy servers
+
+use std::io::{self, ErrorKind};
+
+use log::trace;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::{HttpFronting, ServerAddr};
+
+/// Maximum length of the fronting proxy's response header
+const MAX_RESPONSE_HEADER_LEN: usize = 8192;
+
+/// Ask the fronting proxy connected with `stream` to tunnel to `server_addr`
+pub async fn http_connect<S>(stream: &mut S, fronting: &HttpFronting, server_addr: &ServerAddr) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_addr = server_addr.to_string();
+    let host = fronting.host().unwrap_or(&server_addr);
+
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", server_addr, host);
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte, the shadowsocks stream follows the header immediately
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_RESPONSE_HEADER_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "fronting proxy response header is too long",
+            ));
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    // HTTP/1.1 200 Connection established
+    let status_line = header.split(|b| *b == b'\n').next().unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    let status_line = status_line.trim_end();
+
+    let mut parts = status_line.split(' ');
+    let version = parts.next().unwrap_or_default();
+    let status = parts.next().unwrap_or_default();
+    if !version.starts_with("HTTP/1.") || status.len() != 3 || !status.starts_with('2') {
+        return Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("fronting proxy {} refused CONNECT, {}", fronting.proxy(), status_line),
+        ));
+    }
+
+    trace!(
+        "fronting proxy {} tunneled to {}, host: {}",
+        fronting.proxy(),
+        server_addr,
+        host
+    );
+
+    Ok(())
+}
@@ -8,5 +8,6 @@
 pub use self::{client::ProxyClientStream, server::ProxyServerStream};
 
 pub mod client;
+mod http_fronting;
 pub mod protocol;
 pub mod server;
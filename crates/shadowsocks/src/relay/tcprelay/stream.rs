@@ -30,6 +30,7 @@ pub struct DecryptedReader {
     buffer: BytesMut,
     method: CipherKind,
     iv: Option<Bytes>,
+    sent_iv: Option<Bytes>,
 }
 
 impl DecryptedReader {
@@ -43,6 +44,7 @@ impl DecryptedReader {
                 buffer: BytesMut::with_capacity(method.iv_len()),
                 method,
                 iv: None,
+                sent_iv: None,
             }
         } else {
             DecryptedReader {
@@ -51,6 +53,7 @@ impl DecryptedReader {
                 buffer: BytesMut::new(),
                 method,
                 iv: Some(Bytes::new()),
+                sent_iv: None,
             }
         }
     }
@@ -59,6 +62,11 @@ impl DecryptedReader {
         self.iv.as_deref()
     }
 
+    /// Set IV sent in the same connection, received IV will be checked against it
+    pub fn set_sent_iv(&mut self, sent_iv: Bytes) {
+        self.sent_iv = Some(sent_iv);
+    }
+
     /// Attempt to read decrypted data from reader
     pub fn poll_read_decrypted<S>(
         &mut self,
@@ -119,6 +127,9 @@ impl DecryptedReader {
         }
 
         let iv = &self.buffer[..iv_len];
+        if let Some(ref sent_iv) = self.sent_iv {
+            context.check_nonce_reused(sent_iv, iv)?;
+        }
         context.check_nonce_replay(self.method, iv)?;
 
         trace!("got stream iv {:?}", ByteStr::new(iv));
@@ -8,11 +8,15 @@ use std::{
     io,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use futures::ready;
 use pin_project::pin_project;
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant, Sleep},
+};
 
 use crate::crypto::{CipherCategory, CipherKind};
 
@@ -22,6 +26,7 @@ struct CopyBuffer {
     pos: usize,
     cap: usize,
     amt: u64,
+    read_amt: u64,
     buf: Box<[u8]>,
 }
 
@@ -32,6 +37,7 @@ impl CopyBuffer {
             pos: 0,
             cap: 0,
             amt: 0,
+            read_amt: 0,
             buf: vec![0; buffer_size].into_boxed_slice(),
         }
     }
@@ -59,6 +65,7 @@ impl CopyBuffer {
                 } else {
                     self.pos = 0;
                     self.cap = n;
+                    self.read_amt += n as u64;
                 }
             }
 
@@ -180,6 +187,97 @@ enum TransferState {
     Done(u64),
 }
 
+impl TransferState {
+    // Bytes have been read but not written yet
+    fn buffered(&self) -> u64 {
+        match *self {
+            TransferState::Running(ref buf) => (buf.cap - buf.pos) as u64,
+            TransferState::ShuttingDown(..) | TransferState::Done(..) => 0,
+        }
+    }
+
+    // Bytes have been written
+    fn written(&self) -> u64 {
+        match *self {
+            TransferState::Running(ref buf) => buf.amt,
+            TransferState::ShuttingDown(count) | TransferState::Done(count) => count,
+        }
+    }
+
+    // Changes whenever bytes are read or written, or the reader reaches EOF
+    fn progress(&self) -> u64 {
+        match *self {
+            TransferState::Running(ref buf) => buf.read_amt + buf.amt,
+            TransferState::ShuttingDown(..) | TransferState::Done(..) => u64::MAX,
+        }
+    }
+}
+
+// Fails a bidirectional copy if it makes no progress in `timeout`
+struct IdleTimer {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    progress: (u64, u64),
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration) -> IdleTimer {
+        IdleTimer {
+            timeout,
+            sleep: Box::pin(time::sleep(timeout)),
+            progress: (0, 0),
+        }
+    }
+}
+
+/// Bytes read from one stream but not written to the other when a bidirectional copy stops
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UndeliveredBytes {
+    /// Read from the encrypted stream, not written to the plain stream
+    pub encrypted_to_plain: u64,
+    /// Read from the plain stream, not written to the encrypted stream
+    pub plain_to_encrypted: u64,
+}
+
+impl UndeliveredBytes {
+    /// Total bytes of both directions
+    pub fn total(&self) -> u64 {
+        self.encrypted_to_plain + self.plain_to_encrypted
+    }
+}
+
+/// Stream of a bidirectional copy that reached EOF first, which initiated the close
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseInitiator {
+    /// The encrypted stream
+    Encrypted,
+    /// The plain stream
+    Plain,
+    /// Both streams reached EOF in the same poll, which one was the first is unknown
+    Both,
+}
+
+/// Options of `copy_encrypted_bidirectional_with_state`
+#[derive(Debug, Default, Clone)]
+pub struct CopyBidirectionalOpts {
+    /// Fails the copy with `TimedOut` when no bytes are read or written in either direction for that long
+    pub idle_timeout: Option<Duration>,
+}
+
+/// States of a bidirectional copy, kept up to date while copying
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CopyBidirectionalState {
+    /// Bytes read but not written yet
+    pub undelivered: UndeliveredBytes,
+    /// Stream that reached EOF first, `None` if neither of them has reached EOF
+    pub close_initiator: Option<CloseInitiator>,
+    /// Bytes written to the plain stream
+    pub written_to_plain: u64,
+    /// Bytes written to the encrypted stream
+    pub written_to_encrypted: u64,
+}
+
 #[pin_project(project = CopyBidirectionalProj)]
 struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     #[pin]
@@ -188,6 +286,8 @@ struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     b: &'a mut B,
     a_to_b: TransferState,
     b_to_a: TransferState,
+    state: Option<&'a mut CopyBidirectionalState>,
+    idle_timer: Option<IdleTimer>,
 }
 
 fn transfer_one_direction<A, B>(
@@ -230,20 +330,72 @@ where
             mut b,
             a_to_b,
             b_to_a,
+            state,
+            idle_timer,
         } = self.project();
 
-        let poll_a_to_b = transfer_one_direction(cx, a_to_b, a.as_mut(), b.as_mut())?;
-        let poll_b_to_a = transfer_one_direction(cx, b_to_a, b.as_mut(), a.as_mut())?;
+        let result = poll_transfer_bidirectional(cx, a_to_b, b_to_a, a.as_mut(), b.as_mut());
+
+        // Updated on every poll, so it is still correct if this future is dropped before it completes
+        if let Some(state) = state {
+            state.undelivered.encrypted_to_plain = a_to_b.buffered();
+            state.undelivered.plain_to_encrypted = b_to_a.buffered();
+            state.written_to_plain = a_to_b.written();
+            state.written_to_encrypted = b_to_a.written();
+
+            // A direction stops running only if its reader reached EOF
+            if state.close_initiator.is_none() {
+                let encrypted_eof = !matches!(a_to_b, TransferState::Running(..));
+                let plain_eof = !matches!(b_to_a, TransferState::Running(..));
+                state.close_initiator = match (encrypted_eof, plain_eof) {
+                    (true, true) => Some(CloseInitiator::Both),
+                    (true, false) => Some(CloseInitiator::Encrypted),
+                    (false, true) => Some(CloseInitiator::Plain),
+                    (false, false) => None,
+                };
+            }
+        }
+
+        if let (Poll::Pending, Some(idle_timer)) = (&result, idle_timer) {
+            let progress = (a_to_b.progress(), b_to_a.progress());
+            if progress != idle_timer.progress {
+                idle_timer.progress = progress;
+                let deadline = Instant::now() + idle_timer.timeout;
+                idle_timer.sleep.as_mut().reset(deadline);
+            }
 
-        // It is not a problem if ready! returns early because transfer_one_direction for the
-        // other direction will keep returning TransferState::Done(count) in future calls to poll
-        let a_to_b = ready!(poll_a_to_b);
-        let b_to_a = ready!(poll_b_to_a);
+            if idle_timer.sleep.as_mut().poll(cx).is_ready() {
+                let err = io::Error::new(io::ErrorKind::TimedOut, "no data transferred in idle timeout");
+                return Poll::Ready(Err(err));
+            }
+        }
 
-        Poll::Ready(Ok((a_to_b, b_to_a)))
+        result
     }
 }
 
+fn poll_transfer_bidirectional<A, B>(
+    cx: &mut Context<'_>,
+    a_to_b: &mut TransferState,
+    b_to_a: &mut TransferState,
+    mut a: Pin<&mut A>,
+    mut b: Pin<&mut B>,
+) -> Poll<io::Result<(u64, u64)>>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let poll_a_to_b = transfer_one_direction(cx, a_to_b, a.as_mut(), b.as_mut())?;
+    let poll_b_to_a = transfer_one_direction(cx, b_to_a, b.as_mut(), a.as_mut())?;
+
+    // It is not a problem if ready! returns early because transfer_one_direction for the
+    // other direction will keep returning TransferState::Done(count) in future calls to poll
+    let a_to_b = ready!(poll_a_to_b);
+    let b_to_a = ready!(poll_b_to_a);
+
+    Poll::Ready(Ok((a_to_b, b_to_a)))
+}
+
 /// Copies data in both directions between `encrypted` stream and `plain` stream.
 ///
 /// This function returns a future that will read from both streams,
@@ -285,6 +437,76 @@ where
         b: plain,
         a_to_b: TransferState::Running(CopyBuffer::new(encrypted_read_buffer_size(method))),
         b_to_a: TransferState::Running(CopyBuffer::new(plain_read_buffer_size(method))),
+        state: None,
+        idle_timer: None,
     }
     .await
 }
+
+/// Same as `copy_encrypted_bidirectional`, and records states of the copy into `state`
+///
+/// `state` is kept up to date while copying, so it could also be checked after the future is dropped,
+/// for example, cancelled by `tokio::select!`. Undelivered bytes are always zero after the copy finishes successfully.
+///
+/// If `opts.idle_timeout` is set, the copy fails with `TimedOut` when no bytes are read or written in either
+/// direction for that long, streams are closed after they are dropped by the caller.
+pub async fn copy_encrypted_bidirectional_with_state<E, P>(
+    method: CipherKind,
+    encrypted: &mut E,
+    plain: &mut P,
+    opts: &CopyBidirectionalOpts,
+    state: &mut CopyBidirectionalState,
+) -> Result<(u64, u64), std::io::Error>
+where
+    E: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    P: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    CopyBidirectional {
+        a: encrypted,
+        b: plain,
+        a_to_b: TransferState::Running(CopyBuffer::new(encrypted_read_buffer_size(method))),
+        b_to_a: TransferState::Running(CopyBuffer::new(plain_read_buffer_size(method))),
+        state: Some(state),
+        idle_timer: opts.idle_timeout.map(IdleTimer::new),
+    }
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn close_initiator() {
+        // Plain stream reaches EOF first
+        let (mut encrypted, mut remote) = duplex(64);
+        let (mut plain, client) = duplex(64);
+        drop(client);
+        let copy = tokio::spawn(async move {
+            let mut state = CopyBidirectionalState::default();
+            let opts = CopyBidirectionalOpts::default();
+            copy_encrypted_bidirectional_with_state(CipherKind::NONE, &mut encrypted, &mut plain, &opts, &mut state)
+                .await
+                .unwrap();
+            state
+        });
+        let mut buf = Vec::new();
+        remote.read_to_end(&mut buf).await.unwrap();
+        remote.shutdown().await.unwrap();
+        drop(remote);
+        assert_eq!(copy.await.unwrap().close_initiator, Some(CloseInitiator::Plain));
+
+        // Both streams have already reached EOF before the first poll
+        let (mut encrypted, remote) = duplex(64);
+        let (mut plain, client) = duplex(64);
+        drop((remote, client));
+        let mut state = CopyBidirectionalState::default();
+        let opts = CopyBidirectionalOpts::default();
+        copy_encrypted_bidirectional_with_state(CipherKind::NONE, &mut encrypted, &mut plain, &opts, &mut state)
+            .await
+            .unwrap();
+        assert_eq!(state.close_initiator, Some(CloseInitiator::Both));
+    }
+}
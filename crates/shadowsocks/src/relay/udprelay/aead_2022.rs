@@ -314,6 +314,11 @@ fn get_nonce_len(method: CipherKind) -> usize {
     }
 }
 
+/// Length of `Client -> Server` UDP AEAD protocol packet with a non-empty payload of `payload_len` bytes
+pub fn client_packet_len_aead_2022(method: CipherKind, addr: &Address, payload_len: usize) -> usize {
+    get_nonce_len(method) + 8 + 8 + 1 + 8 + 2 + addr.serialized_len() + payload_len + method.tag_len()
+}
+
 /// Encrypt `Client -> Server` UDP AEAD protocol packet
 pub fn encrypt_client_payload_aead_2022(
     context: &Context,
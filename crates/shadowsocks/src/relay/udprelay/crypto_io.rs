@@ -31,6 +31,7 @@ use crate::{
 
 #[cfg(feature = "aead-cipher-2022")]
 use super::aead_2022::{
+    client_packet_len_aead_2022,
     decrypt_client_payload_aead_2022,
     decrypt_server_payload_aead_2022,
     encrypt_client_payload_aead_2022,
@@ -74,6 +75,19 @@ pub fn encrypt_client_payload(
     }
 }
 
+/// Length of `Client -> Server` ShadowSocks UDP encrypted packet with a non-empty payload of `payload_len` bytes
+pub fn client_packet_len(method: CipherKind, addr: &Address, payload_len: usize) -> usize {
+    let addr_len = addr.serialized_len();
+    match method.category() {
+        CipherCategory::None => addr_len + payload_len,
+        #[cfg(feature = "stream-cipher")]
+        CipherCategory::Stream => method.iv_len() + addr_len + payload_len,
+        CipherCategory::Aead => method.salt_len() + addr_len + payload_len + method.tag_len(),
+        #[cfg(feature = "aead-cipher-2022")]
+        CipherCategory::Aead2022 => client_packet_len_aead_2022(method, addr, payload_len),
+    }
+}
+
 /// Encrypt `Server -> Client` payload into ShadowSocks UDP encrypted packet
 pub fn encrypt_server_payload(
     context: &Context,
@@ -178,3 +192,38 @@ pub async fn decrypt_server_payload(
             .map(|(n, a, c)| (n, a, Some(c))),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::config::{ServerConfig, ServerType};
+
+    use super::*;
+
+    #[test]
+    fn client_packet_len_matches_encrypted() {
+        #[allow(unused_mut)]
+        let mut methods = vec![
+            (CipherKind::NONE, "password"),
+            (CipherKind::AES_128_GCM, "password"),
+            (CipherKind::CHACHA20_POLY1305, "password"),
+        ];
+        #[cfg(feature = "stream-cipher")]
+        methods.push((CipherKind::AES_128_CFB128, "password"));
+        #[cfg(feature = "aead-cipher-2022")]
+        methods.push((CipherKind::AEAD2022_BLAKE3_AES_128_GCM, "AAAAAAAAAAAAAAAAAAAAAA=="));
+
+        let context = Context::new(ServerType::Local);
+        let addr = Address::DomainNameAddress("example.com".to_owned(), 53);
+        let control = UdpSocketControlData::default();
+        let payload = [0u8; 100];
+
+        for (method, password) in methods {
+            let svr_cfg = ServerConfig::new(SocketAddr::from(([127, 0, 0, 1], 8388)), password, method);
+            let mut dst = BytesMut::new();
+            encrypt_client_payload(&context, method, svr_cfg.key(), &addr, &control, &payload, &mut dst);
+            assert_eq!(dst.len(), client_packet_len(method, &addr, payload.len()), "{}", method);
+        }
+    }
+}
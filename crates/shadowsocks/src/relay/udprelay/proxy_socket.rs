@@ -1,6 +1,6 @@
 //! UDP socket for communicating with shadowsocks' proxy server
 
-use std::{io, net::SocketAddr, time::Duration};
+use std::{fmt, io, net::SocketAddr, time::Duration};
 
 use bytes::BytesMut;
 use log::{trace, warn};
@@ -16,6 +16,7 @@ use crate::{
 };
 
 use super::crypto_io::{
+    client_packet_len,
     decrypt_client_payload,
     decrypt_server_payload,
     encrypt_client_payload,
@@ -25,6 +26,40 @@ use super::crypto_io::{
 static DEFAULT_CONNECT_OPTS: Lazy<ConnectOpts> = Lazy::new(Default::default);
 static DEFAULT_SOCKET_CONTROL: Lazy<UdpSocketControlData> = Lazy::new(UdpSocketControlData::default);
 
+/// Error of UDP datagrams filling up the whole receive buffer
+///
+/// UDP datagrams larger than the receive buffer are truncated by the OS, which couldn't be distinguished from a
+/// datagram fitting exactly in the buffer, so both are rejected with `io::ErrorKind::InvalidData` carrying this error.
+#[derive(Debug, Clone, Copy)]
+pub struct DatagramTruncatedError {
+    buffer_size: usize,
+}
+
+impl DatagramTruncatedError {
+    /// Size of the receive buffer filled up by the datagram
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+}
+
+impl fmt::Display for DatagramTruncatedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "udp packet exceeds receive buffer of {} bytes", self.buffer_size)
+    }
+}
+
+impl std::error::Error for DatagramTruncatedError {}
+
+fn check_recv_truncated(recv_buf: &[u8], recv_n: usize) -> io::Result<()> {
+    if recv_n == recv_buf.len() {
+        let err = DatagramTruncatedError {
+            buffer_size: recv_buf.len(),
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+    }
+    Ok(())
+}
+
 /// UDP socket type, defining whether the socket is used in Client or Server
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UdpSocketType {
@@ -143,6 +178,11 @@ impl ProxySocket {
         }
     }
 
+    /// Length of the packet that a client socket sends to `addr` with a non-empty payload of `payload_len` bytes
+    pub fn client_packet_len(&self, addr: &Address, payload_len: usize) -> usize {
+        client_packet_len(self.method, addr, payload_len)
+    }
+
     /// Send a UDP packet to addr through proxy
     #[inline]
     pub async fn send(&self, addr: &Address, payload: &[u8]) -> io::Result<usize> {
@@ -255,7 +295,8 @@ impl ProxySocket {
     ///
     /// This function will use `recv_buf` to store intermediate data, so it has to be big enough to store the whole shadowsocks' packet
     ///
-    /// It is recommended to allocate a buffer to have at least 65536 bytes.
+    /// It is recommended to allocate a buffer to have at least 65536 bytes. Packets filling up the whole `recv_buf`
+    /// may have been truncated, they are rejected with `io::ErrorKind::InvalidData` carrying `DatagramTruncatedError`.
     pub async fn recv_with_ctrl(
         &self,
         recv_buf: &mut [u8],
@@ -270,6 +311,8 @@ impl ProxySocket {
             },
         };
 
+        check_recv_truncated(recv_buf, recv_n)?;
+
         let (n, addr, control) = self.decrypt_recv_buffer(&mut recv_buf[..recv_n]).await?;
 
         trace!(
@@ -298,7 +341,8 @@ impl ProxySocket {
     ///
     /// This function will use `recv_buf` to store intermediate data, so it has to be big enough to store the whole shadowsocks' packet
     ///
-    /// It is recommended to allocate a buffer to have at least 65536 bytes.
+    /// It is recommended to allocate a buffer to have at least 65536 bytes. Packets filling up the whole `recv_buf`
+    /// may have been truncated, they are rejected with `io::ErrorKind::InvalidData` carrying `DatagramTruncatedError`.
     pub async fn recv_from_with_ctrl(
         &self,
         recv_buf: &mut [u8],
@@ -313,6 +357,8 @@ impl ProxySocket {
             },
         };
 
+        check_recv_truncated(recv_buf, recv_n)?;
+
         let (n, addr, control) = self.decrypt_recv_buffer(&mut recv_buf[..recv_n]).await?;
 
         trace!(
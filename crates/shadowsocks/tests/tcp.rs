@@ -175,6 +175,56 @@ async fn tcp_tunnel_stream() {
         .unwrap();
 }
 
+#[cfg(feature = "stream-cipher")]
+#[tokio::test]
+async fn tcp_tunnel_mixed_aead_stream() {
+    use tokio::io::AsyncReadExt;
+
+    let _ = env_logger::try_init();
+
+    // Echo server as the target
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = echo_listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+
+    let ctx_server = Context::new_shared(ServerType::Server);
+    let ctx_local = Context::new_shared(ServerType::Local);
+
+    // Servers with AEAD and stream ciphers, sharing the same local context
+    let mut svr_cfgs = Vec::new();
+    for method in [CipherKind::AES_256_GCM, CipherKind::AES_128_CFB128] {
+        let svr_cfg = ServerConfig::new("127.0.0.1:0".parse::<SocketAddr>().unwrap(), "p$p", method);
+        let listener = ProxyListener::bind(ctx_server.clone(), &svr_cfg).await.unwrap();
+        svr_cfgs.push(ServerConfig::new(listener.local_addr().unwrap(), "p$p", method));
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_tcp_tunnel_server_client(method, stream));
+            }
+        });
+    }
+
+    for svr_cfg in &svr_cfgs {
+        let mut remote = ProxyClientStream::connect(ctx_local.clone(), svr_cfg, echo_addr)
+            .await
+            .unwrap();
+
+        let message = format!("hello through {}", svr_cfg.method());
+        remote.write_all(message.as_bytes()).await.unwrap();
+
+        let mut buffer = vec![0u8; message.len()];
+        remote.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, message.as_bytes());
+    }
+}
+
 #[tokio::test]
 async fn tcp_tunnel_none() {
     let _ = env_logger::try_init();
@@ -219,3 +269,87 @@ async fn tcp_tunnel_aead_2022_chacha20() {
     .await
     .unwrap();
 }
+
+#[tokio::test]
+async fn tcp_tunnel_http_fronting() {
+    use shadowsocks::config::HttpFronting;
+    use tokio::{io::AsyncReadExt, sync::oneshot};
+
+    let _ = env_logger::try_init();
+
+    // Echo server as the target
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = echo_listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+
+    let ctx_server = Context::new_shared(ServerType::Server);
+    let ctx_local = Context::new_shared(ServerType::Local);
+
+    let method = CipherKind::AES_256_GCM;
+    let svr_cfg = ServerConfig::new("127.0.0.1:0".parse::<SocketAddr>().unwrap(), "p$p", method);
+    let listener = ProxyListener::bind(ctx_server, &svr_cfg).await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle_tcp_tunnel_server_client(method, stream));
+        }
+    });
+
+    // Fronting proxy, records the CONNECT request and tunnels to the requested address
+    let fronting_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let fronting_addr = fronting_listener.local_addr().unwrap();
+    let (request_tx, request_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (stream, _) = fronting_listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+
+        let mut request = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            request.push(line.trim_end().to_owned());
+        }
+
+        let target = request[0].split(' ').nth(1).unwrap().to_owned();
+        let _ = request_tx.send(request);
+
+        let mut remote = TcpStream::connect(target).await.unwrap();
+        remote.write_all(stream.buffer()).await.unwrap();
+        let mut stream = stream.into_inner();
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut stream, &mut remote).await;
+    });
+
+    let mut svr_cfg = ServerConfig::new(server_addr, "p$p", method);
+    let mut fronting = HttpFronting::new(fronting_addr.into());
+    fronting.set_host("front.example.com");
+    svr_cfg.set_http_fronting(fronting);
+
+    let mut remote = ProxyClientStream::connect(ctx_local, &svr_cfg, echo_addr)
+        .await
+        .unwrap();
+
+    let request = request_rx.await.unwrap();
+    assert_eq!(request[0], format!("CONNECT {} HTTP/1.1", server_addr));
+    assert!(request.iter().any(|h| h == "Host: front.example.com"), "{:?}", request);
+
+    let message = b"hello through fronting proxy";
+    remote.write_all(message).await.unwrap();
+
+    let mut buffer = vec![0u8; message.len()];
+    remote.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(buffer, message);
+}
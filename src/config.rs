@@ -126,6 +126,9 @@ impl Config {
                 if let Some(without_time) = format.without_time {
                     nformat.without_time = without_time;
                 }
+                if let Some(json) = format.json {
+                    nformat.json = json;
+                }
                 nlog.format = nformat;
             }
 
@@ -208,6 +211,8 @@ pub struct LogConfig {
 #[derive(Debug, Clone, Default)]
 pub struct LogFormatConfig {
     pub without_time: bool,
+    /// Log each record as a JSON object, with fields of relay events
+    pub json: bool,
 }
 
 /// Runtime mode (Tokio)
@@ -278,6 +283,7 @@ struct SSLogConfig {
 #[derive(Deserialize)]
 struct SSLogFormat {
     without_time: Option<bool>,
+    json: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -6,7 +6,7 @@ use log::LevelFilter;
 use log4rs::{
     append::console::{ConsoleAppender, Target},
     config::{Appender, Config, Logger, Root},
-    encode::pattern::PatternEncoder,
+    encode::{json::JsonEncoder, pattern::PatternEncoder, Encode},
 };
 
 use crate::config::LogConfig;
@@ -24,22 +24,29 @@ pub fn init_with_config(bin_name: &str, config: &LogConfig) {
     let debug_level = config.level;
     let without_time = config.format.without_time;
 
-    let mut pattern = String::new();
-    if !without_time {
-        pattern += "{d} ";
-    }
-    pattern += "{h({l}):<5} ";
-    if debug_level >= 1 {
-        pattern += "[{P}:{I}] [{M}] ";
-    }
-    pattern += "{m}{n}";
+    // Fields of relay events are in "mdc" of JSON records, and they are logged at info level for being collected
+    let encoder: Box<dyn Encode> = if config.format.json {
+        shadowsocks_service::net::relay_event::set_relay_events_info(true);
+        Box::new(JsonEncoder::new())
+    } else {
+        let mut pattern = String::new();
+        if !without_time {
+            pattern += "{d} ";
+        }
+        pattern += "{h({l}):<5} ";
+        if debug_level >= 1 {
+            pattern += "[{P}:{I}] [{M}] ";
+        }
+        pattern += "{m}{n}";
+        Box::new(PatternEncoder::new(&pattern))
+    };
 
     let logging_builder = Config::builder().appender(
         Appender::builder().build(
             "console",
             Box::new(
                 ConsoleAppender::builder()
-                    .encoder(Box::new(PatternEncoder::new(&pattern)))
+                    .encoder(encoder)
                     .target(Target::Stderr)
                     .build(),
             ),
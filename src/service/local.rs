@@ -1,6 +1,6 @@
 //! Local server launchers
 
-use std::{net::IpAddr, path::PathBuf, process, time::Duration};
+use std::{net::IpAddr, path::PathBuf, process, sync::Arc, time::Duration};
 
 use clap::{Arg, ArgGroup, ArgMatches, Command, ErrorKind as ClapErrorKind};
 use futures::future::{self, Either};
@@ -15,7 +15,12 @@ use shadowsocks_service::{
     acl::AccessControl,
     config::{read_variable_field_value, Config, ConfigType, LocalConfig, ProtocolType},
     create_local,
-    local::loadbalancing::PingBalancer,
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        PinnedBalancer,
+        LOCAL_DEFAULT_SHUTDOWN_GRACE_PERIOD,
+    },
     shadowsocks::{
         config::{Mode, ServerAddr, ServerConfig},
         crypto::{available_ciphers, CipherKind},
@@ -143,6 +148,7 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
     .arg(Arg::new("TCP_KEEP_ALIVE").long("tcp-keep-alive").takes_value(true).validator(validator::validate_u64).help("Set TCP keep alive timeout seconds"))
     .arg(Arg::new("UDP_TIMEOUT").long("udp-timeout").takes_value(true).validator(validator::validate_u64).help("Timeout seconds for UDP relay"))
     .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").takes_value(true).validator(validator::validate_u64).help("Maximum associations to be kept simultaneously for UDP relay"))
+    .arg(Arg::new("UDP_MAX_DATAGRAM").long("udp-max-datagram").takes_value(true).validator(validator::validate_u64).help("Maximum size of datagrams relayed by UDP relay, larger ones will be dropped"))
     .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_SNDBUF option"))
     .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_RCVBUF option"))
     .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set outbound sockets' SO_SNDBUF option"))
@@ -211,6 +217,19 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 .validator(validator::validate_u32)
                 .help("Set SO_MARK option for outbound sockets"),
         );
+        app = app.arg(
+            Arg::new("TCP_CONGESTION")
+                .long("tcp-congestion")
+                .takes_value(true)
+                .help("Set TCP_CONGESTION option, congestion control algorithm for inbound and outbound sockets"),
+        );
+        app = app.arg(
+            Arg::new("TCP_USER_TIMEOUT")
+                .long("tcp-user-timeout")
+                .takes_value(true)
+                .validator(validator::validate_u64)
+                .help("Set TCP_USER_TIMEOUT option in seconds for inbound and outbound sockets"),
+        );
     }
 
     #[cfg(target_os = "freebsd")]
@@ -654,6 +673,20 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        match matches.value_of_t::<String>("TCP_CONGESTION") {
+            Ok(congestion) => config.tcp_congestion = Some(congestion),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        match matches.value_of_t::<u64>("TCP_USER_TIMEOUT") {
+            Ok(timeout) => config.tcp_user_timeout = Some(Duration::from_secs(timeout)),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
         #[cfg(target_os = "freebsd")]
         match matches.value_of_t::<u32>("OUTBOUND_USER_COOKIE") {
             Ok(user_cookie) => config.outbound_user_cookie = Some(user_cookie),
@@ -707,6 +740,12 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        match matches.value_of_t::<usize>("UDP_MAX_DATAGRAM") {
+            Ok(udp_max_datagram) => config.udp_max_datagram = Some(udp_max_datagram),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
         match matches.value_of_t::<u32>("INBOUND_SEND_BUFFER_SIZE") {
             Ok(bs) => config.inbound_send_buffer_size = Some(bs),
             Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
@@ -781,9 +820,19 @@ pub fn main(matches: &ArgMatches) {
         let instance = create_local(config).await.expect("create local");
 
         if let Some(config_path) = config_path {
-            launch_reload_server_task(config_path, instance.server_balancer().clone());
+            launch_reload_server_task(
+                config_path,
+                instance.server_balancer().clone(),
+                instance.pinned_balancers().to_vec(),
+            );
         }
 
+        if instance.server_context().acl().is_some() {
+            launch_reload_acl_task(instance.server_context().clone());
+        }
+
+        let context = instance.server_context().clone();
+
         let abort_signal = monitor::create_signal_monitor();
         let server = instance.wait_until_exit();
 
@@ -801,21 +850,40 @@ pub fn main(matches: &ArgMatches) {
                 eprintln!("server aborted with {}", err);
                 process::exit(crate::EXIT_CODE_SERVER_ABORTED);
             }
-            // The abort signal future resolved. Means we should just exit.
-            Either::Right(_) => (),
+            // The abort signal future resolved. Servers keep running until active connections are finished, unless it
+            // is signaled again.
+            Either::Right(_) => {
+                let drain = context.drain(LOCAL_DEFAULT_SHUTDOWN_GRACE_PERIOD);
+                let abort_signal = monitor::create_signal_monitor();
+
+                tokio::pin!(drain);
+                tokio::pin!(abort_signal);
+
+                let _ = future::select(drain, abort_signal).await;
+            }
         }
     });
 }
 
 #[cfg(unix)]
-fn launch_reload_server_task(config_path: PathBuf, balancer: PingBalancer) {
+fn launch_reload_server_task(config_path: PathBuf, balancer: PingBalancer, pinned_balancers: Vec<PinnedBalancer>) {
+    use futures::FutureExt;
     use log::error;
     use tokio::signal::unix::{signal, SignalKind};
 
     tokio::spawn(async move {
         let mut sigusr1 = signal(SignalKind::user_defined1()).expect("signal");
+        let mut sighup = signal(SignalKind::hangup()).expect("signal");
+
+        loop {
+            let received = match future::select(sigusr1.recv().boxed(), sighup.recv().boxed()).await {
+                Either::Left((r, ..)) => r,
+                Either::Right((r, ..)) => r,
+            };
+            if received.is_none() {
+                break;
+            }
 
-        while sigusr1.recv().await.is_some() {
             let config = match Config::load_from_file(&config_path, ConfigType::Local) {
                 Ok(c) => c,
                 Err(err) => {
@@ -827,6 +895,12 @@ fn launch_reload_server_task(config_path: PathBuf, balancer: PingBalancer) {
             let servers = config.server;
             info!("auto-reload {} with {} servers", config_path.display(), servers.len());
 
+            for pinned_balancer in &pinned_balancers {
+                if let Err(err) = pinned_balancer.reset_servers(&servers).await {
+                    error!("auto-reload {} but found error: {}", config_path.display(), err);
+                }
+            }
+
             if let Err(err) = balancer.reset_servers(servers).await {
                 error!("auto-reload {} but found error: {}", config_path.display(), err);
             }
@@ -835,4 +909,34 @@ fn launch_reload_server_task(config_path: PathBuf, balancer: PingBalancer) {
 }
 
 #[cfg(not(unix))]
-fn launch_reload_server_task(_: PathBuf, _: PingBalancer) {}
+fn launch_reload_server_task(_: PathBuf, _: PingBalancer, _: Vec<PinnedBalancer>) {}
+
+#[cfg(unix)]
+fn launch_reload_acl_task(context: Arc<ServiceContext>) {
+    use futures::FutureExt;
+    use log::error;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut sigusr2 = signal(SignalKind::user_defined2()).expect("signal");
+        let mut sighup = signal(SignalKind::hangup()).expect("signal");
+
+        loop {
+            let received = match future::select(sigusr2.recv().boxed(), sighup.recv().boxed()).await {
+                Either::Left((r, ..)) => r,
+                Either::Right((r, ..)) => r,
+            };
+            if received.is_none() {
+                break;
+            }
+
+            match context.reload_acl().await {
+                Ok(..) => info!("auto-reload ACL rules"),
+                Err(err) => error!("auto-reload ACL rules failed with error: {}", err),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn launch_reload_acl_task(_: Arc<ServiceContext>) {}
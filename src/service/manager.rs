@@ -98,6 +98,7 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
         .arg(Arg::new("TCP_KEEP_ALIVE").long("tcp-keep-alive").takes_value(true).validator(validator::validate_u64).help("Set TCP keep alive timeout seconds"))
         .arg(Arg::new("UDP_TIMEOUT").long("udp-timeout").takes_value(true).validator(validator::validate_u64).help("Timeout seconds for UDP relay"))
         .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").takes_value(true).validator(validator::validate_u64).help("Maximum associations to be kept simultaneously for UDP relay"))
+        .arg(Arg::new("UDP_MAX_DATAGRAM").long("udp-max-datagram").takes_value(true).validator(validator::validate_u64).help("Maximum size of datagrams relayed by UDP relay, larger ones will be dropped"))
         .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_SNDBUF option"))
         .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set inbound sockets' SO_RCVBUF option"))
         .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").takes_value(true).validator(validator::validate_u32).help("Set outbound sockets' SO_SNDBUF option"))
@@ -175,6 +176,19 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
                 .validator(validator::validate_u32)
                 .help("Set SO_MARK option for outbound sockets"),
         );
+        app = app.arg(
+            Arg::new("TCP_CONGESTION")
+                .long("tcp-congestion")
+                .takes_value(true)
+                .help("Set TCP_CONGESTION option, congestion control algorithm for inbound and outbound sockets"),
+        );
+        app = app.arg(
+            Arg::new("TCP_USER_TIMEOUT")
+                .long("tcp-user-timeout")
+                .takes_value(true)
+                .validator(validator::validate_u64)
+                .help("Set TCP_USER_TIMEOUT option in seconds for inbound and outbound sockets"),
+        );
     }
 
     #[cfg(target_os = "freebsd")]
@@ -281,6 +295,20 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        match matches.value_of_t::<String>("TCP_CONGESTION") {
+            Ok(congestion) => config.tcp_congestion = Some(congestion),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        match matches.value_of_t::<u64>("TCP_USER_TIMEOUT") {
+            Ok(timeout) => config.tcp_user_timeout = Some(Duration::from_secs(timeout)),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
         #[cfg(target_os = "freebsd")]
         match matches.value_of_t::<u32>("OUTBOUND_USER_COOKIE") {
             Ok(user_cookie) => config.outbound_user_cookie = Some(user_cookie),
@@ -401,6 +429,12 @@ pub fn main(matches: &ArgMatches) {
             Err(err) => err.exit(),
         }
 
+        match matches.value_of_t::<usize>("UDP_MAX_DATAGRAM") {
+            Ok(udp_max_datagram) => config.udp_max_datagram = Some(udp_max_datagram),
+            Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}
+            Err(err) => err.exit(),
+        }
+
         match matches.value_of_t::<u32>("INBOUND_SEND_BUFFER_SIZE") {
             Ok(bs) => config.inbound_send_buffer_size = Some(bs),
             Err(ref err) if err.kind() == ClapErrorKind::ArgumentNotFound => {}